@@ -0,0 +1,138 @@
+//! Compares the two read paths of a block: opening the block file for every
+//! point read (how it used to be done), and positioned reads on a read handle
+//! the block keeps open.
+//!
+//! Run with `cargo bench -p dumbdb`.
+use std::{
+    future::Future,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use dumbdb::{Block, BlockStorage, ColumnValue, Durability, Tuple};
+use rand::seq::SliceRandom;
+
+const TUPLES: i64 = 10_000;
+const READS: usize = 20_000;
+const CONCURRENT_TASKS: usize = 8;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let dir = std::env::temp_dir().join(format!("dumbdb-bench-read-path-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let block_path = dir.join("bench.dat");
+    std::fs::File::create(&block_path)?;
+
+    let (block, mut offsets) = setup_block(block_path).await?;
+    offsets.shuffle(&mut rand::thread_rng());
+    let offsets: Vec<u64> = offsets.into_iter().cycle().take(READS).collect();
+
+    println!("{} random point reads over {} tuples", READS, TUPLES);
+    let old = bench("reopen per read", || async {
+        for offset in &offsets {
+            block.seek_to_offset_reopening(*offset).await?;
+        }
+        Ok(())
+    })
+    .await?;
+    let new = bench("shared read handle", || async {
+        for offset in &offsets {
+            as_storage(&block).seek_to_offset(*offset).await?;
+        }
+        Ok(())
+    })
+    .await?;
+    report_speedup(old, new);
+
+    println!(
+        "{} random point reads from {} concurrent tasks",
+        READS, CONCURRENT_TASKS
+    );
+    let old = bench("reopen per read", || {
+        read_concurrently(&block, &offsets, |block, offset| async move {
+            Ok(block.seek_to_offset_reopening(offset).await?)
+        })
+    })
+    .await?;
+    let new = bench("shared read handle", || {
+        read_concurrently(&block, &offsets, |block, offset| async move {
+            Ok(as_storage(&block).seek_to_offset(offset).await?)
+        })
+    })
+    .await?;
+    report_speedup(old, new);
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+// write the tuples to the block; returns the offset of each of them
+async fn setup_block(block_path: PathBuf) -> anyhow::Result<(Block, Vec<u64>)> {
+    let block = Block::new(&block_path, Durability::NoSync)?;
+    let mut offsets = vec![];
+    let mut offset = 0;
+    for i in 0..TUPLES {
+        let tuple: Tuple = vec![
+            Some(ColumnValue::Integer(i)),
+            Some(ColumnValue::Text(format!("author number {}", i))),
+        ];
+        offsets.push(offset);
+        offset += as_storage(&block).write(tuple, None).await?.stored;
+    }
+    block.sync().await?;
+    Ok((block, offsets))
+}
+
+async fn read_concurrently<F, Fut>(block: &Block, offsets: &[u64], read: F) -> anyhow::Result<()>
+where
+    F: Fn(Block, u64) -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = anyhow::Result<Tuple>> + Send,
+{
+    let mut tasks = vec![];
+    for chunk in offsets.chunks(offsets.len().div_ceil(CONCURRENT_TASKS)) {
+        let block = block.clone();
+        let chunk = chunk.to_vec();
+        let read = read.clone();
+        tasks.push(tokio::spawn(async move {
+            for offset in chunk {
+                read(block.clone(), offset).await?;
+            }
+            anyhow::Ok(())
+        }));
+    }
+    for task in tasks {
+        task.await??;
+    }
+    Ok(())
+}
+
+// run the benchmark once to warm up, and then time the best of a few runs
+async fn bench<F, Fut>(name: &str, run: F) -> anyhow::Result<Duration>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = anyhow::Result<()>>,
+{
+    run().await?;
+    let mut best = Duration::MAX;
+    for _ in 0..5 {
+        let start = Instant::now();
+        run().await?;
+        best = best.min(start.elapsed());
+    }
+    println!(
+        "  {:<20} {:>10.2?} total, {:>8.2?} per read",
+        name,
+        best,
+        best / READS as u32
+    );
+    Ok(best)
+}
+
+// the typed API of a block is on its storage
+fn as_storage(block: &Block) -> &dyn BlockStorage {
+    block
+}
+
+fn report_speedup(old: Duration, new: Duration) {
+    println!("  speedup: {:.2}x", old.as_secs_f64() / new.as_secs_f64());
+}
@@ -0,0 +1,480 @@
+/// Storage backends of the database. The entries of a block are kept by a
+/// `BlockStorage`: `Block` keeps them in a file on disk, and `MemoryBlock` in
+/// memory. Everything else the database stores (the catalog, hint files, the
+/// write-ahead log) goes through its `Backend` too, so that a database can
+/// live entirely in memory.
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fmt::Debug,
+    io::{Cursor, ErrorKind, Read},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock},
+};
+
+use async_trait::async_trait;
+
+use crate::{
+    encryption::{
+        decrypt_entry, is_sealed, Cipher, EncryptedBlock, EncryptionError, EncryptionKey,
+    },
+    query::types::Durability,
+    storage::{
+        encode_entry, is_headerless, read_entry_at, stream_entries, Block, EntryDataStream,
+        EntryFlags, EntryHeader, FileHeader, PendingSync, StorageError, FILE_HEADER_SIZE,
+        TAIL_CHECKSUM_SIZE,
+    },
+};
+
+/// The storage of the entries of a block. This is the raw, append-only list of
+/// entries; the typed API (tuples, tombstones and records) is implemented on
+/// top of it, on `dyn BlockStorage`.
+#[async_trait]
+pub trait BlockStorage: Debug + Send + Sync {
+    /// The path of the block. Blocks which are not on disk have one too; it
+    /// names them.
+    fn path(&self) -> &Path;
+
+    fn durability(&self) -> Durability;
+
+    /// In group commit mode, the sync of the entries written to the block so
+    /// far, for the writer to wait for; `write_entry` doesn't wait for it in
+    /// this mode. `None` in the other modes, where `write_entry` syncs the
+    /// block itself (or doesn't sync it at all).
+    fn pending_sync(&self) -> Option<PendingSync> {
+        None
+    }
+
+    /// Append an entry with the given data, and return the number of bytes it
+    /// takes up in the block.
+    async fn write_entry(&self, data: Vec<u8>, flags: EntryFlags) -> Result<u64, StorageError>;
+
+    /// Read the data of the entry at the given byte-offset, along with its
+    /// header.
+    async fn read_entry(&self, offset: u64) -> Result<(Vec<u8>, EntryHeader), StorageError>;
+
+    /// Stream the entries of the block, starting at the given byte-offset.
+    /// Errors are reported the same way as in `get_entries_from`.
+    async fn scan_entries(&self, offset: u64) -> Result<EntryDataStream, StorageError>;
+
+    /// Truncate the block to the given length. Used to cut off a partially
+    /// written entry at the end of the block.
+    async fn truncate(&self, length: u64) -> Result<(), StorageError>;
+
+    /// Flush all the data written to the block to disk.
+    async fn sync(&self) -> Result<(), StorageError>;
+
+    /// Checksum of the bytes of the block right before the given length. This
+    /// is used to check that metadata recorded about the block (e.g. in a hint
+    /// file) is about this block, and not one it was replaced with.
+    async fn tail_checksum(&self, length: u64) -> Result<u32, StorageError>;
+}
+
+/// Where a database keeps its files, and whether they are encrypted.
+#[derive(Debug, Clone)]
+pub(crate) struct Backend {
+    files: Files,
+    // encrypts the blocks and the sealed files, if the database has an
+    // encryption key
+    cipher: Option<Arc<Cipher>>,
+}
+
+#[derive(Debug, Clone)]
+enum Files {
+    /// Files on disk
+    Disk,
+    /// Files in memory, which are gone once the database is dropped
+    Memory(MemoryFiles),
+}
+
+/// The files of an in-memory database, by path.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct MemoryFiles {
+    files: Arc<Mutex<HashMap<PathBuf, MemoryFile>>>,
+}
+
+// the contents of a file in memory; blocks opened on a file share it
+type MemoryFile = Arc<RwLock<Vec<u8>>>;
+
+/// A block kept in memory. It uses the same format as a block on disk.
+#[derive(Debug, Clone)]
+pub(crate) struct MemoryBlock {
+    path: PathBuf,
+    durability: Durability,
+    data: MemoryFile,
+    // see `Block`
+    is_headerless: bool,
+}
+
+impl Backend {
+    pub(crate) fn disk() -> Self {
+        Self {
+            files: Files::Disk,
+            cipher: None,
+        }
+    }
+
+    pub(crate) fn in_memory() -> Self {
+        Self {
+            files: Files::Memory(MemoryFiles::default()),
+            cipher: None,
+        }
+    }
+
+    /// A backend for files on disk, encrypted the same way as the files of
+    /// this one
+    pub(crate) fn on_disk(&self) -> Self {
+        Self {
+            files: Files::Disk,
+            cipher: self.cipher.clone(),
+        }
+    }
+
+    /// Encrypt the files with the given key, if any (see `encryption`).
+    pub(crate) fn with_encryption(self, key: Option<&EncryptionKey>) -> Self {
+        Self {
+            cipher: key.map(|key| Arc::new(Cipher::new(key))),
+            ..self
+        }
+    }
+
+    /// Open the block stored in the file at the given path. The block must be
+    /// in the current format, and belong to a table with the given schema
+    /// fingerprint. With an encryption key, its entries are encrypted.
+    pub(crate) fn open_block(
+        &self,
+        path: &Path,
+        durability: Durability,
+        schema_fingerprint: u32,
+    ) -> Result<Arc<dyn BlockStorage>, StorageError> {
+        self.open_temporary_block(path, path, durability, schema_fingerprint)
+    }
+
+    /// Same as `open_block`, for a block written under a temporary name, which
+    /// is renamed to `final_path` once it is complete. Its entries are
+    /// encrypted as entries of the file at `final_path`, so that they can be
+    /// read once it is renamed.
+    pub(crate) fn open_temporary_block(
+        &self,
+        path: &Path,
+        final_path: &Path,
+        durability: Durability,
+        schema_fingerprint: u32,
+    ) -> Result<Arc<dyn BlockStorage>, StorageError> {
+        let header = self
+            .read_file_header(path)?
+            .ok_or_else(|| StorageError::OutdatedFormat {
+                path: path.to_path_buf(),
+                version: 0,
+            })?;
+        if header.schema_fingerprint != schema_fingerprint {
+            return Err(StorageError::SchemaMismatch {
+                path: path.to_path_buf(),
+            });
+        }
+        let block = self.open_block_unchecked(path, durability)?;
+        Ok(match &self.cipher {
+            Some(cipher) => Arc::new(EncryptedBlock::new(
+                block,
+                cipher.clone(),
+                final_path,
+                self.file_length(path)?,
+            )),
+            None => block,
+        })
+    }
+
+    /// Open the block stored in the file at the given path, whatever format it
+    /// is in. Its entries are read and written as they are stored, i.e. they
+    /// are not encrypted or decrypted.
+    pub(crate) fn open_block_unchecked(
+        &self,
+        path: &Path,
+        durability: Durability,
+    ) -> Result<Arc<dyn BlockStorage>, StorageError> {
+        match &self.files {
+            Files::Disk => Ok(Arc::new(Block::new(path, durability)?)),
+            Files::Memory(files) => {
+                let data = files.get(path).map_err(StorageError::FileOpen)?;
+                let is_headerless = is_headerless(&read_lock(&data));
+                Ok(Arc::new(MemoryBlock {
+                    path: path.to_path_buf(),
+                    durability,
+                    data,
+                    is_headerless,
+                }))
+            }
+        }
+    }
+
+    /// Decrypt the entry at the given byte-offset, read from a block opened
+    /// with `open_block_unchecked`, the way a block opened with `open_block`
+    /// does. Without an encryption key, the entry is returned as it is.
+    pub(crate) fn decrypt_entry(
+        &self,
+        path: &Path,
+        offset: u64,
+        data: Vec<u8>,
+        header: EntryHeader,
+    ) -> Result<(Vec<u8>, EntryHeader), StorageError> {
+        match &self.cipher {
+            Some(cipher) => {
+                let file_name = path.file_name().unwrap_or_default().as_encoded_bytes();
+                decrypt_entry(cipher, path, file_name, offset, data, header)
+            }
+            None => Ok((data, header)),
+        }
+    }
+
+    /// Create an empty block, i.e. a file with just a file header; an existing
+    /// file is replaced. The file is created atomically, so it never exists
+    /// without its header.
+    pub(crate) fn create_block(&self, path: &Path, schema_fingerprint: u32) -> std::io::Result<()> {
+        self.replace_file(path, &FileHeader::new(schema_fingerprint).encode())
+    }
+
+    /// Read the file header of the block file at the given path. Returns
+    /// `None` for a block from before block files had a header.
+    pub(crate) fn read_file_header(&self, path: &Path) -> Result<Option<FileHeader>, StorageError> {
+        let bytes = match &self.files {
+            Files::Disk => {
+                let file = std::fs::File::open(path).map_err(StorageError::FileOpen)?;
+                let mut bytes = vec![];
+                file.take(FILE_HEADER_SIZE).read_to_end(&mut bytes)?;
+                bytes
+            }
+            Files::Memory(files) => {
+                let file = files.get(path).map_err(StorageError::FileOpen)?;
+                let data = read_lock(&file);
+                data[..data.len().min(FILE_HEADER_SIZE as usize)].to_vec()
+            }
+        };
+        FileHeader::decode(path, &bytes)
+    }
+
+    pub(crate) fn directory_exists(&self, path: &Path) -> bool {
+        match &self.files {
+            Files::Disk => path.is_dir(),
+            // directories are implied by the paths of the files in them
+            Files::Memory(_) => true,
+        }
+    }
+
+    pub(crate) fn file_exists(&self, path: &Path) -> bool {
+        match &self.files {
+            Files::Disk => path.exists(),
+            Files::Memory(files) => files.lock().contains_key(path),
+        }
+    }
+
+    pub(crate) fn file_length(&self, path: &Path) -> std::io::Result<u64> {
+        match &self.files {
+            Files::Disk => Ok(std::fs::metadata(path)?.len()),
+            Files::Memory(files) => Ok(read_lock(&files.get(path)?).len() as u64),
+        }
+    }
+
+    /// Names of the files in the given directory
+    pub(crate) fn list_directory(&self, path: &Path) -> std::io::Result<Vec<OsString>> {
+        match &self.files {
+            Files::Disk => std::fs::read_dir(path)?
+                .map(|dir_entry| dir_entry.map(|dir_entry| dir_entry.file_name()))
+                .collect(),
+            Files::Memory(files) => Ok(files
+                .lock()
+                .keys()
+                .filter(|file_path| file_path.parent() == Some(path))
+                .filter_map(|file_path| file_path.file_name().map(ToOwned::to_owned))
+                .collect()),
+        }
+    }
+
+    pub(crate) fn remove_file(&self, path: &Path) -> std::io::Result<()> {
+        match &self.files {
+            Files::Disk => std::fs::remove_file(path),
+            Files::Memory(files) => match files.lock().remove(path) {
+                Some(_) => Ok(()),
+                None => Err(ErrorKind::NotFound.into()),
+            },
+        }
+    }
+
+    /// Rename a file, replacing the file at the destination if there is one.
+    /// Blocks opened on either file keep seeing the contents they were opened
+    /// on, like files on disk do.
+    pub(crate) fn rename(&self, from: &Path, to: &Path) -> std::io::Result<()> {
+        match &self.files {
+            Files::Disk => std::fs::rename(from, to),
+            Files::Memory(files) => {
+                let mut files = files.lock();
+                let file = files.remove(from).ok_or(ErrorKind::NotFound)?;
+                files.insert(to.to_path_buf(), file);
+                Ok(())
+            }
+        }
+    }
+
+    /// Sync the directory containing the given path, which makes files created
+    /// (or renamed) in it durable.
+    pub(crate) fn sync_parent_dir(&self, path: &Path) -> std::io::Result<()> {
+        match &self.files {
+            Files::Disk => {
+                if let Some(parent) = path.parent() {
+                    std::fs::File::open(parent)?.sync_all()?;
+                }
+                Ok(())
+            }
+            Files::Memory(_) => Ok(()),
+        }
+    }
+
+    pub(crate) fn read_file(&self, path: &Path) -> std::io::Result<Vec<u8>> {
+        match &self.files {
+            Files::Disk => std::fs::read(path),
+            Files::Memory(files) => {
+                let file = files.get(path)?;
+                let data = read_lock(&file).clone();
+                Ok(data)
+            }
+        }
+    }
+
+    /// Seal the contents of a file at the given path, i.e. encrypt them with
+    /// the encryption key, if any; to be read back with `unseal_file`.
+    pub(crate) fn seal_file(&self, path: &Path, contents: Vec<u8>) -> Vec<u8> {
+        match &self.cipher {
+            Some(cipher) => cipher.seal_file(path, &contents),
+            None => contents,
+        }
+    }
+
+    /// The contents of a file sealed by `seal_file`
+    pub(crate) fn unseal_file(
+        &self,
+        path: &Path,
+        contents: Vec<u8>,
+    ) -> Result<Vec<u8>, EncryptionError> {
+        match &self.cipher {
+            Some(cipher) => cipher.unseal_file(path, &contents),
+            None if is_sealed(&contents) => Err(EncryptionError::KeyRequired {
+                path: path.to_path_buf(),
+            }),
+            None => Ok(contents),
+        }
+    }
+
+    /// Replace the contents of a file atomically: the contents are written to
+    /// a temporary file, which is then renamed over the file. So a crash never
+    /// leaves a partially written file behind.
+    pub(crate) fn replace_file(&self, path: &Path, contents: &[u8]) -> std::io::Result<()> {
+        match &self.files {
+            Files::Disk => {
+                let mut temp_file_name = path.file_name().unwrap_or_default().to_os_string();
+                temp_file_name.push(".tmp");
+                let temp_path = path.with_file_name(temp_file_name);
+                let mut file = std::fs::File::create(&temp_path)?;
+                std::io::Write::write_all(&mut file, contents)?;
+                file.sync_all()?;
+                std::fs::rename(&temp_path, path)
+            }
+            Files::Memory(files) => {
+                let file = Arc::new(RwLock::new(contents.to_vec()));
+                files.lock().insert(path.to_path_buf(), file);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl MemoryFiles {
+    fn lock(&self) -> MutexGuard<'_, HashMap<PathBuf, MemoryFile>> {
+        self.files.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn get(&self, path: &Path) -> std::io::Result<MemoryFile> {
+        self.lock()
+            .get(path)
+            .cloned()
+            .ok_or_else(|| ErrorKind::NotFound.into())
+    }
+}
+
+#[async_trait]
+impl BlockStorage for MemoryBlock {
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn durability(&self) -> Durability {
+        self.durability
+    }
+
+    async fn write_entry(&self, data: Vec<u8>, flags: EntryFlags) -> Result<u64, StorageError> {
+        let buffer = encode_entry(&data, flags);
+        write_lock(&self.data).extend_from_slice(&buffer);
+        Ok(buffer.len() as u64)
+    }
+
+    async fn read_entry(&self, offset: u64) -> Result<(Vec<u8>, EntryHeader), StorageError> {
+        let data = read_lock(&self.data);
+        let block_length = data.len() as u64;
+        read_entry_at(
+            &self.path,
+            offset,
+            block_length,
+            self.is_headerless,
+            |buf, position| {
+                let start = usize::try_from(position).unwrap_or(usize::MAX);
+                let bytes = data
+                    .get(start..)
+                    .and_then(|bytes| bytes.get(..buf.len()))
+                    .ok_or(ErrorKind::UnexpectedEof)?;
+                buf.copy_from_slice(bytes);
+                Ok(())
+            },
+        )
+    }
+
+    async fn scan_entries(&self, offset: u64) -> Result<EntryDataStream, StorageError> {
+        // the stream reads a copy of the block, as it was when the stream was
+        // opened; like on disk, anything written after that is not read
+        let data = read_lock(&self.data);
+        let length = data.len() as u64;
+        let rest = data.get(offset as usize..).unwrap_or_default().to_vec();
+        Ok(stream_entries(
+            Cursor::new(rest),
+            self.path.clone(),
+            offset,
+            length,
+            self.is_headerless,
+        ))
+    }
+
+    async fn truncate(&self, length: u64) -> Result<(), StorageError> {
+        write_lock(&self.data).truncate(length as usize);
+        Ok(())
+    }
+
+    async fn sync(&self) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    async fn tail_checksum(&self, length: u64) -> Result<u32, StorageError> {
+        let start = length.saturating_sub(TAIL_CHECKSUM_SIZE);
+        let data = read_lock(&self.data);
+        let bytes = data
+            .get(start as usize..length as usize)
+            .ok_or(std::io::Error::from(ErrorKind::UnexpectedEof))?;
+        Ok(crc32fast::hash(bytes))
+    }
+}
+
+// neither the files nor their contents are ever left half updated, so they are
+// fine to use after a panic while they were locked
+fn read_lock(file: &MemoryFile) -> std::sync::RwLockReadGuard<'_, Vec<u8>> {
+    file.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn write_lock(file: &MemoryFile) -> std::sync::RwLockWriteGuard<'_, Vec<u8>> {
+    file.write().unwrap_or_else(PoisonError::into_inner)
+}
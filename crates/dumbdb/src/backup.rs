@@ -0,0 +1,359 @@
+/// Online backups of a database. A backup is a consistent snapshot of the
+/// database: its catalog, and the block of every table up to the offset it was
+/// at when the backup started. As blocks are append-only, anything written
+/// after that is simply left out, so writes can go on while the backup is
+/// being written.
+///
+/// A backup happens in two steps, like a compaction:
+///
+/// 1. `Backup::new` records the length of every segment of every table, and
+///    opens the segment files (needs a shared reference to the database).
+///    Holding on to the files keeps the backup intact even if a compaction
+///    swaps them out in the meantime.
+/// 2. `Backup::write` copies the recorded entries of the segments, and the
+///    catalog, to the backup directory. This doesn't need any reference to the
+///    database.
+///
+/// The backup directory also gets a manifest (`backup.json`), with the length
+/// and checksum of every file of the backup; a restore checks the backup
+/// against it before it copies the backup into a new database directory. The
+/// write-ahead log and the hint files are not backed up: everything in the
+/// write-ahead log is already applied to the tables, and the indexes are
+/// rebuilt when the restored database is opened. Files of an encrypted
+/// database stay encrypted in the backup, and so does the manifest.
+///
+/// The blob files of a table, and the SSTables of an LSM table, are copied
+/// the same way as segments. Its
+/// memtable is written to an SSTable of its own in the backup, along with the
+/// tree's manifest.
+use std::{
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    backend::{Backend, BlockStorage},
+    catalog::{Catalog, CATALOG_FILE_NAME},
+    encryption::EncryptionError,
+    lsm::{LsmError, LsmSnapshot},
+    query::types::{Durability, TableDefinition, TableName},
+    storage::{StorageError, FILE_HEADER_SIZE},
+    table::{get_table_path_, TableStorage},
+};
+
+/// Name of the manifest of a backup, in the backup directory
+const MANIFEST_FILE_NAME: &str = "backup.json";
+
+/// A backup of a database, which is in progress.
+#[derive(Debug)]
+pub struct Backup {
+    /// Where the files of the database are kept
+    backend: Backend,
+    /// When the backup was started, in seconds since the Unix epoch
+    created_at: u64,
+    /// Contents of the catalog file, as it is stored; `None` if the database
+    /// doesn't have one yet
+    catalog: Option<Vec<u8>>,
+    tables: Vec<TableSnapshot>,
+}
+
+// the segments of a table (or the SSTables of an LSM table), as they were
+// when the backup started
+#[derive(Debug)]
+struct TableSnapshot {
+    name: TableName,
+    schema_fingerprint: u32,
+    segments: Vec<SegmentSnapshot>,
+    /// The memtable and manifest of an LSM table
+    tree: Option<LsmSnapshot>,
+}
+
+#[derive(Debug)]
+struct SegmentSnapshot {
+    file_name: String,
+    /// The segment file, opened when the backup started; its entries are read
+    /// as they are stored
+    block: Arc<dyn BlockStorage>,
+    /// Length of the segment when the backup started
+    length: u64,
+}
+
+/// What a backup contains. It is written to the backup directory, next to the
+/// backed up files.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupManifest {
+    /// When the backup was started, in seconds since the Unix epoch
+    pub created_at: u64,
+    /// The catalog; `None` if the database didn't have any tables yet
+    pub catalog: Option<BackupFile>,
+    pub tables: Vec<TableBackup>,
+}
+
+/// The segment files of a table in a backup
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TableBackup {
+    pub name: TableName,
+    /// Ordered by segment, and then the blob files; for an LSM table, its
+    /// SSTables and then its manifest
+    pub segments: Vec<BackupFile>,
+}
+
+/// A file in a backup
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupFile {
+    pub file_name: String,
+    /// Length of the file in bytes; for a segment, the offset its block was
+    /// backed up to
+    pub length: u64,
+    /// CRC32 of the contents of the file
+    pub checksum: u32,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum BackupError {
+    #[error("Directory {0} is not empty.")]
+    DirectoryNotEmpty(PathBuf),
+    #[error("Backup file {0} is missing.")]
+    MissingFile(PathBuf),
+    #[error(
+        "Backup file {0} is corrupt: its length or checksum doesn't match the backup manifest."
+    )]
+    CorruptFile(PathBuf),
+    #[error("Backup manifest is invalid. {0}")]
+    InvalidManifest(#[from] serde_json::Error),
+    #[error("Backup manifest names an invalid file: {0:?}. File names can't be paths.")]
+    InvalidFileName(String),
+    #[error("Invalid backup name: {0:?}. A backup is named by a directory name, not a path.")]
+    InvalidBackupName(String),
+    #[error("{0}")]
+    Encryption(#[from] EncryptionError),
+    #[error("Internal Storage Engine Error: {0}")]
+    StorageError(#[from] StorageError),
+    #[error("Internal Storage Engine Error: {0}")]
+    LsmError(#[from] LsmError),
+    #[error("Backup file operation failed. {0}")]
+    FileOperation(#[from] std::io::Error),
+}
+
+impl Backup {
+    /// Start a backup of the database with the given catalog, by recording
+    /// where its tables' blocks end.
+    pub(crate) fn new(catalog: &Catalog) -> Result<Self, BackupError> {
+        let backend = catalog.backend().clone();
+        let catalog_path = catalog.directory_path.join(CATALOG_FILE_NAME);
+        let catalog_contents = if backend.file_exists(&catalog_path) {
+            Some(backend.read_file(&catalog_path)?)
+        } else {
+            None
+        };
+
+        let mut tables = vec![];
+        for table_name in catalog.list_tables() {
+            let Some(table) = catalog.get_table(&table_name) else {
+                continue;
+            };
+            let (files, tree) = match &table.storage {
+                TableStorage::Log(table_buffer) => {
+                    let mut files: Vec<_> = table_buffer
+                        .block
+                        .segments
+                        .values()
+                        .map(|segment| (segment.block.path(), segment.length))
+                        .collect();
+                    files.extend(table_buffer.blobs.files());
+                    (files, None)
+                }
+                TableStorage::Lsm(tree) => (tree.sstable_files(), Some(tree.snapshot())),
+            };
+            let mut segments = vec![];
+            for (path, length) in files {
+                segments.push(SegmentSnapshot {
+                    file_name: file_name(path),
+                    block: backend.open_block_unchecked(path, Durability::NoSync)?,
+                    length,
+                });
+            }
+            let definition: TableDefinition = table.into();
+            tables.push(TableSnapshot {
+                name: table_name,
+                schema_fingerprint: definition.schema_fingerprint(),
+                segments,
+                tree,
+            });
+        }
+
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        Ok(Self {
+            backend,
+            created_at,
+            catalog: catalog_contents,
+            tables,
+        })
+    }
+
+    /// Write the backup to the given directory, which must be empty (or not
+    /// exist yet). Returns the manifest of the backup.
+    pub async fn write(&self, directory_path: &Path) -> Result<BackupManifest, BackupError> {
+        let backend = self.backend.on_disk();
+        create_empty_directory(directory_path)?;
+
+        let mut tables = vec![];
+        for table in &self.tables {
+            let mut segments = vec![];
+            for segment in &table.segments {
+                let path = directory_path.join(&segment.file_name);
+                copy_segment(&backend, segment, &path, table.schema_fingerprint).await?;
+                segments.push(describe_file(&backend, &path)?);
+            }
+            if let Some(tree) = &table.tree {
+                let table_path = get_table_path_(directory_path, &table.name);
+                for path in tree.write(&backend, &table_path).await? {
+                    segments.push(describe_file(&backend, &path)?);
+                }
+            }
+            tables.push(TableBackup {
+                name: table.name.clone(),
+                segments,
+            });
+        }
+        let catalog = match &self.catalog {
+            Some(contents) => {
+                let path = directory_path.join(CATALOG_FILE_NAME);
+                backend.replace_file(&path, contents)?;
+                Some(describe_file(&backend, &path)?)
+            }
+            None => None,
+        };
+
+        let manifest = BackupManifest {
+            created_at: self.created_at,
+            catalog,
+            tables,
+        };
+        let manifest_path = directory_path.join(MANIFEST_FILE_NAME);
+        let contents = backend.seal_file(&manifest_path, serde_json::to_vec(&manifest)?);
+        backend.replace_file(&manifest_path, &contents)?;
+        backend.sync_parent_dir(&manifest_path)?;
+        Ok(manifest)
+    }
+}
+
+/// The directory of the backup with the given name, in a directory of backups.
+/// The name must be a single directory name, so that the backup can't be
+/// written anywhere else.
+pub fn backup_path(backups_path: &Path, name: &str) -> Result<PathBuf, BackupError> {
+    if !is_file_name(name) {
+        return Err(BackupError::InvalidBackupName(name.to_string()));
+    }
+    Ok(backups_path.join(name))
+}
+
+// whether the name is the name of a file (or directory), rather than a path;
+// i.e. it is neither absolute, nor `..`, nor has more than one component
+fn is_file_name(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    )
+}
+
+/// Restore the backup in the given directory to a new database directory,
+/// which must be empty (or not exist yet). Every file of the backup is checked
+/// against the manifest before anything is copied. `backend` is the backend the
+/// restored database is opened with (i.e. with its encryption key).
+pub(crate) fn restore_backup(
+    backend: &Backend,
+    backup_path: &Path,
+    directory_path: &Path,
+) -> Result<BackupManifest, BackupError> {
+    let manifest_path = backup_path.join(MANIFEST_FILE_NAME);
+    let contents = read_backup_file(backend, &manifest_path)?;
+    let manifest: BackupManifest =
+        serde_json::from_slice(&backend.unseal_file(&manifest_path, contents)?)?;
+
+    let files = manifest
+        .catalog
+        .iter()
+        .chain(manifest.tables.iter().flat_map(|table| &table.segments));
+    let mut verified = vec![];
+    for file in files {
+        // the names come from the manifest, so they must not lead out of the
+        // backup directory, nor out of the directory it's restored to
+        if !is_file_name(&file.file_name) {
+            return Err(BackupError::InvalidFileName(file.file_name.clone()));
+        }
+        let path = backup_path.join(&file.file_name);
+        let contents = read_backup_file(backend, &path)?;
+        if contents.len() as u64 != file.length || crc32fast::hash(&contents) != file.checksum {
+            return Err(BackupError::CorruptFile(path));
+        }
+        verified.push((&file.file_name, contents));
+    }
+
+    create_empty_directory(directory_path)?;
+    for (file_name, contents) in verified {
+        backend.replace_file(&directory_path.join(file_name), &contents)?;
+    }
+    backend.sync_parent_dir(&directory_path.join(MANIFEST_FILE_NAME))?;
+    Ok(manifest)
+}
+
+// copy the entries of a segment, up to the length it had when the backup
+// started, to a new block file. The entries are read one by one from the file
+// the segment was opened on, so that they come from that file even if it has
+// been replaced since.
+async fn copy_segment(
+    backend: &Backend,
+    segment: &SegmentSnapshot,
+    path: &Path,
+    schema_fingerprint: u32,
+) -> Result<(), BackupError> {
+    backend.create_block(path, schema_fingerprint)?;
+    let copy = backend.open_block_unchecked(path, Durability::NoSync)?;
+    let mut offset = FILE_HEADER_SIZE;
+    while offset < segment.length {
+        let (data, header) = segment.block.read_entry(offset).await?;
+        copy.write_entry(data, header.flags()).await?;
+        offset += header.entry_size();
+    }
+    copy.sync().await?;
+    Ok(())
+}
+
+fn describe_file(backend: &Backend, path: &Path) -> Result<BackupFile, BackupError> {
+    let contents = backend.read_file(path)?;
+    Ok(BackupFile {
+        file_name: file_name(path),
+        length: contents.len() as u64,
+        checksum: crc32fast::hash(&contents),
+    })
+}
+
+fn read_backup_file(backend: &Backend, path: &Path) -> Result<Vec<u8>, BackupError> {
+    backend.read_file(path).map_err(|err| match err.kind() {
+        std::io::ErrorKind::NotFound => BackupError::MissingFile(path.to_path_buf()),
+        _ => err.into(),
+    })
+}
+
+fn create_empty_directory(path: &Path) -> Result<(), BackupError> {
+    if path.exists() && std::fs::read_dir(path)?.next().is_some() {
+        return Err(BackupError::DirectoryNotEmpty(path.to_path_buf()));
+    }
+    std::fs::create_dir_all(path)?;
+    Ok(())
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned()
+}
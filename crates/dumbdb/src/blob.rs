@@ -0,0 +1,468 @@
+/// Out-of-line storage of large values. A `Text` value longer than
+/// `DatabaseOptions::blob_threshold_bytes` is not stored in its tuple, but in a
+/// blob file of its table, and the tuple keeps a `ColumnValue::Blob` reference
+/// to it. Scans, updates and compaction then move the reference around instead
+/// of the value. References are resolved to the values they refer to when
+/// records are built from tuples (see `BlobStore::resolve`).
+///
+/// The blob files of a table are numbered, and stored next to its block (i.e.
+/// `<table>.dat.blob.<id>`); a new blob file never reuses the id of another,
+/// even one started by a compaction which is yet to finish. Values are
+/// appended to the last one (the active blob file), each as an entry of its
+/// own, so that they are checksummed, compressed and encrypted like tuples. A blob file is only ever read at the
+/// offsets tuples refer to.
+///
+/// A value is never removed from a blob file. Instead, compaction copies the
+/// values the live tuples refer to into a new blob file (see `BlobCopy`), which
+/// becomes the active one, and the older blob files are retired. They are
+/// removed at the next checkpoint, once the write-ahead log doesn't refer to
+/// them anymore.
+///
+/// Only tables stored in a log have blob files; the tuples of LSM tables keep
+/// their values inline.
+use std::{
+    collections::{btree_map::Entry, BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use futures::StreamExt;
+
+use crate::{
+    backend::{Backend, BlockStorage},
+    query::types::{BlobRef, ColumnValue, Compression, Durability},
+    storage::{
+        calculate_new_offset, decode_entry, BlockEntry, PendingSync, StorageError, Tuple,
+        FILE_HEADER_SIZE,
+    },
+};
+
+/// Infix of the file names of blob files, between the block's file name and
+/// the id of the blob file.
+const BLOB_FILE_INFIX: &str = "blob";
+
+/// The blob files of a table.
+#[derive(Debug, Clone)]
+pub(crate) struct BlobStore {
+    /// Where the files of the table are kept
+    backend: Backend,
+    /// Path of the table's block; the blob files are named after it
+    base_path: PathBuf,
+    durability: Durability,
+    /// Fingerprint of the table's schema, in the file header of every blob
+    /// file
+    schema_fingerprint: u32,
+    /// How values are compressed
+    compression: Option<Compression>,
+    /// Text values longer than this (in bytes) are stored out of line; none are
+    /// if not set
+    threshold: Option<u64>,
+    /// The blob files, by id; the last one is the active one
+    files: BTreeMap<u64, BlobFile>,
+    /// Id of the next blob file to start, shared with the copies made by
+    /// compactions, so that a copy and the table don't start the same file
+    next_id: Arc<AtomicU64>,
+    /// Blob files which no tuple refers to anymore, to be removed at the next
+    /// checkpoint
+    retired: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone)]
+struct BlobFile {
+    block: Arc<dyn BlockStorage>,
+    length: u64,
+}
+
+/// A copy of the values which the live tuples of a table refer to, into a new
+/// blob file, made by a compaction of the table. The copy of a tuple refers to
+/// the new blob file.
+#[derive(Debug)]
+pub(crate) struct BlobCopy {
+    store: BlobStore,
+    /// Id of the new blob file
+    id: u64,
+    /// The new blob file, once a value is copied to it
+    file: Option<BlobFile>,
+    /// Where the values copied so far were, and where they are now
+    copied: HashMap<BlobRef, BlobRef>,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum BlobError {
+    #[error("Blob file {id} of table block {path} not found.")]
+    MissingFile { path: PathBuf, id: u64 },
+    #[error("Entry at byte-offset {offset} in blob file {path} is not a value.")]
+    NotAValue { path: PathBuf, offset: u64 },
+    #[error("Internal Storage Engine Error: {0}")]
+    StorageError(#[from] StorageError),
+    #[error("Blob file operation failed. {0}")]
+    FileOperation(#[from] std::io::Error),
+}
+
+impl BlobStore {
+    /// Open the blob files of the table whose block is at the given path.
+    pub(crate) fn open(
+        backend: &Backend,
+        base_path: &Path,
+        durability: Durability,
+        schema_fingerprint: u32,
+        compression: Option<Compression>,
+        threshold: Option<u64>,
+    ) -> Result<Self, BlobError> {
+        let mut files = BTreeMap::new();
+        for (id, path) in find_blob_files(backend, base_path)? {
+            let file = BlobFile {
+                block: backend.open_block(&path, durability, schema_fingerprint)?,
+                length: backend.file_length(&path)?,
+            };
+            files.insert(id, file);
+        }
+        let next_id = files.keys().next_back().map_or(0, |id| id + 1);
+        Ok(Self {
+            backend: backend.clone(),
+            base_path: base_path.to_path_buf(),
+            durability,
+            schema_fingerprint,
+            compression,
+            threshold,
+            files,
+            next_id: Arc::new(AtomicU64::new(next_id)),
+            retired: vec![],
+        })
+    }
+
+    /// Cut off a partially written value (e.g. from a crash in the middle of
+    /// a write) at the end of the active blob file, so that it can be read
+    /// entry by entry. Only the part of it after the given length (from the
+    /// hint file) is read, if it is at least that long.
+    pub(crate) async fn recover(&mut self, hinted: Option<(u64, u64)>) -> Result<(), BlobError> {
+        let Some((id, file)) = self.files.iter_mut().next_back() else {
+            return Ok(());
+        };
+        let start = match hinted {
+            Some((hinted_id, length)) if hinted_id == *id && length <= file.length => length,
+            _ => FILE_HEADER_SIZE,
+        };
+        let mut stream = file.block.scan_entries(start).await?;
+        while let Some(result) = stream.next().await {
+            match result {
+                Ok(_) => (),
+                Err(StorageError::TornTail { offset, .. }) => {
+                    drop(stream);
+                    file.block.truncate(offset).await?;
+                    file.length = offset;
+                    return Ok(());
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Id and length of the active blob file, for the hint file
+    pub(crate) fn hint(&self) -> Option<(u64, u64)> {
+        self.files
+            .iter()
+            .next_back()
+            .map(|(id, file)| (*id, file.length))
+    }
+
+    /// Move the values of the tuple which are too long out of line, except for
+    /// the primary key.
+    pub(crate) async fn store(
+        &mut self,
+        tuple: &mut Tuple,
+        pk_position: usize,
+    ) -> Result<(), BlobError> {
+        let Some(threshold) = self.threshold else {
+            return Ok(());
+        };
+        for (position, value) in tuple.iter_mut().enumerate() {
+            let is_long =
+                matches!(value, Some(ColumnValue::Text(text)) if text.len() as u64 > threshold);
+            if position == pk_position || !is_long {
+                continue;
+            }
+            if let Some(text) = value.take() {
+                *value = Some(ColumnValue::Blob(self.write(text).await?));
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace the references in the tuple with the values they refer to, for
+    /// the columns at the positions `needs` is true for.
+    pub(crate) async fn resolve(
+        &self,
+        tuple: &mut Tuple,
+        needs: impl Fn(usize) -> bool,
+    ) -> Result<(), BlobError> {
+        for (position, value) in tuple.iter_mut().enumerate() {
+            if let Some(ColumnValue::Blob(blob)) = value {
+                if needs(position) {
+                    *value = Some(self.read(*blob).await?);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Sync the active blob file.
+    pub(crate) async fn sync(&self) -> Result<(), BlobError> {
+        if let Some(file) = self.files.values().next_back() {
+            file.block.sync().await?;
+        }
+        Ok(())
+    }
+
+    /// The sync of the values written to the active blob file, in group
+    /// commit mode.
+    pub(crate) fn pending_sync(&self) -> Option<PendingSync> {
+        self.files
+            .values()
+            .next_back()
+            .and_then(|file| file.block.pending_sync())
+    }
+
+    /// Size of the blob files, in bytes
+    pub(crate) fn total_bytes(&self) -> u64 {
+        self.files.values().map(|file| file.length).sum()
+    }
+
+    /// The blob files and their lengths, e.g. for a backup
+    pub(crate) fn files(&self) -> Vec<(&Path, u64)> {
+        self.files
+            .values()
+            .map(|file| (file.block.path(), file.length))
+            .collect()
+    }
+
+    /// Start copying the values live tuples refer to into a new blob file.
+    pub(crate) fn start_copy(&self) -> BlobCopy {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        BlobCopy {
+            store: self.clone(),
+            id,
+            file: None,
+            copied: HashMap::new(),
+        }
+    }
+
+    /// Make the blob file of the copy the active one. With `retire`, every
+    /// other blob file is retired, as no tuple refers to them anymore.
+    pub(crate) fn finish_copy(&mut self, copy: BlobCopy, retire: bool) {
+        if retire {
+            let retired = std::mem::take(&mut self.files);
+            // the copy's blob file is never removed, whatever else is
+            self.retired.extend(
+                retired
+                    .into_iter()
+                    .filter(|(id, _)| *id != copy.id)
+                    .map(|(_, file)| file.block.path().to_path_buf()),
+            );
+        }
+        if let Some(file) = copy.file {
+            self.files.insert(copy.id, file);
+        }
+    }
+
+    /// Remove the retired blob files.
+    pub(crate) fn remove_retired(&mut self) -> Result<(), std::io::Error> {
+        for path in self.retired.drain(..) {
+            match self.backend.remove_file(&path) {
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+                result => result?,
+            }
+        }
+        Ok(())
+    }
+
+    async fn read(&self, blob: BlobRef) -> Result<ColumnValue, BlobError> {
+        let file = self.files.get(&blob.file).ok_or(BlobError::MissingFile {
+            path: self.base_path.clone(),
+            id: blob.file,
+        })?;
+        read_blob(file.block.as_ref(), blob.offset).await
+    }
+
+    // append a value to the active blob file, starting one if there is none
+    async fn write(&mut self, value: ColumnValue) -> Result<BlobRef, BlobError> {
+        let id = match self.files.keys().next_back() {
+            Some(id) => *id,
+            None => self.next_id.fetch_add(1, Ordering::SeqCst),
+        };
+        let file = match self.files.entry(id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(create_blob_file(
+                &self.backend,
+                &self.base_path,
+                id,
+                self.durability,
+                self.schema_fingerprint,
+            )?),
+        };
+        let size = file
+            .block
+            .write(vec![Some(value)], self.compression)
+            .await?;
+        let blob = BlobRef {
+            file: id,
+            offset: file.length,
+        };
+        file.length = calculate_new_offset(size.stored, file.length);
+        Ok(blob)
+    }
+}
+
+impl BlobCopy {
+    /// Copy the values the tuple refers to (unless they are copied already),
+    /// and point the tuple to the copies. Returns whether the tuple refers to
+    /// any values.
+    pub(crate) async fn copy(&mut self, tuple: &mut Tuple) -> Result<bool, BlobError> {
+        let mut has_blobs = false;
+        for value in tuple.iter_mut() {
+            let Some(ColumnValue::Blob(blob)) = value else {
+                continue;
+            };
+            has_blobs = true;
+            let copy = match self.copied.get(blob) {
+                Some(copy) => *copy,
+                None => {
+                    let copy = self.write(self.store.read(*blob).await?).await?;
+                    self.copied.insert(*blob, copy);
+                    copy
+                }
+            };
+            *blob = copy;
+        }
+        Ok(has_blobs)
+    }
+
+    /// Read the values tuples refer to from the table's current blob files;
+    /// tuples written since the copy started may refer to a blob file which
+    /// didn't exist yet.
+    pub(crate) fn update_sources(&mut self, store: &BlobStore) {
+        self.store.files = store.files.clone();
+    }
+
+    pub(crate) async fn sync(&self) -> Result<(), BlobError> {
+        if let Some(file) = &self.file {
+            file.block.sync().await?;
+        }
+        Ok(())
+    }
+
+    /// Remove the new blob file, if the copy is not used.
+    pub(crate) fn discard(self) -> Result<(), std::io::Error> {
+        match &self.file {
+            Some(file) => self.store.backend.remove_file(file.block.path()),
+            None => Ok(()),
+        }
+    }
+
+    // append a value to the new blob file, creating it if this is the first
+    async fn write(&mut self, value: ColumnValue) -> Result<BlobRef, BlobError> {
+        let store = &self.store;
+        let file = match &mut self.file {
+            Some(file) => file,
+            // the new blob file is synced once, when the copy is finished
+            None => self.file.insert(create_blob_file(
+                &store.backend,
+                &store.base_path,
+                self.id,
+                Durability::NoSync,
+                store.schema_fingerprint,
+            )?),
+        };
+        let size = file
+            .block
+            .write(vec![Some(value)], store.compression)
+            .await?;
+        let blob = BlobRef {
+            file: self.id,
+            offset: file.length,
+        };
+        file.length = calculate_new_offset(size.stored, file.length);
+        Ok(blob)
+    }
+}
+
+/// Read the value at the given byte-offset of a blob file.
+pub(crate) async fn read_blob(
+    block: &dyn BlockStorage,
+    offset: u64,
+) -> Result<ColumnValue, BlobError> {
+    let (data, header) = block.read_entry(offset).await?;
+    match decode_entry(block.path(), data, &header)? {
+        BlockEntry::Tuple(mut values) if values.len() == 1 => {
+            values.pop().flatten().ok_or(BlobError::NotAValue {
+                path: block.path().to_path_buf(),
+                offset,
+            })
+        }
+        _ => Err(BlobError::NotAValue {
+            path: block.path().to_path_buf(),
+            offset,
+        }),
+    }
+}
+
+/// The blob files of the table whose block is at the given path, by id.
+pub(crate) fn find_blob_files(
+    backend: &Backend,
+    base_path: &Path,
+) -> Result<Vec<(u64, PathBuf)>, std::io::Error> {
+    let (Some(directory), Some(base_name)) = (base_path.parent(), base_path.file_name()) else {
+        return Ok(vec![]);
+    };
+    let prefix = format!("{}.{}.", base_name.to_string_lossy(), BLOB_FILE_INFIX);
+    let mut files = vec![];
+    for file_name in backend.list_directory(directory)? {
+        let id = file_name
+            .to_str()
+            .and_then(|name| name.strip_prefix(&prefix))
+            .and_then(|suffix| {
+                let id = suffix.parse::<u64>().ok()?;
+                (id.to_string() == suffix).then_some(id)
+            });
+        if let Some(id) = id {
+            files.push((id, get_blob_path(base_path, id)));
+        }
+    }
+    Ok(files)
+}
+
+/// Remove the blob files of the table whose block is at the given path.
+pub(crate) fn remove_blob_files(backend: &Backend, base_path: &Path) -> Result<(), std::io::Error> {
+    for (_, path) in find_blob_files(backend, base_path)? {
+        backend.remove_file(&path)?;
+    }
+    Ok(())
+}
+
+fn get_blob_path(base_path: &Path, id: u64) -> PathBuf {
+    let mut file_name = base_path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(format!(".{}.{}", BLOB_FILE_INFIX, id));
+    base_path.with_file_name(file_name)
+}
+
+// create an empty blob file, replacing any file left over by a compaction
+// which failed
+fn create_blob_file(
+    backend: &Backend,
+    base_path: &Path,
+    id: u64,
+    durability: Durability,
+    schema_fingerprint: u32,
+) -> Result<BlobFile, BlobError> {
+    let path = get_blob_path(base_path, id);
+    backend.create_block(&path, schema_fingerprint)?;
+    backend.sync_parent_dir(&path)?;
+    Ok(BlobFile {
+        block: backend.open_block(&path, durability, schema_fingerprint)?,
+        length: FILE_HEADER_SIZE,
+    })
+}
@@ -1,17 +1,21 @@
 use std::{
-    fs::File,
-    io::{BufReader, BufWriter},
     path::{Path, PathBuf},
+    sync::{atomic::AtomicBool, Arc},
 };
 
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    query::types::{ColumnDefinition, ColumnName, TableDefinition, TableName},
-    table::{self, TableBuffer, TableBufferError},
+    backend::Backend,
+    encryption::EncryptionError,
+    query::types::{
+        ColumnDefinition, ColumnName, Compression, Durability, StorageEngine, TableDefinition,
+        TableName,
+    },
+    table::{self, TableBufferError, TableOptions, TableStats, TableStorage, TailRecovery},
 };
 
-const CATALOG_FILE_NAME: &str = "catalog.json";
+pub(crate) const CATALOG_FILE_NAME: &str = "catalog.json";
 
 /// Internal metadata of what tables are there, their schema etc. that we can
 /// serialize to disk.
@@ -24,9 +28,11 @@ pub(crate) struct SerializableCatalog {
 /// in memory. Contains computed in-memory state like indexes.
 #[derive(Debug, Clone)]
 pub(crate) struct Catalog {
-    directory_path: PathBuf,
+    pub(crate) directory_path: PathBuf,
     catalog_path: PathBuf,
     tables: Vec<Table>,
+    /// Storage options of the tables
+    table_options: TableOptions,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -39,27 +45,25 @@ pub enum CatalogError {
     FileOperationError(#[from] std::io::Error),
     #[error("Internal Error: {0}")]
     DeserError(#[from] serde_json::Error),
+    #[error("{0}")]
+    EncryptionError(#[from] EncryptionError),
 }
 
 impl Catalog {
-    pub(crate) async fn new(dir_path: PathBuf) -> Result<Self, CatalogError> {
-        if !dir_path.exists() {
-            return Err(CatalogError::DbDirNotExist(dir_path));
-        }
+    pub(crate) async fn new(
+        dir_path: PathBuf,
+        table_options: TableOptions,
+    ) -> Result<Self, CatalogError> {
         let catalog_path = dir_path.join(CATALOG_FILE_NAME);
-        let stored_catalog: SerializableCatalog = if catalog_path.exists() {
-            read_json_file(&catalog_path)?
-        } else {
-            SerializableCatalog { tables: vec![] }
-        };
         let mut tables = vec![];
-        for table in stored_catalog.tables {
-            tables.push(Table::new(table, &dir_path).await?);
+        for table in read_table_definitions(&table_options.backend, &dir_path)? {
+            tables.push(Table::new(table, &dir_path, &table_options).await?);
         }
         Ok(Self {
             catalog_path,
             directory_path: dir_path,
             tables,
+            table_options,
         })
     }
 
@@ -75,6 +79,11 @@ impl Catalog {
         self.tables.iter_mut().find(|table| table.name == *name)
     }
 
+    /// Where the files of the database are kept
+    pub(crate) fn backend(&self) -> &Backend {
+        &self.table_options.backend
+    }
+
     pub(crate) fn get_table_path(&self, table_name: &TableName) -> PathBuf {
         table::get_table_path_(&self.directory_path, table_name)
     }
@@ -83,7 +92,7 @@ impl Catalog {
         &mut self,
         table_def: TableDefinition,
     ) -> Result<(), CatalogError> {
-        let table = Table::new(table_def, &self.directory_path).await?;
+        let table = Table::new(table_def, &self.directory_path, &self.table_options).await?;
         self.tables.push(table);
         self.flush()?;
         Ok(())
@@ -96,14 +105,48 @@ impl Catalog {
     }
 
     pub(crate) fn get_table_size(&self, name: &TableName) -> Option<usize> {
-        self.get_table(name).map(|table| table.table_buffer.size())
+        self.get_table(name).map(|table| table.storage.size())
+    }
+
+    pub(crate) async fn get_table_stats(&self, name: &TableName) -> Option<TableStats> {
+        match self.get_table(name) {
+            None => None,
+            Some(table) => Some(table.storage.stats().await),
+        }
+    }
+
+    /// Partially written entries that were cut off the tables' blocks, when
+    /// they were opened
+    pub(crate) fn get_tail_recoveries(&self) -> Vec<TailRecovery> {
+        self.tables
+            .iter()
+            .filter_map(|table| table.storage.recovery().cloned())
+            .collect()
+    }
+
+    /// Sync the blocks of all the tables to disk, and save their indexes to
+    /// their hint files; flush the memtables of LSM tables
+    pub(crate) async fn checkpoint_tables(&mut self) -> Result<(), CatalogError> {
+        for table in &mut self.tables {
+            table.storage.checkpoint().await?;
+        }
+        Ok(())
+    }
+
+    /// Remove the files the tables don't need anymore, once nothing (i.e. the
+    /// write-ahead log) refers to them.
+    pub(crate) fn remove_retired_files(&mut self) -> Result<(), CatalogError> {
+        for table in &mut self.tables {
+            table.storage.remove_retired_files()?;
+        }
+        Ok(())
     }
 
     fn flush(&self) -> Result<(), CatalogError> {
         let stored_catalog = SerializableCatalog {
             tables: self.tables.iter().map(Into::into).collect(),
         };
-        write_json_file(&self.catalog_path, &stored_catalog)
+        write_json_file(self.backend(), &self.catalog_path, &stored_catalog)
     }
 }
 
@@ -112,28 +155,38 @@ pub(crate) struct Table {
     pub(crate) name: TableName,
     pub(crate) columns: Vec<ColumnDefinition>,
     pub(crate) primary_key: ColumnName,
-    pub(crate) table_buffer: TableBuffer,
+    pub(crate) durability: Option<Durability>,
+    pub(crate) compression: Option<Compression>,
+    pub(crate) engine: Option<StorageEngine>,
+    pub(crate) storage: TableStorage,
+    /// Is a compaction of the table in progress
+    pub(crate) compacting: Arc<AtomicBool>,
+    /// Did a write to the table fail to be made durable (see `PendingCommit`);
+    /// then the table is not used until the database is reopened
+    pub(crate) poisoned: Arc<AtomicBool>,
 }
 
 impl Table {
     pub async fn new(
         table_definition: TableDefinition,
         directory_path: &Path,
+        options: &TableOptions,
     ) -> Result<Self, CatalogError> {
-        let table_buffer = TableBuffer::new(&table_definition, directory_path).await?;
+        let storage = TableStorage::new(&table_definition, directory_path, options).await?;
 
         let table = Self {
             name: table_definition.name,
             columns: table_definition.columns,
             primary_key: table_definition.primary_key,
-            table_buffer,
+            durability: table_definition.durability,
+            compression: table_definition.compression,
+            engine: table_definition.engine,
+            storage,
+            compacting: Arc::new(AtomicBool::new(false)),
+            poisoned: Arc::new(AtomicBool::new(false)),
         };
         Ok(table)
     }
-
-    pub fn get_column(&self, name: &ColumnName) -> Option<&ColumnDefinition> {
-        self.columns.iter().find(|col| col.name == *name)
-    }
 }
 
 impl<'a> From<&'a Table> for TableDefinition {
@@ -142,25 +195,49 @@ impl<'a> From<&'a Table> for TableDefinition {
             name: table.name.clone(),
             columns: table.columns.clone(),
             primary_key: table.primary_key.clone(),
+            durability: table.durability,
+            compression: table.compression,
+            engine: table.engine,
         }
     }
 }
 
+/// Read the definitions of the tables in the catalog of the database in the
+/// given directory, without opening the tables.
+pub(crate) fn read_table_definitions(
+    backend: &Backend,
+    dir_path: &Path,
+) -> Result<Vec<TableDefinition>, CatalogError> {
+    if !backend.directory_exists(dir_path) {
+        return Err(CatalogError::DbDirNotExist(dir_path.to_path_buf()));
+    }
+    let catalog_path = dir_path.join(CATALOG_FILE_NAME);
+    if !backend.file_exists(&catalog_path) {
+        return Ok(vec![]);
+    }
+    let stored_catalog: SerializableCatalog = read_json_file(backend, &catalog_path)?;
+    Ok(stored_catalog.tables)
+}
+
 // helpers
-fn read_json_file<T: for<'a> Deserialize<'a>>(file_path: &PathBuf) -> Result<T, CatalogError> {
-    // Open the file in read-only mode.
-    let file = File::open(file_path)?;
-    // Create a buffered reader for more efficient file reading.
-    let reader = BufReader::new(file);
-    Ok(serde_json::from_reader(reader)?)
+fn read_json_file<T: for<'a> Deserialize<'a>>(
+    backend: &Backend,
+    file_path: &Path,
+) -> Result<T, CatalogError> {
+    let contents = backend.unseal_file(file_path, backend.read_file(file_path)?)?;
+    Ok(serde_json::from_slice(&contents)?)
 }
 
-fn write_json_file<T: Serialize>(file_path: &PathBuf, item: &T) -> Result<(), CatalogError> {
-    // Open the file in write-only mode, create it if it doesn't exist.
-    let file = File::create(file_path)?;
-    // Create a buffered writer for efficient file writing.
-    let writer = BufWriter::new(file);
-    // Serialize the item struct to JSON and write it to the file.
-    serde_json::to_writer(writer, &item)?;
+// The file is replaced atomically, so a crash never leaves a partially written
+// file behind.
+fn write_json_file<T: Serialize>(
+    backend: &Backend,
+    file_path: &Path,
+    item: &T,
+) -> Result<(), CatalogError> {
+    let contents = backend.seal_file(file_path, serde_json::to_vec(item)?);
+    backend.replace_file(file_path, &contents)?;
+    // the rename is only durable once the directory is synced
+    backend.sync_parent_dir(file_path)?;
     Ok(())
 }
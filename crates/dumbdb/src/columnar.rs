@@ -0,0 +1,320 @@
+/// The columnar layout of SSTables, for tables defined with
+/// `"engine": "columnar"`. A columnar table is an LSM tree (see `LsmTree`),
+/// whose SSTables store their entries by column instead of by tuple.
+///
+/// The entries of a columnar SSTable are split into chunks of up to
+/// `CHUNK_ROWS` consecutive entries. A chunk is stored as an entry with the keys
+/// of its tuples, followed by an entry for every other column, with the values
+/// of that column of all the tuples (all of them encoded like tuples, so that
+/// they are compressed and encrypted the same way). The footer of the SSTable
+/// has the statistics of every chunk: where its entries are, which of its
+/// entries are tombstones, and the minimum and maximum value of every column.
+///
+/// A scan which only needs some of the columns reads only those (see
+/// `ScanFilter`), and skips chunks whose statistics rule out a match of the
+/// filter expression, reading only their keys.
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    backend::BlockStorage,
+    query::types::{ColumnDefinition, ColumnName, ColumnValue, Compression, Expression, Operator},
+    storage::{calculate_new_offset, decode_entry, BlockEntry, StorageError, Tuple},
+};
+
+/// Maximum number of entries of a chunk.
+const CHUNK_ROWS: usize = 1024;
+
+/// Which tuples, and which of their columns, a scan of a table needs. Columnar
+/// tables skip the rest; other tables return every tuple in full.
+#[derive(Debug, Clone)]
+pub(crate) struct ScanFilter {
+    /// The table's columns
+    pub(crate) columns: Vec<ColumnDefinition>,
+    /// Only tuples which may match this expression are needed
+    pub(crate) expression: Option<Expression>,
+    /// Positions of the columns which are needed; the values of the other
+    /// columns may be left out of the tuples
+    pub(crate) needed_columns: Vec<usize>,
+}
+
+/// Statistics of a chunk, in the footer of its SSTable
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ChunkStats {
+    /// Byte-offset of the entry with the keys of the chunk
+    pub(crate) offset: u64,
+    pub(crate) min_key: ColumnValue,
+    pub(crate) max_key: ColumnValue,
+    /// Entries of the chunk which are tombstones, by their position in it
+    pub(crate) tombstones: Vec<u32>,
+    /// Statistics of every column, by its position in the table's tuples;
+    /// `None` for the primary key, which is stored as the chunk's keys
+    pub(crate) columns: Vec<Option<ColumnStats>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ColumnStats {
+    /// Byte-offset of the entry with the values of the column
+    pub(crate) offset: u64,
+    /// Smallest and largest value of the column in the chunk; `None` if all
+    /// of them are null
+    pub(crate) min: Option<ColumnValue>,
+    pub(crate) max: Option<ColumnValue>,
+    /// Number of null values, besides the tombstones
+    pub(crate) nulls: u64,
+}
+
+/// The entries of a chunk, before it is written
+#[derive(Debug)]
+pub(crate) struct ChunkBuilder {
+    pk_position: usize,
+    keys: Vec<ColumnValue>,
+    /// The values of every column; empty for the primary key
+    values: Vec<Vec<Option<ColumnValue>>>,
+    tombstones: Vec<u32>,
+}
+
+/// Number of bytes written for a chunk, and saved by compressing it
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ChunkSize {
+    pub(crate) stored: u64,
+    pub(crate) saved: u64,
+}
+
+impl ScanFilter {
+    /// Could any of the tuples of the chunk match the expression
+    pub(crate) fn may_match(&self, chunk: &ChunkStats) -> bool {
+        self.expression
+            .as_ref()
+            .is_none_or(|expression| self.may_match_expression(expression, chunk))
+    }
+
+    /// Does the scan need the column at the given position
+    pub(crate) fn needs(&self, position: usize) -> bool {
+        self.needed_columns.contains(&position)
+    }
+
+    // anything the statistics don't say anything about may match. A comparison
+    // with a null value is never true, so only the values which are not null
+    // matter for comparisons.
+    fn may_match_expression(&self, expression: &Expression, chunk: &ChunkStats) -> bool {
+        let column_stats = |name: &ColumnName| {
+            self.columns
+                .iter()
+                .position(|column| column.name == *name)
+                .and_then(|position| chunk.columns.get(position))
+        };
+        match expression {
+            Expression::And(expressions) => expressions
+                .iter()
+                .all(|expression| self.may_match_expression(expression, chunk)),
+            Expression::Or(expressions) => expressions
+                .iter()
+                .any(|expression| self.may_match_expression(expression, chunk)),
+            Expression::Not(_) => true,
+            Expression::IsNull(column) => match column_stats(column) {
+                // the primary key is never null
+                Some(None) => false,
+                Some(Some(stats)) => stats.nulls > 0,
+                None => true,
+            },
+            Expression::IsNotNull(column) => match column_stats(column) {
+                Some(Some(stats)) => stats.min.is_some(),
+                _ => true,
+            },
+            Expression::ColumnComparison(comparison) => {
+                let (min, max) = match column_stats(&comparison.column) {
+                    // the primary key
+                    Some(None) => (&chunk.min_key, &chunk.max_key),
+                    Some(Some(ColumnStats {
+                        min: Some(min),
+                        max: Some(max),
+                        ..
+                    })) => (min, max),
+                    // all of the values are null
+                    Some(Some(_)) => return false,
+                    None => return true,
+                };
+                let value = &comparison.value;
+                let (min, max) = (min.compare(value), max.compare(value));
+                match comparison.operator {
+                    Operator::Eq => min.is_le() && max.is_ge(),
+                    Operator::Neq => !(min.is_eq() && max.is_eq()),
+                    Operator::Gt => max.is_gt(),
+                    Operator::Lt => min.is_lt(),
+                    Operator::Gte => max.is_ge(),
+                    Operator::Lte => min.is_le(),
+                }
+            }
+        }
+    }
+}
+
+impl ChunkStats {
+    /// Read the keys of the chunk, and the values of the columns which are
+    /// needed (all of them if `needs` is always true). The tuples have `None`
+    /// for the columns which are not read; tombstones are `None` themselves.
+    pub(crate) async fn read(
+        &self,
+        block: &dyn BlockStorage,
+        pk_position: usize,
+        needs: impl Fn(usize) -> bool,
+    ) -> Result<Vec<(ColumnValue, Option<Tuple>)>, StorageError> {
+        let keys = self.read_keys(block).await?;
+        let mut tuples = vec![vec![None; self.columns.len()]; keys.len()];
+        for (position, column) in self.columns.iter().enumerate() {
+            let Some(column) = column else {
+                for (tuple, key) in tuples.iter_mut().zip(&keys) {
+                    tuple[pk_position] = Some(key.clone());
+                }
+                continue;
+            };
+            if !needs(position) {
+                continue;
+            }
+            let values = read_values(block, column.offset).await?;
+            for (tuple, value) in tuples.iter_mut().zip(values) {
+                tuple[position] = value;
+            }
+        }
+        let mut entries: Vec<_> = keys
+            .into_iter()
+            .zip(tuples)
+            .map(|(key, tuple)| (key, Some(tuple)))
+            .collect();
+        for row in &self.tombstones {
+            if let Some(entry) = entries.get_mut(*row as usize) {
+                entry.1 = None;
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Read only the keys of the chunk
+    pub(crate) async fn read_keys(
+        &self,
+        block: &dyn BlockStorage,
+    ) -> Result<Vec<ColumnValue>, StorageError> {
+        Ok(read_values(block, self.offset)
+            .await?
+            .into_iter()
+            .flatten()
+            .collect())
+    }
+}
+
+impl ChunkBuilder {
+    pub(crate) fn new(pk_position: usize, column_count: usize) -> Self {
+        Self {
+            pk_position,
+            keys: vec![],
+            values: vec![vec![]; column_count],
+            tombstones: vec![],
+        }
+    }
+
+    /// A new chunk, for the same table
+    pub(crate) fn empty(&self) -> Self {
+        Self::new(self.pk_position, self.values.len())
+    }
+
+    pub(crate) fn is_full(&self) -> bool {
+        self.keys.len() >= CHUNK_ROWS
+    }
+
+    /// Add an entry (a tuple, or a tombstone) to the chunk
+    pub(crate) fn push(&mut self, key: ColumnValue, entry: Option<Tuple>) {
+        if entry.is_none() {
+            self.tombstones.push(self.keys.len() as u32);
+        }
+        let mut tuple = entry.unwrap_or_default();
+        tuple.resize(self.values.len(), None);
+        for (position, value) in tuple.into_iter().enumerate() {
+            if position != self.pk_position {
+                self.values[position].push(value);
+            }
+        }
+        self.keys.push(key);
+    }
+
+    /// Write the chunk to the block, at the given byte-offset (i.e. the end of
+    /// the block). Returns its statistics, and how many bytes it takes up;
+    /// nothing is written for an empty chunk.
+    pub(crate) async fn write(
+        self,
+        block: &dyn BlockStorage,
+        compression: Option<Compression>,
+        offset: u64,
+    ) -> Result<Option<(ChunkStats, ChunkSize)>, StorageError> {
+        let (Some(min_key), Some(max_key)) = (self.keys.first(), self.keys.last()) else {
+            return Ok(None);
+        };
+        let (min_key, max_key) = (min_key.clone(), max_key.clone());
+        let keys = self.keys.into_iter().map(Some).collect();
+        let mut size = write_values(block, keys, compression).await?;
+        let mut columns = vec![];
+        for (position, values) in self.values.into_iter().enumerate() {
+            if position == self.pk_position {
+                columns.push(None);
+                continue;
+            }
+            let live = values
+                .iter()
+                .enumerate()
+                .filter(|(row, _)| !self.tombstones.contains(&(*row as u32)));
+            let nulls = live.clone().filter(|(_, value)| value.is_none()).count() as u64;
+            let min = live
+                .clone()
+                .filter_map(|(_, value)| value.as_ref())
+                .min()
+                .cloned();
+            let max = live.filter_map(|(_, value)| value.as_ref()).max().cloned();
+            let column_offset = calculate_new_offset(size.stored, offset);
+            let column_size = write_values(block, values, compression).await?;
+            size.stored += column_size.stored;
+            size.saved += column_size.saved;
+            columns.push(Some(ColumnStats {
+                offset: column_offset,
+                min,
+                max,
+                nulls,
+            }));
+        }
+        let stats = ChunkStats {
+            offset,
+            min_key,
+            max_key,
+            tombstones: self.tombstones,
+            columns,
+        };
+        Ok(Some((stats, size)))
+    }
+}
+
+// the values of a column chunk are stored as a tuple
+async fn write_values(
+    block: &dyn BlockStorage,
+    values: Vec<Option<ColumnValue>>,
+    compression: Option<Compression>,
+) -> Result<ChunkSize, StorageError> {
+    let size = block.write(values, compression).await?;
+    Ok(ChunkSize {
+        stored: size.stored,
+        saved: size.uncompressed - size.stored,
+    })
+}
+
+async fn read_values(
+    block: &dyn BlockStorage,
+    offset: u64,
+) -> Result<Vec<Option<ColumnValue>>, StorageError> {
+    let (data, header) = block.read_entry(offset).await?;
+    match decode_entry(block.path(), data, &header)? {
+        BlockEntry::Tuple(values) => Ok(values),
+        BlockEntry::Tombstone(_) => Err(StorageError::DeserializeBinary(
+            rmp_serde::decode::Error::Syntax(format!(
+                "expected column values at byte-offset {}, found a tombstone",
+                offset
+            )),
+        )),
+    }
+}
@@ -0,0 +1,477 @@
+/// Compaction of table blocks. As blocks are append-only, updates and deletes
+/// leave dead tuple versions behind in the block's segments. Compaction rewrites
+/// each segment with only the live tuples reachable from the index, and swaps
+/// the new file in place of the old one. Segments are swapped in oldest first,
+/// so a crash in the middle leaves some segments compacted and the rest as they
+/// were, which still adds up to the same table.
+///
+/// A compaction happens in three steps, so that the table is not blocked for
+/// the entire duration of the compaction:
+///
+/// 1. `Compaction::new` takes a snapshot of the index (needs a shared reference
+///    to the table).
+/// 2. `Compaction::run` copies the live tuples of the snapshot into a new file
+///    for every segment. This doesn't need any reference to the table, so reads and writes
+///    can continue in the meantime.
+/// 3. `Compaction::finish` copies over whatever was written to the table since
+///    the snapshot, and swaps the new segments in (needs an exclusive reference to
+///    the table).
+///
+/// The values a log table stores out of line are copied into a new blob file
+/// along with the tuples which refer to them (see `BlobCopy`), which leaves
+/// the values no live tuple refers to behind, in the old blob files.
+///
+/// LSM tables compact themselves as they are written to; compacting one by
+/// hand merges all of its SSTables into one level (see `MajorCompaction`), in
+/// the same three steps.
+///
+/// Only one compaction of a table can be in progress at a time; both would
+/// write their compacted segments to the same files.
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    backend::{Backend, BlockStorage},
+    blob::{BlobCopy, BlobError},
+    catalog::Catalog,
+    lsm::{LsmError, MajorCompaction},
+    query::{
+        error::QueryError,
+        types::{ColumnValue, Compression, Durability, TableName},
+    },
+    segment::{EntryPosition, Segment, SegmentId},
+    storage::{calculate_new_offset, BlockEntry, StorageError, Tuple, FILE_HEADER_SIZE},
+    table::{TableBuffer, TableStorage},
+};
+
+/// Suffix of the temporary file a segment is compacted to.
+const COMPACTION_FILE_SUFFIX: &str = "compact";
+
+/// A compaction of a table, which is in progress.
+#[derive(Debug)]
+pub struct Compaction {
+    table_name: TableName,
+    job: CompactionJob,
+    /// Marks the table as being compacted, until the compaction is finished
+    /// or dropped
+    _in_progress: InProgress,
+}
+
+// the flag of a table which says that it is being compacted; it is cleared
+// when the compaction is done with, however that happens
+#[derive(Debug)]
+struct InProgress(Arc<AtomicBool>);
+
+impl Drop for InProgress {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+// the compaction of a table, in its storage engine
+#[derive(Debug)]
+enum CompactionJob {
+    Log(LogCompaction),
+    Lsm(MajorCompaction),
+}
+
+#[derive(Debug)]
+struct LogCompaction {
+    /// Where the files of the table are kept
+    backend: Backend,
+    /// Fingerprint of the table's schema, for the file headers of the
+    /// compacted segments
+    schema_fingerprint: u32,
+    /// How tuples are compressed in the compacted segments
+    compression: Option<Compression>,
+    /// The segments being compacted, as they were when the compaction
+    /// started. The blocks are used to check that the table was not dropped,
+    /// re-created or compacted in the meantime.
+    segments: Vec<(SegmentId, Segment)>,
+    /// Column index of the primary key
+    pk_position: usize,
+    /// Snapshot of the index when the compaction started
+    snapshot: HashMap<ColumnValue, EntryPosition>,
+    /// Copy of the values the live tuples refer to
+    blobs: BlobCopy,
+    /// The compacted segments. Available after `run`.
+    compacted: Option<Vec<CompactedSegment>>,
+}
+
+// a compacted copy of a segment
+#[derive(Debug)]
+struct CompactedSegment {
+    segment_id: SegmentId,
+    block: Arc<dyn BlockStorage>,
+    compression: Option<Compression>,
+    length: u64,
+    saved_bytes: u64,
+    /// Position of the latest version of every tuple in the compacted segment
+    index: HashMap<ColumnValue, EntryPosition>,
+    /// Does any of the tuples refer to values stored out of line
+    has_blobs: bool,
+}
+
+/// Statistics of a finished compaction
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompactionStats {
+    pub table_name: TableName,
+    /// Size of the block before compaction, in bytes
+    pub old_size: u64,
+    /// Size of the block after compaction, in bytes
+    pub new_size: u64,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum CompactionError {
+    #[error("Compaction of table '{0}' is not run yet.")]
+    NotRun(TableName),
+    #[error("Table '{0}' was changed by another compaction or DDL while it was being compacted.")]
+    Conflict(TableName),
+    #[error("Table '{0}' is already being compacted.")]
+    InProgress(TableName),
+    #[error("Unexpected invariant violation: primary key not found in data tuple.")]
+    PrimaryKeyNotInTuple,
+    #[error("Internal Storage Engine Error: {0}")]
+    StorageError(#[from] StorageError),
+    #[error("Internal Storage Engine Error: {0}")]
+    LsmError(#[from] LsmError),
+    #[error("Internal Storage Engine Error: {0}")]
+    BlobError(#[from] BlobError),
+    #[error("Compaction file operation failed. {0}")]
+    FileOperation(#[from] std::io::Error),
+}
+
+impl Compaction {
+    /// Start a compaction of the table, by taking a snapshot of its index (or
+    /// of the SSTables of an LSM table).
+    fn new(table_name: TableName, storage: &TableStorage, in_progress: InProgress) -> Self {
+        let job = match storage {
+            TableStorage::Log(table_buffer) => CompactionJob::Log(LogCompaction::new(table_buffer)),
+            TableStorage::Lsm(tree) => CompactionJob::Lsm(MajorCompaction::new(tree)),
+        };
+        Self {
+            table_name,
+            job,
+            _in_progress: in_progress,
+        }
+    }
+
+    pub fn table_name(&self) -> &TableName {
+        &self.table_name
+    }
+
+    /// Copy the live tuples of the snapshot to new files.
+    pub async fn run(&mut self) -> Result<(), CompactionError> {
+        match &mut self.job {
+            CompactionJob::Log(compaction) => compaction.run().await,
+            CompactionJob::Lsm(compaction) => Ok(compaction.run().await?),
+        }
+    }
+
+    /// Swap the new files in place of the table's files, and carry over
+    /// whatever was written to the table since the snapshot.
+    pub(crate) async fn finish(
+        self,
+        storage: &mut TableStorage,
+    ) -> Result<CompactionStats, CompactionError> {
+        match (self.job, storage) {
+            (CompactionJob::Log(compaction), TableStorage::Log(table_buffer)) => {
+                compaction.finish(self.table_name, table_buffer).await
+            }
+            (CompactionJob::Lsm(compaction), TableStorage::Lsm(tree)) => {
+                if !compaction.is_run() {
+                    return Err(CompactionError::NotRun(self.table_name));
+                }
+                if !compaction.is_current(tree) {
+                    compaction.discard()?;
+                    return Err(CompactionError::Conflict(self.table_name));
+                }
+                let (old_size, new_size) = compaction.finish(tree)?;
+                Ok(CompactionStats {
+                    table_name: self.table_name,
+                    old_size,
+                    new_size,
+                })
+            }
+            // the table was dropped, and re-created with another engine
+            (_, _) => Err(CompactionError::Conflict(self.table_name)),
+        }
+    }
+}
+
+impl LogCompaction {
+    fn new(table_buffer: &TableBuffer) -> Self {
+        Self {
+            backend: table_buffer.block.backend().clone(),
+            schema_fingerprint: table_buffer.block.schema_fingerprint(),
+            compression: table_buffer.block.compression(),
+            segments: table_buffer
+                .block
+                .segments
+                .iter()
+                .map(|(segment_id, segment)| (*segment_id, segment.clone()))
+                .collect(),
+            pk_position: table_buffer.pk_position,
+            snapshot: table_buffer.index.index.clone(),
+            blobs: table_buffer.blobs.start_copy(),
+            compacted: None,
+        }
+    }
+
+    /// Copy the live tuples of the snapshot to new segment files.
+    async fn run(&mut self) -> Result<(), CompactionError> {
+        let mut compacted = vec![];
+        for (segment_id, segment) in &self.segments {
+            let compacted_path = get_compaction_path(segment.block.path());
+            // truncates any leftover file from an earlier failed compaction
+            self.backend
+                .create_block(&compacted_path, self.schema_fingerprint)?;
+            // the compacted segment is synced once, when it is finished
+            let mut compacted_segment = CompactedSegment {
+                segment_id: *segment_id,
+                block: self.backend.open_temporary_block(
+                    &compacted_path,
+                    segment.block.path(),
+                    Durability::NoSync,
+                    self.schema_fingerprint,
+                )?,
+                compression: self.compression,
+                length: FILE_HEADER_SIZE,
+                saved_bytes: 0,
+                index: HashMap::new(),
+                has_blobs: false,
+            };
+
+            let mut stream = Box::pin(
+                segment
+                    .get_entries_from(*segment_id, FILE_HEADER_SIZE)
+                    .await?,
+            );
+            while let Some(result) = stream.next().await {
+                let (entry, position, _size) = result?;
+                // only the part of the segment in the snapshot is compacted now
+                if position.offset >= segment.length {
+                    break;
+                }
+                // tombstones are not copied; the tuples they delete are not live
+                let BlockEntry::Tuple(mut tuple) = entry else {
+                    continue;
+                };
+                let key = tuple[self.pk_position]
+                    .clone()
+                    .ok_or(CompactionError::PrimaryKeyNotInTuple)?;
+                if self.snapshot.get(&key) == Some(&position) {
+                    compacted_segment.has_blobs |= self.blobs.copy(&mut tuple).await?;
+                    compacted_segment.write(key, tuple).await?;
+                }
+            }
+            compacted.push(compacted_segment);
+        }
+        self.compacted = Some(compacted);
+        Ok(())
+    }
+
+    /// Copy the entries written to the table since the snapshot to the
+    /// compacted segments, and swap the compacted segments in place of the
+    /// table's segments.
+    async fn finish(
+        self,
+        table_name: TableName,
+        table_buffer: &mut TableBuffer,
+    ) -> Result<CompactionStats, CompactionError> {
+        for (segment_id, segment) in &self.segments {
+            let is_same = table_buffer
+                .block
+                .segments
+                .get(segment_id)
+                .is_some_and(|current| Arc::ptr_eq(&current.block, &segment.block));
+            if !is_same {
+                self.blobs.discard()?;
+                return Err(CompactionError::Conflict(table_name));
+            }
+        }
+        let Some(mut compacted) = self.compacted else {
+            self.blobs.discard()?;
+            return Err(CompactionError::NotRun(table_name));
+        };
+        let mut blobs = self.blobs;
+        blobs.update_sources(&table_buffer.blobs);
+        let old_size = table_buffer.block.total_bytes();
+
+        // copy over the tails of the segments; only the segment which was
+        // active when the compaction started can have one
+        for (compacted_segment, (segment_id, segment)) in compacted.iter_mut().zip(&self.segments) {
+            let current = table_buffer.block.get_segment(*segment_id)?;
+            if current.length == segment.length {
+                continue;
+            }
+            let mut stream = Box::pin(
+                current
+                    .get_entries_from(*segment_id, segment.length)
+                    .await?,
+            );
+            while let Some(result) = stream.next().await {
+                match result?.0 {
+                    BlockEntry::Tuple(mut tuple) => {
+                        let key = tuple[self.pk_position]
+                            .clone()
+                            .ok_or(CompactionError::PrimaryKeyNotInTuple)?;
+                        compacted_segment.has_blobs |= blobs.copy(&mut tuple).await?;
+                        compacted_segment.write(key, tuple).await?;
+                    }
+                    BlockEntry::Tombstone(key) => {
+                        // the tombstone is only needed if a compacted segment
+                        // has a version of the tuple
+                        if compacted_segment.index.contains_key(&key)
+                            || self.snapshot.contains_key(&key)
+                        {
+                            compacted_segment.write_tombstone(&key).await?;
+                        }
+                    }
+                }
+            }
+        }
+
+        // the compacted segments refer to the copied values
+        blobs.sync().await?;
+
+        // swap the compacted segments in, oldest first
+        // the hint file describes the old segments
+        table_buffer.remove_hint()?;
+        let backend = &self.backend;
+        let durability = table_buffer.block.durability();
+        let active_segment = table_buffer.block.active_segment();
+        for compacted_segment in compacted {
+            let segment_id = compacted_segment.segment_id;
+            let compacted_path = compacted_segment.block.path().to_path_buf();
+            let segment = table_buffer.block.get_segment(segment_id)?;
+            let segment_path = segment.block.path().to_path_buf();
+            let reclaimed = segment.length.saturating_sub(compacted_segment.length);
+            if reclaimed == 0 && !compacted_segment.has_blobs {
+                // nothing in the segment was dead
+                backend.remove_file(&compacted_path)?;
+                continue;
+            }
+
+            let is_empty = compacted_segment.length == FILE_HEADER_SIZE;
+            if is_empty && segment_id != 0 && segment_id != active_segment {
+                // nothing in the segment was live
+                backend.remove_file(&segment_path)?;
+                backend.remove_file(&compacted_path)?;
+                backend.sync_parent_dir(&segment_path)?;
+                table_buffer.block.segments.remove(&segment_id);
+            } else {
+                compacted_segment.block.sync().await?;
+                backend.rename(&compacted_path, &segment_path)?;
+                backend.sync_parent_dir(&segment_path)?;
+                let segment = Segment {
+                    block: backend.open_block(
+                        &segment_path,
+                        durability,
+                        self.schema_fingerprint,
+                    )?,
+                    length: compacted_segment.length,
+                    saved_bytes: compacted_segment.saved_bytes,
+                };
+                table_buffer.block.segments.insert(segment_id, segment);
+            }
+
+            // everything which was left out was dead
+            let index = &mut table_buffer.index;
+            index.dead_bytes = index.dead_bytes.saturating_sub(reclaimed);
+            for (key, position) in compacted_segment.index {
+                if let Some(current) = index.index.get_mut(&key) {
+                    if current.segment == segment_id {
+                        *current = position;
+                    }
+                }
+            }
+        }
+
+        // the old blob files are only referred to by segments which were
+        // started during the compaction, if any
+        let is_whole_table = table_buffer
+            .block
+            .segments
+            .keys()
+            .all(|segment_id| self.segments.iter().any(|(id, _)| id == segment_id));
+        table_buffer.blobs.finish_copy(blobs, is_whole_table);
+
+        Ok(CompactionStats {
+            table_name,
+            old_size,
+            new_size: table_buffer.block.total_bytes(),
+        })
+    }
+}
+
+impl CompactedSegment {
+    async fn write(&mut self, key: ColumnValue, tuple: Tuple) -> Result<(), CompactionError> {
+        let size = self.block.write(tuple, self.compression).await?;
+        let position = EntryPosition {
+            segment: self.segment_id,
+            offset: self.length,
+            length: size.stored,
+        };
+        self.length = calculate_new_offset(size.stored, self.length);
+        self.saved_bytes += size.uncompressed - size.stored;
+        self.index.insert(key, position);
+        Ok(())
+    }
+
+    async fn write_tombstone(&mut self, key: &ColumnValue) -> Result<(), CompactionError> {
+        let size = self.block.write_tombstone(key).await?;
+        self.length = calculate_new_offset(size.stored, self.length);
+        self.index.remove(key);
+        Ok(())
+    }
+}
+
+/// Start a compaction of the given table, unless one is in progress already.
+/// See `Compaction` for the rest of the steps.
+pub(crate) async fn start_compaction(
+    table_name: &TableName,
+    catalog: &Catalog,
+) -> Result<Compaction, QueryError> {
+    let Some(table) = catalog.get_table(table_name) else {
+        return Err(QueryError::TableNotFound(table_name.clone()));
+    };
+    if table.compacting.swap(true, Ordering::SeqCst) {
+        return Err(CompactionError::InProgress(table_name.clone()).into());
+    }
+    let in_progress = InProgress(table.compacting.clone());
+    Ok(Compaction::new(
+        table_name.clone(),
+        &table.storage,
+        in_progress,
+    ))
+}
+
+/// Finish a compaction which has been run, by swapping in the compacted
+/// segments.
+pub(crate) async fn finish_compaction(
+    compaction: Compaction,
+    catalog: &mut Catalog,
+) -> Result<CompactionStats, QueryError> {
+    match catalog.get_table_mut(&compaction.table_name) {
+        None => Err(QueryError::TableNotFound(compaction.table_name)),
+        Some(table) => Ok(compaction.finish(&mut table.storage).await?),
+    }
+}
+
+fn get_compaction_path(segment_path: &Path) -> PathBuf {
+    let mut file_name = segment_path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".");
+    file_name.push(COMPACTION_FILE_SUFFIX);
+    segment_path.with_file_name(file_name)
+}
@@ -0,0 +1,67 @@
+/// Compression of the data of the entries of a block. Every tuple is compressed
+/// on its own (i.e. per entry), so that a tuple can still be read from its
+/// byte-offset without reading anything else of the block.
+///
+/// Compressed data starts with the codec it was compressed with, and the
+/// length of the uncompressed data, followed by the compressed bytes:
+///
+/// ```text
+/// | codec (1 byte) | uncompressed length (u32 LE) | compressed data |
+/// ```
+///
+/// The only codec is LZ4 (the block format, without the frame around it), as
+/// implemented by `lz4_flex`.
+use crate::query::types::Compression;
+
+/// Codec of LZ4 compressed data
+const LZ4_CODEC: u8 = 1;
+
+// Size of the codec and the uncompressed length, before the compressed data
+const PREAMBLE_SIZE: usize = 5;
+
+#[derive(thiserror::Error, Debug)]
+pub enum DecompressError {
+    #[error("Unknown compression codec: {0}.")]
+    UnknownCodec(u8),
+    #[error("Compressed data is malformed.")]
+    Malformed,
+}
+
+/// Compress the data of an entry. Returns `None` if the compressed data
+/// wouldn't be any smaller; then the data is better stored as it is.
+pub(crate) fn compress(data: &[u8], compression: Compression) -> Option<Vec<u8>> {
+    let buffer = encode(data, compression)?;
+    (buffer.len() < data.len()).then_some(buffer)
+}
+
+/// Compress the data with the codec, whether that makes it smaller or not.
+/// Returns `None` if the data is too large for its length to be stored.
+pub(crate) fn encode(data: &[u8], compression: Compression) -> Option<Vec<u8>> {
+    let length = u32::try_from(data.len()).ok()?;
+    let mut buffer = Vec::with_capacity(data.len());
+    match compression {
+        Compression::Lz4 => {
+            buffer.push(LZ4_CODEC);
+            buffer.extend_from_slice(&length.to_le_bytes());
+            buffer.extend_from_slice(&lz4_flex::block::compress(data));
+        }
+    }
+    Some(buffer)
+}
+
+/// Decompress data compressed by `compress`.
+pub(crate) fn decompress(data: &[u8]) -> Result<Vec<u8>, DecompressError> {
+    let preamble = data
+        .get(..PREAMBLE_SIZE)
+        .ok_or(DecompressError::Malformed)?;
+    let length = u32::from_le_bytes(preamble[1..].try_into().unwrap()) as usize;
+    let decompressed = match preamble[0] {
+        LZ4_CODEC => lz4_flex::block::decompress(&data[PREAMBLE_SIZE..], length)
+            .map_err(|_| DecompressError::Malformed)?,
+        codec => return Err(DecompressError::UnknownCodec(codec)),
+    };
+    if decompressed.len() != length {
+        return Err(DecompressError::Malformed);
+    }
+    Ok(decompressed)
+}
@@ -0,0 +1,376 @@
+/// Encryption at rest. A database opened with an encryption key encrypts the
+/// data of every entry of its blocks (tuples, tombstones, and the records of
+/// the write-ahead log), and its catalog and hint files. The file headers of
+/// blocks and the length prefixes of entries are not encrypted, so that the
+/// entries of a block can still be found without the key.
+///
+/// Encryption is authenticated, with XChaCha20-Poly1305 (i.e. the
+/// ChaCha20-Poly1305 of RFC 8439, with a 192-bit nonce), from the
+/// `chacha20poly1305` crate. Every piece of data is encrypted with a random
+/// nonce, which is long enough for random nonces never to repeat. Encrypted
+/// data is laid out as
+///
+/// ```text
+/// | nonce (24 bytes) | ciphertext | tag (16 bytes) |
+/// ```
+///
+/// The data of an entry is authenticated along with the name of its file, its
+/// byte-offset in the file and its flags; so an entry can't be moved to
+/// another place, in its file or in another one, without failing the check of
+/// its tag.
+///
+/// Data decrypted with the wrong key fails the check of its tag, so a wrong key
+/// is reported as such, instead of being decrypted into garbage.
+///
+/// A sealed file (i.e. the catalog, or a hint file) of an encrypted database is
+/// its encrypted contents, after magic bytes which tell it apart from a file
+/// which isn't encrypted.
+use std::{
+    fmt::Debug,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use chacha20poly1305::{
+    aead::{Aead, Payload},
+    KeyInit, XChaCha20Poly1305, XNonce,
+};
+use futures::StreamExt;
+use rand::RngCore;
+use tokio::sync::Mutex;
+
+use crate::{
+    backend::BlockStorage,
+    query::types::Durability,
+    storage::{EntryDataStream, EntryFlags, EntryHeader, PendingSync, StorageError},
+};
+
+/// Size of an encryption key, in bytes
+pub const KEY_SIZE: usize = 32;
+
+pub(crate) const NONCE_SIZE: usize = 24;
+
+const TAG_SIZE: usize = 16;
+
+// Every sealed file of an encrypted database starts with these bytes
+const SEALED_FILE_MAGIC: [u8; 8] = *b"DMDBSEAL";
+
+/// A key to encrypt a database with. It is written as 64 hex digits, in a key
+/// file or in an environment variable; e.g. `openssl rand -hex 32` generates
+/// one.
+#[derive(Clone, PartialEq, Eq)]
+pub struct EncryptionKey([u8; KEY_SIZE]);
+
+#[derive(thiserror::Error, Debug)]
+pub enum KeyError {
+    #[error("Could not read key file {path}. {error}")]
+    KeyFile {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    #[error("Environment variable {0} is not set.")]
+    EnvVarNotSet(String),
+    #[error("An encryption key must be {} hex digits.", KEY_SIZE * 2)]
+    InvalidKey,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum EncryptionError {
+    #[error("{path} is encrypted. The database can only be opened with its encryption key.")]
+    KeyRequired { path: PathBuf },
+    #[error("Could not decrypt {path}: wrong encryption key, or the file was tampered with.")]
+    WrongKey { path: PathBuf },
+    #[error("{path} is not encrypted, but the database was opened with an encryption key.")]
+    NotEncrypted { path: PathBuf },
+}
+
+impl EncryptionKey {
+    /// Parse a key from its hex digits. Whitespace around them is ignored.
+    pub fn from_hex(hex: &str) -> Result<Self, KeyError> {
+        let digits = hex
+            .trim()
+            .chars()
+            .map(|digit| digit.to_digit(16).map(|value| value as u8))
+            .collect::<Option<Vec<_>>>()
+            .ok_or(KeyError::InvalidKey)?;
+        if digits.len() != KEY_SIZE * 2 {
+            return Err(KeyError::InvalidKey);
+        }
+        let mut key = [0u8; KEY_SIZE];
+        for (byte, pair) in key.iter_mut().zip(digits.chunks(2)) {
+            *byte = (pair[0] << 4) | pair[1];
+        }
+        Ok(Self(key))
+    }
+
+    /// Read a key from a key file
+    pub fn from_file(path: &Path) -> Result<Self, KeyError> {
+        let contents = std::fs::read_to_string(path).map_err(|error| KeyError::KeyFile {
+            path: path.to_path_buf(),
+            error,
+        })?;
+        Self::from_hex(&contents)
+    }
+
+    /// Read a key from the environment variable with the given name
+    pub fn from_env(name: &str) -> Result<Self, KeyError> {
+        let value = std::env::var(name).map_err(|_| KeyError::EnvVarNotSet(name.to_string()))?;
+        Self::from_hex(&value)
+    }
+}
+
+// keys never end up in logs
+impl Debug for EncryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "EncryptionKey(..)")
+    }
+}
+
+/// A block whose entries are encrypted. It wraps the storage of the entries of
+/// the block, encrypting their data when they are written, and decrypting it
+/// when they are read; so the typed API of the block sees them decrypted.
+#[derive(Debug)]
+pub(crate) struct EncryptedBlock {
+    inner: Arc<dyn BlockStorage>,
+    cipher: Arc<Cipher>,
+    // the name of the file the entries are authenticated with; a block written
+    // under a temporary name is renamed to it once it is complete
+    file_name: Vec<u8>,
+    // length of the block, i.e. the byte-offset the next entry is written at;
+    // held while an entry is written, so that it lands where it was encrypted
+    // for
+    length: Mutex<u64>,
+}
+
+/// Encrypts and decrypts data with an encryption key.
+#[derive(Clone)]
+pub(crate) struct Cipher {
+    aead: XChaCha20Poly1305,
+}
+
+impl Cipher {
+    pub(crate) fn new(key: &EncryptionKey) -> Self {
+        Self {
+            aead: XChaCha20Poly1305::new(&key.0.into()),
+        }
+    }
+
+    /// Encrypt the data. The associated data is not encrypted (nor stored),
+    /// but it is authenticated along with the data: decrypting fails unless it
+    /// is given the same associated data.
+    pub(crate) fn encrypt(&self, data: &[u8], associated_data: &[u8]) -> Vec<u8> {
+        let mut nonce = [0u8; NONCE_SIZE];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        self.encrypt_with_nonce(&nonce, data, associated_data)
+    }
+
+    /// Same as `encrypt`, with the given nonce, which must never be used with
+    /// the same key again.
+    pub(crate) fn encrypt_with_nonce(
+        &self,
+        nonce: &[u8; NONCE_SIZE],
+        data: &[u8],
+        associated_data: &[u8],
+    ) -> Vec<u8> {
+        let payload = Payload {
+            msg: data,
+            aad: associated_data,
+        };
+        // this only fails for data of more than 256 GiB, far more than an
+        // entry or a sealed file holds
+        let ciphertext = self
+            .aead
+            .encrypt(XNonce::from_slice(nonce), payload)
+            .expect("data is too long to encrypt");
+        let mut encrypted = Vec::with_capacity(NONCE_SIZE + ciphertext.len());
+        encrypted.extend_from_slice(nonce);
+        encrypted.extend(ciphertext);
+        encrypted
+    }
+
+    /// Decrypt data encrypted by `encrypt`. Returns `None` if it wasn't
+    /// encrypted with this key and the given associated data, or was altered
+    /// after.
+    pub(crate) fn decrypt(&self, encrypted: &[u8], associated_data: &[u8]) -> Option<Vec<u8>> {
+        if encrypted.len() < NONCE_SIZE + TAG_SIZE {
+            return None;
+        }
+        let (nonce, ciphertext) = encrypted.split_at(NONCE_SIZE);
+        let payload = Payload {
+            msg: ciphertext,
+            aad: associated_data,
+        };
+        self.aead.decrypt(XNonce::from_slice(nonce), payload).ok()
+    }
+
+    /// Seal the contents of the file at the given path. The name of the file
+    /// is authenticated along with them, so that sealed files can't be swapped
+    /// for one another.
+    pub(crate) fn seal_file(&self, path: &Path, contents: &[u8]) -> Vec<u8> {
+        let mut sealed = SEALED_FILE_MAGIC.to_vec();
+        sealed.extend(self.encrypt(contents, file_name(path)));
+        sealed
+    }
+
+    /// The contents of a file sealed by `seal_file`
+    pub(crate) fn unseal_file(
+        &self,
+        path: &Path,
+        sealed: &[u8],
+    ) -> Result<Vec<u8>, EncryptionError> {
+        if !is_sealed(sealed) {
+            return Err(EncryptionError::NotEncrypted {
+                path: path.to_path_buf(),
+            });
+        }
+        self.decrypt(&sealed[SEALED_FILE_MAGIC.len()..], file_name(path))
+            .ok_or_else(|| EncryptionError::WrongKey {
+                path: path.to_path_buf(),
+            })
+    }
+}
+
+impl Debug for Cipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Cipher(..)")
+    }
+}
+
+impl EncryptedBlock {
+    /// Wrap a block of the given length, whose entries are authenticated as
+    /// entries of the file at `final_path` (which is the block's own path,
+    /// unless it is written under a temporary name).
+    pub(crate) fn new(
+        inner: Arc<dyn BlockStorage>,
+        cipher: Arc<Cipher>,
+        final_path: &Path,
+        length: u64,
+    ) -> Self {
+        Self {
+            inner,
+            cipher,
+            file_name: file_name(final_path).to_vec(),
+            length: Mutex::new(length),
+        }
+    }
+}
+
+#[async_trait]
+impl BlockStorage for EncryptedBlock {
+    fn path(&self) -> &Path {
+        self.inner.path()
+    }
+
+    fn durability(&self) -> Durability {
+        self.inner.durability()
+    }
+
+    fn pending_sync(&self) -> Option<PendingSync> {
+        self.inner.pending_sync()
+    }
+
+    async fn write_entry(&self, data: Vec<u8>, flags: EntryFlags) -> Result<u64, StorageError> {
+        let mut length = self.length.lock().await;
+        let associated_data = associated_data(&self.file_name, *length, flags);
+        let encrypted = self.cipher.encrypt(&data, &associated_data);
+        let flags = EntryFlags {
+            is_encrypted: true,
+            ..flags
+        };
+        let size = self.inner.write_entry(encrypted, flags).await?;
+        *length += size;
+        Ok(size)
+    }
+
+    async fn read_entry(&self, offset: u64) -> Result<(Vec<u8>, EntryHeader), StorageError> {
+        let (data, header) = self.inner.read_entry(offset).await?;
+        decrypt_entry(
+            &self.cipher,
+            self.path(),
+            &self.file_name,
+            offset,
+            data,
+            header,
+        )
+    }
+
+    async fn scan_entries(&self, offset: u64) -> Result<EntryDataStream, StorageError> {
+        let stream = self.inner.scan_entries(offset).await?;
+        let cipher = self.cipher.clone();
+        let path = self.path().to_path_buf();
+        let file_name = self.file_name.clone();
+        let mut offset = offset;
+        Ok(Box::pin(stream.map(move |result| {
+            let (data, header) = result?;
+            let entry_offset = offset;
+            offset += header.entry_size();
+            decrypt_entry(&cipher, &path, &file_name, entry_offset, data, header)
+        })))
+    }
+
+    async fn truncate(&self, length: u64) -> Result<(), StorageError> {
+        let mut current_length = self.length.lock().await;
+        self.inner.truncate(length).await?;
+        *current_length = length;
+        Ok(())
+    }
+
+    async fn sync(&self) -> Result<(), StorageError> {
+        self.inner.sync().await
+    }
+
+    async fn tail_checksum(&self, length: u64) -> Result<u32, StorageError> {
+        self.inner.tail_checksum(length).await
+    }
+}
+
+/// Decrypt the data of the entry at the given byte-offset of an encrypted
+/// block, which is authenticated as an entry of the file with the given name;
+/// the header it is returned with doesn't have the encrypted flag anymore.
+pub(crate) fn decrypt_entry(
+    cipher: &Cipher,
+    path: &Path,
+    file_name: &[u8],
+    offset: u64,
+    data: Vec<u8>,
+    header: EntryHeader,
+) -> Result<(Vec<u8>, EntryHeader), StorageError> {
+    let flags = header.flags();
+    if !flags.is_encrypted {
+        return Err(EncryptionError::NotEncrypted {
+            path: path.to_path_buf(),
+        }
+        .into());
+    }
+    let flags = EntryFlags {
+        is_encrypted: false,
+        ..flags
+    };
+    let data = cipher
+        .decrypt(&data, &associated_data(file_name, offset, flags))
+        .ok_or_else(|| EncryptionError::WrongKey {
+            path: path.to_path_buf(),
+        })?;
+    Ok((data, header.with_flags(flags)))
+}
+
+// the place of an entry (its file and byte-offset) is authenticated along with
+// its data, so that it can't be swapped with or replayed over another entry;
+// and so are its flags, so that e.g. a tuple can't be turned into a tombstone
+fn associated_data(file_name: &[u8], offset: u64, flags: EntryFlags) -> Vec<u8> {
+    let mut associated_data = Vec::with_capacity(9 + file_name.len());
+    associated_data.push(flags.is_tombstone as u8 | (flags.is_compressed as u8) << 1);
+    associated_data.extend_from_slice(&offset.to_le_bytes());
+    associated_data.extend_from_slice(file_name);
+    associated_data
+}
+
+/// Is the file with these contents sealed with an encryption key
+pub(crate) fn is_sealed(contents: &[u8]) -> bool {
+    contents.starts_with(&SEALED_FILE_MAGIC)
+}
+
+fn file_name(path: &Path) -> &[u8] {
+    path.file_name().unwrap_or_default().as_encoded_bytes()
+}
@@ -0,0 +1,909 @@
+/// Integrity check ("fsck") of a database directory. Every entry of every
+/// table's block is read, and checked that:
+///
+/// - it can be read and decoded, i.e. its checksum matches, and it can be
+///   decrypted, decompressed and deserialized,
+/// - a tuple has a value for each of the table's columns (and no more), of the
+///   column's type, and has a primary key.
+///
+/// Besides the entries, the catalog must be readable, the table definitions
+/// must make sense, the index in a table's hint file must be the one the
+/// table's block has, every value a tuple stores out of line must be readable
+/// from the table's blob files, and every block file in the directory (i.e.
+/// the segments, blob files and hint files) must belong to a table in the
+/// catalog.
+///
+/// The SSTables of an LSM table are checked the same way, and besides, the
+/// manifest of the tree must be readable, and the keys of every SSTable must be
+/// sorted. Problems of LSM tables are not repaired.
+///
+/// In repair mode, the problems which can be repaired are repaired:
+///
+/// - a partially written or corrupt entry is cut off, along with everything
+///   after it in its segment (which can't be read past it anyway),
+/// - a segment with entries which can't be decoded, or with bad tuples
+///   (including tuples whose values stored out of line can't be read), is
+///   rewritten without them,
+/// - a partially written value at the end of a table's active blob file is cut
+///   off,
+/// - a missing block (i.e. segment 0 of a table) is created empty,
+/// - a hint file which doesn't match its block is removed, so that the index
+///   is rebuilt when the database is opened,
+/// - orphan files are moved to the `lost+found` directory, in the database
+///   directory.
+///
+/// A segment is rewritten the same way as in a migration: to a temporary file,
+/// which is then renamed over it. The database must not be open while it is
+/// checked.
+use std::{
+    collections::{BTreeMap, HashSet},
+    ffi::OsString,
+    fmt::Display,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use futures::StreamExt;
+use serde::Serialize;
+
+use crate::{
+    backend::{Backend, BlockStorage},
+    blob::{find_blob_files, read_blob},
+    catalog::{read_table_definitions, CatalogError, CATALOG_FILE_NAME},
+    columnar::ChunkStats,
+    lsm::{find_sstable_files, get_manifest_path, read_manifest, LsmError, SsTableFooter},
+    query::{
+        dml::put_item::typecheck_column,
+        types::{
+            ColumnName, ColumnType, ColumnValue, Durability, StorageEngine, TableDefinition,
+            TableName,
+        },
+    },
+    segment::{find_segment_files, EntryPosition, SegmentId},
+    storage::{decode_entry, BlockEntry, StorageError, Tuple, FILE_HEADER_SIZE},
+    table::{
+        get_hint_path, get_table_path_, remove_hint_file, Index, TableBuffer, TableBufferError,
+        TableOptions,
+    },
+    wal::get_wal_path,
+};
+
+/// Directory (in the database directory) orphan files are moved to, in repair
+/// mode.
+const LOST_AND_FOUND_DIR: &str = "lost+found";
+
+/// Suffix of the temporary file a segment is rewritten to.
+const REPAIR_FILE_SUFFIX: &str = "repair";
+
+/// Result of an integrity check of a database.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct FsckReport {
+    /// Number of tables checked
+    pub tables: usize,
+    /// Number of entries checked, in all the tables' blocks
+    pub entries: u64,
+    /// The problems found, in the order they were found
+    pub problems: Vec<FsckProblem>,
+}
+
+/// A problem found by an integrity check.
+#[derive(Debug, Clone, Serialize)]
+pub struct FsckProblem {
+    /// The file with the problem
+    pub path: PathBuf,
+    /// Byte-offset of the entry with the problem, if it is a problem of an
+    /// entry
+    pub offset: Option<u64>,
+    pub kind: FsckProblemKind,
+    /// Was the problem repaired (in repair mode)
+    pub repaired: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FsckProblemKind {
+    /// The catalog can't be deserialized
+    CorruptCatalog { error: String },
+    /// A table definition in the catalog is invalid
+    InvalidTableDefinition { table: TableName, error: String },
+    /// Segment 0 of a table's block doesn't exist; or the manifest or an
+    /// SSTable of an LSM table
+    MissingBlock { table: TableName },
+    /// The manifest of an LSM table can't be read
+    CorruptManifest { error: String },
+    /// The file header of a segment is corrupt, or for an older format or a
+    /// different schema
+    BadFileHeader { error: String },
+    /// The last segment of a block ends with a partially written entry
+    TornTail,
+    /// The checksum of an entry doesn't match. The rest of the segment can't be
+    /// read.
+    CorruptEntry,
+    /// An entry can't be decrypted, decompressed or deserialized
+    UndecodableEntry { error: String },
+    /// A tuple doesn't have as many values as the table has columns
+    ArityMismatch { expected: usize, found: usize },
+    /// A value of a tuple is not of its column's type
+    TypeMismatch {
+        column: ColumnName,
+        expected: ColumnType,
+        found: ColumnType,
+    },
+    /// A tuple doesn't have a primary key
+    MissingPrimaryKey,
+    /// A tuple has no value for a column which is not nullable
+    NullValue { column: ColumnName },
+    /// A value a tuple stores out of line can't be read from the table's blob
+    /// files
+    UnreadableBlob { error: String },
+    /// The key of an entry of an SSTable is not greater than the key of the
+    /// entry before it
+    UnsortedEntry,
+    /// The index in a hint file is not the index of its block
+    StaleHint,
+    /// A block file (or hint file) of a table which is not in the catalog
+    OrphanFile,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum FsckError {
+    #[error("Could not read the catalog. {0}")]
+    CatalogError(#[from] CatalogError),
+    #[error("Internal Storage Engine Error: {0}")]
+    StorageError(#[from] StorageError),
+    #[error("Internal Storage Engine Error: {0}")]
+    TableStorageError(#[from] TableBufferError),
+    #[error("Fsck file operation failed. {0}")]
+    FileOperation(#[from] std::io::Error),
+}
+
+impl FsckReport {
+    /// Does the database have no problems, or only problems which were
+    /// repaired
+    pub fn is_clean(&self) -> bool {
+        self.problems.iter().all(|problem| problem.repaired)
+    }
+}
+
+impl Display for FsckProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.path.display())?;
+        if let Some(offset) = self.offset {
+            write!(f, " (byte-offset {})", offset)?;
+        }
+        write!(f, ": {}", self.kind)?;
+        if self.repaired {
+            write!(f, " [repaired]")?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for FsckProblemKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::CorruptCatalog { error } => write!(f, "corrupt catalog. {}", error),
+            Self::InvalidTableDefinition { table, error } => {
+                write!(f, "invalid definition of table '{}': {}", table, error)
+            }
+            Self::MissingBlock { table } => write!(f, "block of table '{}' is missing", table),
+            Self::CorruptManifest { error } => write!(f, "corrupt LSM manifest. {}", error),
+            Self::BadFileHeader { error } => write!(f, "bad file header. {}", error),
+            Self::TornTail => write!(f, "partially written entry"),
+            Self::CorruptEntry => write!(f, "corrupt entry: checksum mismatch"),
+            Self::UndecodableEntry { error } => write!(f, "entry can't be decoded. {}", error),
+            Self::ArityMismatch { expected, found } => write!(
+                f,
+                "tuple has {} values, but the table has {} columns",
+                found, expected
+            ),
+            Self::TypeMismatch {
+                column,
+                expected,
+                found,
+            } => write!(
+                f,
+                "value of column '{}' has type {}, but the column is of type {}",
+                column, found, expected
+            ),
+            Self::MissingPrimaryKey => write!(f, "tuple has no primary key"),
+            Self::NullValue { column } => {
+                write!(
+                    f,
+                    "tuple has no value for column '{}', which is not nullable",
+                    column
+                )
+            }
+            Self::UnreadableBlob { error } => {
+                write!(f, "value stored out of line can't be read. {}", error)
+            }
+            Self::UnsortedEntry => write!(f, "entry is out of order in its SSTable"),
+            Self::StaleHint => write!(f, "hint file doesn't match the table's block"),
+            Self::OrphanFile => write!(f, "file doesn't belong to any table in the catalog"),
+        }
+    }
+}
+
+/// Check the integrity of the database in the given directory, and repair it
+/// if `repair` is set.
+pub(crate) async fn check_database(
+    options: &TableOptions,
+    directory_path: &Path,
+    repair: bool,
+) -> Result<FsckReport, FsckError> {
+    let mut checker = Checker {
+        backend: &options.backend,
+        options,
+        directory_path,
+        repair,
+        report: FsckReport::default(),
+        blob_files: BTreeMap::new(),
+    };
+    let tables = match read_table_definitions(&options.backend, directory_path) {
+        Ok(tables) => tables,
+        // without the catalog, there is nothing else to check against
+        Err(CatalogError::DeserError(err)) => {
+            checker.report.problems.push(FsckProblem {
+                path: directory_path.join(CATALOG_FILE_NAME),
+                offset: None,
+                kind: FsckProblemKind::CorruptCatalog {
+                    error: err.to_string(),
+                },
+                repaired: false,
+            });
+            return Ok(checker.report);
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut table_names = HashSet::new();
+    for table in &tables {
+        if !table_names.insert(&table.name) {
+            checker.invalid_definition(table, "there is another table with the same name");
+            continue;
+        }
+        checker.check_table(table).await?;
+    }
+    checker.check_orphans(&tables)?;
+    Ok(checker.report)
+}
+
+struct Checker<'a> {
+    backend: &'a Backend,
+    options: &'a TableOptions,
+    directory_path: &'a Path,
+    repair: bool,
+    report: FsckReport,
+    /// The blob files of the table being checked; `None` for one whose file
+    /// header is bad
+    blob_files: BTreeMap<u64, Option<Arc<dyn BlockStorage>>>,
+}
+
+// what scanning a segment found
+struct SegmentScan {
+    /// Byte-offsets of the entries which are fine
+    valid_offsets: Vec<u64>,
+    /// Are there entries which are not fine, besides the tail
+    has_bad_entries: bool,
+    /// Byte-offset the segment can be read up to, if the rest of it can't be
+    /// read
+    readable_length: Option<u64>,
+}
+
+impl Checker<'_> {
+    async fn check_table(&mut self, table: &TableDefinition) -> Result<(), FsckError> {
+        self.report.tables += 1;
+        let mut column_names = HashSet::new();
+        if let Some(column) = table
+            .columns
+            .iter()
+            .find(|column| !column_names.insert(&column.name))
+        {
+            let error = format!("column '{}' is defined more than once", column.name);
+            self.invalid_definition(table, &error);
+        }
+        // the tuples can't be checked without knowing where their key is
+        let Some(pk_position) = table.primary_key_position() else {
+            let error = format!(
+                "primary key '{}' is not one of the table's columns",
+                table.primary_key
+            );
+            self.invalid_definition(table, &error);
+            return Ok(());
+        };
+
+        let base_path = get_table_path_(self.directory_path, &table.name);
+        if matches!(
+            table.engine,
+            Some(StorageEngine::Lsm | StorageEngine::Columnar)
+        ) {
+            return self.check_tree(table, pk_position, &base_path).await;
+        }
+        let schema_fingerprint = table.schema_fingerprint();
+        let mut problems = vec![];
+        if !self.backend.file_exists(&base_path) {
+            if self.repair {
+                self.backend.create_block(&base_path, schema_fingerprint)?;
+            }
+            problems.push(self.problem(
+                &base_path,
+                None,
+                FsckProblemKind::MissingBlock {
+                    table: table.name.clone(),
+                },
+            ));
+        }
+
+        self.check_blob_files(&base_path, schema_fingerprint, &mut problems)
+            .await?;
+        let mut segments = find_segment_files(self.backend, &base_path)?;
+        segments.sort();
+        let active_segment = segments.last().map(|(segment_id, _)| *segment_id);
+        let mut index = Index::new();
+        for (segment_id, path) in segments {
+            if let Err(err) = self
+                .backend
+                .open_block(&path, Durability::NoSync, schema_fingerprint)
+            {
+                match err {
+                    StorageError::OutdatedFormat { .. }
+                    | StorageError::UnsupportedFormat { .. }
+                    | StorageError::CorruptFileHeader { .. }
+                    | StorageError::SchemaMismatch { .. } => {
+                        let kind = FsckProblemKind::BadFileHeader {
+                            error: err.to_string(),
+                        };
+                        // a segment in another format is not touched
+                        problems.push(FsckProblem {
+                            repaired: false,
+                            ..self.problem(&path, None, kind)
+                        });
+                        continue;
+                    }
+                    err => return Err(err.into()),
+                }
+            }
+            let is_active = Some(segment_id) == active_segment;
+            let mut segment_problems = vec![];
+            let scan = self
+                .scan_segment(
+                    table,
+                    pk_position,
+                    (segment_id, &path),
+                    is_active,
+                    &mut index,
+                    &mut segment_problems,
+                )
+                .await?;
+            if self.repair && !segment_problems.is_empty() {
+                // the entries move (or go away), so the index in the hint file
+                // is no good anymore
+                remove_hint_file(self.backend, &base_path)?;
+                if scan.has_bad_entries {
+                    self.rewrite_segment(&path, schema_fingerprint, &scan.valid_offsets)
+                        .await?;
+                } else if let Some(length) = scan.readable_length {
+                    self.backend
+                        .open_block_unchecked(&path, Durability::NoSync)?
+                        .truncate(length)
+                        .await?;
+                }
+            }
+            problems.extend(segment_problems);
+        }
+
+        // the hint file can only be checked against a block without problems
+        // (a repaired block has its hint file removed)
+        if problems.is_empty() && self.backend.file_exists(&get_hint_path(&base_path)) {
+            let table_buffer = TableBuffer::new(table, self.directory_path, self.options).await?;
+            if table_buffer.index.index != index.index {
+                if self.repair {
+                    remove_hint_file(self.backend, &base_path)?;
+                }
+                problems.push(self.problem(
+                    &get_hint_path(&base_path),
+                    None,
+                    FsckProblemKind::StaleHint,
+                ));
+            }
+        }
+        self.report.problems.extend(problems);
+        Ok(())
+    }
+
+    // read every entry of a segment, replaying the valid ones on the index
+    async fn scan_segment(
+        &mut self,
+        table: &TableDefinition,
+        pk_position: usize,
+        (segment_id, path): (SegmentId, &Path),
+        is_active: bool,
+        index: &mut Index,
+        problems: &mut Vec<FsckProblem>,
+    ) -> Result<SegmentScan, FsckError> {
+        let mut scan = SegmentScan {
+            valid_offsets: vec![],
+            has_bad_entries: false,
+            readable_length: None,
+        };
+        // the entries are read as they are stored, so that an entry which
+        // can't be decrypted doesn't hide where the next one starts
+        let block = self
+            .backend
+            .open_block_unchecked(path, Durability::NoSync)?;
+        let mut stream = block.scan_entries(FILE_HEADER_SIZE).await?;
+        let mut offset = FILE_HEADER_SIZE;
+        while let Some(result) = stream.next().await {
+            let (data, header) = match result {
+                Ok(entry) => entry,
+                // only the active segment is written to, so anywhere else a
+                // partially written entry is a corrupt one
+                Err(StorageError::TornTail { offset, .. }) if is_active => {
+                    problems.push(self.problem(path, Some(offset), FsckProblemKind::TornTail));
+                    scan.readable_length = Some(offset);
+                    break;
+                }
+                Err(
+                    StorageError::TornTail { offset, .. }
+                    | StorageError::CorruptEntry { offset, .. },
+                ) => {
+                    problems.push(self.problem(path, Some(offset), FsckProblemKind::CorruptEntry));
+                    scan.readable_length = Some(offset);
+                    break;
+                }
+                Err(err) => return Err(err.into()),
+            };
+            self.report.entries += 1;
+            let position = EntryPosition {
+                segment: segment_id,
+                offset,
+                length: header.entry_size(),
+            };
+            offset += header.entry_size();
+
+            let entry = self
+                .backend
+                .decrypt_entry(path, position.offset, data, header)
+                .and_then(|(data, header)| decode_entry(path, data, &header));
+            let problem = match entry {
+                Err(err) => Some(FsckProblemKind::UndecodableEntry {
+                    error: err.to_string(),
+                }),
+                Ok(BlockEntry::Tombstone(key)) => {
+                    index.remove(&key, position.length);
+                    None
+                }
+                Ok(BlockEntry::Tuple(tuple)) => {
+                    let problem = match check_tuple(table, pk_position, &tuple) {
+                        None => self.check_blobs(&tuple).await,
+                        problem => problem,
+                    };
+                    if problem.is_none() {
+                        if let Some(key) = &tuple[pk_position] {
+                            index.update(key.clone(), position);
+                        }
+                    }
+                    problem
+                }
+            };
+            match problem {
+                Some(kind) => {
+                    problems.push(self.problem(path, Some(position.offset), kind));
+                    scan.has_bad_entries = true;
+                }
+                None => scan.valid_offsets.push(position.offset),
+            }
+        }
+        Ok(scan)
+    }
+
+    // check the file headers of the blob files of a log table, and that the
+    // active one doesn't end with a partially written value. The values
+    // themselves are checked as tuples refer to them.
+    async fn check_blob_files(
+        &mut self,
+        base_path: &Path,
+        schema_fingerprint: u32,
+        problems: &mut Vec<FsckProblem>,
+    ) -> Result<(), FsckError> {
+        self.blob_files.clear();
+        let mut files = find_blob_files(self.backend, base_path)?;
+        files.sort();
+        let active_file = files.last().map(|(id, _)| *id);
+        for (id, path) in files {
+            let block = match self
+                .backend
+                .open_block(&path, Durability::NoSync, schema_fingerprint)
+            {
+                Ok(block) => block,
+                Err(
+                    err @ (StorageError::OutdatedFormat { .. }
+                    | StorageError::UnsupportedFormat { .. }
+                    | StorageError::CorruptFileHeader { .. }
+                    | StorageError::SchemaMismatch { .. }),
+                ) => {
+                    let kind = FsckProblemKind::BadFileHeader {
+                        error: err.to_string(),
+                    };
+                    problems.push(FsckProblem {
+                        repaired: false,
+                        ..self.problem(&path, None, kind)
+                    });
+                    self.blob_files.insert(id, None);
+                    continue;
+                }
+                Err(err) => return Err(err.into()),
+            };
+            let raw_block = self
+                .backend
+                .open_block_unchecked(&path, Durability::NoSync)?;
+            let mut stream = raw_block.scan_entries(FILE_HEADER_SIZE).await?;
+            while let Some(result) = stream.next().await {
+                match result {
+                    Ok(_) => self.report.entries += 1,
+                    // only the active blob file is written to
+                    Err(StorageError::TornTail { offset, .. }) if Some(id) == active_file => {
+                        if self.repair {
+                            raw_block.truncate(offset).await?;
+                        }
+                        problems.push(self.problem(&path, Some(offset), FsckProblemKind::TornTail));
+                        break;
+                    }
+                    // the values after it can still be read at their offsets
+                    Err(
+                        StorageError::TornTail { offset, .. }
+                        | StorageError::CorruptEntry { offset, .. },
+                    ) => {
+                        problems.push(FsckProblem {
+                            repaired: false,
+                            ..self.problem(&path, Some(offset), FsckProblemKind::CorruptEntry)
+                        });
+                        break;
+                    }
+                    Err(err) => return Err(err.into()),
+                }
+            }
+            self.blob_files.insert(id, Some(block));
+        }
+        Ok(())
+    }
+
+    // the first value the tuple stores out of line which can't be read
+    async fn check_blobs(&self, tuple: &Tuple) -> Option<FsckProblemKind> {
+        for value in tuple.iter().flatten() {
+            let ColumnValue::Blob(blob) = value else {
+                continue;
+            };
+            let error = match self.blob_files.get(&blob.file) {
+                // the blob file's header is reported already
+                Some(None) => continue,
+                Some(Some(block)) => match read_blob(block.as_ref(), blob.offset).await {
+                    Ok(_) => continue,
+                    Err(err) => err.to_string(),
+                },
+                None => format!("blob file {} is missing", blob.file),
+            };
+            return Some(FsckProblemKind::UnreadableBlob { error });
+        }
+        None
+    }
+
+    // check the manifest and the SSTables of the tree of an LSM table. None of
+    // the problems are repaired: the tree is only ever changed by replacing
+    // SSTables as a whole.
+    async fn check_tree(
+        &mut self,
+        table: &TableDefinition,
+        pk_position: usize,
+        base_path: &Path,
+    ) -> Result<(), FsckError> {
+        let manifest_path = get_manifest_path(base_path);
+        let missing = FsckProblemKind::MissingBlock {
+            table: table.name.clone(),
+        };
+        if !self.backend.file_exists(&manifest_path) {
+            self.unrepaired_problem(&manifest_path, None, missing);
+            return Ok(());
+        }
+        let manifest = match read_manifest(self.backend, base_path) {
+            Ok(manifest) => manifest,
+            Err(err @ (LsmError::CorruptManifest { .. } | LsmError::Encryption(_))) => {
+                let kind = FsckProblemKind::CorruptManifest {
+                    error: err.to_string(),
+                };
+                self.unrepaired_problem(&manifest_path, None, kind);
+                return Ok(());
+            }
+            Err(err) => return Err(TableBufferError::from(err).into()),
+        };
+
+        let schema_fingerprint = table.schema_fingerprint();
+        for (path, footer_offset) in manifest.sstables(base_path) {
+            if !self.backend.file_exists(&path) {
+                self.unrepaired_problem(&path, None, missing.clone());
+                continue;
+            }
+            let block = match self
+                .backend
+                .open_block(&path, Durability::NoSync, schema_fingerprint)
+            {
+                Ok(block) => block,
+                Err(err) => {
+                    let kind = FsckProblemKind::BadFileHeader {
+                        error: err.to_string(),
+                    };
+                    self.unrepaired_problem(&path, None, kind);
+                    continue;
+                }
+            };
+            let chunks = match block.read_record::<SsTableFooter>(footer_offset).await {
+                Ok(footer) => footer.chunks,
+                Err(err) => {
+                    let kind = FsckProblemKind::UndecodableEntry {
+                        error: err.to_string(),
+                    };
+                    self.unrepaired_problem(&path, Some(footer_offset), kind);
+                    vec![]
+                }
+            };
+            let is_columnar = !chunks.is_empty();
+            self.scan_sstable(table, pk_position, &path, footer_offset, is_columnar)
+                .await?;
+            if is_columnar {
+                self.check_chunks(table, pk_position, &path, block.as_ref(), &chunks)
+                    .await;
+            }
+        }
+        Ok(())
+    }
+
+    // the entries of a columnar SSTable are columns of tuples, which are
+    // checked chunk by chunk
+    async fn check_chunks(
+        &mut self,
+        table: &TableDefinition,
+        pk_position: usize,
+        path: &Path,
+        block: &dyn BlockStorage,
+        chunks: &[ChunkStats],
+    ) {
+        let mut last_key: Option<ColumnValue> = None;
+        for chunk in chunks {
+            // an entry which can't be read is reported by the scan already
+            let Ok(entries) = chunk.read(block, pk_position, |_| true).await else {
+                continue;
+            };
+            for (key, tuple) in entries {
+                let problem = tuple
+                    .and_then(|tuple| check_tuple(table, pk_position, &tuple))
+                    .or(last_key
+                        .as_ref()
+                        .is_some_and(|last_key| key <= *last_key)
+                        .then_some(FsckProblemKind::UnsortedEntry));
+                if let Some(kind) = problem {
+                    self.unrepaired_problem(path, Some(chunk.offset), kind);
+                }
+                last_key = Some(key);
+            }
+        }
+    }
+
+    // read every entry of an SSTable, up to its footer
+    async fn scan_sstable(
+        &mut self,
+        table: &TableDefinition,
+        pk_position: usize,
+        path: &Path,
+        footer_offset: u64,
+        is_columnar: bool,
+    ) -> Result<(), FsckError> {
+        let block = self
+            .backend
+            .open_block_unchecked(path, Durability::NoSync)?;
+        let mut stream = block.scan_entries(FILE_HEADER_SIZE).await?;
+        let mut offset = FILE_HEADER_SIZE;
+        let mut last_key: Option<ColumnValue> = None;
+        while offset < footer_offset {
+            let Some(result) = stream.next().await else {
+                break;
+            };
+            let (data, header) = match result {
+                Ok(entry) => entry,
+                Err(
+                    StorageError::TornTail { offset, .. }
+                    | StorageError::CorruptEntry { offset, .. },
+                ) => {
+                    self.unrepaired_problem(path, Some(offset), FsckProblemKind::CorruptEntry);
+                    return Ok(());
+                }
+                Err(err) => return Err(err.into()),
+            };
+            self.report.entries += 1;
+            let entry_offset = offset;
+            offset += header.entry_size();
+
+            let entry = self
+                .backend
+                .decrypt_entry(path, entry_offset, data, header)
+                .and_then(|(data, header)| decode_entry(path, data, &header));
+            let (key, problem) = match entry {
+                Err(err) => {
+                    let kind = FsckProblemKind::UndecodableEntry {
+                        error: err.to_string(),
+                    };
+                    (None, Some(kind))
+                }
+                // the entries of a columnar SSTable are not tuples
+                Ok(_) if is_columnar => (None, None),
+                Ok(BlockEntry::Tombstone(key)) => (Some(key), None),
+                Ok(BlockEntry::Tuple(tuple)) => (
+                    tuple.get(pk_position).cloned().flatten(),
+                    check_tuple(table, pk_position, &tuple),
+                ),
+            };
+            let is_unsorted = key.is_some() && last_key.is_some() && key <= last_key;
+            let problem = problem.or(is_unsorted.then_some(FsckProblemKind::UnsortedEntry));
+            if let Some(kind) = problem {
+                self.unrepaired_problem(path, Some(entry_offset), kind);
+            }
+            if key.is_some() {
+                last_key = key;
+            }
+        }
+        Ok(())
+    }
+
+    // rewrite a segment with only the entries at the given offsets; encrypted
+    // entries are encrypted again, for their new offsets
+    async fn rewrite_segment(
+        &self,
+        path: &Path,
+        schema_fingerprint: u32,
+        offsets: &[u64],
+    ) -> Result<(), FsckError> {
+        let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+        file_name.push(".");
+        file_name.push(REPAIR_FILE_SUFFIX);
+        let repair_path = path.with_file_name(file_name);
+
+        self.backend
+            .create_block(&repair_path, schema_fingerprint)?;
+        let old_block = self
+            .backend
+            .open_block_unchecked(path, Durability::NoSync)?;
+        let new_block = self.backend.open_temporary_block(
+            &repair_path,
+            path,
+            Durability::NoSync,
+            schema_fingerprint,
+        )?;
+        for offset in offsets {
+            let (data, header) = old_block.read_entry(*offset).await?;
+            let (data, header) = self.backend.decrypt_entry(path, *offset, data, header)?;
+            new_block.write_entry(data, header.flags()).await?;
+        }
+        new_block.sync().await?;
+        self.backend.rename(&repair_path, path)?;
+        self.backend.sync_parent_dir(path)?;
+        Ok(())
+    }
+
+    // find the block files (and hint files) in the directory, which don't
+    // belong to any table in the catalog
+    fn check_orphans(&mut self, tables: &[TableDefinition]) -> Result<(), FsckError> {
+        let mut known_files: HashSet<OsString> = HashSet::new();
+        for table in tables {
+            let base_path = get_table_path_(self.directory_path, &table.name);
+            for (_, path) in find_segment_files(self.backend, &base_path)? {
+                known_files.extend(path.file_name().map(ToOwned::to_owned));
+            }
+            known_files.extend(get_hint_path(&base_path).file_name().map(ToOwned::to_owned));
+            for (_, path) in find_blob_files(self.backend, &base_path)? {
+                known_files.extend(path.file_name().map(ToOwned::to_owned));
+            }
+            // SSTables which are not in the manifest are removed when the
+            // table is opened
+            for (_, path) in find_sstable_files(self.backend, &base_path)? {
+                known_files.extend(path.file_name().map(ToOwned::to_owned));
+            }
+            known_files.extend(
+                get_manifest_path(&base_path)
+                    .file_name()
+                    .map(ToOwned::to_owned),
+            );
+        }
+        known_files.extend(
+            get_wal_path(self.directory_path)
+                .file_name()
+                .map(ToOwned::to_owned),
+        );
+
+        let mut file_names = self.backend.list_directory(self.directory_path)?;
+        file_names.sort();
+        for file_name in file_names {
+            let is_block_file = file_name.to_string_lossy().contains(".dat");
+            if !is_block_file || known_files.contains(&file_name) {
+                continue;
+            }
+            let path = self.directory_path.join(&file_name);
+            if self.repair {
+                let lost_and_found = self.directory_path.join(LOST_AND_FOUND_DIR);
+                std::fs::create_dir_all(&lost_and_found)?;
+                self.backend
+                    .rename(&path, &lost_and_found.join(&file_name))?;
+            }
+            let problem = self.problem(&path, None, FsckProblemKind::OrphanFile);
+            self.report.problems.push(problem);
+        }
+        Ok(())
+    }
+
+    fn invalid_definition(&mut self, table: &TableDefinition, error: &str) {
+        let kind = FsckProblemKind::InvalidTableDefinition {
+            table: table.name.clone(),
+            error: error.to_string(),
+        };
+        let catalog_path = self.directory_path.join(CATALOG_FILE_NAME);
+        self.unrepaired_problem(&catalog_path, None, kind);
+    }
+
+    // a problem which is not repaired, even in repair mode
+    fn unrepaired_problem(&mut self, path: &Path, offset: Option<u64>, kind: FsckProblemKind) {
+        let problem = FsckProblem {
+            repaired: false,
+            ..self.problem(path, offset, kind)
+        };
+        self.report.problems.push(problem);
+    }
+
+    // a problem found; in repair mode, it is repaired by the caller
+    fn problem(&self, path: &Path, offset: Option<u64>, kind: FsckProblemKind) -> FsckProblem {
+        FsckProblem {
+            path: path.to_path_buf(),
+            offset,
+            kind,
+            repaired: self.repair,
+        }
+    }
+}
+
+// the first problem of a tuple, if it has any
+fn check_tuple(
+    table: &TableDefinition,
+    pk_position: usize,
+    tuple: &Tuple,
+) -> Option<FsckProblemKind> {
+    if tuple.len() != table.columns.len() {
+        return Some(FsckProblemKind::ArityMismatch {
+            expected: table.columns.len(),
+            found: tuple.len(),
+        });
+    }
+    for (column, value) in table.columns.iter().zip(tuple) {
+        // a text value stored out of line
+        if matches!(
+            (value, &column.r#type),
+            (Some(ColumnValue::Blob(_)), ColumnType::Text)
+        ) {
+            continue;
+        }
+        let Some(value) = value else {
+            if !column.nullable && column.name != table.primary_key {
+                return Some(FsckProblemKind::NullValue {
+                    column: column.name.clone(),
+                });
+            }
+            continue;
+        };
+        if typecheck_column(column, value).is_err() {
+            return Some(FsckProblemKind::TypeMismatch {
+                column: column.name.clone(),
+                expected: column.r#type.clone(),
+                found: value.to_type(),
+            });
+        }
+    }
+    tuple[pk_position]
+        .is_none()
+        .then_some(FsckProblemKind::MissingPrimaryKey)
+}
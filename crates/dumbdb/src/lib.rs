@@ -1,46 +1,462 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
+use backend::Backend;
+pub use backup::{backup_path, Backup, BackupError, BackupFile, BackupManifest, TableBackup};
 use catalog::Catalog;
-pub use dml::{FilterItemCommand, GetItemCommand, PutItemCommand, Record};
+pub use compaction::{Compaction, CompactionError, CompactionStats};
+pub use dml::{
+    DeleteItemCommand, DeleteWhereCommand, FilterItemCommand, GetItemCommand, PutItemCommand,
+    Record, UpdateItemCommand,
+};
+pub use encryption::{EncryptionError, EncryptionKey, KeyError};
+pub use fsck::{FsckError, FsckProblem, FsckProblemKind, FsckReport};
+pub use migration::MigrationError;
+use query::batch::BatchPlan;
+pub use query::batch::{WriteBatchCommand, WriteCommand};
 use query::ddl;
 pub use query::ddl::{CreateTableCommand, DropTableCommand};
 use query::dml;
 pub use query::error;
 use query::error::QueryError;
-pub use query::types::{ColumnValue, TableDefinition, TableName};
+pub use query::types::{
+    ColumnValue, Compression, Durability, StorageEngine, TableDefinition, TableName,
+};
+// the block is only exposed for the benchmarks in `benches/`
+use storage::PendingSync;
+use table::TableBufferError;
+use table::TableOptions;
+pub use table::{TableStats, TailRecovery};
+use wal::{Wal, WalRecord};
+#[doc(hidden)]
+pub use {
+    backend::BlockStorage,
+    storage::{Block, Tuple},
+};
 
+mod backend;
+mod backup;
+mod blob;
 mod catalog;
+mod columnar;
+mod compaction;
+mod compression;
+mod encryption;
+mod fsck;
+mod lsm;
+mod migration;
 mod query;
+mod segment;
 mod storage;
 mod table;
+mod wal;
+
+/// The directory of an in-memory database. Its files are named as if they were
+/// in this directory.
+const IN_MEMORY_PATH: &str = ":memory:";
 
 #[derive(Debug, Clone)]
 pub struct Database {
     catalog: Catalog,
+    wal: Wal,
+    options: DatabaseOptions,
+    /// Tables which writes have left due for an automatic compaction
+    due_compactions: Vec<TableName>,
+}
+
+/// Options to configure a `Database`
+#[derive(Debug, Clone)]
+pub struct DatabaseOptions {
+    /// Compact a table automatically after a write, when this fraction of its
+    /// block is dead (i.e. old versions of updated tuples, deleted tuples and
+    /// tombstones); see `take_due_compactions`. `None` disables automatic
+    /// compaction.
+    pub compaction_threshold: Option<f64>,
+    /// Tables whose blocks are smaller than this (in bytes) are not compacted
+    /// automatically.
+    pub compaction_min_bytes: u64,
+    /// How writes are made durable. Tables can override this in their
+    /// definition. This also applies to the write-ahead log, except that
+    /// batches of more than one operation are always synced to it, so that
+    /// they stay atomic.
+    pub durability: Durability,
+    /// A table's data is split into segment files; a new segment is started
+    /// once the current one grows beyond this size (in bytes).
+    pub max_segment_bytes: u64,
+    /// The memtable of an LSM table is flushed to a new SSTable once it grows
+    /// beyond this size (in bytes). The levels of the tree are sized after it.
+    pub memtable_bytes: u64,
+    /// `Text` values longer than this (in bytes) are stored out of line, in a
+    /// blob file of their table, instead of in their tuple. `None` keeps all
+    /// values inline. Only applies to tables stored in a log.
+    pub blob_threshold_bytes: Option<u64>,
+    /// Checkpoint the database (i.e. sync all the tables, and empty the
+    /// write-ahead log) when the write-ahead log grows beyond this size (in
+    /// bytes).
+    pub wal_checkpoint_bytes: u64,
+    /// Encrypt the files of the database with this key. A database created
+    /// with a key can only be opened with the same key, and one created without
+    /// a key can't be opened with one.
+    pub encryption_key: Option<EncryptionKey>,
+}
+
+impl Default for DatabaseOptions {
+    fn default() -> Self {
+        Self {
+            compaction_threshold: Some(0.5),
+            compaction_min_bytes: 1024 * 1024,
+            durability: Durability::default(),
+            max_segment_bytes: 64 * 1024 * 1024,
+            memtable_bytes: 4 * 1024 * 1024,
+            blob_threshold_bytes: Some(64 * 1024),
+            wal_checkpoint_bytes: 4 * 1024 * 1024,
+            encryption_key: None,
+        }
+    }
+}
+
+/// A write applied to the database, whose sync to disk (in group commit mode)
+/// is yet to be waited for. It doesn't borrow the database, so that the
+/// database can be let go of (e.g. a lock on it released) before waiting, and
+/// the writes which come in meanwhile share the sync. Until then, the write is
+/// visible to reads but may not survive a crash, so it must not be reported
+/// as done before `wait` returns.
+///
+/// If the sync fails, the tables written to are poisoned: reads and writes of
+/// them fail with `QueryError::TablePoisoned` from then on, rather than see a
+/// write which may be lost, until the database is reopened.
+#[must_use = "the write may not be durable until the commit is waited for"]
+#[derive(Debug)]
+pub struct PendingCommit<T> {
+    value: T,
+    syncs: Vec<PendingSync>,
+    /// The poison flags of the tables written to
+    tables: Vec<Arc<AtomicBool>>,
+}
+
+impl<T> PendingCommit<T> {
+    /// Wait for the write to be synced to disk, and return its result.
+    pub async fn wait(self) -> Result<T, QueryError> {
+        let result =
+            futures::future::try_join_all(self.syncs.into_iter().map(PendingSync::wait)).await;
+        if let Err(err) = result {
+            for poisoned in &self.tables {
+                poisoned.store(true, Ordering::SeqCst);
+            }
+            return Err(TableBufferError::from(err).into());
+        }
+        Ok(self.value)
+    }
 }
 
 impl Database {
     pub async fn new(path: &str) -> Result<Self, QueryError> {
-        let catalog = Catalog::new(PathBuf::from(path)).await?;
-        Ok(Self { catalog })
+        Self::new_with_options(path, DatabaseOptions::default()).await
+    }
+
+    pub async fn new_with_options(
+        path: &str,
+        options: DatabaseOptions,
+    ) -> Result<Self, QueryError> {
+        Self::open(PathBuf::from(path), options, Backend::disk()).await
+    }
+
+    /// Create a database which is kept entirely in memory. Nothing is written
+    /// to disk, and all of it is gone once the database is dropped.
+    pub async fn new_in_memory() -> Result<Self, QueryError> {
+        Self::new_in_memory_with_options(DatabaseOptions::default()).await
+    }
+
+    pub async fn new_in_memory_with_options(options: DatabaseOptions) -> Result<Self, QueryError> {
+        Self::open(PathBuf::from(IN_MEMORY_PATH), options, Backend::in_memory()).await
+    }
+
+    /// Migrate the files of the database at the given path, written by an
+    /// older version of dumbdb, to the current format. Such a database fails
+    /// to open until it is migrated. Returns the paths of the files which were
+    /// migrated. The database must not be open while it is being migrated.
+    pub async fn migrate(path: &str) -> Result<Vec<PathBuf>, QueryError> {
+        Self::migrate_with_options(path, DatabaseOptions::default()).await
+    }
+
+    /// Same as `migrate`, for a database which is opened with these options
+    /// (i.e. one with an encryption key).
+    pub async fn migrate_with_options(
+        path: &str,
+        options: DatabaseOptions,
+    ) -> Result<Vec<PathBuf>, QueryError> {
+        let backend = Backend::disk().with_encryption(options.encryption_key.as_ref());
+        Ok(migration::migrate_database(&backend, Path::new(path)).await?)
+    }
+
+    /// Check the integrity of the database at the given path (see `FsckReport`
+    /// for what is checked), and repair what can be repaired if `repair` is
+    /// set. The database must not be open while it is being checked.
+    pub async fn fsck(path: &str, repair: bool) -> Result<FsckReport, QueryError> {
+        Self::fsck_with_options(path, DatabaseOptions::default(), repair).await
+    }
+
+    /// Same as `fsck`, for a database which is opened with these options
+    /// (i.e. one with an encryption key).
+    pub async fn fsck_with_options(
+        path: &str,
+        options: DatabaseOptions,
+        repair: bool,
+    ) -> Result<FsckReport, QueryError> {
+        let table_options = TableOptions {
+            backend: Backend::disk().with_encryption(options.encryption_key.as_ref()),
+            durability: options.durability,
+            max_segment_bytes: options.max_segment_bytes,
+            memtable_bytes: options.memtable_bytes,
+            blob_threshold_bytes: options.blob_threshold_bytes,
+        };
+        Ok(fsck::check_database(&table_options, Path::new(path), repair).await?)
+    }
+
+    /// Restore the backup in the given directory (see `backup`) as a new
+    /// database, in the given directory, which must be empty (or not exist
+    /// yet); and open it. The backup is checked against its manifest first.
+    /// The options must have the encryption key of the backed up database, if
+    /// it has one.
+    pub async fn restore(
+        backup_path: &str,
+        path: &str,
+        options: DatabaseOptions,
+    ) -> Result<Self, QueryError> {
+        let backend = Backend::disk().with_encryption(options.encryption_key.as_ref());
+        backup::restore_backup(&backend, Path::new(backup_path), Path::new(path))?;
+        Self::new_with_options(path, options).await
+    }
+
+    async fn open(
+        path: PathBuf,
+        options: DatabaseOptions,
+        backend: Backend,
+    ) -> Result<Self, QueryError> {
+        let backend = backend.with_encryption(options.encryption_key.as_ref());
+        let table_options = TableOptions {
+            backend: backend.clone(),
+            durability: options.durability,
+            max_segment_bytes: options.max_segment_bytes,
+            memtable_bytes: options.memtable_bytes,
+            blob_threshold_bytes: options.blob_threshold_bytes,
+        };
+        let mut catalog = Catalog::new(path, table_options).await?;
+        // apply whatever might not have been applied before the database was
+        // closed
+        let (wal, records) =
+            Wal::open(&backend, &catalog.directory_path, options.durability).await?;
+        let needs_checkpoint = !records.is_empty();
+        for record in records {
+            for operation in record.operations {
+                operation.apply(&mut catalog).await?;
+            }
+        }
+        let mut db = Self {
+            catalog,
+            wal,
+            options,
+            due_compactions: vec![],
+        };
+        if needs_checkpoint {
+            db.checkpoint().await?;
+        }
+        Ok(db)
     }
 
     pub async fn create_table(&mut self, table: CreateTableCommand) -> Result<(), QueryError> {
-        ddl::create_table(table, &mut self.catalog).await
+        self.start_create_table(table).await?.wait().await
+    }
+
+    /// Same as `create_table`, but doesn't wait for the write to be synced to
+    /// disk; see `PendingCommit`. So do the other `start_` write methods.
+    pub async fn start_create_table(
+        &mut self,
+        table: CreateTableCommand,
+    ) -> Result<PendingCommit<()>, QueryError> {
+        let mut plan = BatchPlan::new(&self.catalog);
+        ddl::create_table(table, &mut plan)?;
+        self.commit(plan.into_record(), ()).await
     }
 
     pub async fn drop_table(&mut self, command: DropTableCommand) -> Result<(), QueryError> {
-        ddl::drop_table(command, &mut self.catalog).await
+        self.start_drop_table(command).await?.wait().await
+    }
+
+    pub async fn start_drop_table(
+        &mut self,
+        command: DropTableCommand,
+    ) -> Result<PendingCommit<()>, QueryError> {
+        let mut plan = BatchPlan::new(&self.catalog);
+        ddl::drop_table(command, &mut plan)?;
+        self.commit(plan.into_record(), ()).await
+    }
+
+    /// Returns the primary key of the new record, which is generated if the
+    /// item leaves it out (see `ColumnDefinition::generated`).
+    pub async fn put_item(
+        &mut self,
+        command: dml::PutItemCommand,
+    ) -> Result<ColumnValue, QueryError> {
+        self.start_put_item(command).await?.wait().await
+    }
+
+    pub async fn start_put_item(
+        &mut self,
+        command: dml::PutItemCommand,
+    ) -> Result<PendingCommit<ColumnValue>, QueryError> {
+        let mut plan = BatchPlan::new(&self.catalog);
+        let key = dml::put_item(command, &mut plan).await?;
+        self.commit(plan.into_record(), key).await
+    }
+
+    pub async fn update_item(&mut self, command: dml::UpdateItemCommand) -> Result<(), QueryError> {
+        self.start_update_item(command).await?.wait().await
+    }
+
+    pub async fn start_update_item(
+        &mut self,
+        command: dml::UpdateItemCommand,
+    ) -> Result<PendingCommit<()>, QueryError> {
+        let mut plan = BatchPlan::new(&self.catalog);
+        dml::update_item(command, &mut plan).await?;
+        self.commit(plan.into_record(), ()).await
+    }
+
+    pub async fn delete_item(&mut self, command: dml::DeleteItemCommand) -> Result<(), QueryError> {
+        self.start_delete_item(command).await?.wait().await
+    }
+
+    pub async fn start_delete_item(
+        &mut self,
+        command: dml::DeleteItemCommand,
+    ) -> Result<PendingCommit<()>, QueryError> {
+        let mut plan = BatchPlan::new(&self.catalog);
+        dml::delete_item(command, &mut plan).await?;
+        self.commit(plan.into_record(), ()).await
+    }
+
+    /// Delete all records matching the filter; returns the number of records
+    /// deleted.
+    pub async fn delete_where(
+        &mut self,
+        command: dml::DeleteWhereCommand,
+    ) -> Result<usize, QueryError> {
+        self.start_delete_where(command).await?.wait().await
+    }
+
+    pub async fn start_delete_where(
+        &mut self,
+        command: dml::DeleteWhereCommand,
+    ) -> Result<PendingCommit<usize>, QueryError> {
+        let mut plan = BatchPlan::new(&self.catalog);
+        let deleted = dml::delete_where(command, &mut plan).await?;
+        self.commit(plan.into_record(), deleted).await
+    }
+
+    /// Apply a batch of writes atomically, possibly spanning multiple tables.
+    /// If any of the writes fails, none of them are applied.
+    pub async fn write_batch(&mut self, command: WriteBatchCommand) -> Result<(), QueryError> {
+        self.start_write_batch(command).await?.wait().await
+    }
+
+    pub async fn start_write_batch(
+        &mut self,
+        command: WriteBatchCommand,
+    ) -> Result<PendingCommit<()>, QueryError> {
+        let mut plan = BatchPlan::new(&self.catalog);
+        for write in command.writes {
+            match write {
+                WriteCommand::CreateTable(command) => ddl::create_table(command, &mut plan)?,
+                WriteCommand::DropTable(command) => ddl::drop_table(command, &mut plan)?,
+                WriteCommand::PutItem(command) => {
+                    dml::put_item(command, &mut plan).await?;
+                }
+                WriteCommand::UpdateItem(command) => dml::update_item(command, &mut plan).await?,
+                WriteCommand::DeleteItem(command) => dml::delete_item(command, &mut plan).await?,
+            }
+        }
+        self.commit(plan.into_record(), ()).await
     }
 
-    pub async fn put_item(&mut self, command: dml::PutItemCommand) -> Result<(), QueryError> {
-        dml::put_item(command, &mut self.catalog).await
+    /// Sync all the tables to disk, save their indexes (so that they don't
+    /// have to be rebuilt when the database is opened), and empty the
+    /// write-ahead log. This happens automatically when the write-ahead log
+    /// grows large (see `DatabaseOptions::wal_checkpoint_bytes`), and should be
+    /// done before closing the database.
+    pub async fn checkpoint(&mut self) -> Result<(), QueryError> {
+        self.catalog.checkpoint_tables().await?;
+        self.wal.truncate().await?;
+        self.catalog.remove_retired_files()?;
+        Ok(())
+    }
+
+    // log the batch to the WAL, and then apply it; the syncs of the WAL and
+    // the tables written to are left to the returned commit
+    async fn commit<T>(
+        &mut self,
+        record: WalRecord,
+        value: T,
+    ) -> Result<PendingCommit<T>, QueryError> {
+        if record.operations.is_empty() {
+            return Ok(PendingCommit {
+                value,
+                syncs: vec![],
+                tables: vec![],
+            });
+        }
+        let mut table_names = vec![];
+        for operation in &record.operations {
+            self.check_poisoned(operation.table_name())?;
+            if !table_names.contains(operation.table_name()) {
+                table_names.push(operation.table_name().clone());
+            }
+        }
+        let is_batch = record.operations.len() > 1;
+        self.wal.append(&record, is_batch).await?;
+
+        for operation in record.operations {
+            if let Err(err) = operation.apply(&mut self.catalog).await {
+                // the batch is logged, but only partly applied (or not made
+                // durable, in sync-each-write mode)
+                self.poison(&table_names);
+                return Err(err);
+            }
+        }
+        let mut syncs: Vec<_> = self.wal.pending_sync().into_iter().collect();
+        let mut tables = vec![];
+        for table_name in &table_names {
+            if let Some(table) = self.catalog.get_table(table_name) {
+                syncs.extend(table.storage.pending_syncs()?);
+                tables.push(table.poisoned.clone());
+            }
+        }
+
+        if self.wal.size() >= self.options.wal_checkpoint_bytes {
+            self.checkpoint().await?;
+        }
+        // compacting the tables is left to the caller, so that the write
+        // doesn't wait for it (nor fail because of it)
+        for table_name in table_names {
+            if !self.due_compactions.contains(&table_name)
+                && self.is_compaction_due(&table_name).await
+            {
+                self.due_compactions.push(table_name);
+            }
+        }
+        Ok(PendingCommit {
+            value,
+            syncs,
+            tables,
+        })
     }
 
     pub async fn get_item(
         &self,
         command: dml::GetItemCommand,
     ) -> Result<Option<dml::Record>, QueryError> {
+        self.check_poisoned(&command.table_name)?;
         dml::get_item(command, &self.catalog, false).await
     }
 
@@ -48,24 +464,134 @@ impl Database {
         &self,
         command: dml::FilterItemCommand,
     ) -> Result<Vec<dml::Record>, QueryError> {
+        self.check_poisoned(&command.table_name)?;
         dml::filter_item(command, &self.catalog).await
     }
 
+    // fail if a write to the table failed to be made durable
+    fn check_poisoned(&self, table: &TableName) -> Result<(), QueryError> {
+        match self.catalog.get_table(table) {
+            Some(table) if table.poisoned.load(Ordering::SeqCst) => {
+                Err(QueryError::TablePoisoned(table.name.clone()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn poison(&self, tables: &[TableName]) {
+        for table in tables {
+            if let Some(table) = self.catalog.get_table(table) {
+                table.poisoned.store(true, Ordering::SeqCst);
+            }
+        }
+    }
+
     pub fn get_size(&self, table: &TableName) -> Option<usize> {
         self.catalog.get_table_size(table)
     }
 
+    pub async fn get_stats(&self, table: &TableName) -> Option<TableStats> {
+        self.catalog.get_table_stats(table).await
+    }
+
     pub fn list_tables(&self) -> Vec<TableName> {
         self.catalog.list_tables()
     }
+
+    /// Tables whose block ended with a partially written entry (e.g. because
+    /// of a crash in the middle of a write) when the database was opened. The
+    /// partial entry is cut off, and the table is usable as of its last valid
+    /// entry.
+    pub fn tail_recoveries(&self) -> Vec<TailRecovery> {
+        self.catalog.get_tail_recoveries()
+    }
+
+    /// Compact a table, i.e. rewrite its block with only the live tuples. This
+    /// holds an exclusive reference to the database for the entire duration of
+    /// the compaction. To avoid that, see `start_compaction`.
+    pub async fn compact_table(
+        &mut self,
+        table: &TableName,
+    ) -> Result<CompactionStats, QueryError> {
+        let mut compaction = self.start_compaction(table).await?;
+        compaction.run().await?;
+        self.finish_compaction(compaction).await
+    }
+
+    /// Start compacting a table. The returned `Compaction` should be `run`
+    /// (which doesn't need a reference to the database), and then passed to
+    /// `finish_compaction`.
+    pub async fn start_compaction(&self, table: &TableName) -> Result<Compaction, QueryError> {
+        self.check_poisoned(table)?;
+        compaction::start_compaction(table, &self.catalog).await
+    }
+
+    /// Finish a compaction started with `start_compaction`, by swapping in the
+    /// compacted block. Anything written to the table in the meantime is
+    /// carried over.
+    pub async fn finish_compaction(
+        &mut self,
+        compaction: Compaction,
+    ) -> Result<CompactionStats, QueryError> {
+        let stats = compaction::finish_compaction(compaction, &mut self.catalog).await?;
+        // the compacted block is synced; replaying the write-ahead log on top
+        // of it would only add dead tuples back
+        self.checkpoint().await?;
+        Ok(stats)
+    }
+
+    /// Back up the database to the given directory, which must be empty (or
+    /// not exist yet), and return the manifest of the backup. This holds a
+    /// shared reference to the database for the entire duration of the
+    /// backup. To avoid that, see `start_backup`.
+    pub async fn backup(&self, path: &str) -> Result<BackupManifest, QueryError> {
+        let backup = self.start_backup()?;
+        Ok(backup.write(Path::new(path)).await?)
+    }
+
+    /// Start a backup of the database, as it is right now. The returned
+    /// `Backup` should be written with `Backup::write`, which doesn't need a
+    /// reference to the database; writes to the database in the meantime are
+    /// not part of the backup.
+    pub fn start_backup(&self) -> Result<Backup, QueryError> {
+        Ok(Backup::new(&self.catalog)?)
+    }
+
+    /// Take the tables which writes have left due for an automatic compaction
+    /// (see `DatabaseOptions::compaction_threshold`), and which still are.
+    /// Writes never compact a table themselves; that's up to the caller, e.g.
+    /// with a background task which compacts each of these tables in the steps
+    /// of `start_compaction`, so that the database isn't held on to while the
+    /// compaction runs.
+    pub async fn take_due_compactions(&mut self) -> Vec<TableName> {
+        let mut tables = vec![];
+        for table in std::mem::take(&mut self.due_compactions) {
+            if self.is_compaction_due(&table).await {
+                tables.push(table);
+            }
+        }
+        tables
+    }
+
+    // is enough of the table dead for it to be compacted
+    async fn is_compaction_due(&self, table: &TableName) -> bool {
+        let Some(threshold) = self.options.compaction_threshold else {
+            return false;
+        };
+        self.get_stats(table).await.is_some_and(|stats| {
+            stats.total_bytes >= self.options.compaction_min_bytes && stats.dead_ratio() > threshold
+        })
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use std::fs::{self};
+    use std::io::Write;
 
-    use futures::StreamExt;
-    use query::types::ColumnValue;
+    use columnar::ScanFilter;
+    use futures::{StreamExt, TryStreamExt};
+    use query::types::{ColumnType, ColumnValue, Date, OrderedFloat, Timestamp, Uuid};
     use rand::Rng;
     use serde_json::json;
 
@@ -75,14 +601,14 @@ mod tests {
 
     #[tokio::test]
     async fn test_create_table() -> anyhow::Result<()> {
-        let db = setup("create_table").await?;
+        let db = setup_in_memory().await?;
         assert!(db.catalog.get_table(&"authors".into()).is_some());
         Ok(())
     }
 
     #[tokio::test]
     async fn test_write_data() -> anyhow::Result<()> {
-        let mut db = setup("write_data").await?;
+        let mut db = setup_in_memory().await?;
         for i in 0..10 {
             let author_item = create_put_item(i)?;
             db.put_item(author_item).await?;
@@ -90,7 +616,9 @@ mod tests {
         let table = db.catalog.get_table(&"authors".into()).unwrap();
 
         let lines = table
-            .table_buffer
+            .storage
+            .as_log()
+            .unwrap()
             .block
             .get_reader()
             .await?
@@ -107,7 +635,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_read_data() -> anyhow::Result<()> {
-        let mut db = setup("read_data").await?;
+        let mut db = setup_in_memory().await?;
         for i in 0..10 {
             let author_item = create_put_item(i)?;
             db.put_item(author_item).await?;
@@ -125,7 +653,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_writes_with_same_id() -> anyhow::Result<()> {
-        let mut db = setup("write_data_same_id").await?;
+        let mut db = setup_in_memory().await?;
 
         // insert one record; and read it
         let id = 42;
@@ -152,134 +680,2481 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_writing_data_updates_index() -> anyhow::Result<()> {
-        let mut db = setup("index_write").await?;
-        for i in 0..20 {
+    async fn test_update_item() -> anyhow::Result<()> {
+        let mut db = setup("update_item").await?;
+        for i in 0..10 {
             let author_item = create_put_item(i)?;
             db.put_item(author_item).await?;
         }
 
-        let table = db.catalog.get_table(&"authors".into()).unwrap();
+        let cmd = serde_json::from_value(json!({
+            "table_name": "authors",
+            "key": 4,
+            "item": { "name": "Douglas Adams" }
+        }))?;
+        db.update_item(cmd).await?;
+        let record = db.get_item(create_get_item(4)?).await?.unwrap();
+        assert_eq!(
+            record.get(&"name".into()).unwrap(),
+            &Some(ColumnValue::Text("Douglas Adams".to_string()))
+        );
+        assert_eq!(db.get_size(&"authors".into()), Some(10));
 
-        let byte_offset = table.table_buffer.index.get(&ColumnValue::Integer(0));
-        assert!(byte_offset.is_some());
-        let byte_offset = byte_offset.unwrap();
-        assert_eq!(byte_offset, &0);
+        // the old version of the tuple should not show up in scans
+        let cmd = serde_json::from_value(json!({
+            "table_name": "authors",
+            "filter": { "column": "id", "op": "$eq", "value": 4 }
+        }))?;
+        assert_eq!(db.filter_item(cmd).await?.len(), 1);
 
-        let byte_offset = table.table_buffer.index.get(&ColumnValue::Integer(6));
-        assert!(byte_offset.is_some());
-        let byte_offset = byte_offset.unwrap();
-        let tuple = table
-            .table_buffer
-            .block
-            .seek_to_offset(*byte_offset)
-            .await?;
-        let primary_key = tuple[table.table_buffer.pk_position].clone().unwrap();
-        assert_eq!(primary_key, ColumnValue::Integer(6));
+        // updating a missing record, or the primary key, is an error
+        let cmd = serde_json::from_value(json!({
+            "table_name": "authors",
+            "key": 42,
+            "item": { "name": "Nobody" }
+        }))?;
+        let res = db.update_item(cmd).await.map_err(|e| e.to_string());
+        assert_eq!(
+            res,
+            Err("Record with primary key '42' does not exist.".to_string())
+        );
+        let cmd = serde_json::from_value(json!({
+            "table_name": "authors",
+            "key": 4,
+            "item": { "id": 5 }
+        }))?;
+        assert!(db.update_item(cmd).await.is_err());
 
-        let byte_offset = table.table_buffer.index.get(&ColumnValue::Integer(9));
-        assert!(byte_offset.is_some());
-        let byte_offset = byte_offset.unwrap();
-        let tuple = table
-            .table_buffer
-            .block
-            .seek_to_offset(*byte_offset)
-            .await?;
-        let primary_key = tuple[table.table_buffer.pk_position].clone().unwrap();
-        assert_eq!(primary_key, ColumnValue::Integer(9));
+        // the index rebuilt on startup points to the latest version
+        let db = Database::new(db.catalog.directory_path.to_str().unwrap()).await?;
+        let record = db.get_item(create_get_item(4)?).await?.unwrap();
+        assert_eq!(
+            record.get(&"name".into()).unwrap(),
+            &Some(ColumnValue::Text("Douglas Adams".to_string()))
+        );
         Ok(())
     }
 
     #[tokio::test]
-    async fn test_write_lots_of_data() -> anyhow::Result<()> {
-        let mut db = setup("write_data_lots").await?;
-        for i in 0..1001 {
+    async fn test_delete_item() -> anyhow::Result<()> {
+        let mut db = setup("delete_item").await?;
+        for i in 0..100 {
             let author_item = create_put_item(i)?;
             db.put_item(author_item).await?;
         }
-        let table = db.catalog.get_table(&"authors".into()).unwrap();
-        let mut stream = table.table_buffer.block.get_reader().await?;
-        while let Some(tuple) = stream.next().await {
-            let tuple = tuple?;
-            assert_eq!(tuple.len(), 2);
-        }
+
+        let cmd = serde_json::from_value(json!({ "table_name": "authors", "key": 42 }))?;
+        db.delete_item(cmd).await?;
+        assert!(db.get_item(create_get_item(42)?).await?.is_none());
+        let cmd = serde_json::from_value(json!({ "table_name": "authors", "key": 42 }))?;
+        assert!(db.delete_item(cmd).await.is_err());
+
+        // deletes 0..=9, except the already deleted record
+        let cmd = serde_json::from_value(json!({
+            "table_name": "authors",
+            "filter": {
+                "$or": [
+                    { "column": "id", "op": "$lt", "value": 10 },
+                    { "column": "id", "op": "$eq", "value": 42 }
+                ]
+            }
+        }))?;
+        assert_eq!(db.delete_where(cmd).await?, 10);
+        assert_eq!(db.get_size(&"authors".into()), Some(89));
+        // only 10 is left out of (1, 10] and 42
+        assert_eq!(db.filter_item(create_filter_item_2()?).await?.len(), 1);
+        assert_eq!(db.filter_item(create_filter_item_1()?).await?.len(), 79);
+
+        // a deleted key can be written again
+        db.put_item(create_put_item(5)?).await?;
+        assert!(db.get_item(create_get_item(5)?).await?.is_some());
+
+        // tombstones are honored when the index is rebuilt on startup
+        let db = Database::new(db.catalog.directory_path.to_str().unwrap()).await?;
+        assert_eq!(db.get_size(&"authors".into()), Some(90));
+        assert!(db.get_item(create_get_item(42)?).await?.is_none());
+        assert!(db.get_item(create_get_item(5)?).await?.is_some());
+        assert_eq!(db.filter_item(create_filter_item_2()?).await?.len(), 2);
         Ok(())
     }
 
     #[tokio::test]
-    async fn test_filtering() -> anyhow::Result<()> {
-        let mut db = setup("filtering").await?;
+    async fn test_compaction() -> anyhow::Result<()> {
+        let mut db = setup("compaction").await?;
+        let table: TableName = "authors".into();
         for i in 0..100 {
-            let author_item = create_put_item(i)?;
-            db.put_item(author_item).await?;
+            db.put_item(create_put_item(i)?).await?;
+        }
+        for i in 0..50 {
+            db.update_item(create_update_item(i)?).await?;
         }
+        let cmd = serde_json::from_value(json!({
+            "table_name": "authors",
+            "filter": { "column": "id", "op": "$gte", "value": 90 }
+        }))?;
+        db.delete_where(cmd).await?;
+        let stats = db.get_stats(&table).await.unwrap();
+        assert_eq!(stats.rows, 90);
+        assert!(stats.dead_bytes > 0);
 
-        // test expression 1
-        let cmd = create_filter_item_1()?;
-        let res = db.filter_item(cmd).await?;
-        assert_eq!(res.len(), 80);
+        // writes that happen while the compaction runs are carried over
+        let mut compaction = db.start_compaction(&table).await?;
+        db.put_item(create_put_item(100)?).await?;
+        db.update_item(create_update_item(1)?).await?;
+        let cmd = serde_json::from_value(json!({ "table_name": "authors", "key": 2 }))?;
+        db.delete_item(cmd).await?;
+        compaction.run().await?;
+        let compaction_stats = db.finish_compaction(compaction).await?;
+        assert!(compaction_stats.new_size < compaction_stats.old_size);
 
-        // test expression 2
-        let cmd = create_filter_item_2()?;
-        let res = db.filter_item(cmd).await?;
-        assert_eq!(res.len(), 10);
+        let stats = db.get_stats(&table).await.unwrap();
+        assert_eq!(stats.rows, 90);
+        assert_eq!(stats.total_bytes, compaction_stats.new_size);
+        assert_eq!(db.filter_item(create_filter_item_1()?).await?.len(), 80);
+        assert!(db.get_item(create_get_item(2)?).await?.is_none());
+        assert!(db.get_item(create_get_item(100)?).await?.is_some());
+        let record = db.get_item(create_get_item(1)?).await?.unwrap();
+        assert_eq!(
+            record.get(&"name".into()).unwrap(),
+            &Some(ColumnValue::Text("updated".to_string()))
+        );
+
+        // a compaction can't be started before another one is finished
+        let mut compaction = db.start_compaction(&table).await?;
+        compaction.run().await?;
+        db.update_item(create_update_item(3)?).await?;
+        for result in [
+            db.start_compaction(&table).await.map(|_| ()),
+            db.compact_table(&table).await.map(|_| ()),
+        ] {
+            assert!(matches!(
+                result,
+                Err(QueryError::CompactionError(CompactionError::InProgress(_)))
+            ));
+        }
+        db.finish_compaction(compaction).await?;
+        db.update_item(create_update_item(4)?).await?;
+        let mut compaction = db.start_compaction(&table).await?;
+        compaction.run().await?;
+        db.finish_compaction(compaction).await?;
+        assert_eq!(db.get_stats(&table).await.unwrap().dead_bytes, 0);
+        assert_eq!(db.filter_item(create_filter_item_1()?).await?.len(), 80);
+        for i in [3, 4] {
+            assert!(db.get_item(create_get_item(i)?).await?.is_some());
+        }
+        // nor is a table left marked as being compacted by a compaction which
+        // is dropped instead of finished
+        drop(db.start_compaction(&table).await?);
+        db.compact_table(&table).await?;
 
+        // the compacted block is read back the same way on startup
+        let db = Database::new(db.catalog.directory_path.to_str().unwrap()).await?;
+        let reopened_stats = db.get_stats(&table).await.unwrap();
+        assert_eq!(reopened_stats.rows, 90);
+        assert_eq!(reopened_stats.dead_bytes, 0);
+        assert_eq!(db.filter_item(create_filter_item_1()?).await?.len(), 80);
         Ok(())
     }
 
-    async fn setup(test_name: &str) -> anyhow::Result<Database> {
-        let authors_table = json!({
-            "name": "authors",
-            "columns": [
-                {
-                    "name": "id",
-                    "type": "Integer",
-                },
-                {
-                    "name": "name",
-                    "type": "Text",
+    #[tokio::test]
+    async fn test_auto_compaction() -> anyhow::Result<()> {
+        let options = DatabaseOptions {
+            compaction_threshold: Some(0.5),
+            compaction_min_bytes: 0,
+            ..Default::default()
+        };
+        let mut db = setup_with_options("auto_compaction", options).await?;
+        let table: TableName = "authors".into();
+        for i in 0..10 {
+            db.put_item(create_put_item(i)?).await?;
+        }
+        for _ in 0..10 {
+            for i in 0..10 {
+                db.update_item(create_update_item(i)?).await?;
+            }
+        }
+        // the writes only mark the table as due for a compaction
+        let stats = db.get_stats(&table).await.unwrap();
+        assert_eq!(stats.rows, 10);
+        assert!(stats.dead_ratio() > 0.5);
+        assert_eq!(db.take_due_compactions().await, vec![table.clone()]);
+        assert!(db.take_due_compactions().await.is_empty());
+        db.update_item(create_update_item(1)?).await?;
+
+        // which is compacted in the background, with reads going on while
+        // the compaction runs
+        let db = std::sync::Arc::new(tokio::sync::RwLock::new(db));
+        let background = tokio::spawn({
+            let db = db.clone();
+            async move {
+                let tables = db.write().await.take_due_compactions().await;
+                for table in tables {
+                    let mut compaction = db.read().await.start_compaction(&table).await?;
+                    compaction.run().await?;
+                    db.write().await.finish_compaction(compaction).await?;
                 }
-            ],
-            "primary_key": "id"
+                Ok::<_, QueryError>(())
+            }
         });
+        assert!(db
+            .read()
+            .await
+            .get_item(create_get_item(1)?)
+            .await?
+            .is_some());
+        background.await??;
+        let db = db.read().await;
+        let stats = db.get_stats(&table).await.unwrap();
+        assert_eq!(stats.rows, 10);
+        assert_eq!(stats.dead_bytes, 0);
+        Ok(())
+    }
 
-        let root_test_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-        // if test dir already exists; remove it.
-        let dir_path = root_test_dir.join(format!("{}_{}", DB_PATH, test_name));
-        if dir_path.exists() {
-            let _ = fs::remove_dir_all(&dir_path);
+    #[tokio::test]
+    async fn test_segment_rollover() -> anyhow::Result<()> {
+        let options = DatabaseOptions {
+            max_segment_bytes: 256,
+            ..Default::default()
+        };
+        let mut db = setup_with_options("segment_rollover", options.clone()).await?;
+        let table: TableName = "authors".into();
+        for i in 0..100 {
+            db.put_item(create_put_item(i)?).await?;
         }
-        fs::create_dir_all(&dir_path)?;
-        let mut db = Database::new(dir_path.to_str().unwrap()).await?;
-        db.create_table(serde_json::from_value(authors_table)?)
+        for i in 0..50 {
+            db.update_item(create_update_item(i)?).await?;
+        }
+        for i in 90..100 {
+            let cmd = serde_json::from_value(json!({ "table_name": "authors", "key": i }))?;
+            db.delete_item(cmd).await?;
+        }
+        let table_path = db.catalog.get_table_path(&table);
+        let segment_path = |segment| segment::get_segment_path(&table_path, segment);
+        let stats = db.get_stats(&table).await.unwrap();
+        assert!(stats.segments > 2);
+        assert!(segment_path(stats.segments as u32 - 1).exists());
+        assert_eq!(stats.rows, 90);
+        assert_eq!(db.filter_item(create_filter_item_1()?).await?.len(), 80);
+        let record = db.get_item(create_get_item(1)?).await?.unwrap();
+        assert_eq!(
+            record.get(&"name".into()).unwrap(),
+            &Some(ColumnValue::Text("updated".to_string()))
+        );
+
+        // the index is rebuilt from all the segments on startup
+        db.checkpoint().await?;
+        let db_path = db.catalog.directory_path.clone();
+        fs::remove_file(db_path.join("authors.dat.hint"))?;
+        let mut db = Database::new_with_options(db_path.to_str().unwrap(), options.clone()).await?;
+        let reopened = db.get_stats(&table).await.unwrap();
+        assert_eq!(reopened.segments, stats.segments);
+        assert_eq!(reopened.total_bytes, stats.total_bytes);
+        assert_eq!(reopened.dead_bytes, stats.dead_bytes);
+
+        // compaction rewrites every segment, and removes the ones with
+        // nothing live in them
+        let compaction_stats = db.compact_table(&table).await?;
+        assert!(compaction_stats.new_size < compaction_stats.old_size);
+        let compacted = db.get_stats(&table).await.unwrap();
+        assert_eq!(compacted.rows, 90);
+        assert_eq!(compacted.dead_bytes, 0);
+        assert!(compacted.segments < stats.segments);
+        assert!(!segment_path(1).exists());
+        assert_eq!(db.filter_item(create_filter_item_1()?).await?.len(), 80);
+        let mut db = Database::new_with_options(db_path.to_str().unwrap(), options).await?;
+        let reopened = db.get_stats(&table).await.unwrap();
+        assert_eq!(reopened.segments, compacted.segments);
+        assert_eq!(reopened.total_bytes, compacted.total_bytes);
+        assert_eq!(db.filter_item(create_filter_item_1()?).await?.len(), 80);
+
+        db.drop_table(serde_json::from_value(json!({ "table_name": "authors" }))?)
             .await?;
-        Ok(db)
+        assert!(!table_path.exists());
+        assert!(!segment_path(stats.segments as u32 - 1).exists());
+        Ok(())
     }
 
-    fn create_get_item(id: u64) -> anyhow::Result<dml::GetItemCommand> {
-        Ok(serde_json::from_value(json!({
-            "table_name": "authors",
-            "key": ColumnValue::Integer(id),
-        }))?)
+    #[tokio::test]
+    async fn test_hint_file() -> anyhow::Result<()> {
+        let mut db = setup("hint_file").await?;
+        let table_name: TableName = "authors".into();
+        for i in 0..20 {
+            db.put_item(create_put_item(i)?).await?;
+        }
+        for i in 0..5 {
+            db.update_item(create_update_item(i)?).await?;
+        }
+        for i in 5..8 {
+            db.delete_item(serde_json::from_value(
+                json!({ "table_name": "authors", "key": i }),
+            )?)
+            .await?;
+        }
+        db.checkpoint().await?;
+        let hinted_length = db.get_stats(&table_name).await.unwrap().total_bytes;
+        // the tail of the block, which is not covered by the hint
+        for i in 20..25 {
+            db.put_item(create_put_item(i)?).await?;
+        }
+        db.delete_item(serde_json::from_value(
+            json!({ "table_name": "authors", "key": 0 }),
+        )?)
+        .await?;
+
+        let dir = db.catalog.directory_path.clone();
+        let table = db.catalog.get_table(&table_name).unwrap();
+        let definition = TableDefinition::from(table);
+        let table_options = TableOptions {
+            backend: Backend::disk(),
+            durability: Durability::NoSync,
+            max_segment_bytes: DatabaseOptions::default().max_segment_bytes,
+            memtable_bytes: DatabaseOptions::default().memtable_bytes,
+            blob_threshold_bytes: DatabaseOptions::default().blob_threshold_bytes,
+        };
+        let open_table = || table::TableBuffer::new(&definition, &dir, &table_options);
+        let assert_same_index = |opened: &table::TableBuffer| {
+            assert_eq!(
+                opened.index.index,
+                table.storage.as_log().unwrap().index.index
+            );
+            assert_eq!(
+                opened.index.dead_bytes,
+                table.storage.as_log().unwrap().index.dead_bytes
+            );
+        };
+
+        // the index is loaded from the hint file, and the tail is read
+        let opened = open_table().await?;
+        assert_eq!(opened.hinted_segments, Some(vec![(0, hinted_length)]));
+        assert_same_index(&opened);
+        assert_eq!(opened.size(), 21);
+
+        // a missing hint file means a full rebuild
+        let hint_path = dir.join("authors.dat.hint");
+        let stale_hint = fs::read(&hint_path)?;
+        fs::remove_file(&hint_path)?;
+        let opened = open_table().await?;
+        assert_eq!(opened.hinted_segments, None);
+        assert_same_index(&opened);
+
+        // so does a hint file of an older version of the block
+        db.compact_table(&table_name).await?;
+        assert!(hint_path.exists());
+        fs::write(&hint_path, stale_hint)?;
+        let table = db.catalog.get_table(&table_name).unwrap();
+        let opened = open_table().await?;
+        assert_eq!(opened.hinted_segments, None);
+        assert_eq!(
+            opened.index.index,
+            table.storage.as_log().unwrap().index.index
+        );
+        Ok(())
     }
 
-    fn create_put_item(id: u64) -> anyhow::Result<dml::PutItemCommand> {
-        const CHARSET: &[u8] =
-            b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789 )(*&^%$#@!~\"',;";
-        const STRING_LEN: usize = 20;
-        let mut rng = rand::thread_rng();
-        let rand_string: String = (0..STRING_LEN)
-            .map(|_| {
-                let idx = rng.gen_range(0..CHARSET.len());
-                CHARSET[idx] as char
-            })
-            .collect();
+    #[tokio::test]
+    async fn test_torn_tail_recovery() -> anyhow::Result<()> {
+        let mut db = setup("torn_tail").await?;
+        for i in 0..10 {
+            db.put_item(create_put_item(i)?).await?;
+        }
+        // the writes are not in the write-ahead log anymore, so the torn entry
+        // is the only thing recovery has to deal with
+        db.checkpoint().await?;
+        let db_path = db.catalog.directory_path.clone();
+        let table_path = db.catalog.get_table_path(&"authors".into());
+        let valid_length = fs::metadata(&table_path)?.len();
 
-        Ok(serde_json::from_value(json!({
-            "table_name": "authors",
-            "item": {
-                "id": id,
-                "name": rand_string,
+        // simulate a crash in the middle of writing an entry
+        let mut file = fs::OpenOptions::new().append(true).open(&table_path)?;
+        file.write_all(&100u64.to_le_bytes())?;
+        file.write_all(b"partial")?;
+        drop(file);
+
+        let mut db = Database::new(db_path.to_str().unwrap()).await?;
+        let recoveries = db.tail_recoveries();
+        assert_eq!(recoveries.len(), 1);
+        assert_eq!(recoveries[0].valid_length, valid_length);
+        assert_eq!(recoveries[0].truncated_bytes, 15);
+        assert_eq!(fs::metadata(&table_path)?.len(), valid_length);
+        assert_eq!(db.get_size(&"authors".into()), Some(10));
+
+        // new writes land right after the last valid entry
+        db.put_item(create_put_item(10)?).await?;
+        let db = Database::new(db_path.to_str().unwrap()).await?;
+        assert!(db.tail_recoveries().is_empty());
+        assert_eq!(db.get_size(&"authors".into()), Some(11));
+        assert!(db.get_item(create_get_item(10)?).await?.is_some());
+
+        // an entry in the middle of the segment whose length runs past the end
+        // of the file is corrupt, not torn; the entries after it are kept
+        let position = *db
+            .catalog
+            .get_table(&"authors".into())
+            .unwrap()
+            .storage
+            .as_log()
+            .unwrap()
+            .index
+            .get(&ColumnValue::Integer(3))
+            .unwrap();
+        drop(db);
+        let mut contents = fs::read(&table_path)?;
+        contents[position.offset as usize + 5] ^= 0x01;
+        fs::write(&table_path, &contents)?;
+        fs::remove_file(db_path.join("authors.dat.hint"))?;
+        let res = Database::new(db_path.to_str().unwrap()).await;
+        assert!(res.is_err_and(|err| err.to_string().contains("Corrupt entry")));
+        assert_eq!(fs::read(&table_path)?, contents);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_entry() -> anyhow::Result<()> {
+        let mut db = setup("corrupt_entry").await?;
+        for i in 0..10 {
+            db.put_item(create_put_item(i)?).await?;
+        }
+        let db_path = db.catalog.directory_path.clone();
+        let table_path = db.catalog.get_table_path(&"authors".into());
+
+        // flip a byte in the data of the third entry
+        let table = db.catalog.get_table(&"authors".into()).unwrap();
+        let position = *table
+            .storage
+            .as_log()
+            .unwrap()
+            .index
+            .get(&ColumnValue::Integer(2))
+            .unwrap();
+        let contents = fs::read(&table_path)?;
+        let mut corrupt = contents.clone();
+        corrupt[position.offset as usize + 14] ^= 0xff;
+        fs::write(&table_path, corrupt)?;
+
+        let res = db.get_item(create_get_item(2)?).await;
+        assert!(res.is_err());
+        let res = Database::new(db_path.to_str().unwrap()).await;
+        assert!(res.is_err_and(|err| err.to_string().contains("checksum mismatch")));
+
+        // the checksum covers the flags in the length prefix too: neither a
+        // flipped flag, nor a cleared checksum flag goes unnoticed
+        let flags_byte = position.offset as usize + 7;
+        for flag in [0x80, 0x20, 0x40] {
+            let mut corrupt = contents.clone();
+            corrupt[flags_byte] ^= flag;
+            fs::write(&table_path, corrupt)?;
+            assert!(db.get_item(create_get_item(2)?).await.is_err());
+            let res = Database::new(db_path.to_str().unwrap()).await;
+            assert!(res.is_err_and(|err| err.to_string().contains("checksum mismatch")));
+        }
+
+        // a length running past the end of the block is not trusted, rather
+        // than the read allocating a buffer of that length
+        let mut corrupt = contents.clone();
+        corrupt[position.offset as usize + 5] ^= 0x10;
+        fs::write(&table_path, corrupt)?;
+        let res = db.get_item(create_get_item(2)?).await;
+        assert!(res.is_err_and(|err| err.to_string().contains("Corrupt entry")));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fsck() -> anyhow::Result<()> {
+        let mut db = setup("fsck").await?;
+        for i in 0..10 {
+            db.put_item(create_put_item(i)?).await?;
+        }
+        db.checkpoint().await?;
+        let db_path = db.catalog.directory_path.display().to_string();
+        let table_path = db.catalog.get_table_path(&"authors".into());
+
+        // tuples which don't fit the table's schema, a partially written entry
+        // and a block file of a table which is not in the catalog
+        let table = db.catalog.get_table_mut(&"authors".into()).unwrap();
+        let block = &mut table.storage.as_log_mut().unwrap().block;
+        block.write(vec![Some(ColumnValue::Integer(10))]).await?;
+        let tuple = vec![Some(ColumnValue::Text("11".to_string())), None];
+        block.write(tuple).await?;
+        drop(db);
+        let mut file = fs::OpenOptions::new().append(true).open(&table_path)?;
+        file.write_all(&100u64.to_le_bytes())?;
+        drop(file);
+        let orphan_path = Path::new(&db_path).join("books.dat");
+        fs::write(&orphan_path, b"")?;
+
+        let report = Database::fsck(&db_path, false).await?;
+        assert_eq!(report.tables, 1);
+        assert_eq!(report.entries, 12);
+        let kinds: Vec<_> = report.problems.iter().map(|p| &p.kind).collect();
+        assert!(matches!(
+            kinds[..],
+            [
+                FsckProblemKind::ArityMismatch {
+                    expected: 2,
+                    found: 1
+                },
+                FsckProblemKind::TypeMismatch { .. },
+                FsckProblemKind::TornTail,
+                FsckProblemKind::OrphanFile,
+            ]
+        ));
+        assert!(!report.is_clean());
+        // nothing is changed without repair
+        assert!(orphan_path.exists());
+        assert!(!Database::fsck(&db_path, false).await?.is_clean());
+
+        let report = Database::fsck(&db_path, true).await?;
+        assert_eq!(report.problems.len(), 4);
+        assert!(report.is_clean());
+        assert!(Database::fsck(&db_path, false).await?.problems.is_empty());
+        assert!(Path::new(&db_path).join("lost+found/books.dat").exists());
+
+        let db = Database::new(&db_path).await?;
+        assert!(db.tail_recoveries().is_empty());
+        assert_eq!(db.get_size(&"authors".into()), Some(10));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_entries_without_checksum() -> anyhow::Result<()> {
+        let mut db = setup("no_checksum").await?;
+        db.put_item(create_put_item(0)?).await?;
+        db.checkpoint().await?;
+        let db_path = db.catalog.directory_path.clone();
+        let table_path = db.catalog.get_table_path(&"authors".into());
+
+        // only blocks from before block files had a header (see
+        // `test_migrate_legacy_blocks`) have entries with just a length
+        // prefix; in any other block, such an entry is corrupt
+        let mut file = fs::OpenOptions::new().append(true).open(&table_path)?;
+        for i in 1..10 {
+            let tuple = vec![
+                Some(ColumnValue::Integer(i)),
+                Some(ColumnValue::Text("legacy".to_string())),
+            ];
+            let data = rmp_serde::to_vec(&tuple)?;
+            file.write_all(&(data.len() as u64).to_le_bytes())?;
+            file.write_all(&data)?;
+        }
+        drop(file);
+
+        let res = Database::new(db_path.to_str().unwrap()).await;
+        assert!(res.is_err_and(|err| err.to_string().contains("checksum mismatch")));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_migrate_legacy_blocks() -> anyhow::Result<()> {
+        let db = setup("migrate").await?;
+        let db_path = db.catalog.directory_path.clone();
+        let table_path = db.catalog.get_table_path(&"authors".into());
+        let wal_path = db_path.join("wal.log");
+        let path = db_path.to_str().unwrap().to_string();
+        drop(db);
+
+        // blocks written before block files had a header start right away with
+        // the first entry
+        let mut file = fs::File::create(&table_path)?;
+        for i in 0..10 {
+            let tuple = vec![
+                Some(ColumnValue::Integer(i)),
+                Some(ColumnValue::Text("legacy".to_string())),
+            ];
+            let data = rmp_serde::to_vec(&tuple)?;
+            file.write_all(&(data.len() as u64).to_le_bytes())?;
+            file.write_all(&data)?;
+        }
+        drop(file);
+        fs::File::create(&wal_path)?;
+
+        let err = Database::new(&path).await.unwrap_err();
+        assert!(err.to_string().contains("older format"), "{}", err);
+
+        let migrated = Database::migrate(&path).await?;
+        assert_eq!(migrated, vec![table_path.clone(), wal_path]);
+        // there is nothing left to migrate
+        assert!(Database::migrate(&path).await?.is_empty());
+
+        let mut db = Database::new(&path).await?;
+        assert_eq!(db.get_size(&"authors".into()), Some(10));
+        db.put_item(create_put_item(10)?).await?;
+        assert_eq!(db.filter_item(create_filter_item_2()?).await?.len(), 9);
+        db.checkpoint().await?;
+        drop(db);
+
+        // a block in a format version this version of dumbdb doesn't know is
+        // neither opened, nor migrated
+        let mut contents = fs::read(&table_path)?;
+        contents[4..8].copy_from_slice(&2u32.to_le_bytes());
+        let checksum = crc32fast::hash(&contents[..12]);
+        contents[12..16].copy_from_slice(&checksum.to_le_bytes());
+        fs::write(&table_path, contents)?;
+        let err = Database::new(&path).await.unwrap_err();
+        assert!(err.to_string().contains("format version 2"), "{}", err);
+        let err = Database::migrate(&path).await.unwrap_err();
+        assert!(err.to_string().contains("format version 2"), "{}", err);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_durability_modes() -> anyhow::Result<()> {
+        let options = DatabaseOptions {
+            durability: Durability::SyncEachWrite,
+            ..Default::default()
+        };
+        let mut db = setup_with_options("durability", options).await?;
+        for i in 0..10 {
+            db.put_item(create_put_item(i)?).await?;
+        }
+        assert!(db.get_item(create_get_item(9)?).await?.is_some());
+
+        // tables can override the database's durability mode
+        let table = json!({
+            "name": "events",
+            "columns": [{ "name": "id", "type": "Integer" }],
+            "primary_key": "id",
+            "durability": { "mode": "group_commit", "window_ms": 50 }
+        });
+        db.create_table(serde_json::from_value(table)?).await?;
+        let table = db.catalog.get_table(&"events".into()).unwrap();
+        assert_eq!(
+            table.storage.as_log().unwrap().block.durability(),
+            Durability::GroupCommit { window_ms: 50 }
+        );
+        let authors = db.catalog.get_table(&"authors".into()).unwrap();
+        assert_eq!(
+            authors.storage.as_log().unwrap().block.durability(),
+            Durability::SyncEachWrite
+        );
+
+        let writes: Vec<_> = (0..20)
+            .map(|i| json!({ "type": "put_item", "table_name": "events", "item": { "id": i } }))
+            .collect();
+        let command = json!({ "writes": writes });
+        db.write_batch(serde_json::from_value(command)?).await?;
+
+        // the definition is persisted in the catalog
+        let db = Database::new(db.catalog.directory_path.to_str().unwrap()).await?;
+        let table = db.catalog.get_table(&"events".into()).unwrap();
+        assert_eq!(table.storage.as_log().unwrap().size(), 20);
+        assert_eq!(
+            table.storage.as_log().unwrap().block.durability(),
+            Durability::GroupCommit { window_ms: 50 }
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_group_commit() -> anyhow::Result<()> {
+        let options = DatabaseOptions {
+            durability: Durability::GroupCommit { window_ms: 200 },
+            compaction_threshold: None,
+            ..Default::default()
+        };
+        let db = setup_with_options("group_commit", options).await?;
+        let wal_syncs = db.wal.pending_sync().unwrap().syncs();
+        let table = db.catalog.get_table(&"authors".into()).unwrap();
+        let table_syncs = table.storage.pending_syncs()?[0].syncs();
+
+        // concurrent writers let go of the database before waiting for their
+        // commits, so they share the syncs of the WAL and the table, instead of
+        // waiting out the commit window one after the other
+        let db = std::sync::Arc::new(tokio::sync::RwLock::new(db));
+        let start = std::time::Instant::now();
+        let writes = (0..20).map(|i| {
+            let db = db.clone();
+            async move {
+                let commit = db.write().await.start_put_item(create_put_item(i)?).await?;
+                anyhow::Ok(commit.wait().await?)
+            }
+        });
+        for result in futures::future::join_all(writes).await {
+            result?;
+        }
+        assert!(start.elapsed() < std::time::Duration::from_millis(1000));
+
+        let db = db.read().await;
+        assert_eq!(db.wal.pending_sync().unwrap().syncs(), wal_syncs + 1);
+        let table = db.catalog.get_table(&"authors".into()).unwrap();
+        assert_eq!(table.storage.pending_syncs()?[0].syncs(), table_syncs + 1);
+        assert_eq!(db.get_size(&"authors".into()), Some(20));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failed_sync_poisons_table() -> anyhow::Result<()> {
+        let options = DatabaseOptions {
+            durability: Durability::GroupCommit { window_ms: 10 },
+            compaction_threshold: None,
+            ..Default::default()
+        };
+        let mut db = setup_with_options("failed_sync", options.clone()).await?;
+        let db_path = db.catalog.directory_path.display().to_string();
+        db.put_item(create_put_item(1)?).await?;
+
+        // a write whose sync fails is not reported as done, and the table is
+        // not used from then on, since the write it shows may be lost
+        let pending = db.start_put_item(create_put_item(2)?).await?;
+        let table = db.catalog.get_table(&"authors".into()).unwrap();
+        table.storage.pending_syncs()?[0].fail_next_sync();
+        assert!(pending.wait().await.is_err());
+        assert!(matches!(
+            db.get_item(create_get_item(1)?).await,
+            Err(QueryError::TablePoisoned(_))
+        ));
+        assert!(matches!(
+            db.put_item(create_put_item(3)?).await,
+            Err(QueryError::TablePoisoned(_))
+        ));
+        assert!(matches!(
+            db.start_compaction(&"authors".into()).await,
+            Err(QueryError::TablePoisoned(_))
+        ));
+        drop(db);
+
+        // reopening the database reads the table back from disk
+        let mut db = Database::new_with_options(&db_path, options).await?;
+        assert!(db.get_item(create_get_item(1)?).await?.is_some());
+        db.put_item(create_put_item(3)?).await?;
+        assert!(db.get_item(create_get_item(3)?).await?.is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compression() -> anyhow::Result<()> {
+        let options = DatabaseOptions {
+            compaction_threshold: None,
+            ..Default::default()
+        };
+        let mut db = setup_with_options("compression", options).await?;
+        let table = json!({
+            "name": "posts",
+            "columns": [
+                { "name": "id", "type": "Integer" },
+                { "name": "body", "type": "Text" }
+            ],
+            "primary_key": "id",
+            "compression": "lz4"
+        });
+        db.create_table(serde_json::from_value(table)?).await?;
+        let table_name: TableName = "posts".into();
+
+        let body =
+            |id: u64| "all work and no play makes jack a dull boy. ".repeat(id as usize % 20);
+        for id in 0..200 {
+            let command = json!({ "table_name": "posts", "item": { "id": id, "body": body(id) } });
+            db.put_item(serde_json::from_value(command)?).await?;
+        }
+        // tuples which don't get smaller are stored uncompressed
+        let command = json!({ "table_name": "posts", "item": { "id": 200, "body": "x" } });
+        db.put_item(serde_json::from_value(command)?).await?;
+
+        let check = |db: Database| async move {
+            for id in [0, 1, 19, 150, 199] {
+                let command = json!({ "table_name": "posts", "key": id });
+                let record = db
+                    .get_item(serde_json::from_value(command)?)
+                    .await?
+                    .unwrap();
+                assert_eq!(
+                    record.get(&"body".into()).unwrap(),
+                    &Some(ColumnValue::Text(body(id)))
+                );
+            }
+            let command = json!({ "table_name": "posts", "key": 200 });
+            let record = db
+                .get_item(serde_json::from_value(command)?)
+                .await?
+                .unwrap();
+            assert_eq!(
+                record.get(&"body".into()).unwrap(),
+                &Some(ColumnValue::Text("x".to_string()))
+            );
+            let filter = json!({
+                "table_name": "posts",
+                "filter": { "column": "body", "op": "$eq", "value": body(3) }
+            });
+            assert_eq!(
+                db.filter_item(serde_json::from_value(filter)?).await?.len(),
+                10
+            );
+            anyhow::Ok(db.get_stats(&"posts".into()).await.unwrap())
+        };
+        let stats = check(db.clone()).await?;
+        assert!(stats.compression_ratio > 5.0, "{:?}", stats);
+        // tables without compression don't have any savings
+        let authors = db.get_stats(&"authors".into()).await.unwrap();
+        assert_eq!(authors.uncompressed_bytes, authors.total_bytes);
+
+        // the savings are loaded from the hint file, and counted again when the
+        // block is read
+        let db_path = db.catalog.directory_path.clone();
+        db.checkpoint().await?;
+        let db = Database::new(db_path.to_str().unwrap()).await?;
+        assert_eq!(
+            check(db).await?.uncompressed_bytes,
+            stats.uncompressed_bytes
+        );
+        fs::remove_file(db_path.join("posts.dat.hint"))?;
+        let mut db = Database::new(db_path.to_str().unwrap()).await?;
+        assert_eq!(
+            db.get_stats(&table_name).await.unwrap().uncompressed_bytes,
+            stats.uncompressed_bytes
+        );
+
+        // compaction keeps the tuples compressed
+        for id in 100..=200 {
+            let command = json!({ "table_name": "posts", "key": id });
+            db.delete_item(serde_json::from_value(command)?).await?;
+        }
+        db.compact_table(&table_name).await?;
+        let stats = db.get_stats(&table_name).await.unwrap();
+        assert_eq!(stats.rows, 100);
+        assert!(stats.compression_ratio > 5.0, "{:?}", stats);
+        let command = json!({ "table_name": "posts", "key": 19 });
+        let record = db
+            .get_item(serde_json::from_value(command)?)
+            .await?
+            .unwrap();
+        assert_eq!(
+            record.get(&"body".into()).unwrap(),
+            &Some(ColumnValue::Text(body(19)))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_lsm_engine() -> anyhow::Result<()> {
+        let options = DatabaseOptions {
+            compaction_threshold: None,
+            memtable_bytes: 512,
+            ..Default::default()
+        };
+        let mut db = setup_with_options("lsm", options.clone()).await?;
+        let table = json!({
+            "name": "books",
+            "columns": [
+                { "name": "id", "type": "Integer" },
+                { "name": "title", "type": "Text" }
+            ],
+            "primary_key": "id",
+            "engine": "lsm"
+        });
+        db.create_table(serde_json::from_value(table)?).await?;
+        let table_name: TableName = "books".into();
+
+        // enough to flush the memtable many times, and compact level 0 into
+        // the levels below it
+        for id in 0..300 {
+            let command = json!({ "table_name": "books", "item": { "id": id, "title": format!("book {}", id) } });
+            db.put_item(serde_json::from_value(command)?).await?;
+        }
+        for id in 0..50 {
+            let command =
+                json!({ "table_name": "books", "key": id, "item": { "title": "updated" } });
+            db.update_item(serde_json::from_value(command)?).await?;
+        }
+        for id in 250..300 {
+            let command = json!({ "table_name": "books", "key": id });
+            db.delete_item(serde_json::from_value(command)?).await?;
+        }
+
+        let check = |db: Database| async move {
+            assert_eq!(db.get_size(&"books".into()), Some(250));
+            let title = |db: &Database, id: u64| {
+                let command = json!({ "table_name": "books", "key": id });
+                let command = serde_json::from_value(command);
+                let db = db.clone();
+                async move {
+                    let record = db.get_item(command?).await?;
+                    anyhow::Ok(record.map(|record| record.get(&"title".into()).cloned()))
+                }
+            };
+            let updated = Some(Some(Some(ColumnValue::Text("updated".to_string()))));
+            assert_eq!(title(&db, 10).await?, updated);
+            let book = Some(Some(Some(ColumnValue::Text("book 100".to_string()))));
+            assert_eq!(title(&db, 100).await?, book);
+            assert_eq!(title(&db, 260).await?, None);
+            assert_eq!(title(&db, 1000).await?, None);
+            let filter = json!({
+                "table_name": "books",
+                "filter": { "column": "id", "op": "$gte", "value": 240 }
+            });
+            let records = db.filter_item(serde_json::from_value(filter)?).await?;
+            assert_eq!(records.len(), 10);
+            anyhow::Ok(db)
+        };
+        let db = check(db).await?;
+        let stats = db.get_stats(&table_name).await.unwrap();
+        assert!(stats.segments > 1, "{:?}", stats);
+        assert_eq!(stats.dead_bytes, 0);
+
+        // the memtable is written back from the write-ahead log, and flushed on
+        // a checkpoint
+        let db_path = db.catalog.directory_path.display().to_string();
+        drop(db);
+        let mut db = check(Database::new_with_options(&db_path, options.clone()).await?).await?;
+        db.checkpoint().await?;
+        let mut db = check(Database::new_with_options(&db_path, options.clone()).await?).await?;
+
+        // compacting the table by hand merges all of its SSTables
+        let compaction_stats = db.compact_table(&table_name).await?;
+        assert!(compaction_stats.new_size < compaction_stats.old_size);
+        let mut db = check(db).await?;
+
+        // a backup has the memtable as well
+        let command =
+            json!({ "table_name": "books", "item": { "id": 1000, "title": "unflushed" } });
+        db.put_item(serde_json::from_value(command)?).await?;
+        let backup_path = format!("{}_files", db_path);
+        let restore_path = format!("{}_restored", db_path);
+        for path in [&backup_path, &restore_path] {
+            let _ = fs::remove_dir_all(path);
+        }
+        db.backup(&backup_path).await?;
+        let restored = Database::restore(&backup_path, &restore_path, options.clone()).await?;
+        assert_eq!(restored.get_size(&table_name), Some(251));
+        let command = json!({ "table_name": "books", "key": 1000 });
+        assert!(restored
+            .get_item(serde_json::from_value(command)?)
+            .await?
+            .is_some());
+
+        db.checkpoint().await?;
+        drop(db);
+        let report = Database::fsck(&db_path, false).await?;
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+        assert!(report.entries > 250);
+
+        // dropping the table removes its files
+        let mut db = Database::new_with_options(&db_path, options).await?;
+        let command = json!({ "table_name": "books" });
+        db.drop_table(serde_json::from_value(command)?).await?;
+        let leftovers = fs::read_dir(&db_path)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with("books"))
+            .count();
+        assert_eq!(leftovers, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_columnar_engine() -> anyhow::Result<()> {
+        let options = DatabaseOptions {
+            compaction_threshold: None,
+            ..Default::default()
+        };
+        let mut db = setup_with_options("columnar", options.clone()).await?;
+        let table = json!({
+            "name": "scores",
+            "columns": [
+                { "name": "id", "type": "Integer" },
+                { "name": "score", "type": "Integer" },
+                { "name": "player", "type": "Text" }
+            ],
+            "primary_key": "id",
+            "engine": "columnar"
+        });
+        db.create_table(serde_json::from_value(table)?).await?;
+        let table_name: TableName = "scores".into();
+        for id in 0..3000 {
+            let command = json!({
+                "table_name": "scores",
+                "item": { "id": id, "score": id, "player": format!("player {}", id) }
+            });
+            db.put_item(serde_json::from_value(command)?).await?;
+        }
+        db.checkpoint().await?;
+        // a newer version of a tuple in a chunk which is skipped still hides
+        // the older one
+        let command = json!({ "table_name": "scores", "key": 5, "item": { "score": 2990 } });
+        db.update_item(serde_json::from_value(command)?).await?;
+        let command = json!({ "table_name": "scores", "key": 6 });
+        db.delete_item(serde_json::from_value(command)?).await?;
+        db.checkpoint().await?;
+
+        let check = |db: Database| async move {
+            let filter = json!({
+                "table_name": "scores",
+                "filter": { "column": "score", "op": "$lt", "value": 10 },
+                "columns": ["player"]
+            });
+            let records = db.filter_item(serde_json::from_value(filter)?).await?;
+            assert_eq!(records.len(), 8);
+            assert!(records.iter().all(|record| record.len() == 1));
+            let filter = json!({
+                "table_name": "scores",
+                "filter": { "column": "score", "op": "$gte", "value": 2990 }
+            });
+            let records = db.filter_item(serde_json::from_value(filter)?).await?;
+            assert_eq!(records.len(), 11);
+            let command = json!({ "table_name": "scores", "key": 2500 });
+            let record = db
+                .get_item(serde_json::from_value(command)?)
+                .await?
+                .unwrap();
+            let player = ColumnValue::Text("player 2500".to_string());
+            assert_eq!(record.get(&"player".into()), Some(&Some(player)));
+            let command = json!({ "table_name": "scores", "key": 6 });
+            assert!(db
+                .get_item(serde_json::from_value(command)?)
+                .await?
+                .is_none());
+
+            // the scan leaves out the chunks which can't match, and the
+            // columns which are not needed
+            let filter = ScanFilter {
+                columns: db
+                    .catalog
+                    .get_table(&"scores".into())
+                    .unwrap()
+                    .columns
+                    .clone(),
+                expression: serde_json::from_value(
+                    json!({ "column": "score", "op": "$gt", "value": 2500 }),
+                )?,
+                needed_columns: vec![1],
+            };
+            let table = db.catalog.get_table(&"scores".into()).unwrap();
+            let tuples: Vec<_> = table
+                .storage
+                .scan_filtered(filter)
+                .await?
+                .try_collect()
+                .await?;
+            assert!(tuples.len() < 2000, "{}", tuples.len());
+            assert!(tuples.iter().all(|tuple| tuple[2].is_none()));
+            let above = Some(ColumnValue::Integer(2500));
+            assert_eq!(tuples.iter().filter(|tuple| tuple[1] > above).count(), 500);
+            anyhow::Ok(db)
+        };
+        let db = check(db).await?;
+
+        // an unknown column in the projection is an error
+        let filter = json!({
+            "table_name": "scores",
+            "filter": { "column": "score", "op": "$lt", "value": 10 },
+            "columns": ["nope"]
+        });
+        let result = db.filter_item(serde_json::from_value(filter)?).await;
+        assert!(matches!(result, Err(QueryError::UnknownColumn(_))));
+
+        let db_path = db.catalog.directory_path.display().to_string();
+        drop(db);
+        let mut db = check(Database::new_with_options(&db_path, options.clone()).await?).await?;
+        db.compact_table(&table_name).await?;
+        let db = check(db).await?;
+        drop(db);
+        let report = Database::fsck(&db_path, false).await?;
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_float_values() -> anyhow::Result<()> {
+        let mut db = setup("floats").await?;
+        let table = json!({
+            "name": "readings",
+            "columns": [
+                { "name": "at", "type": "Float" },
+                { "name": "value", "type": "Float" }
+            ],
+            "primary_key": "at"
+        });
+        db.create_table(serde_json::from_value(table)?).await?;
+        for (at, value) in [
+            (json!(0.5), json!(1.5)),
+            (json!(1), json!(2)),
+            (json!(1.5), json!(-0.25)),
+        ] {
+            let command = json!({ "table_name": "readings", "item": { "at": at, "value": value } });
+            db.put_item(serde_json::from_value(command)?).await?;
+        }
+        // an integer key is the same as the float key
+        let command = json!({ "table_name": "readings", "item": { "at": 1.0 } });
+        let result = db.put_item(serde_json::from_value(command)?).await;
+        assert!(matches!(
+            result,
+            Err(QueryError::PrimaryKeyAlreadyExists(_))
+        ));
+        let command = json!({ "table_name": "readings", "item": { "at": 2.5, "value": "high" } });
+        let result = db.put_item(serde_json::from_value(command)?).await;
+        assert!(matches!(result, Err(QueryError::ColumnTypeMismatch { .. })));
+
+        let check = |db: Database| async move {
+            let command = json!({ "table_name": "readings", "key": 1 });
+            let record = db
+                .get_item(serde_json::from_value(command)?)
+                .await?
+                .unwrap();
+            let value = ColumnValue::Float(OrderedFloat::new(2.0));
+            assert_eq!(record.get(&"value".into()), Some(&Some(value)));
+            let filter = json!({
+                "table_name": "readings",
+                "filter": { "column": "value", "op": "$gt", "value": 1 }
+            });
+            assert_eq!(
+                db.filter_item(serde_json::from_value(filter)?).await?.len(),
+                2
+            );
+            let filter = json!({
+                "table_name": "readings",
+                "filter": { "column": "value", "op": "$lte", "value": -0.25 }
+            });
+            assert_eq!(
+                db.filter_item(serde_json::from_value(filter)?).await?.len(),
+                1
+            );
+            anyhow::Ok(db)
+        };
+        let db = check(db).await?;
+        let db_path = db.catalog.directory_path.display().to_string();
+        drop(db);
+        let mut db = check(Database::new(&db_path).await?).await?;
+
+        // the REPL's values
+        assert_eq!(
+            ColumnValue::from("0.5"),
+            ColumnValue::Float(OrderedFloat::new(0.5))
+        );
+        assert_eq!(ColumnValue::from("NaN"), ColumnValue::Text("NaN".into()));
+        let command = json!({ "table_name": "readings", "key": 0.5 });
+        db.delete_item(serde_json::from_value(command)?).await?;
+        assert_eq!(db.get_size(&"readings".into()), Some(2));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_signed_integers() -> anyhow::Result<()> {
+        let mut db = setup("signed_integers").await?;
+        let table = json!({
+            "name": "balances",
+            "columns": [
+                { "name": "id", "type": "Integer" },
+                { "name": "amount", "type": "Integer" }
+            ],
+            "primary_key": "id",
+            "engine": "lsm"
+        });
+        db.create_table(serde_json::from_value(table)?).await?;
+        for (id, amount) in [(-1, -5), (0, 0), (1, 7), (2, i64::MIN), (3, i64::MAX)] {
+            let command =
+                json!({ "table_name": "balances", "item": { "id": id, "amount": amount } });
+            db.put_item(serde_json::from_value(command)?).await?;
+        }
+        // the keys are in an SSTable, behind its bloom filter
+        db.checkpoint().await?;
+
+        let command = json!({ "table_name": "balances", "key": -1 });
+        let record = db
+            .get_item(serde_json::from_value(command)?)
+            .await?
+            .unwrap();
+        assert_eq!(
+            record.get(&"amount".into()),
+            Some(&Some(ColumnValue::Integer(-5)))
+        );
+        for (op, value, expected) in [
+            ("$lt", json!(0), 2),
+            ("$gt", json!(-6), 4),
+            ("$lt", json!(0.5), 3),
+        ] {
+            let filter = json!({
+                "table_name": "balances",
+                "filter": { "column": "amount", "op": op, "value": value }
+            });
+            let records = db.filter_item(serde_json::from_value(filter)?).await?;
+            assert_eq!(records.len(), expected, "{} {}", op, value);
+        }
+
+        // non-negative integers are stored as they were when they were unsigned
+        assert_eq!(
+            rmp_serde::to_vec(&ColumnValue::Integer(200))?,
+            rmp_serde::to_vec(&200u64)?
+        );
+        // and integers out of range are not values
+        assert!(serde_json::from_value::<ColumnValue>(json!(u64::MAX)).is_err());
+        let command = json!({ "table_name": "balances", "item": { "id": 4, "amount": "lots" } });
+        let error = db
+            .put_item(serde_json::from_value(command)?)
+            .await
+            .unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("Column 'amount' defined as type: Integer"));
+
+        // a tuple written when integers were unsigned, with one which doesn't
+        // fit anymore, keeps the database from opening until it is repaired
+        let table = json!({
+            "name": "legacy",
+            "columns": [
+                { "name": "id", "type": "Integer" },
+                { "name": "amount", "type": "Integer" }
+            ],
+            "primary_key": "id"
+        });
+        db.create_table(serde_json::from_value(table)?).await?;
+        for id in [1, 2] {
+            let command = json!({ "table_name": "legacy", "item": { "id": id, "amount": 5 } });
+            db.put_item(serde_json::from_value(command)?).await?;
+        }
+        db.checkpoint().await?;
+        let table = db.catalog.get_table(&"legacy".into()).unwrap();
+        let segment = table.storage.as_log().unwrap().block.get_segment(0)?;
+        let offset = segment.length;
+        let data = rmp_serde::to_vec(&vec![Some(3u64), Some(u64::MAX)])?;
+        segment
+            .block
+            .write_entry(data, storage::EntryFlags::default())
+            .await?;
+        let db_path = db.catalog.directory_path.display().to_string();
+        drop(db);
+        let error = Database::new(&db_path).await.unwrap_err().to_string();
+        assert!(error.contains("Table 'legacy'"), "{}", error);
+        assert!(
+            error.contains(&format!("byte-offset {}", offset)),
+            "{}",
+            error
+        );
+        assert!(error.contains("fsck --repair"), "{}", error);
+
+        let report = Database::fsck(&db_path, true).await?;
+        assert!(!report.problems.is_empty() && report.is_clean());
+        let db = Database::new(&db_path).await?;
+        assert_eq!(db.get_size(&"legacy".into()), Some(2));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_nullable_columns() -> anyhow::Result<()> {
+        let mut db = setup("nullable_columns").await?;
+        let table = json!({
+            "name": "books",
+            "columns": [
+                { "name": "id", "type": "Integer" },
+                { "name": "title", "type": "Text", "nullable": false },
+                { "name": "pages", "type": "Float", "default": 100 },
+                { "name": "subtitle", "type": "Text" }
+            ],
+            "primary_key": "id"
+        });
+        db.create_table(serde_json::from_value(table)?).await?;
+
+        // defaults are filled in, and the nullable columns left out are null
+        let command = json!({ "table_name": "books", "item": { "id": 1, "title": "Dune" } });
+        db.put_item(serde_json::from_value(command)?).await?;
+        let record = db
+            .get_item(serde_json::from_value(
+                json!({ "table_name": "books", "key": 1 }),
+            )?)
+            .await?
+            .unwrap();
+        let pages = ColumnValue::Float(OrderedFloat::new(100.0));
+        assert_eq!(record.get(&"pages".into()), Some(&Some(pages)));
+        assert_eq!(record.get(&"subtitle".into()), Some(&None));
+        let command = json!({ "table_name": "books", "item": { "id": 2, "pages": 5 } });
+        let result = db.put_item(serde_json::from_value(command)?).await;
+        assert!(
+            matches!(result, Err(QueryError::NullNotAllowed(column)) if column == "title".into())
+        );
+
+        // comparisons with nulls don't match, rather than crash
+        let filter = json!({
+            "table_name": "books",
+            "filter": { "column": "subtitle", "op": "$neq", "value": "x" }
+        });
+        assert!(db
+            .filter_item(serde_json::from_value(filter)?)
+            .await?
+            .is_empty());
+
+        // defaults must be of their column's type, and the primary key has none
+        let table = json!({
+            "name": "bad_default",
+            "columns": [
+                { "name": "id", "type": "Integer" },
+                { "name": "title", "type": "Text", "default": 5 }
+            ],
+            "primary_key": "id"
+        });
+        let result = db.create_table(serde_json::from_value(table)?).await;
+        assert!(matches!(result, Err(QueryError::ColumnTypeMismatch { .. })));
+        let table = json!({
+            "name": "bad_default",
+            "columns": [{ "name": "id", "type": "Integer", "default": 5 }],
+            "primary_key": "id"
+        });
+        let result = db.create_table(serde_json::from_value(table)?).await;
+        assert!(matches!(
+            result,
+            Err(QueryError::PrimaryKeyDefaultNotAllowed(_))
+        ));
+
+        // the constraints outlive a restart
+        let db_path = db.catalog.directory_path.display().to_string();
+        drop(db);
+        let mut db = Database::new(&db_path).await?;
+        let command = json!({ "table_name": "books", "item": { "id": 3 } });
+        let result = db.put_item(serde_json::from_value(command)?).await;
+        assert!(matches!(result, Err(QueryError::NullNotAllowed(_))));
+        drop(db);
+        let report = Database::fsck(&db_path, false).await?;
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_null_filters() -> anyhow::Result<()> {
+        let mut db = setup("null_filters").await?;
+        for engine in ["log", "columnar"] {
+            let table = json!({
+                "name": engine,
+                "columns": [
+                    { "name": "id", "type": "Integer" },
+                    { "name": "email", "type": "Text" },
+                    { "name": "phone", "type": "Text" },
+                    { "name": "age", "type": "Integer" }
+                ],
+                "primary_key": "id",
+                "engine": engine
+            });
+            db.create_table(serde_json::from_value(table)?).await?;
+            // sparse rows, written without some of the columns
+            for item in [
+                json!({ "id": 1, "email": "a", "phone": "p1", "age": 30 }),
+                json!({ "id": 2, "email": "b", "age": 40 }),
+                json!({ "id": 3, "phone": "p3" }),
+                json!({ "id": 4 }),
+            ] {
+                let command = json!({ "table_name": engine, "item": item });
+                db.put_item(serde_json::from_value(command)?).await?;
+            }
+        }
+        db.checkpoint().await?;
+
+        let age_above_35 = json!({ "column": "age", "op": "$gt", "value": 35 });
+        let cases = [
+            (json!({ "$is_null": "email" }), vec![3, 4]),
+            (json!({ "$is_not_null": "phone" }), vec![1, 3]),
+            (age_above_35.clone(), vec![2]),
+            // unknown for the rows without an age
+            (json!({ "$not": age_above_35 }), vec![1]),
+            (
+                json!({ "$or": [age_above_35, { "$is_null": "age" }] }),
+                vec![2, 3, 4],
+            ),
+            (
+                json!({ "$not": { "$and": [
+                    { "column": "age", "op": "$gt", "value": 100 },
+                    { "column": "email", "op": "$eq", "value": "a" }
+                ] } }),
+                vec![1, 2],
+            ),
+            (
+                json!({ "$not": { "$or": [
+                    { "column": "age", "op": "$lt", "value": 35 },
+                    { "$is_null": "email" }
+                ] } }),
+                vec![2],
+            ),
+        ];
+        for engine in ["log", "columnar"] {
+            for (filter, expected) in &cases {
+                let command = json!({ "table_name": engine, "filter": filter });
+                let records = db.filter_item(serde_json::from_value(command)?).await?;
+                let mut ids: Vec<_> = records
+                    .iter()
+                    .filter_map(|record| match record.get(&"id".into()) {
+                        Some(Some(ColumnValue::Integer(id))) => Some(*id),
+                        _ => None,
+                    })
+                    .collect();
+                ids.sort();
+                assert_eq!(&ids, expected, "{} {}", engine, filter);
+            }
+        }
+
+        let command = json!({ "table_name": "log", "filter": { "$is_null": "age" } });
+        assert_eq!(db.delete_where(serde_json::from_value(command)?).await?, 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_filter_validation() -> anyhow::Result<()> {
+        let mut db = setup("filter_validation").await?;
+        for id in 0..5 {
+            db.put_item(create_put_item(id)?).await?;
+        }
+        let filter_item = |filter: serde_json::Value| {
+            serde_json::from_value::<dml::FilterItemCommand>(
+                json!({ "table_name": "authors", "filter": filter }),
+            )
+        };
+
+        // unknown columns, wherever they are in the expression
+        let filter = json!({ "$and": [
+            { "column": "id", "op": "$gt", "value": 1 },
+            { "$not": { "column": "age", "op": "$gt", "value": 1 } }
+        ] });
+        let result = db.filter_item(filter_item(filter)?).await;
+        assert!(matches!(result, Err(QueryError::UnknownColumn(column)) if column == "age".into()));
+        let result = db
+            .filter_item(filter_item(json!({ "$is_null": "age" }))?)
+            .await;
+        assert!(matches!(result, Err(QueryError::UnknownColumn(_))));
+
+        // values of another type than their column
+        let filter = json!({ "$or": [{ "column": "name", "op": "$lt", "value": 3 }] });
+        let result = db.filter_item(filter_item(filter)?).await;
+        assert!(matches!(
+            result,
+            Err(QueryError::IncomparableTypes {
+                column_type: ColumnType::Text,
+                value_type: ColumnType::Integer,
+                ..
+            })
+        ));
+        let filter = json!({ "column": "id", "op": "$eq", "value": "1" });
+        let result = db.filter_item(filter_item(filter)?).await;
+        assert!(matches!(result, Err(QueryError::IncomparableTypes { .. })));
+        // but numbers of either type can be compared with each other
+        let filter = json!({ "column": "id", "op": "$lt", "value": 2.5 });
+        assert_eq!(db.filter_item(filter_item(filter)?).await?.len(), 3);
+
+        // deletes are checked the same way, and don't delete anything
+        let command = json!({
+            "table_name": "authors",
+            "filter": { "column": "id", "op": "$gte", "value": true }
+        });
+        let result = db.delete_where(serde_json::from_value(command)?).await;
+        assert!(matches!(result, Err(QueryError::IncomparableTypes { .. })));
+        assert_eq!(db.get_size(&"authors".into()), Some(5));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_timestamps_and_dates() -> anyhow::Result<()> {
+        let mut db = setup("timestamps").await?;
+        let table = json!({
+            "name": "events",
+            "columns": [
+                { "name": "id", "type": "Integer" },
+                { "name": "at", "type": "Timestamp" },
+                { "name": "day", "type": "Date" },
+                { "name": "created", "type": "Timestamp", "default": "now" }
+            ],
+            "primary_key": "id"
+        });
+        db.create_table(serde_json::from_value(table)?).await?;
+        for (id, at, day) in [
+            (1, "2024-10-01T12:30:00Z", "2024-10-01"),
+            (2, "2024-10-01T14:30:00.25+02:00", "2024-02-29"),
+            (3, "1969-12-31T23:59:59.5Z", "1969-12-31"),
+        ] {
+            let command =
+                json!({ "table_name": "events", "item": { "id": id, "at": at, "day": day } });
+            db.put_item(serde_json::from_value(command)?).await?;
+        }
+        for item in [
+            json!({ "id": 4, "at": "2024-13-01T00:00:00Z" }),
+            json!({ "id": 4, "day": "2023-02-29" }),
+            json!({ "id": 4, "at": 1727785800 }),
+        ] {
+            let command = json!({ "table_name": "events", "item": item });
+            let result = db.put_item(serde_json::from_value(command)?).await;
+            assert!(matches!(result, Err(QueryError::ColumnTypeMismatch { .. })));
+        }
+
+        let check = |db: Database| async move {
+            let command = json!({ "table_name": "events", "key": 2 });
+            let record = db
+                .get_item(serde_json::from_value(command)?)
+                .await?
+                .unwrap();
+            let record = serde_json::to_value(record)?;
+            assert_eq!(record["at"], json!("2024-10-01T12:30:00.250000Z"));
+            assert_eq!(record["day"], json!("2024-02-29"));
+            for (filter, count) in [
+                (
+                    json!({ "column": "at", "op": "$gt", "value": "2024-10-01T12:30:00Z" }),
+                    1,
+                ),
+                (
+                    json!({ "column": "at", "op": "$gte", "value": "2024-10-01T14:30:00+02:00" }),
+                    2,
+                ),
+                (
+                    json!({ "column": "day", "op": "$lt", "value": "2000-01-01" }),
+                    1,
+                ),
+                (
+                    json!({ "column": "created", "op": "$gt", "value": "now - 1 day" }),
+                    3,
+                ),
+                (
+                    json!({ "column": "created", "op": "$lt", "value": "now - 1 hour" }),
+                    0,
+                ),
+                (
+                    json!({ "column": "day", "op": "$lte", "value": "now + 2 weeks" }),
+                    3,
+                ),
+            ] {
+                let command = json!({ "table_name": "events", "filter": filter });
+                let records = db.filter_item(serde_json::from_value(command)?).await?;
+                assert_eq!(records.len(), count, "{}", filter);
+            }
+            anyhow::Ok(db)
+        };
+        let mut db = check(db).await?;
+        db.checkpoint().await?;
+        let db_path = db.catalog.directory_path.display().to_string();
+        drop(db);
+        let db = check(Database::new(&db_path).await?).await?;
+
+        // a time which can't be parsed can't be compared with
+        let filter = json!({ "column": "at", "op": "$gt", "value": "now - 1 fortnight" });
+        let command = json!({ "table_name": "events", "filter": filter });
+        let result = db.filter_item(serde_json::from_value(command)?).await;
+        assert!(matches!(result, Err(QueryError::IncomparableTypes { .. })));
+
+        assert_eq!(
+            Timestamp::parse("1970-01-01T00:00:00Z").map(Timestamp::micros),
+            Some(0)
+        );
+        assert_eq!(
+            Timestamp::from_micros(-1).to_string(),
+            "1969-12-31T23:59:59.999999Z"
+        );
+        assert!(Date::parse("2000-02-29").is_some());
+        assert!(Date::parse("1900-02-29").is_none());
+        assert_eq!(Date::from_days(0).to_string(), "1970-01-01");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_uuid_values() -> anyhow::Result<()> {
+        let mut db = setup("uuids").await?;
+        let table = json!({
+            "name": "sessions",
+            "columns": [
+                { "name": "id", "type": "Uuid", "generated": "uuid_v7" },
+                { "name": "user", "type": "Uuid" }
+            ],
+            "primary_key": "id"
+        });
+        db.create_table(serde_json::from_value(table)?).await?;
+        let user = "67E55044-10B1-426F-9247-BB680E5FE0C8";
+        let mut keys = vec![];
+        for _ in 0..3 {
+            let command = json!({ "table_name": "sessions", "item": { "user": user } });
+            keys.push(db.put_item(serde_json::from_value(command)?).await?);
+            tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+        }
+        // time-ordered keys sort in the order they were generated
+        let mut sorted = keys.clone();
+        sorted.sort();
+        assert_eq!(sorted, keys);
+        let key = &keys[0].to_string();
+        assert_eq!(key.as_bytes()[14], b'7');
+        for user in ["67e55044-10b1-426f-9247-bb680e5fe0c", "not a uuid"] {
+            let command = json!({ "table_name": "sessions", "item": { "user": user } });
+            let result = db.put_item(serde_json::from_value(command)?).await;
+            assert!(matches!(result, Err(QueryError::ColumnTypeMismatch { .. })));
+        }
+        // a given key is kept
+        let given = "00000000-0000-4000-8000-000000000000";
+        let command = json!({ "table_name": "sessions", "item": { "id": given, "user": user } });
+        let result = db.put_item(serde_json::from_value(command)?).await?;
+        assert_eq!(result.to_string(), given);
+
+        let check = |db: Database| async move {
+            let command = json!({ "table_name": "sessions", "key": key });
+            let record = db
+                .get_item(serde_json::from_value(command)?)
+                .await?
+                .unwrap();
+            let record = serde_json::to_value(record)?;
+            assert_eq!(record["user"], json!(user.to_lowercase()));
+            let filter = json!({ "column": "user", "op": "$eq", "value": user });
+            let command = json!({ "table_name": "sessions", "filter": filter });
+            let records = db.filter_item(serde_json::from_value(command)?).await?;
+            assert_eq!(records.len(), 4);
+            anyhow::Ok(db)
+        };
+        let mut db = check(db).await?;
+        db.checkpoint().await?;
+        let db_path = db.catalog.directory_path.display().to_string();
+        drop(db);
+        check(Database::new(&db_path).await?).await?;
+
+        // only a primary key of type Uuid can be generated
+        for columns in [
+            json!([{ "name": "id", "type": "Integer", "generated": "uuid_v4" }]),
+            json!([
+                { "name": "id", "type": "Uuid" },
+                { "name": "other", "type": "Uuid", "generated": "uuid_v4" }
+            ]),
+        ] {
+            let table = json!({ "name": "other", "columns": columns, "primary_key": "id" });
+            let mut db = setup_in_memory().await?;
+            let result = db.create_table(serde_json::from_value(table)?).await;
+            assert!(matches!(
+                result,
+                Err(QueryError::GeneratedColumnNotAllowed(_))
+            ));
+        }
+
+        // stored as MessagePack binary data: a 2 byte header and the 16 bytes
+        let uuid = Uuid::parse(user).unwrap();
+        assert_eq!(uuid.to_string(), user.to_lowercase());
+        assert_eq!(rmp_serde::to_vec(&ColumnValue::Uuid(uuid))?.len(), 18);
+        assert_ne!(Uuid::new_v4(), Uuid::new_v4());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_blob_storage() -> anyhow::Result<()> {
+        let options = DatabaseOptions {
+            compaction_threshold: None,
+            blob_threshold_bytes: Some(100),
+            ..Default::default()
+        };
+        let mut db = setup_with_options("blobs", options.clone()).await?;
+        let table = json!({
+            "name": "posts",
+            "columns": [
+                { "name": "id", "type": "Integer" },
+                { "name": "title", "type": "Text" },
+                { "name": "body", "type": "Text" }
+            ],
+            "primary_key": "id"
+        });
+        db.create_table(serde_json::from_value(table.clone())?).await?;
+        let table_name: TableName = "posts".into();
+        let body = |id: u64| format!("{} ", id).repeat(100);
+        for id in 0..20 {
+            let command = json!({
+                "table_name": "posts",
+                "item": { "id": id, "title": format!("post {}", id), "body": body(id) }
+            });
+            db.put_item(serde_json::from_value(command)?).await?;
+        }
+        let db_path = db.catalog.directory_path.clone();
+        assert!(db_path.join("posts.dat.blob.0").exists());
+        assert!(db.get_stats(&table_name).await.unwrap().blob_bytes > 0);
+
+        // updating other columns keeps the stored value
+        let command = json!({ "table_name": "posts", "key": 3, "item": { "title": "edited" } });
+        db.update_item(serde_json::from_value(command)?).await?;
+        let command = json!({ "table_name": "posts", "filter": { "column": "id", "op": "$gte", "value": 13 } });
+        assert_eq!(db.delete_where(serde_json::from_value(command)?).await?, 7);
+        let blob_bytes = db.get_stats(&table_name).await.unwrap().blob_bytes;
+
+        let check = |db: Database| async move {
+            let command = json!({ "table_name": "posts", "key": 3 });
+            let record = db
+                .get_item(serde_json::from_value(command)?)
+                .await?
+                .unwrap();
+            assert_eq!(
+                record.get(&"body".into()),
+                Some(&Some(ColumnValue::Text(body(3))))
+            );
+            assert!(record
+                .values()
+                .flatten()
+                .all(|value| !matches!(value, ColumnValue::Blob(_))));
+            let filter = json!({
+                "table_name": "posts",
+                "filter": { "column": "body", "op": "$eq", "value": body(12) },
+                "columns": ["id"]
+            });
+            let records = db.filter_item(serde_json::from_value(filter)?).await?;
+            assert_eq!(records.len(), 1);
+            assert_eq!(db.get_size(&"posts".into()), Some(13));
+            anyhow::Ok(db)
+        };
+        let db = check(db).await?;
+        // the writes are replayed from the write-ahead log
+        drop(db);
+        let db = Database::new_with_options(db_path.to_str().unwrap(), options.clone()).await?;
+        let mut db = check(db).await?;
+
+        // compaction copies the live values to a new blob file, and the old
+        // one is removed at the next checkpoint
+        db.compact_table(&table_name).await?;
+        assert!(db.get_stats(&table_name).await.unwrap().blob_bytes < blob_bytes);
+        db.checkpoint().await?;
+        assert!(!db_path.join("posts.dat.blob.0").exists());
+        assert!(db_path.join("posts.dat.blob.1").exists());
+        let db = check(db).await?;
+        drop(db);
+        let report = Database::fsck(db_path.to_str().unwrap(), false).await?;
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+        let mut db =
+            check(Database::new_with_options(db_path.to_str().unwrap(), options.clone()).await?)
+                .await?;
+
+        // a reference to a stored value is not a value
+        let command = json!({ "table_name": "posts", "item": { "id": 50, "body": [0, 16] } });
+        let result = db.put_item(serde_json::from_value(command)?).await;
+        assert!(matches!(result, Err(QueryError::InvalidValue(_))));
+
+        db.drop_table(serde_json::from_value(json!({ "table_name": "posts" }))?)
+            .await?;
+        assert!(!db_path.join("posts.dat.blob.1").exists());
+
+        // a value stored while a compaction of a table without blob files is
+        // in progress doesn't start the blob file the compaction starts
+        db.create_table(serde_json::from_value(table)?).await?;
+        let command = json!({ "table_name": "posts", "item": { "id": 1, "body": "short" } });
+        db.put_item(serde_json::from_value(command)?).await?;
+        let mut compaction = db.start_compaction(&table_name).await?;
+        compaction.run().await?;
+        let command = json!({ "table_name": "posts", "item": { "id": 2, "body": body(2) } });
+        db.put_item(serde_json::from_value(command)?).await?;
+        db.finish_compaction(compaction).await?;
+        db.checkpoint().await?;
+        drop(db);
+        let db = Database::new_with_options(db_path.to_str().unwrap(), options).await?;
+        let command = json!({ "table_name": "posts", "key": 2 });
+        let record = db
+            .get_item(serde_json::from_value(command)?)
+            .await?
+            .unwrap();
+        assert_eq!(
+            record.get(&"body".into()),
+            Some(&Some(ColumnValue::Text(body(2))))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_encryption() -> anyhow::Result<()> {
+        let key = EncryptionKey::from_hex(&"2b".repeat(32))?;
+        let options = DatabaseOptions {
+            compaction_threshold: None,
+            encryption_key: Some(key.clone()),
+            ..Default::default()
+        };
+        let mut db = setup_with_options("encryption", options.clone()).await?;
+        let table = json!({
+            "name": "secrets",
+            "columns": [
+                { "name": "id", "type": "Integer" },
+                { "name": "body", "type": "Text" }
+            ],
+            "primary_key": "id",
+            "compression": "lz4"
+        });
+        db.create_table(serde_json::from_value(table)?).await?;
+        let table_name: TableName = "secrets".into();
+        let body = |id: u64| format!("top secret number {id}. ").repeat(4);
+        for id in 0..50 {
+            let command =
+                json!({ "table_name": "secrets", "item": { "id": id, "body": body(id) } });
+            db.put_item(serde_json::from_value(command)?).await?;
+        }
+        for id in 0..10 {
+            let command = json!({ "table_name": "secrets", "key": id });
+            db.delete_item(serde_json::from_value(command)?).await?;
+        }
+
+        // nothing of the data shows up in any of the files; neither in the
+        // write-ahead log, nor after a checkpoint in the blocks, the hint files
+        // and the catalog
+        let db_path = db.catalog.directory_path.clone();
+        let assert_encrypted = || -> anyhow::Result<()> {
+            for dir_entry in fs::read_dir(&db_path)? {
+                let contents = fs::read(dir_entry?.path())?;
+                for plaintext in [&b"top secret"[..], b"secrets", b"body"] {
+                    assert!(!contents
+                        .windows(plaintext.len())
+                        .any(|bytes| bytes == plaintext));
+                }
+            }
+            Ok(())
+        };
+        assert_encrypted()?;
+        db.checkpoint().await?;
+        assert_encrypted()?;
+
+        let check = |db: Database| async move {
+            let command = json!({ "table_name": "secrets", "key": 20 });
+            let record = db
+                .get_item(serde_json::from_value(command)?)
+                .await?
+                .unwrap();
+            assert_eq!(
+                record.get(&"body".into()).unwrap(),
+                &Some(ColumnValue::Text(body(20)))
+            );
+            let command = json!({ "table_name": "secrets", "key": 5 });
+            assert!(db
+                .get_item(serde_json::from_value(command)?)
+                .await?
+                .is_none());
+            assert_eq!(db.get_stats(&"secrets".into()).await.unwrap().rows, 40);
+            anyhow::Ok(db)
+        };
+        let path = db_path.to_str().unwrap();
+        let mut db = check(Database::new_with_options(path, options.clone()).await?).await?;
+        db.compact_table(&table_name).await?;
+        check(Database::new_with_options(path, options.clone()).await?).await?;
+        assert_encrypted()?;
+
+        // the database can't be opened without its key, or with another key
+        assert!(matches!(
+            Database::new(path).await,
+            Err(QueryError::CatalogError(
+                catalog::CatalogError::EncryptionError(EncryptionError::KeyRequired { .. })
+            ))
+        ));
+        let wrong_key = DatabaseOptions {
+            encryption_key: Some(EncryptionKey::from_hex(&"2c".repeat(32))?),
+            ..Default::default()
+        };
+        assert!(matches!(
+            Database::new_with_options(path, wrong_key).await,
+            Err(QueryError::CatalogError(
+                catalog::CatalogError::EncryptionError(EncryptionError::WrongKey { .. })
+            ))
+        ));
+
+        // and a database which isn't encrypted can't be opened with a key
+        let mut plain_db = setup("encryption_plain").await?;
+        plain_db.checkpoint().await?;
+        let plain_path = plain_db.catalog.directory_path.clone();
+        assert!(matches!(
+            Database::new_with_options(plain_path.to_str().unwrap(), options).await,
+            Err(QueryError::CatalogError(
+                catalog::CatalogError::EncryptionError(EncryptionError::NotEncrypted { .. })
+            ))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_compression_round_trip() -> anyhow::Result<()> {
+        use rand::RngCore;
+
+        let mut incompressible = vec![0u8; 64 * 1024];
+        rand::thread_rng().fill_bytes(&mut incompressible);
+        let repetitive = b"dumbdb ".repeat(10_000);
+        let empty = Vec::new();
+
+        for data in [&incompressible, &repetitive, &empty] {
+            let encoded = compression::encode(data, Compression::Lz4).unwrap();
+            assert_eq!(&compression::decompress(&encoded)?, data);
+        }
+        // only the data which gets smaller is stored compressed
+        assert!(compression::compress(&incompressible, Compression::Lz4).is_none());
+        assert!(compression::compress(&empty, Compression::Lz4).is_none());
+        let compressed = compression::compress(&repetitive, Compression::Lz4).unwrap();
+        assert!(compressed.len() < repetitive.len() / 10);
+        assert_eq!(compression::decompress(&compressed)?, repetitive);
+
+        // a truncated or corrupted block doesn't decompress
+        let truncated = &compressed[..compressed.len() / 2];
+        assert!(compression::decompress(truncated).is_err());
+        let mut wrong_length = compressed.clone();
+        wrong_length[1] = wrong_length[1].wrapping_add(1);
+        assert!(compression::decompress(&wrong_length).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_encryption_test_vectors() -> anyhow::Result<()> {
+        use chacha20poly1305::{
+            aead::{Aead, Payload},
+            ChaCha20Poly1305, KeyInit,
+        };
+
+        let hex = |digits: &str| -> Vec<u8> {
+            (0..digits.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).unwrap())
+                .collect()
+        };
+        let plaintext = b"Ladies and Gentlemen of the class of '99: If I could offer you \
+            only one tip for the future, sunscreen would be it.";
+        let associated_data = hex("50515253c0c1c2c3c4c5c6c7");
+        let key: Vec<u8> = (0x80..=0x9f).collect();
+
+        // RFC 8439, section 2.8.2
+        let aead = ChaCha20Poly1305::new_from_slice(&key).unwrap();
+        let nonce = hex("070000004041424344454647");
+        let payload = Payload {
+            msg: plaintext,
+            aad: &associated_data,
+        };
+        let encrypted = aead.encrypt(nonce.as_slice().into(), payload).unwrap();
+        let expected = hex(concat!(
+            "d31a8d34648e60db7b86afbc53ef7ec2a4aded51296e08fea9e2b5a736ee62d6",
+            "3dbea45e8ca9671282fafb69da92728b1a71de0a9e060b2905d6a5b67ecd3b36",
+            "92ddbd7f2d778b8c9803aee328091b58fab324e4fad675945585808b4831d7bc",
+            "3ff4def08e4b7a9de576d26586cec64b6116",
+            "1ae10b594f09e26a7e902ecbd0600691",
+        ));
+        assert_eq!(encrypted, expected);
+
+        // draft-irtf-cfrg-xchacha-03, appendix A.3.1, with the cipher the
+        // database encrypts with; its output starts with the nonce
+        let key_hex: String = key.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let cipher = encryption::Cipher::new(&EncryptionKey::from_hex(&key_hex)?);
+        let nonce: [u8; encryption::NONCE_SIZE] =
+            hex("404142434445464748494a4b4c4d4e4f5051525354555657")
+                .try_into()
+                .unwrap();
+        let encrypted = cipher.encrypt_with_nonce(&nonce, plaintext, &associated_data);
+        let expected = hex(concat!(
+            "404142434445464748494a4b4c4d4e4f5051525354555657",
+            "bd6d179d3e83d43b9576579493c0e939572a1700252bfaccbed2902c21396cbb",
+            "731c7f1b0b4aa6440bf3a82f4eda7e39ae64c6708c54c216cb96b72e1213b452",
+            "2f8c9ba40db5d945b11b69b982c1bb9e3f3fac2bc369488f76b2383565d3fff9",
+            "21f9664c97637da9768812f615c68b13b52e",
+            "c0875924c1c7987947deafd8780acf49",
+        ));
+        assert_eq!(encrypted, expected);
+        assert_eq!(
+            cipher.decrypt(&encrypted, &associated_data).as_deref(),
+            Some(&plaintext[..])
+        );
+        assert!(cipher.decrypt(&encrypted, b"other").is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_encryption_tampering() -> anyhow::Result<()> {
+        let key = EncryptionKey::from_hex(&"2b".repeat(32))?;
+        let options = DatabaseOptions {
+            compaction_threshold: None,
+            encryption_key: Some(key),
+            ..Default::default()
+        };
+        let mut db = setup_with_options("encryption_tampering", options.clone()).await?;
+        for name in ["secrets", "others"] {
+            let table = json!({
+                "name": name,
+                "columns": [
+                    { "name": "id", "type": "Integer" },
+                    { "name": "body", "type": "Text" }
+                ],
+                "primary_key": "id"
+            });
+            db.create_table(serde_json::from_value(table)?).await?;
+            for (id, body) in [(1, "yes"), (2, "no!")] {
+                let command = json!({ "table_name": name, "item": { "id": id, "body": body } });
+                db.put_item(serde_json::from_value(command)?).await?;
+            }
+        }
+        db.checkpoint().await?;
+        let position = |db: &Database, table: &str, id: i64| {
+            let table = db.catalog.get_table(&table.into()).unwrap();
+            let index = &table.storage.as_log().unwrap().index;
+            *index.get(&ColumnValue::Integer(id)).unwrap()
+        };
+        let (first, second) = (position(&db, "secrets", 1), position(&db, "secrets", 2));
+        assert_eq!(first.length, second.length);
+        assert_eq!(position(&db, "others", 1).offset, first.offset);
+        let db_path = db.catalog.directory_path.clone();
+        drop(db);
+
+        // swap the two entries of a table, which both pass their checksums,
+        // and copy one of them over the entry at the same offset of another
+        // table
+        let entry = |contents: &[u8], position: segment::EntryPosition| {
+            contents[position.offset as usize..(position.offset + position.length) as usize]
+                .to_vec()
+        };
+        let secrets_path = db_path.join("secrets.dat");
+        let mut contents = fs::read(&secrets_path)?;
+        let (first_entry, second_entry) = (entry(&contents, first), entry(&contents, second));
+        contents[first.offset as usize..(first.offset + first.length) as usize]
+            .copy_from_slice(&second_entry);
+        contents[second.offset as usize..(second.offset + second.length) as usize]
+            .copy_from_slice(&first_entry);
+        fs::write(&secrets_path, contents)?;
+        let others_path = db_path.join("others.dat");
+        let mut contents = fs::read(&others_path)?;
+        contents[first.offset as usize..(first.offset + first.length) as usize]
+            .copy_from_slice(&first_entry);
+        fs::write(&others_path, contents)?;
+
+        // none of them decrypt in their new place, so the database doesn't
+        // open, and fsck finds all three
+        let path = db_path.to_str().unwrap();
+        let error = Database::new_with_options(path, options.clone())
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("tampered with"), "{}", error);
+        let report = Database::fsck_with_options(path, options.clone(), false).await?;
+        let undecodable = report
+            .problems
+            .iter()
+            .filter(|problem| matches!(problem.kind, FsckProblemKind::UndecodableEntry { .. }))
+            .count();
+        assert_eq!(undecodable, 3);
+
+        // repairing moves the entry left in "others" to a new offset, where it
+        // is encrypted again
+        Database::fsck_with_options(path, options.clone(), true).await?;
+        let db = Database::new_with_options(path, options).await?;
+        let command = json!({ "table_name": "others", "key": 2 });
+        let record = db
+            .get_item(serde_json::from_value(command)?)
+            .await?
+            .unwrap();
+        assert_eq!(
+            record.get(&"body".into()),
+            Some(&Some(ColumnValue::Text("no!".to_string())))
+        );
+        assert_eq!(db.get_size(&"secrets".into()), Some(0));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_backup_and_restore() -> anyhow::Result<()> {
+        let options = DatabaseOptions {
+            compaction_threshold: None,
+            max_segment_bytes: 1024,
+            ..Default::default()
+        };
+        let mut db = setup_with_options("backup", options.clone()).await?;
+        let table_name: TableName = "authors".into();
+        for i in 0..50 {
+            db.put_item(create_put_item(i)?).await?;
+        }
+        for i in 0..5 {
+            let command = json!({ "table_name": "authors", "key": i });
+            db.delete_item(serde_json::from_value(command)?).await?;
+        }
+
+        // writes go on while the backup is written, including a compaction
+        // which swaps out the segment files
+        let backup = db.start_backup()?;
+        for i in 50..80 {
+            db.put_item(create_put_item(i)?).await?;
+        }
+        for i in 5..10 {
+            let command = json!({ "table_name": "authors", "key": i });
+            db.delete_item(serde_json::from_value(command)?).await?;
+        }
+        db.compact_table(&table_name).await?;
+
+        let db_path = db.catalog.directory_path.display().to_string();
+        let backup_path = format!("{}_files", db_path);
+        let restore_path = format!("{}_restored", db_path);
+        for path in [&backup_path, &restore_path] {
+            let _ = fs::remove_dir_all(path);
+        }
+        // backups named in a directory of backups stay in it
+        let backups_path = Path::new(&db_path).parent().unwrap();
+        assert_eq!(
+            backup::backup_path(backups_path, "daily")?,
+            backups_path.join("daily")
+        );
+        for name in ["../daily", "/tmp/daily", "daily/1", "..", ".", ""] {
+            assert!(matches!(
+                backup::backup_path(backups_path, name),
+                Err(BackupError::InvalidBackupName(_))
+            ));
+        }
+        let manifest = backup.write(Path::new(&backup_path)).await?;
+        assert!(manifest.catalog.is_some());
+        assert_eq!(manifest.tables.len(), 1);
+        assert!(manifest.tables[0].segments.len() > 1);
+
+        // the restored database is the database as of the start of the backup
+        let restored = Database::restore(&backup_path, &restore_path, options.clone()).await?;
+        assert_eq!(restored.get_size(&table_name), Some(45));
+        assert!(restored.get_item(create_get_item(4)?).await?.is_none());
+        assert!(restored.get_item(create_get_item(7)?).await?.is_some());
+        assert!(restored.get_item(create_get_item(60)?).await?.is_none());
+
+        // a backup is never restored over a database
+        assert!(matches!(
+            Database::restore(&backup_path, &restore_path, options.clone()).await,
+            Err(QueryError::BackupError(BackupError::DirectoryNotEmpty(_)))
+        ));
+        // nor restored if it doesn't match its manifest
+        let segment = &manifest.tables[0].segments[0];
+        let segment_path = Path::new(&backup_path).join(&segment.file_name);
+        let mut contents = fs::read(&segment_path)?;
+        *contents.last_mut().unwrap() ^= 0xff;
+        fs::write(&segment_path, contents)?;
+        let restore_path = format!("{}_corrupt", db_path);
+        let _ = fs::remove_dir_all(&restore_path);
+        assert!(matches!(
+            Database::restore(&backup_path, &restore_path, options.clone()).await,
+            Err(QueryError::BackupError(BackupError::CorruptFile(_)))
+        ));
+        assert!(!Path::new(&restore_path).exists());
+
+        // nor are files read or written outside of the directories, whatever
+        // the manifest names
+        let manifest_path = Path::new(&backup_path).join("backup.json");
+        let mut tampered: serde_json::Value = serde_json::from_slice(&fs::read(&manifest_path)?)?;
+        for file_name in ["../escaped.dat", "/tmp/escaped.dat", "segments/0.dat", ""] {
+            tampered["tables"][0]["segments"][0]["file_name"] = json!(file_name);
+            fs::write(&manifest_path, serde_json::to_vec(&tampered)?)?;
+            assert!(matches!(
+                Database::restore(&backup_path, &restore_path, options.clone()).await,
+                Err(QueryError::BackupError(BackupError::InvalidFileName(name))) if name == file_name
+            ));
+        }
+        assert!(!Path::new(&restore_path).exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_batch() -> anyhow::Result<()> {
+        let mut db = setup("write_batch").await?;
+        db.put_item(create_put_item(1)?).await?;
+
+        // create a table and fill it, along with writes to another table
+        let batch = serde_json::from_value(json!({
+            "writes": [
+                {
+                    "type": "create_table",
+                    "name": "books",
+                    "columns": [
+                        { "name": "id", "type": "Integer" },
+                        { "name": "author_id", "type": "Integer" }
+                    ],
+                    "primary_key": "id"
+                },
+                { "type": "put_item", "table_name": "books", "item": { "id": 1, "author_id": 2 } },
+                { "type": "put_item", "table_name": "authors", "item": { "id": 2, "name": "Ursula" } },
+                { "type": "update_item", "table_name": "books", "key": 1, "item": { "author_id": 1 } },
+                { "type": "delete_item", "table_name": "authors", "key": 2 }
+            ]
+        }))?;
+        db.write_batch(batch).await?;
+        assert_eq!(db.get_size(&"books".into()), Some(1));
+        assert_eq!(db.get_size(&"authors".into()), Some(1));
+
+        // a failing write fails the whole batch
+        let batch = serde_json::from_value(json!({
+            "writes": [
+                { "type": "drop_table", "table_name": "books" },
+                { "type": "put_item", "table_name": "authors", "item": { "id": 3, "name": "Iain" } },
+                { "type": "put_item", "table_name": "authors", "item": { "id": 3, "name": "Iain" } }
+            ]
+        }))?;
+        let res = db.write_batch(batch).await.map_err(|e| e.to_string());
+        assert_eq!(
+            res,
+            Err("Record with primary key '3' already exists.".to_string())
+        );
+        assert_eq!(db.get_size(&"books".into()), Some(1));
+        assert!(db.get_item(create_get_item(3)?).await?.is_none());
+
+        let db = Database::new(db.catalog.directory_path.to_str().unwrap()).await?;
+        let record = db
+            .get_item(serde_json::from_value(
+                json!({ "table_name": "books", "key": 1 }),
+            )?)
+            .await?
+            .unwrap();
+        assert_eq!(
+            record.get(&"author_id".into()).unwrap(),
+            &Some(ColumnValue::Integer(1))
+        );
+        assert_eq!(db.get_size(&"authors".into()), Some(1));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_wal_replay() -> anyhow::Result<()> {
+        let mut db = setup("wal_replay").await?;
+        for i in 0..10 {
+            db.put_item(create_put_item(i)?).await?;
+        }
+        let db_path = db.catalog.directory_path.clone();
+
+        // simulate a crash right after a batch is logged, before it is applied
+        let mut plan = BatchPlan::new(&db.catalog);
+        dml::delete_item(
+            serde_json::from_value(json!({ "table_name": "authors", "key": 3 }))?,
+            &mut plan,
+        )
+        .await?;
+        dml::put_item(create_put_item(10)?, &mut plan).await?;
+        ddl::drop_table(
+            serde_json::from_value(json!({ "table_name": "authors" }))?,
+            &mut plan,
+        )?;
+        ddl::create_table(
+            serde_json::from_value(json!({
+                "name": "authors",
+                "columns": [{ "name": "id", "type": "Integer" }],
+                "primary_key": "id"
+            }))?,
+            &mut plan,
+        )?;
+        dml::put_item(
+            serde_json::from_value(json!({ "table_name": "authors", "item": { "id": 42 } }))?,
+            &mut plan,
+        )
+        .await?;
+        let record = plan.into_record();
+        db.wal.append(&record, true).await?;
+
+        // and a crash in the middle of logging the next one
+        let mut file = fs::OpenOptions::new()
+            .append(true)
+            .open(db_path.join("wal.log"))?;
+        file.write_all(&100u64.to_le_bytes())?;
+        file.write_all(b"partial")?;
+        drop(file);
+
+        let db = Database::new(db_path.to_str().unwrap()).await?;
+        assert_eq!(db.get_size(&"authors".into()), Some(1));
+        assert!(db.get_item(create_get_item(42)?).await?.is_some());
+        assert_eq!(db.wal.size(), 0);
+        assert_eq!(
+            fs::metadata(db_path.join("wal.log"))?.len(),
+            storage::FILE_HEADER_SIZE
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_database() -> anyhow::Result<()> {
+        let options = DatabaseOptions {
+            max_segment_bytes: 256,
+            compaction_threshold: None,
+            ..Default::default()
+        };
+        let mut db = Database::new_in_memory_with_options(options).await?;
+        create_authors_table(&mut db).await?;
+        let table: TableName = "authors".into();
+        for i in 0..100 {
+            db.put_item(create_put_item(i)?).await?;
+        }
+        for i in 0..50 {
+            db.update_item(create_update_item(i)?).await?;
+        }
+        let cmd = serde_json::from_value(json!({
+            "table_name": "authors",
+            "filter": { "column": "id", "op": "$gte", "value": 90 }
+        }))?;
+        assert_eq!(db.delete_where(cmd).await?, 10);
+        db.checkpoint().await?;
+        let stats = db.get_stats(&table).await.unwrap();
+        assert!(stats.segments > 1);
+        assert!(stats.dead_bytes > 0);
+
+        // segments are compacted in memory the same way as on disk
+        db.compact_table(&table).await?;
+        let compacted = db.get_stats(&table).await.unwrap();
+        assert_eq!(compacted.rows, 90);
+        assert_eq!(compacted.dead_bytes, 0);
+        assert_eq!(db.filter_item(create_filter_item_1()?).await?.len(), 80);
+        let record = db.get_item(create_get_item(1)?).await?.unwrap();
+        assert_eq!(
+            record.get(&"name".into()).unwrap(),
+            &Some(ColumnValue::Text("updated".to_string()))
+        );
+        assert!(!PathBuf::from(IN_MEMORY_PATH).exists());
+
+        // every in-memory database is a separate one
+        let other = Database::new_in_memory().await?;
+        assert!(other.list_tables().is_empty());
+
+        db.drop_table(serde_json::from_value(json!({ "table_name": "authors" }))?)
+            .await?;
+        db.create_table(serde_json::from_value(json!({
+            "name": "authors",
+            "columns": [{ "name": "id", "type": "Integer" }],
+            "primary_key": "id"
+        }))?)
+        .await?;
+        assert_eq!(db.get_size(&table), Some(0));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_writing_data_updates_index() -> anyhow::Result<()> {
+        let mut db = setup_in_memory().await?;
+        for i in 0..20 {
+            let author_item = create_put_item(i)?;
+            db.put_item(author_item).await?;
+        }
+
+        let table = db.catalog.get_table(&"authors".into()).unwrap();
+
+        let position = table
+            .storage
+            .as_log()
+            .unwrap()
+            .index
+            .get(&ColumnValue::Integer(0));
+        assert!(position.is_some());
+        let position = position.unwrap();
+        assert_eq!(position.offset, storage::FILE_HEADER_SIZE);
+
+        let position = table
+            .storage
+            .as_log()
+            .unwrap()
+            .index
+            .get(&ColumnValue::Integer(6));
+        assert!(position.is_some());
+        let position = position.unwrap();
+        let tuple = table
+            .storage
+            .as_log()
+            .unwrap()
+            .block
+            .seek_to_offset(position)
+            .await?;
+        let primary_key = tuple[table.storage.as_log().unwrap().pk_position]
+            .clone()
+            .unwrap();
+        assert_eq!(primary_key, ColumnValue::Integer(6));
+
+        let position = table
+            .storage
+            .as_log()
+            .unwrap()
+            .index
+            .get(&ColumnValue::Integer(9));
+        assert!(position.is_some());
+        let position = position.unwrap();
+        let tuple = table
+            .storage
+            .as_log()
+            .unwrap()
+            .block
+            .seek_to_offset(position)
+            .await?;
+        let primary_key = tuple[table.storage.as_log().unwrap().pk_position]
+            .clone()
+            .unwrap();
+        assert_eq!(primary_key, ColumnValue::Integer(9));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_lots_of_data() -> anyhow::Result<()> {
+        let mut db = setup_in_memory().await?;
+        for i in 0..1001 {
+            let author_item = create_put_item(i)?;
+            db.put_item(author_item).await?;
+        }
+        let table = db.catalog.get_table(&"authors".into()).unwrap();
+        let mut stream = Box::pin(table.storage.as_log().unwrap().block.get_reader().await?);
+        while let Some(tuple) = stream.next().await {
+            let tuple = tuple?;
+            assert_eq!(tuple.len(), 2);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_filtering() -> anyhow::Result<()> {
+        let mut db = setup_in_memory().await?;
+        for i in 0..100 {
+            let author_item = create_put_item(i)?;
+            db.put_item(author_item).await?;
+        }
+
+        // test expression 1
+        let cmd = create_filter_item_1()?;
+        let res = db.filter_item(cmd).await?;
+        assert_eq!(res.len(), 80);
+
+        // test expression 2
+        let cmd = create_filter_item_2()?;
+        let res = db.filter_item(cmd).await?;
+        assert_eq!(res.len(), 10);
+
+        Ok(())
+    }
+
+    async fn setup(test_name: &str) -> anyhow::Result<Database> {
+        setup_with_options(test_name, DatabaseOptions::default()).await
+    }
+
+    async fn setup_with_options(
+        test_name: &str,
+        options: DatabaseOptions,
+    ) -> anyhow::Result<Database> {
+        let root_test_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        // if test dir already exists; remove it.
+        let dir_path = root_test_dir.join(format!("{}_{}", DB_PATH, test_name));
+        if dir_path.exists() {
+            let _ = fs::remove_dir_all(&dir_path);
+        }
+        fs::create_dir_all(&dir_path)?;
+        let mut db = Database::new_with_options(dir_path.to_str().unwrap(), options).await?;
+        create_authors_table(&mut db).await?;
+        Ok(db)
+    }
+
+    async fn setup_in_memory() -> anyhow::Result<Database> {
+        let mut db = Database::new_in_memory().await?;
+        create_authors_table(&mut db).await?;
+        Ok(db)
+    }
+
+    async fn create_authors_table(db: &mut Database) -> anyhow::Result<()> {
+        let authors_table = json!({
+            "name": "authors",
+            "columns": [
+                {
+                    "name": "id",
+                    "type": "Integer",
+                },
+                {
+                    "name": "name",
+                    "type": "Text",
+                }
+            ],
+            "primary_key": "id"
+        });
+        db.create_table(serde_json::from_value(authors_table)?)
+            .await?;
+        Ok(())
+    }
+
+    fn create_get_item(id: i64) -> anyhow::Result<dml::GetItemCommand> {
+        Ok(serde_json::from_value(json!({
+            "table_name": "authors",
+            "key": ColumnValue::Integer(id),
+        }))?)
+    }
+
+    fn create_put_item(id: i64) -> anyhow::Result<dml::PutItemCommand> {
+        const CHARSET: &[u8] =
+            b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789 )(*&^%$#@!~\"',;";
+        const STRING_LEN: usize = 20;
+        let mut rng = rand::thread_rng();
+        let rand_string: String = (0..STRING_LEN)
+            .map(|_| {
+                let idx = rng.gen_range(0..CHARSET.len());
+                CHARSET[idx] as char
+            })
+            .collect();
+
+        Ok(serde_json::from_value(json!({
+            "table_name": "authors",
+            "item": {
+                "id": id,
+                "name": rand_string,
+            }
+        }))?)
+    }
+
+    fn create_update_item(id: i64) -> anyhow::Result<dml::UpdateItemCommand> {
+        Ok(serde_json::from_value(json!({
+            "table_name": "authors",
+            "key": id,
+            "item": {
+                "name": "updated",
             }
         }))?)
     }
@@ -0,0 +1,1133 @@
+/// An LSM tree, the storage engine of tables defined with `"engine": "lsm"`.
+/// Unlike the log (see `TableBuffer`), it doesn't keep an index of all the keys
+/// in memory, so that it works for tables whose keys don't fit in memory.
+///
+/// Writes go to the memtable, a sorted map in memory. Once it grows beyond
+/// `TableOptions::memtable_bytes` (or the database is checkpointed), it is
+/// flushed to a new SSTable file in level 0. An SSTable is a block (see
+/// `BlockStorage`) of tuples and tombstones sorted by key, followed by a footer
+/// record with a sparse index of the keys (every `SPARSE_INDEX_INTERVAL`th
+/// entry) and a bloom filter of all of them. The footers are kept in memory.
+///
+/// Compaction is leveled. The SSTables of level 0 can overlap; once there are
+/// `L0_COMPACTION_TRIGGER` of them, they are merged with the overlapping
+/// SSTables of level 1 into level 1. The SSTables of every other level don't
+/// overlap, and once a level grows beyond its size (ten times the size of the
+/// previous level), one of its SSTables is merged into the next level.
+/// Tombstones are dropped when they are merged into the last level.
+///
+/// The SSTables of every level are listed in the tree's manifest
+/// (`<table>.dat.lsm`), which is replaced atomically after every flush and
+/// compaction. SSTables are stored next to it, as `<table>.dat.lsm.<id>`; files
+/// which are not in the manifest (e.g. from a compaction which didn't finish)
+/// are removed when the tree is opened. The memtable is not written anywhere
+/// but the write-ahead log of the database, which is only emptied once the
+/// memtable is flushed.
+///
+/// The SSTables of a columnar table store their entries by column (see
+/// `columnar`), in chunks instead of one by one; the chunks' statistics take
+/// the place of the sparse index.
+use std::{
+    collections::{btree_map, BTreeMap, VecDeque},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use futures::Stream;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    backend::{Backend, BlockStorage},
+    columnar::{ChunkBuilder, ChunkStats, ScanFilter},
+    encryption::EncryptionError,
+    query::types::{ColumnValue, Compression, Durability, StorageEngine, TableDefinition},
+    storage::{
+        calculate_new_offset, decode_entry, BlockEntry, StorageError, Tuple, FILE_HEADER_SIZE,
+    },
+    table::{TableOptions, TableStats},
+};
+
+/// Suffix of the manifest of a tree, next to the table's path.
+const MANIFEST_FILE_SUFFIX: &str = "lsm";
+
+/// Number of entries of an SSTable between the keys in its sparse index.
+const SPARSE_INDEX_INTERVAL: u64 = 16;
+
+/// Number of SSTables in level 0 which triggers a compaction into level 1.
+const L0_COMPACTION_TRIGGER: usize = 4;
+
+/// How many times larger every level (from level 1 on) is than the previous
+/// one. Level 1 is this many times larger than the memtable.
+const LEVEL_SIZE_MULTIPLIER: u64 = 10;
+
+/// Bits of the bloom filter of an SSTable per key, and the number of hashes;
+/// about 1% false positives.
+const BLOOM_BITS_PER_KEY: u64 = 10;
+const BLOOM_HASHES: u64 = 7;
+
+/// An entry of the memtable or of an SSTable: a tuple, or `None` for a
+/// tombstone.
+type Entry = (ColumnValue, Option<Tuple>);
+
+#[derive(Debug, Clone)]
+pub(crate) struct LsmTree {
+    files: TreeFiles,
+    /// Writes which are not flushed to an SSTable yet
+    memtable: BTreeMap<ColumnValue, Option<Tuple>>,
+    /// Approximate size of the memtable, in bytes
+    memtable_size: u64,
+    /// SSTables of every level. Level 0 is ordered newest first, and the other
+    /// levels by key.
+    levels: Vec<Vec<Arc<SsTable>>>,
+    /// Number of live tuples
+    rows: u64,
+    /// Number of live tuples in the SSTables, i.e. without the memtable
+    flushed_rows: u64,
+}
+
+/// Where, and how, the SSTables of a tree are written.
+#[derive(Debug, Clone)]
+struct TreeFiles {
+    backend: Backend,
+    /// The table's path; the files of the tree are named after it
+    base_path: PathBuf,
+    durability: Durability,
+    schema_fingerprint: u32,
+    compression: Option<Compression>,
+    /// Column index of the primary key
+    pk_position: usize,
+    /// Number of columns of the table
+    column_count: usize,
+    layout: Layout,
+    /// Size of the memtable which triggers a flush, in bytes; also the size
+    /// SSTables are split at, when they are compacted
+    memtable_bytes: u64,
+    /// Id of the next SSTable; shared with the compactions of the tree
+    next_id: Arc<AtomicU64>,
+}
+
+/// How the entries of the SSTables of a tree are stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Layout {
+    /// One by one
+    Row,
+    /// By column, in chunks
+    Columnar,
+}
+
+/// An SSTable file, and its footer.
+#[derive(Debug)]
+pub(crate) struct SsTable {
+    id: u64,
+    block: Arc<dyn BlockStorage>,
+    /// Length of the file, in bytes
+    length: u64,
+    /// Byte-offset of the footer, i.e. the end of the entries
+    footer_offset: u64,
+    footer: SsTableFooter,
+}
+
+/// The last entry of an SSTable
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct SsTableFooter {
+    entries: u64,
+    min_key: ColumnValue,
+    max_key: ColumnValue,
+    /// Bytes saved by compressing the entries
+    saved_bytes: u64,
+    /// Every `SPARSE_INDEX_INTERVAL`th key, and the byte-offset of its entry
+    sparse_index: Vec<(ColumnValue, u64)>,
+    bloom_filter: BloomFilter,
+    /// Statistics of the chunks of a columnar SSTable
+    #[serde(default)]
+    pub(crate) chunks: Vec<ChunkStats>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BloomFilter {
+    bits: Vec<u64>,
+}
+
+/// The manifest of a tree: its SSTables, by level.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct LsmManifest {
+    next_id: u64,
+    /// Number of live tuples in the SSTables
+    rows: u64,
+    levels: Vec<Vec<ManifestSsTable>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ManifestSsTable {
+    id: u64,
+    footer_offset: u64,
+}
+
+/// A compaction of all the SSTables of a tree into one level, which is in
+/// progress; it runs in the same steps as a `Compaction` of a log.
+#[derive(Debug)]
+pub(crate) struct MajorCompaction {
+    files: TreeFiles,
+    /// The SSTables when the compaction started, newest first
+    tables: Vec<Arc<SsTable>>,
+    /// The level the SSTables are compacted into
+    output_level: usize,
+    /// The compacted SSTables. Available after `run`.
+    compacted: Option<Vec<Arc<SsTable>>>,
+}
+
+/// The memtable and the SSTables of a tree, for a backup. The memtable is
+/// written to an SSTable of its own in the backup.
+#[derive(Debug)]
+pub(crate) struct LsmSnapshot {
+    files: TreeFiles,
+    manifest: LsmManifest,
+    memtable: Vec<Entry>,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum LsmError {
+    #[error("LSM manifest {path} is corrupt. {error}")]
+    CorruptManifest {
+        path: PathBuf,
+        error: serde_json::Error,
+    },
+    #[error("SSTable {path} is corrupt: its entries are not sorted by key.")]
+    UnsortedSsTable { path: PathBuf },
+    #[error("Unexpected invariant violation: SSTable {path} has no entries.")]
+    EmptySsTable { path: PathBuf },
+    #[error("Unexpected invariant violation: primary key not found in data tuple.")]
+    PrimaryKeyNotInTuple,
+    #[error("{0}")]
+    Encryption(#[from] EncryptionError),
+    #[error("Internal Storage Engine Error: {0}")]
+    StorageError(#[from] StorageError),
+    #[error("LSM file operation failed. {0}")]
+    FileOperation(#[from] std::io::Error),
+}
+
+impl LsmTree {
+    /// Open the tree of the table at the given path. Its manifest must exist.
+    pub(crate) async fn open(
+        table_definition: &TableDefinition,
+        table_path: &Path,
+        options: &TableOptions,
+    ) -> Result<Self, LsmError> {
+        let pk_position = table_definition
+            .primary_key_position()
+            .ok_or(LsmError::PrimaryKeyNotInTuple)?;
+        let backend = &options.backend;
+        let manifest = read_manifest(backend, table_path)?;
+        let files = TreeFiles {
+            backend: backend.clone(),
+            base_path: table_path.to_path_buf(),
+            durability: table_definition.durability.unwrap_or(options.durability),
+            schema_fingerprint: table_definition.schema_fingerprint(),
+            compression: table_definition.compression,
+            pk_position,
+            column_count: table_definition.columns.len(),
+            layout: match table_definition.engine {
+                Some(StorageEngine::Columnar) => Layout::Columnar,
+                _ => Layout::Row,
+            },
+            memtable_bytes: options.memtable_bytes,
+            next_id: Arc::new(AtomicU64::new(manifest.next_id)),
+        };
+
+        let mut levels = vec![];
+        for level in &manifest.levels {
+            let mut tables = vec![];
+            for table in level {
+                tables.push(Arc::new(files.open_sstable(table).await?));
+            }
+            levels.push(tables);
+        }
+        // leftovers of a flush or a compaction which didn't finish
+        let ids: Vec<_> = manifest.levels.iter().flatten().map(|t| t.id).collect();
+        for (id, path) in find_sstable_files(backend, table_path)? {
+            if !ids.contains(&id) {
+                backend.remove_file(&path)?;
+            }
+        }
+
+        Ok(Self {
+            files,
+            memtable: BTreeMap::new(),
+            memtable_size: 0,
+            levels,
+            rows: manifest.rows,
+            flushed_rows: manifest.rows,
+        })
+    }
+
+    /// The live version of the tuple with the given key
+    pub(crate) async fn get(&self, key: &ColumnValue) -> Result<Option<Tuple>, LsmError> {
+        if let Some(entry) = self.memtable.get(key) {
+            return Ok(entry.clone());
+        }
+        for (level, tables) in self.levels.iter().enumerate() {
+            // only level 0 has more than one SSTable with the key in its range
+            for table in tables {
+                if level > 0 && !table.covers(key) {
+                    continue;
+                }
+                if let Some(entry) = table.get(key, self.files.pk_position).await? {
+                    return Ok(entry);
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    pub(crate) async fn contains_key(&self, key: &ColumnValue) -> Result<bool, LsmError> {
+        Ok(self.get(key).await?.is_some())
+    }
+
+    pub(crate) async fn write(&mut self, key: ColumnValue, tuple: Tuple) -> Result<(), LsmError> {
+        if !self.contains_key(&key).await? {
+            self.rows += 1;
+        }
+        self.insert(key, Some(tuple)).await
+    }
+
+    /// Delete the tuple with the given key, by writing a tombstone for it.
+    pub(crate) async fn delete(&mut self, key: ColumnValue) -> Result<(), LsmError> {
+        if self.contains_key(&key).await? {
+            self.rows -= 1;
+        }
+        self.insert(key, None).await
+    }
+
+    /// Stream the live tuples, in the order of their keys. With a filter, the
+    /// SSTables of a columnar table leave out what it doesn't need.
+    pub(crate) async fn scan(
+        &self,
+        filter: Option<Arc<ScanFilter>>,
+    ) -> Result<impl Stream<Item = Result<Tuple, LsmError>> + Send + '_, LsmError> {
+        let mut runs = vec![Run::Memtable(self.memtable.iter())];
+        runs.extend(
+            self.tables()
+                .map(|table| Run::SsTable(Cursor::new(table.clone(), filter.clone()))),
+        );
+        let merge = Merge::new(runs, self.files.pk_position).await?;
+        Ok(futures::stream::try_unfold(merge, |mut merge| async move {
+            // tombstones hide the older versions of their tuple, and are
+            // skipped themselves
+            while let Some((_, entry)) = merge.next().await? {
+                if let Some(tuple) = entry {
+                    return Ok(Some((tuple, merge)));
+                }
+            }
+            Ok(None)
+        }))
+    }
+
+    pub(crate) fn size(&self) -> usize {
+        self.rows as usize
+    }
+
+    pub(crate) fn stats(&self) -> TableStats {
+        let total_bytes = self.total_bytes();
+        let saved_bytes: u64 = self.tables().map(|table| table.footer.saved_bytes).sum();
+        let uncompressed_bytes = total_bytes + saved_bytes;
+        TableStats {
+            rows: self.size(),
+            total_bytes,
+            segments: self.tables().count(),
+            // dead versions are dropped by the tree's own compactions
+            dead_bytes: 0,
+            uncompressed_bytes,
+            blob_bytes: 0,
+            compression_ratio: if total_bytes == 0 {
+                1.0
+            } else {
+                uncompressed_bytes as f64 / total_bytes as f64
+            },
+        }
+    }
+
+    /// Flush the memtable to a new SSTable in level 0, and compact the levels
+    /// which need it.
+    pub(crate) async fn flush(&mut self) -> Result<(), LsmError> {
+        if self.memtable.is_empty() {
+            return Ok(());
+        }
+        let memtable = std::mem::take(&mut self.memtable);
+        let table = self.files.write_sstable(memtable).await?;
+        if self.levels.is_empty() {
+            self.levels.push(vec![]);
+        }
+        self.levels[0].insert(0, Arc::new(table));
+        self.memtable_size = 0;
+        self.flushed_rows = self.rows;
+        self.write_manifest()?;
+        self.compact().await
+    }
+
+    /// Paths and lengths of the SSTable files
+    pub(crate) fn sstable_files(&self) -> Vec<(&Path, u64)> {
+        self.tables()
+            .map(|table| (table.block.path(), table.length))
+            .collect()
+    }
+
+    /// Snapshot of the tree for a backup. The SSTables in it are the ones in
+    /// `sstable_files`, which are copied to the backup as they are.
+    pub(crate) fn snapshot(&self) -> LsmSnapshot {
+        LsmSnapshot {
+            files: self.files.clone(),
+            manifest: LsmManifest {
+                rows: self.rows,
+                ..self.manifest()
+            },
+            memtable: self
+                .memtable
+                .iter()
+                .map(|(key, entry)| (key.clone(), entry.clone()))
+                .collect(),
+        }
+    }
+
+    fn tables(&self) -> impl Iterator<Item = &Arc<SsTable>> {
+        self.levels.iter().flatten()
+    }
+
+    fn total_bytes(&self) -> u64 {
+        self.tables().map(|table| table.length).sum()
+    }
+
+    async fn insert(&mut self, key: ColumnValue, entry: Option<Tuple>) -> Result<(), LsmError> {
+        self.memtable_size += approximate_size(&key, &entry);
+        self.memtable.insert(key, entry);
+        if self.memtable_size >= self.files.memtable_bytes {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    // compact the levels which are too large, until none of them is
+    async fn compact(&mut self) -> Result<(), LsmError> {
+        loop {
+            if self.levels[0].len() >= L0_COMPACTION_TRIGGER {
+                let tables = self.levels[0].clone();
+                self.compact_into_next_level(0, tables).await?;
+                continue;
+            }
+            let full_level = (1..self.levels.len()).find(|level| {
+                let size: u64 = self.levels[*level].iter().map(|t| t.length).sum();
+                size > self.max_level_bytes(*level)
+            });
+            match full_level {
+                Some(level) => {
+                    let tables = vec![self.levels[level][0].clone()];
+                    self.compact_into_next_level(level, tables).await?;
+                }
+                None => return Ok(()),
+            }
+        }
+    }
+
+    fn max_level_bytes(&self, level: usize) -> u64 {
+        self.files.memtable_bytes * LEVEL_SIZE_MULTIPLIER.pow(level as u32)
+    }
+
+    // merge the given SSTables of a level with the SSTables of the next level
+    // they overlap with, into the next level
+    async fn compact_into_next_level(
+        &mut self,
+        level: usize,
+        tables: Vec<Arc<SsTable>>,
+    ) -> Result<(), LsmError> {
+        let next_level = level + 1;
+        if self.levels.len() == next_level {
+            self.levels.push(vec![]);
+        }
+        let min_key = tables.iter().map(|t| &t.footer.min_key).min();
+        let max_key = tables.iter().map(|t| &t.footer.max_key).max();
+        let (Some(min_key), Some(max_key)) = (min_key, max_key) else {
+            return Ok(());
+        };
+        let overlapping: Vec<_> = self.levels[next_level]
+            .iter()
+            .filter(|t| t.footer.min_key <= *max_key && *min_key <= t.footer.max_key)
+            .cloned()
+            .collect();
+        // newer SSTables first
+        let inputs: Vec<_> = tables.iter().chain(&overlapping).cloned().collect();
+        let is_last_level = self.levels[next_level + 1..].iter().all(Vec::is_empty);
+        let compacted = self.files.merge(&inputs, is_last_level).await?;
+
+        self.levels[level].retain(|t| !contains_table(&tables, t));
+        let next = &mut self.levels[next_level];
+        next.retain(|t| !contains_table(&overlapping, t));
+        next.extend(compacted);
+        next.sort_by(|a, b| a.footer.min_key.cmp(&b.footer.min_key));
+        self.write_manifest()?;
+        remove_sstables(&self.files.backend, &inputs)
+    }
+
+    fn manifest(&self) -> LsmManifest {
+        LsmManifest {
+            next_id: self.files.next_id.load(Ordering::SeqCst),
+            rows: self.flushed_rows,
+            levels: self
+                .levels
+                .iter()
+                .map(|tables| {
+                    tables
+                        .iter()
+                        .map(|table| ManifestSsTable {
+                            id: table.id,
+                            footer_offset: table.footer_offset,
+                        })
+                        .collect()
+                })
+                .collect(),
+        }
+    }
+
+    fn write_manifest(&self) -> Result<(), LsmError> {
+        write_manifest(&self.files.backend, &self.files.base_path, &self.manifest())
+    }
+}
+
+impl MajorCompaction {
+    /// Start a compaction of all the SSTables of the tree, by taking a
+    /// snapshot of them.
+    pub(crate) fn new(tree: &LsmTree) -> Self {
+        Self {
+            files: tree.files.clone(),
+            tables: tree.tables().cloned().collect(),
+            output_level: tree.levels.len().saturating_sub(1).max(1),
+            compacted: None,
+        }
+    }
+
+    /// Merge the SSTables of the snapshot into new SSTables. As they are all
+    /// of the tree's SSTables, tombstones are dropped.
+    pub(crate) async fn run(&mut self) -> Result<(), LsmError> {
+        self.compacted = Some(self.files.merge(&self.tables, true).await?);
+        Ok(())
+    }
+
+    pub(crate) fn is_run(&self) -> bool {
+        self.compacted.is_some()
+    }
+
+    /// Are the SSTables of the snapshot still in the tree, i.e. it wasn't
+    /// compacted (or dropped and re-created) in the meantime
+    pub(crate) fn is_current(&self, tree: &LsmTree) -> bool {
+        let current: Vec<_> = tree.tables().cloned().collect();
+        self.tables
+            .iter()
+            .all(|table| contains_table(&current, table))
+    }
+
+    /// Swap the compacted SSTables in place of the ones of the snapshot. SSTables
+    /// flushed since the snapshot are newer, and stay in level 0. Returns the
+    /// size of the tree's SSTables before and after.
+    pub(crate) fn finish(self, tree: &mut LsmTree) -> Result<(u64, u64), LsmError> {
+        let old_size = tree.total_bytes();
+        let compacted = self.compacted.unwrap_or_default();
+        for level in &mut tree.levels {
+            level.retain(|table| !contains_table(&self.tables, table));
+        }
+        while tree.levels.len() <= self.output_level {
+            tree.levels.push(vec![]);
+        }
+        tree.levels[self.output_level] = compacted;
+        tree.write_manifest()?;
+        remove_sstables(&self.files.backend, &self.tables)?;
+        Ok((old_size, tree.total_bytes()))
+    }
+
+    /// Remove the compacted SSTables, of a compaction which is given up on
+    pub(crate) fn discard(self) -> Result<(), LsmError> {
+        remove_sstables(&self.files.backend, &self.compacted.unwrap_or_default())
+    }
+}
+
+impl LsmSnapshot {
+    /// Write the memtable of the snapshot to an SSTable, and the manifest, in
+    /// the given directory; the SSTables of the snapshot are copied there
+    /// separately. Returns the paths of the files written.
+    pub(crate) async fn write(
+        &self,
+        backend: &Backend,
+        table_path: &Path,
+    ) -> Result<Vec<PathBuf>, LsmError> {
+        let mut manifest = self.manifest.clone();
+        let mut paths = vec![];
+        if !self.memtable.is_empty() {
+            let files = TreeFiles {
+                backend: backend.clone(),
+                base_path: table_path.to_path_buf(),
+                next_id: Arc::new(AtomicU64::new(manifest.next_id)),
+                ..self.files.clone()
+            };
+            let table = files.write_sstable(self.memtable.clone()).await?;
+            if manifest.levels.is_empty() {
+                manifest.levels.push(vec![]);
+            }
+            manifest.levels[0].insert(
+                0,
+                ManifestSsTable {
+                    id: table.id,
+                    footer_offset: table.footer_offset,
+                },
+            );
+            manifest.next_id = files.next_id.load(Ordering::SeqCst);
+            paths.push(table.block.path().to_path_buf());
+        }
+        write_manifest(backend, table_path, &manifest)?;
+        paths.push(get_manifest_path(table_path));
+        Ok(paths)
+    }
+}
+
+impl LsmManifest {
+    /// Paths of the SSTables of the tree of the table at the given path, and
+    /// the byte-offsets of their footers
+    pub(crate) fn sstables(&self, table_path: &Path) -> Vec<(PathBuf, u64)> {
+        self.levels
+            .iter()
+            .flatten()
+            .map(|table| (get_sstable_path(table_path, table.id), table.footer_offset))
+            .collect()
+    }
+}
+
+impl TreeFiles {
+    async fn open_sstable(&self, table: &ManifestSsTable) -> Result<SsTable, LsmError> {
+        let path = get_sstable_path(&self.base_path, table.id);
+        let block = self
+            .backend
+            .open_block(&path, self.durability, self.schema_fingerprint)?;
+        let footer = block.read_record(table.footer_offset).await?;
+        Ok(SsTable {
+            id: table.id,
+            length: self.backend.file_length(&path)?,
+            block,
+            footer_offset: table.footer_offset,
+            footer,
+        })
+    }
+
+    // write the given entries, which must be sorted by key, to a new SSTable
+    async fn write_sstable(
+        &self,
+        entries: impl IntoIterator<Item = Entry>,
+    ) -> Result<SsTable, LsmError> {
+        let mut writer = SsTableWriter::create(self)?;
+        for (key, entry) in entries {
+            writer.add(key, entry).await?;
+        }
+        writer.finish().await
+    }
+
+    // merge the given SSTables (newest first) into new SSTables, of about the
+    // size of the memtable each
+    async fn merge(
+        &self,
+        tables: &[Arc<SsTable>],
+        drop_tombstones: bool,
+    ) -> Result<Vec<Arc<SsTable>>, LsmError> {
+        let runs = tables
+            .iter()
+            .map(|table| Run::SsTable(Cursor::new(table.clone(), None)))
+            .collect();
+        let mut merge = Merge::new(runs, self.pk_position).await?;
+        let mut merged = vec![];
+        let mut writer: Option<SsTableWriter> = None;
+        while let Some((key, entry)) = merge.next().await? {
+            if entry.is_none() && drop_tombstones {
+                continue;
+            }
+            let current = match &mut writer {
+                Some(writer) => writer,
+                None => writer.insert(SsTableWriter::create(self)?),
+            };
+            current.add(key, entry).await?;
+            if current.length >= self.memtable_bytes {
+                if let Some(full) = writer.take() {
+                    merged.push(Arc::new(full.finish().await?));
+                }
+            }
+        }
+        if let Some(writer) = writer {
+            merged.push(Arc::new(writer.finish().await?));
+        }
+        Ok(merged)
+    }
+}
+
+// writes the entries of a new SSTable, and then its footer
+struct SsTableWriter {
+    id: u64,
+    block: Arc<dyn BlockStorage>,
+    compression: Option<Compression>,
+    length: u64,
+    entries: u64,
+    keys: Vec<ColumnValue>,
+    saved_bytes: u64,
+    sparse_index: Vec<(ColumnValue, u64)>,
+    /// The chunk being filled, for a columnar SSTable
+    chunk: Option<ChunkBuilder>,
+    chunks: Vec<ChunkStats>,
+}
+
+impl SsTableWriter {
+    fn create(files: &TreeFiles) -> Result<Self, LsmError> {
+        let id = files.next_id.fetch_add(1, Ordering::SeqCst);
+        let path = get_sstable_path(&files.base_path, id);
+        files
+            .backend
+            .create_block(&path, files.schema_fingerprint)?;
+        // the SSTable is synced once, when it is finished
+        let block =
+            files
+                .backend
+                .open_block(&path, Durability::NoSync, files.schema_fingerprint)?;
+        Ok(Self {
+            id,
+            block,
+            compression: files.compression,
+            length: files.backend.file_length(&path)?,
+            entries: 0,
+            keys: vec![],
+            saved_bytes: 0,
+            sparse_index: vec![],
+            chunk: (files.layout == Layout::Columnar)
+                .then(|| ChunkBuilder::new(files.pk_position, files.column_count)),
+            chunks: vec![],
+        })
+    }
+
+    async fn add(&mut self, key: ColumnValue, entry: Option<Tuple>) -> Result<(), LsmError> {
+        if self.keys.last().is_some_and(|last| *last >= key) {
+            return Err(LsmError::UnsortedSsTable {
+                path: self.block.path().to_path_buf(),
+            });
+        }
+        if let Some(chunk) = &mut self.chunk {
+            chunk.push(key.clone(), entry);
+            self.entries += 1;
+            self.keys.push(key);
+            if chunk.is_full() {
+                self.write_chunk().await?;
+            }
+            return Ok(());
+        }
+        if self.entries.is_multiple_of(SPARSE_INDEX_INTERVAL) {
+            self.sparse_index.push((key.clone(), self.length));
+        }
+        let size = match entry {
+            Some(tuple) => self.block.write(tuple, self.compression).await?,
+            None => self.block.write_tombstone(&key).await?,
+        };
+        self.length = calculate_new_offset(size.stored, self.length);
+        self.saved_bytes += size.uncompressed - size.stored;
+        self.entries += 1;
+        self.keys.push(key);
+        Ok(())
+    }
+
+    // write the chunk being filled, and start a new one
+    async fn write_chunk(&mut self) -> Result<(), LsmError> {
+        let Some(chunk) = &mut self.chunk else {
+            return Ok(());
+        };
+        let full = std::mem::replace(chunk, chunk.empty());
+        let written = full
+            .write(self.block.as_ref(), self.compression, self.length)
+            .await?;
+        if let Some((stats, size)) = written {
+            self.length = calculate_new_offset(size.stored, self.length);
+            self.saved_bytes += size.saved;
+            self.chunks.push(stats);
+        }
+        Ok(())
+    }
+
+    async fn finish(mut self) -> Result<SsTable, LsmError> {
+        self.write_chunk().await?;
+        let (Some(min_key), Some(max_key)) = (self.keys.first(), self.keys.last()) else {
+            return Err(LsmError::EmptySsTable {
+                path: self.block.path().to_path_buf(),
+            });
+        };
+        let footer = SsTableFooter {
+            entries: self.entries,
+            min_key: min_key.clone(),
+            max_key: max_key.clone(),
+            saved_bytes: self.saved_bytes,
+            sparse_index: self.sparse_index,
+            bloom_filter: BloomFilter::new(&self.keys),
+            chunks: self.chunks,
+        };
+        let footer_offset = self.length;
+        let footer_length = self.block.write_record(&footer).await?;
+        self.block.sync().await?;
+        Ok(SsTable {
+            id: self.id,
+            length: calculate_new_offset(footer_length, footer_offset),
+            footer_offset,
+            footer,
+            block: self.block,
+        })
+    }
+}
+
+impl SsTable {
+    /// Is the key in the range of keys of this SSTable
+    fn covers(&self, key: &ColumnValue) -> bool {
+        self.footer.min_key <= *key && *key <= self.footer.max_key
+    }
+
+    // the entry with the given key, if the SSTable has one
+    async fn get(
+        &self,
+        key: &ColumnValue,
+        pk_position: usize,
+    ) -> Result<Option<Option<Tuple>>, LsmError> {
+        if !self.covers(key) || !self.footer.bloom_filter.may_contain(key) {
+            return Ok(None);
+        }
+        if self.is_columnar() {
+            return self.get_from_chunk(key, pk_position).await;
+        }
+        // the key is between the entry the sparse index points at, and the
+        // next one in the sparse index
+        let sparse_index = &self.footer.sparse_index;
+        let next = sparse_index.partition_point(|(indexed, _)| indexed <= key);
+        let Some((_, mut offset)) = next.checked_sub(1).map(|i| &sparse_index[i]) else {
+            return Ok(None);
+        };
+        let end = sparse_index
+            .get(next)
+            .map_or(self.footer_offset, |(_, offset)| *offset);
+        while offset < end {
+            let ((entry_key, entry), next_offset) = self.read_entry(offset, pk_position).await?;
+            if entry_key == *key {
+                return Ok(Some(entry));
+            }
+            if entry_key > *key {
+                break;
+            }
+            offset = next_offset;
+        }
+        Ok(None)
+    }
+
+    fn is_columnar(&self) -> bool {
+        !self.footer.chunks.is_empty()
+    }
+
+    // the entry with the given key, in the chunk of a columnar SSTable with the
+    // key in its range
+    async fn get_from_chunk(
+        &self,
+        key: &ColumnValue,
+        pk_position: usize,
+    ) -> Result<Option<Option<Tuple>>, LsmError> {
+        let chunks = &self.footer.chunks;
+        let Some(chunk) = chunks
+            .get(chunks.partition_point(|chunk| chunk.max_key < *key))
+            .filter(|chunk| chunk.min_key <= *key)
+        else {
+            return Ok(None);
+        };
+        let keys = chunk.read_keys(self.block.as_ref()).await?;
+        let Ok(row) = keys.binary_search(key) else {
+            return Ok(None);
+        };
+        if chunk.tombstones.contains(&(row as u32)) {
+            return Ok(Some(None));
+        }
+        let mut entries = chunk
+            .read(self.block.as_ref(), pk_position, |_| true)
+            .await?;
+        Ok(Some(entries.swap_remove(row).1))
+    }
+
+    // the entry at the given byte-offset, and the byte-offset of the next one
+    async fn read_entry(&self, offset: u64, pk_position: usize) -> Result<(Entry, u64), LsmError> {
+        let (data, header) = self.block.read_entry(offset).await?;
+        let next_offset = calculate_new_offset(header.entry_size(), offset);
+        let entry = match decode_entry(self.block.path(), data, &header)? {
+            BlockEntry::Tombstone(key) => (key, None),
+            BlockEntry::Tuple(tuple) => {
+                let key = tuple
+                    .get(pk_position)
+                    .cloned()
+                    .flatten()
+                    .ok_or(LsmError::PrimaryKeyNotInTuple)?;
+                (key, Some(tuple))
+            }
+        };
+        Ok((entry, next_offset))
+    }
+}
+
+impl BloomFilter {
+    fn new(keys: &[ColumnValue]) -> Self {
+        let words = (keys.len() as u64 * BLOOM_BITS_PER_KEY).div_ceil(64).max(1);
+        let mut filter = Self {
+            bits: vec![0; words as usize],
+        };
+        for key in keys {
+            for bit in filter.bit_positions(key) {
+                filter.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+            }
+        }
+        filter
+    }
+
+    fn may_contain(&self, key: &ColumnValue) -> bool {
+        self.bit_positions(key)
+            .all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+
+    // double hashing, with two hashes of the serialized key; the hashes must
+    // not change between versions, as bloom filters are stored
+    fn bit_positions(&self, key: &ColumnValue) -> impl Iterator<Item = u64> {
+        let data = rmp_serde::to_vec(key).unwrap_or_default();
+        let h1 = crc32fast::hash(&data) as u64;
+        let mut hasher = crc32fast::Hasher::new_with_initial(0x9e37_79b9);
+        hasher.update(&data);
+        let h2 = hasher.finalize() as u64 | 1;
+        let bits = self.bits.len() as u64 * 64;
+        (0..BLOOM_HASHES).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % bits)
+    }
+}
+
+// a sorted run of entries: the memtable, or an SSTable
+enum Run<'a> {
+    Memtable(btree_map::Iter<'a, ColumnValue, Option<Tuple>>),
+    SsTable(Cursor),
+}
+
+// reads the entries of an SSTable one by one (or a chunk at a time, for a
+// columnar SSTable), from the file the SSTable was opened on; so it can still
+// be read after it is compacted away
+struct Cursor {
+    table: Arc<SsTable>,
+    offset: u64,
+    /// What a scan needs of a columnar SSTable
+    filter: Option<Arc<ScanFilter>>,
+    /// The next chunk of a columnar SSTable, and the entries of the chunk
+    /// before it which are not read yet
+    chunk: usize,
+    buffered: VecDeque<Entry>,
+}
+
+// merges runs (newest first) into one run, with the newest entry of every key
+struct Merge<'a> {
+    runs: Vec<(Run<'a>, Option<Entry>)>,
+    pk_position: usize,
+}
+
+impl Cursor {
+    fn new(table: Arc<SsTable>, filter: Option<Arc<ScanFilter>>) -> Self {
+        Self {
+            table,
+            offset: FILE_HEADER_SIZE,
+            filter,
+            chunk: 0,
+            buffered: VecDeque::new(),
+        }
+    }
+
+    async fn next(&mut self, pk_position: usize) -> Result<Option<Entry>, LsmError> {
+        if !self.table.is_columnar() {
+            if self.offset >= self.table.footer_offset {
+                return Ok(None);
+            }
+            let (entry, next_offset) = self.table.read_entry(self.offset, pk_position).await?;
+            self.offset = next_offset;
+            return Ok(Some(entry));
+        }
+        while self.buffered.is_empty() {
+            let Some(chunk) = self.table.footer.chunks.get(self.chunk) else {
+                return Ok(None);
+            };
+            self.chunk += 1;
+            let block = self.table.block.as_ref();
+            let entries = match &self.filter {
+                None => chunk.read(block, pk_position, |_| true).await?,
+                Some(filter) if filter.may_match(chunk) => {
+                    chunk
+                        .read(block, pk_position, |position| filter.needs(position))
+                        .await?
+                }
+                // none of the tuples of the chunk are needed; they still hide
+                // the older versions of their tuple, as tombstones do
+                Some(_) => chunk
+                    .read_keys(block)
+                    .await?
+                    .into_iter()
+                    .map(|key| (key, None))
+                    .collect(),
+            };
+            self.buffered = entries.into();
+        }
+        Ok(self.buffered.pop_front())
+    }
+}
+
+impl Run<'_> {
+    async fn next(&mut self, pk_position: usize) -> Result<Option<Entry>, LsmError> {
+        match self {
+            Run::Memtable(entries) => Ok(entries
+                .next()
+                .map(|(key, entry)| (key.clone(), entry.clone()))),
+            Run::SsTable(cursor) => cursor.next(pk_position).await,
+        }
+    }
+}
+
+impl<'a> Merge<'a> {
+    async fn new(runs: Vec<Run<'a>>, pk_position: usize) -> Result<Self, LsmError> {
+        let mut merge = Self {
+            runs: vec![],
+            pk_position,
+        };
+        for mut run in runs {
+            let head = run.next(pk_position).await?;
+            merge.runs.push((run, head));
+        }
+        Ok(merge)
+    }
+
+    async fn next(&mut self) -> Result<Option<Entry>, LsmError> {
+        let Some(min_key) = self
+            .runs
+            .iter()
+            .filter_map(|(_, head)| head.as_ref().map(|(key, _)| key))
+            .min()
+            .cloned()
+        else {
+            return Ok(None);
+        };
+        // the first run with the key has the newest entry; the other runs'
+        // entries of the key are older versions
+        let mut newest = None;
+        for (run, head) in &mut self.runs {
+            if head.as_ref().is_some_and(|(key, _)| *key == min_key) {
+                let entry = std::mem::replace(head, run.next(self.pk_position).await?);
+                newest = newest.or(entry);
+            }
+        }
+        Ok(newest)
+    }
+}
+
+/// Create an empty tree for the table at the given path; an existing tree is
+/// replaced.
+pub(crate) fn create_tree(backend: &Backend, table_path: &Path) -> Result<(), LsmError> {
+    remove_tree_files(backend, table_path)?;
+    write_manifest(backend, table_path, &LsmManifest::default())
+}
+
+/// Remove the manifest and the SSTables of the tree of the table at the given
+/// path, if there are any.
+pub(crate) fn remove_tree_files(
+    backend: &Backend,
+    table_path: &Path,
+) -> Result<(), std::io::Error> {
+    for (_, path) in find_sstable_files(backend, table_path)? {
+        backend.remove_file(&path)?;
+    }
+    match backend.remove_file(&get_manifest_path(table_path)) {
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// Read the manifest of the tree of the table at the given path.
+pub(crate) fn read_manifest(backend: &Backend, table_path: &Path) -> Result<LsmManifest, LsmError> {
+    let path = get_manifest_path(table_path);
+    let contents = backend.unseal_file(&path, backend.read_file(&path)?)?;
+    serde_json::from_slice(&contents).map_err(|error| LsmError::CorruptManifest { path, error })
+}
+
+fn write_manifest(
+    backend: &Backend,
+    table_path: &Path,
+    manifest: &LsmManifest,
+) -> Result<(), LsmError> {
+    let path = get_manifest_path(table_path);
+    let contents = serde_json::to_vec(manifest).map_err(|error| LsmError::CorruptManifest {
+        path: path.clone(),
+        error,
+    })?;
+    backend.replace_file(&path, &backend.seal_file(&path, contents))?;
+    // the rename is only durable once the directory is synced
+    backend.sync_parent_dir(&path)?;
+    Ok(())
+}
+
+pub(crate) fn get_manifest_path(table_path: &Path) -> PathBuf {
+    let mut file_name = table_path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".");
+    file_name.push(MANIFEST_FILE_SUFFIX);
+    table_path.with_file_name(file_name)
+}
+
+fn get_sstable_path(table_path: &Path, id: u64) -> PathBuf {
+    let manifest_path = get_manifest_path(table_path);
+    let mut file_name = manifest_path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(format!(".{}", id));
+    manifest_path.with_file_name(file_name)
+}
+
+/// The SSTable files of the tree of the table at the given path, that exist
+pub(crate) fn find_sstable_files(
+    backend: &Backend,
+    table_path: &Path,
+) -> Result<Vec<(u64, PathBuf)>, std::io::Error> {
+    let manifest_path = get_manifest_path(table_path);
+    let (Some(directory), Some(manifest_name)) =
+        (manifest_path.parent(), manifest_path.file_name())
+    else {
+        return Ok(vec![]);
+    };
+    let prefix = format!("{}.", manifest_name.to_string_lossy());
+    let mut tables = vec![];
+    for file_name in backend.list_directory(directory)? {
+        let id = file_name
+            .to_str()
+            .and_then(|name| name.strip_prefix(&prefix))
+            .and_then(|suffix| {
+                let id = suffix.parse::<u64>().ok()?;
+                (id.to_string() == suffix).then_some(id)
+            });
+        if let Some(id) = id {
+            tables.push((id, get_sstable_path(table_path, id)));
+        }
+    }
+    Ok(tables)
+}
+
+fn remove_sstables(backend: &Backend, tables: &[Arc<SsTable>]) -> Result<(), LsmError> {
+    for table in tables {
+        backend.remove_file(table.block.path())?;
+    }
+    Ok(())
+}
+
+fn contains_table(tables: &[Arc<SsTable>], table: &Arc<SsTable>) -> bool {
+    tables.iter().any(|t| Arc::ptr_eq(t, table))
+}
+
+// approximate size of an entry in the memtable, in bytes
+fn approximate_size(key: &ColumnValue, entry: &Option<Tuple>) -> u64 {
+    let data = match entry {
+        Some(tuple) => rmp_serde::to_vec(tuple),
+        None => rmp_serde::to_vec(key),
+    };
+    data.map_or(0, |data| data.len() as u64)
+}
@@ -0,0 +1,127 @@
+/// Migration of the block files of a database (the tables' segments and the
+/// write-ahead log) to the current format, i.e. `FORMAT_VERSION`. Block files
+/// from before block files had a file header (format version 0) are rewritten
+/// with one. Their entries are copied over as they are, except that entries
+/// without a checksum get one.
+///
+/// A block file is migrated by writing the new version of it to a temporary
+/// file, which is then renamed over the old one. So a crash in the middle of a
+/// migration leaves every block file either migrated or as it was, and the
+/// migration can simply be run again.
+use std::path::{Path, PathBuf};
+
+use futures::StreamExt;
+
+use crate::{
+    backend::Backend,
+    catalog::{read_table_definitions, CatalogError},
+    query::types::Durability,
+    segment::find_segment_files,
+    storage::StorageError,
+    table::{get_table_path_, remove_hint_file},
+    wal::{get_wal_path, WAL_SCHEMA_FINGERPRINT},
+};
+
+/// Suffix of the temporary file a block file is migrated to.
+const MIGRATION_FILE_SUFFIX: &str = "migrate";
+
+#[derive(thiserror::Error, Debug)]
+pub enum MigrationError {
+    #[error("Could not read the catalog. {0}")]
+    CatalogError(#[from] CatalogError),
+    #[error("Internal Storage Engine Error: {0}")]
+    StorageError(#[from] StorageError),
+    #[error("Migration file operation failed. {0}")]
+    FileOperation(#[from] std::io::Error),
+}
+
+/// Migrate the block files of the database in the given directory to the
+/// current format. Returns the paths of the files which were migrated; files
+/// already in the current format are left alone.
+pub(crate) async fn migrate_database(
+    backend: &Backend,
+    directory_path: &Path,
+) -> Result<Vec<PathBuf>, MigrationError> {
+    let mut migrated = vec![];
+    for table in read_table_definitions(backend, directory_path)? {
+        let table_path = get_table_path_(directory_path, &table.name);
+        let schema_fingerprint = table.schema_fingerprint();
+        let mut outdated = vec![];
+        for (_, path) in find_segment_files(backend, &table_path)? {
+            if is_outdated(backend, &path, schema_fingerprint)? {
+                outdated.push(path);
+            }
+        }
+        if outdated.is_empty() {
+            continue;
+        }
+        // the entries move, so the index in the hint file is no good anymore
+        remove_hint_file(backend, &table_path)?;
+        for path in outdated {
+            migrate_block(backend, &path, schema_fingerprint).await?;
+            migrated.push(path);
+        }
+    }
+
+    let wal_path = get_wal_path(directory_path);
+    if backend.file_exists(&wal_path) && is_outdated(backend, &wal_path, WAL_SCHEMA_FINGERPRINT)? {
+        migrate_block(backend, &wal_path, WAL_SCHEMA_FINGERPRINT).await?;
+        migrated.push(wal_path);
+    }
+    Ok(migrated)
+}
+
+// is the block file in an older format; files in a format newer than the
+// current one are not migrated (they can't be read), and result in an error
+fn is_outdated(
+    backend: &Backend,
+    path: &Path,
+    schema_fingerprint: u32,
+) -> Result<bool, StorageError> {
+    match backend.read_file_header(path)? {
+        None => Ok(true),
+        Some(header) if header.schema_fingerprint == schema_fingerprint => Ok(false),
+        Some(_) => Err(StorageError::SchemaMismatch {
+            path: path.to_path_buf(),
+        }),
+    }
+}
+
+// rewrite a block file in format version 0 in the current format
+async fn migrate_block(
+    backend: &Backend,
+    path: &Path,
+    schema_fingerprint: u32,
+) -> Result<(), MigrationError> {
+    let migration_path = get_migration_path(path);
+    backend.create_block(&migration_path, schema_fingerprint)?;
+    let old_block = backend.open_block_unchecked(path, Durability::NoSync)?;
+    // the entries are copied as they are stored; blocks from before the file
+    // header were never encrypted
+    let new_block = backend.open_block_unchecked(&migration_path, Durability::NoSync)?;
+
+    // entries of a block without a header start right at the beginning
+    let mut stream = old_block.scan_entries(0).await?;
+    while let Some(result) = stream.next().await {
+        match result {
+            Ok((data, header)) => {
+                new_block.write_entry(data, header.flags()).await?;
+            }
+            // the last write didn't complete; it would be cut off when the
+            // block is opened anyway
+            Err(StorageError::TornTail { .. }) => break,
+            Err(err) => return Err(err.into()),
+        }
+    }
+    new_block.sync().await?;
+    backend.rename(&migration_path, path)?;
+    backend.sync_parent_dir(path)?;
+    Ok(())
+}
+
+fn get_migration_path(block_path: &Path) -> PathBuf {
+    let mut file_name = block_path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".");
+    file_name.push(MIGRATION_FILE_SUFFIX);
+    block_path.with_file_name(file_name)
+}
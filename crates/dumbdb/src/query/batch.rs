@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    catalog::Catalog,
+    storage::Tuple,
+    table::TableBufferError,
+    wal::{Operation, WalRecord},
+};
+
+use super::{
+    ddl::{CreateTableCommand, DropTableCommand},
+    dml::{DeleteItemCommand, PutItemCommand, UpdateItemCommand},
+    error::QueryError,
+    types::{ColumnValue, TableDefinition, TableName},
+};
+
+/// A list of writes, which are applied atomically: either all of them happen,
+/// or (if any of them is invalid, or the database crashes before the batch is
+/// logged) none of them. Later writes see the effects of earlier ones, e.g. a
+/// table can be created and filled in the same batch.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WriteBatchCommand {
+    pub writes: Vec<WriteCommand>,
+}
+
+/// A single write in a `WriteBatchCommand`
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WriteCommand {
+    CreateTable(CreateTableCommand),
+    DropTable(DropTableCommand),
+    PutItem(PutItemCommand),
+    UpdateItem(UpdateItemCommand),
+    DeleteItem(DeleteItemCommand),
+}
+
+/// The operations of a batch being planned. Writes are validated against the
+/// catalog, with the effects of the earlier writes of the batch on top of it,
+/// and turned into the operations that are logged to the WAL. Nothing is
+/// written to the tables until the whole batch is planned.
+pub(crate) struct BatchPlan<'a> {
+    catalog: &'a Catalog,
+    /// Tables created (`Some`) or dropped (`None`) in this batch
+    tables: HashMap<TableName, Option<TableDefinition>>,
+    /// Tuples written (`Some`) or deleted (`None`) in this batch
+    tuples: HashMap<TableName, HashMap<ColumnValue, Option<Tuple>>>,
+    operations: Vec<Operation>,
+}
+
+impl<'a> BatchPlan<'a> {
+    pub(crate) fn new(catalog: &'a Catalog) -> Self {
+        Self {
+            catalog,
+            tables: HashMap::new(),
+            tuples: HashMap::new(),
+            operations: vec![],
+        }
+    }
+
+    pub(crate) fn get_table(&self, name: &TableName) -> Option<TableDefinition> {
+        match self.tables.get(name) {
+            Some(table) => table.clone(),
+            None => self.catalog.get_table(name).map(Into::into),
+        }
+    }
+
+    /// Get the current version of the tuple with the given key
+    pub(crate) async fn get_tuple(
+        &self,
+        table_name: &TableName,
+        key: &ColumnValue,
+    ) -> Result<Option<Tuple>, QueryError> {
+        if let Some(tuple) = self.tuples.get(table_name).and_then(|t| t.get(key)) {
+            return Ok(tuple.clone());
+        }
+        // a table created in this batch has nothing on disk yet
+        if self.tables.contains_key(table_name) {
+            return Ok(None);
+        }
+        match self.catalog.get_table(table_name) {
+            None => Ok(None),
+            Some(table) => Ok(table.storage.get(key.clone(), false).await?),
+        }
+    }
+
+    pub(crate) async fn contains_key(
+        &self,
+        table_name: &TableName,
+        key: &ColumnValue,
+    ) -> Result<bool, QueryError> {
+        if let Some(tuple) = self.tuples.get(table_name).and_then(|t| t.get(key)) {
+            return Ok(tuple.is_some());
+        }
+        if self.tables.contains_key(table_name) {
+            return Ok(false);
+        }
+        match self.catalog.get_table(table_name) {
+            None => Ok(false),
+            Some(table) => Ok(table.storage.contains_key(key).await?),
+        }
+    }
+
+    /// Keys of the current tuples of the table which match the predicate
+    pub(crate) async fn find_keys(
+        &self,
+        table: &TableDefinition,
+        predicate: impl Fn(&Tuple) -> bool,
+    ) -> Result<Vec<ColumnValue>, QueryError> {
+        let pk_position = table
+            .primary_key_position()
+            .ok_or(TableBufferError::PrimaryKeyNotInDefn)?;
+        let written = self.tuples.get(&table.name);
+        // a table created in this batch has nothing on disk yet
+        let stored_table = if self.tables.contains_key(&table.name) {
+            None
+        } else {
+            self.catalog.get_table(&table.name)
+        };
+        let mut keys = vec![];
+        if let Some(stored_table) = stored_table {
+            let mut stream = stored_table.storage.scan().await?;
+            while let Some(tuple) = stream.next().await {
+                let mut tuple = tuple?;
+                let key = tuple[pk_position]
+                    .clone()
+                    .ok_or(TableBufferError::PrimaryKeyNotInTuple)?;
+                // tuples written in this batch are checked below
+                if written.is_some_and(|written| written.contains_key(&key)) {
+                    continue;
+                }
+                stored_table.storage.resolve(&mut tuple, |_| true).await?;
+                if predicate(&tuple) {
+                    keys.push(key);
+                }
+            }
+        }
+        for (key, tuple) in written.into_iter().flatten() {
+            let Some(tuple) = tuple else {
+                continue;
+            };
+            // an updated tuple can refer to values stored out of line
+            let mut tuple = tuple.clone();
+            if let Some(stored_table) = stored_table {
+                stored_table.storage.resolve(&mut tuple, |_| true).await?;
+            }
+            if predicate(&tuple) {
+                keys.push(key.clone());
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Add an operation to the batch
+    pub(crate) fn push(&mut self, operation: Operation) {
+        match &operation {
+            Operation::CreateTable(table) => {
+                self.tables.insert(table.name.clone(), Some(table.clone()));
+                self.tuples.remove(&table.name);
+            }
+            Operation::DropTable(table_name) => {
+                self.tables.insert(table_name.clone(), None);
+                self.tuples.remove(table_name);
+            }
+            Operation::Put { table, key, tuple } => {
+                self.tuples
+                    .entry(table.clone())
+                    .or_default()
+                    .insert(key.clone(), Some(tuple.clone()));
+            }
+            Operation::Delete { table, key } => {
+                self.tuples
+                    .entry(table.clone())
+                    .or_default()
+                    .insert(key.clone(), None);
+            }
+        }
+        self.operations.push(operation);
+    }
+
+    pub(crate) fn into_record(self) -> WalRecord {
+        WalRecord {
+            operations: self.operations,
+        }
+    }
+}
@@ -1,44 +1,83 @@
+use std::path::Path;
+
 use serde::{Deserialize, Serialize};
-use tokio::fs::{remove_file, File};
 
-use crate::{catalog::Catalog, TableName};
+use crate::{
+    backend::Backend,
+    blob::remove_blob_files,
+    catalog::Catalog,
+    lsm::{self, remove_tree_files},
+    segment::remove_segment_files,
+    table::{remove_hint_file, TableBufferError},
+    wal::Operation,
+    TableName,
+};
 
 use super::{
+    batch::BatchPlan,
+    dml::put_item::typecheck_column,
     error::{InternalError, QueryError},
-    types::TableDefinition,
+    types::{ColumnType, StorageEngine, TableDefinition},
 };
 
 /// A type alias for TableDefinition; this is to be consistent on the public API.
 pub type CreateTableCommand = TableDefinition;
 
-/// creates a table in the catalog and also on the disk
-pub async fn create_table(table: TableDefinition, catalog: &mut Catalog) -> Result<(), QueryError> {
-    if catalog.get_table(&table.name).is_some() {
+/// Plans the creation of a table in the catalog and also on the disk
+pub fn create_table(table: TableDefinition, plan: &mut BatchPlan) -> Result<(), QueryError> {
+    if plan.get_table(&table.name).is_some() {
         return Err(QueryError::TableAlreadyExists(table.name));
     }
-    create_table_on_disk(&table, catalog).await?;
-    catalog.add_table(table).await?;
+    if table.primary_key_position().is_none() {
+        return Err(QueryError::PrimaryKeyNotInColumns(table.primary_key));
+    }
+    // the default values must be values of their columns. They are converted
+    // to them on every write (see `put_item`), so that e.g. a default of `now`
+    // is the time of the write.
+    for column in &table.columns {
+        if column.generated.is_some()
+            && (column.name != table.primary_key || !matches!(column.r#type, ColumnType::Uuid))
+        {
+            return Err(QueryError::GeneratedColumnNotAllowed(column.name.clone()));
+        }
+        let Some(default) = &column.default else {
+            continue;
+        };
+        if column.name == table.primary_key {
+            return Err(QueryError::PrimaryKeyDefaultNotAllowed(column.name.clone()));
+        }
+        let mut default = default.clone();
+        default.coerce(&column.r#type);
+        typecheck_column(column, &default)?;
+    }
+    plan.push(Operation::CreateTable(table));
     Ok(())
 }
 
-async fn create_table_on_disk(
-    table: &TableDefinition,
-    catalog: &Catalog,
+/// Creates the table's file (or the manifest of an empty LSM tree), and adds
+/// the table to the catalog. Any leftover files of the table (e.g. if it was
+/// dropped right before a crash) are removed.
+pub(crate) async fn create_table_on_disk(
+    table: TableDefinition,
+    catalog: &mut Catalog,
 ) -> Result<(), QueryError> {
     let table_path = catalog.get_table_path(&table.name);
-    if table_path.exists() {
-        return Err(QueryError::InternalError(
-            InternalError::FilepathAlreadyExists(table_path),
-        ));
+    remove_table_files(catalog.backend(), &table_path)?;
+    match table.engine.unwrap_or_default() {
+        StorageEngine::Log => catalog
+            .backend()
+            .create_block(&table_path, table.schema_fingerprint())
+            .map_err(|e| {
+                QueryError::InternalError(InternalError::FailedToCreateFile {
+                    filepath: table_path,
+                    error: e,
+                })
+            })?,
+        StorageEngine::Lsm | StorageEngine::Columnar => {
+            lsm::create_tree(catalog.backend(), &table_path).map_err(TableBufferError::from)?
+        }
     }
-
-    File::create(&table_path).await.map_err(|e| {
-        QueryError::InternalError(InternalError::FailedToCreateFile {
-            filepath: table_path,
-            error: e,
-        })
-    })?;
-
+    catalog.add_table(table).await?;
     Ok(())
 }
 
@@ -47,28 +86,42 @@ pub struct DropTableCommand {
     pub table_name: TableName,
 }
 
-/// drops a table from the catalog and also on the disk
-pub async fn drop_table(
+/// Plans dropping a table from the catalog and also from the disk
+pub fn drop_table(
     DropTableCommand { table_name }: DropTableCommand,
-    catalog: &mut Catalog,
+    plan: &mut BatchPlan,
 ) -> Result<(), QueryError> {
-    if catalog.get_table(&table_name).is_none() {
+    if plan.get_table(&table_name).is_none() {
         return Err(QueryError::TableNotFound(table_name));
     }
-    drop_table_from_disk(&table_name, catalog).await?;
-    catalog.drop_table(table_name).await?;
+    plan.push(Operation::DropTable(table_name));
     Ok(())
 }
 
-async fn drop_table_from_disk(table_name: &TableName, catalog: &Catalog) -> Result<(), QueryError> {
-    let table_path = catalog.get_table_path(table_name);
-    if table_path.exists() {
-        remove_file(&table_path).await.map_err(|e| {
+/// Removes the table from the catalog, and then its files. Either of them may
+/// already be gone.
+pub(crate) async fn drop_table_from_disk(
+    table_name: TableName,
+    catalog: &mut Catalog,
+) -> Result<(), QueryError> {
+    let table_path = catalog.get_table_path(&table_name);
+    if catalog.get_table(&table_name).is_some() {
+        catalog.drop_table(table_name).await?;
+    }
+    remove_table_files(catalog.backend(), &table_path)
+}
+
+// removes the segment files of a table's block, and its hint file; or the
+// files of its LSM tree
+fn remove_table_files(backend: &Backend, table_path: &Path) -> Result<(), QueryError> {
+    remove_hint_file(backend, table_path)
+        .and_then(|()| remove_segment_files(backend, table_path))
+        .and_then(|()| remove_tree_files(backend, table_path))
+        .and_then(|()| remove_blob_files(backend, table_path))
+        .map_err(|e| {
             QueryError::InternalError(InternalError::FailedToDeleteFile {
-                filepath: table_path,
+                filepath: table_path.to_path_buf(),
                 error: e,
             })
-        })?;
-    }
-    Ok(())
+        })
 }
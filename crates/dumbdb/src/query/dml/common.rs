@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 
 use crate::{
+    query::dml::put_item::Item,
     query::types::{ColumnDefinition, ColumnName, ColumnValue},
     storage::Tuple,
 };
@@ -18,3 +19,24 @@ pub fn build_record(columns: &[ColumnDefinition], item: Tuple) -> Record {
     }
     record
 }
+
+/// Convert the values of an item to the types of their columns, where they
+/// can be (see `ColumnValue::coerce`).
+pub(crate) fn coerce_item(columns: &[ColumnDefinition], item: &mut Item) {
+    for (column_name, value) in item.iter_mut() {
+        if let Some(column) = columns.iter().find(|column| column.name == *column_name) {
+            value.coerce(&column.r#type);
+        }
+    }
+}
+
+/// Convert a primary key value to the type of the primary key column
+pub(crate) fn coerce_key(
+    columns: &[ColumnDefinition],
+    primary_key: &ColumnName,
+    key: &mut ColumnValue,
+) {
+    if let Some(column) = columns.iter().find(|column| column.name == *primary_key) {
+        key.coerce(&column.r#type);
+    }
+}
@@ -0,0 +1,71 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    query::{
+        batch::BatchPlan,
+        error::QueryError,
+        types::{ColumnValue, Expression, TableName},
+    },
+    wal::Operation,
+};
+
+use super::common::coerce_key;
+use super::filter_item::{evaluate_expression, validate_expression};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeleteItemCommand {
+    pub table_name: TableName,
+    pub key: ColumnValue,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeleteWhereCommand {
+    pub table_name: TableName,
+    pub filter: Expression,
+}
+
+/// Delete the record with the given primary key.
+pub async fn delete_item(
+    command: DeleteItemCommand,
+    plan: &mut BatchPlan<'_>,
+) -> Result<(), QueryError> {
+    let Some(table) = plan.get_table(&command.table_name) else {
+        return Err(QueryError::TableNotFound(command.table_name));
+    };
+    let mut key = command.key;
+    coerce_key(&table.columns, &table.primary_key, &mut key);
+    if !plan.contains_key(&command.table_name, &key).await? {
+        return Err(QueryError::RecordNotFound(key));
+    }
+    plan.push(Operation::Delete {
+        table: command.table_name,
+        key,
+    });
+    Ok(())
+}
+
+/// Delete all the records matching the filter expression. Returns the number of
+/// records deleted.
+pub async fn delete_where(
+    mut command: DeleteWhereCommand,
+    plan: &mut BatchPlan<'_>,
+) -> Result<usize, QueryError> {
+    let table = match plan.get_table(&command.table_name) {
+        None => return Err(QueryError::TableNotFound(command.table_name)),
+        Some(table) => table,
+    };
+    validate_expression(&table.columns, &mut command.filter)?;
+    let keys = plan
+        .find_keys(&table, |tuple| {
+            evaluate_expression(&table.columns, &command.filter, tuple)
+        })
+        .await?;
+    let deleted = keys.len();
+    for key in keys {
+        plan.push(Operation::Delete {
+            table: table.name.clone(),
+            key,
+        });
+    }
+    Ok(deleted)
+}
@@ -3,12 +3,14 @@ use serde::{Deserialize, Serialize};
 
 use crate::{
     catalog::Catalog,
+    columnar::ScanFilter,
     query::{
         error::QueryError,
-        types::{ColumnDefinition, ColumnValue, Expression, Operator, TableName},
+        types::{
+            ColumnDefinition, ColumnName, ColumnType, ColumnValue, Expression, Operator, TableName,
+        },
     },
     storage::Tuple,
-    table::TableBufferError,
 };
 
 use super::{common::build_record, Record};
@@ -17,26 +19,63 @@ use super::{common::build_record, Record};
 pub struct FilterItemCommand {
     pub table_name: TableName,
     pub filter: Expression,
+    /// Columns to return of the matching records; all of them if not given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub columns: Option<Vec<ColumnName>>,
 }
 
 pub async fn filter_item(
-    command: FilterItemCommand,
+    mut command: FilterItemCommand,
     catalog: &Catalog,
 ) -> Result<Vec<Record>, QueryError> {
     match catalog.get_table(&command.table_name) {
         None => Err(QueryError::TableNotFound(command.table_name)),
         Some(table) => {
+            validate_expression(&table.columns, &mut command.filter)?;
+            let projection = match &command.columns {
+                None => (0..table.columns.len()).collect(),
+                Some(names) => names
+                    .iter()
+                    .map(|name| {
+                        table
+                            .columns
+                            .iter()
+                            .position(|column| column.name == *name)
+                            .ok_or_else(|| QueryError::UnknownColumn(name.clone()))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?,
+            };
+            let mut needed_columns = projection.clone();
+            let mut filter_columns = vec![];
+            expression_columns(&command.filter, &mut filter_columns);
+            needed_columns.extend(
+                table
+                    .columns
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, column)| filter_columns.contains(&&column.name))
+                    .map(|(position, _)| position),
+            );
+            let is_filtered =
+                |position: usize| filter_columns.contains(&&table.columns[position].name);
+            let filter = ScanFilter {
+                columns: table.columns.clone(),
+                expression: Some(command.filter.clone()),
+                needed_columns,
+            };
+
             let mut res = vec![];
-            let mut stream = table
-                .table_buffer
-                .block
-                .get_reader()
-                .await
-                .map_err(TableBufferError::StorageError)?;
+            let mut stream = table.storage.scan_filtered(filter).await?;
             while let Some(tuple) = stream.next().await {
-                let tuple = tuple.map_err(TableBufferError::StorageError)?;
+                let mut tuple = tuple?;
+                // values stored out of line are only read if they are needed
+                table.storage.resolve(&mut tuple, is_filtered).await?;
                 if evaluate_expression(&table.columns, &command.filter, &tuple) {
-                    res.push(build_record(&table.columns, tuple));
+                    table
+                        .storage
+                        .resolve(&mut tuple, |position| projection.contains(&position))
+                        .await?;
+                    res.push(project_record(&table.columns, &projection, tuple));
                 }
             }
             Ok(res)
@@ -44,40 +83,145 @@ pub async fn filter_item(
     }
 }
 
-/// Evaluate an `Expression` to be true or false, given a `Tuple`.
-fn evaluate_expression(
+// the columns of the tuple at the given positions, as a record
+fn project_record(columns: &[ColumnDefinition], projection: &[usize], tuple: Tuple) -> Record {
+    if projection.len() == columns.len() {
+        return build_record(columns, tuple);
+    }
+    projection
+        .iter()
+        .map(|position| (columns[*position].name.clone(), tuple[*position].clone()))
+        .collect()
+}
+
+// the columns the expression compares
+fn expression_columns<'a>(expression: &'a Expression, columns: &mut Vec<&'a ColumnName>) {
+    match expression {
+        Expression::ColumnComparison(comparison) => columns.push(&comparison.column),
+        Expression::And(expressions) | Expression::Or(expressions) => expressions
+            .iter()
+            .for_each(|expression| expression_columns(expression, columns)),
+        Expression::Not(expression) => expression_columns(expression, columns),
+        Expression::IsNull(column) | Expression::IsNotNull(column) => columns.push(column),
+    }
+}
+
+/// Check that the columns of an `Expression` are columns of the table, and
+/// that the values they are compared with are of their types (or, for numbers,
+/// of the other numeric type). The values are converted to the types of their
+/// columns first, where they can be (see `ColumnValue::coerce`).
+pub(crate) fn validate_expression(
+    columns: &[ColumnDefinition],
+    expression: &mut Expression,
+) -> Result<(), QueryError> {
+    let column_type = |name: &ColumnName| {
+        columns
+            .iter()
+            .find(|column| column.name == *name)
+            .map(|column| &column.r#type)
+            .ok_or_else(|| QueryError::UnknownColumn(name.clone()))
+    };
+    match expression {
+        Expression::ColumnComparison(comparison) => {
+            let column_type = column_type(&comparison.column)?;
+            comparison.value.coerce(column_type);
+            let value_type = match &comparison.value {
+                ColumnValue::Blob(_) => {
+                    return Err(QueryError::InvalidValue(comparison.value.clone()))
+                }
+                value => value.to_type(),
+            };
+            match (column_type, &value_type) {
+                (
+                    ColumnType::Integer | ColumnType::Float,
+                    ColumnType::Integer | ColumnType::Float,
+                )
+                | (ColumnType::Text, ColumnType::Text)
+                | (ColumnType::Boolean, ColumnType::Boolean)
+                | (ColumnType::Timestamp, ColumnType::Timestamp)
+                | (ColumnType::Date, ColumnType::Date)
+                | (ColumnType::Uuid, ColumnType::Uuid) => Ok(()),
+                _ => Err(QueryError::IncomparableTypes {
+                    column: comparison.column.clone(),
+                    column_type: column_type.clone(),
+                    value_type,
+                }),
+            }
+        }
+        Expression::IsNull(column) | Expression::IsNotNull(column) => {
+            column_type(column).map(|_| ())
+        }
+        Expression::And(expressions) | Expression::Or(expressions) => expressions
+            .iter_mut()
+            .try_for_each(|expression| validate_expression(columns, expression)),
+        Expression::Not(expression) => validate_expression(columns, expression),
+    }
+}
+
+/// Evaluate an `Expression` to be true or false, given a `Tuple`. A tuple only
+/// matches the expression if it is true; see `evaluate` for how null values
+/// are treated.
+pub(crate) fn evaluate_expression(
     columns: &[ColumnDefinition],
     expression: &Expression,
     tuple: &Tuple,
 ) -> bool {
+    evaluate(columns, expression, tuple) == Some(true)
+}
+
+// three-valued logic, as in SQL: a comparison with a null value is unknown
+// (`None`), and so is `$not` of it. `$and` is false if any of its expressions
+// is false, and `$or` is true if any of them is true; otherwise either of them
+// is unknown if any of its expressions is.
+fn evaluate(columns: &[ColumnDefinition], expression: &Expression, tuple: &Tuple) -> Option<bool> {
+    // a column which is not one of the table's (which `validate_expression`
+    // rules out) is null
+    let value = |column: &ColumnName| {
+        columns
+            .iter()
+            .position(|col_def| col_def.name == *column)
+            .and_then(|col_pos| tuple.get(col_pos)?.as_ref())
+    };
     match expression {
-        Expression::ColumnComparison(comparison) => {
-            let col_pos = columns
-                .iter()
-                .position(|col_def| col_def.name == comparison.column)
-                .unwrap();
-            // .with_context(|| "Internal Error: Column must exist.")?;
-            let column_value = tuple[col_pos].clone().unwrap();
-            // .with_context(|| "invariant violation: column value not found in tuple.")?;
-            evaluate_binary_operator(&comparison.operator, &column_value, &comparison.value)
+        Expression::ColumnComparison(comparison) => value(&comparison.column).map(|column_value| {
+            evaluate_binary_operator(&comparison.operator, column_value, &comparison.value)
+        }),
+        Expression::IsNull(column) => Some(value(column).is_none()),
+        Expression::IsNotNull(column) => Some(value(column).is_some()),
+        Expression::And(expressions) => {
+            let mut result = Some(true);
+            for exp in expressions {
+                match evaluate(columns, exp, tuple) {
+                    Some(false) => return Some(false),
+                    Some(true) => (),
+                    None => result = None,
+                }
+            }
+            result
         }
-        Expression::And(expressions) => expressions
-            .iter()
-            .all(|exp| evaluate_expression(columns, exp, tuple)),
-        Expression::Or(expressions) => expressions
-            .iter()
-            .any(|exp| evaluate_expression(columns, exp, tuple)),
-        Expression::Not(expression) => !evaluate_expression(columns, expression, tuple),
+        Expression::Or(expressions) => {
+            let mut result = Some(false);
+            for exp in expressions {
+                match evaluate(columns, exp, tuple) {
+                    Some(true) => return Some(true),
+                    Some(false) => (),
+                    None => result = None,
+                }
+            }
+            result
+        }
+        Expression::Not(expression) => evaluate(columns, expression, tuple).map(|result| !result),
     }
 }
 
 fn evaluate_binary_operator(operator: &Operator, val_a: &ColumnValue, val_b: &ColumnValue) -> bool {
+    let ordering = val_a.compare(val_b);
     match operator {
-        Operator::Eq => val_a == val_b,
-        Operator::Neq => val_a != val_b,
-        Operator::Gt => val_a > val_b,
-        Operator::Lt => val_a < val_b,
-        Operator::Gte => val_a >= val_b,
-        Operator::Lte => val_a <= val_b,
+        Operator::Eq => ordering.is_eq(),
+        Operator::Neq => ordering.is_ne(),
+        Operator::Gt => ordering.is_gt(),
+        Operator::Lt => ordering.is_lt(),
+        Operator::Gte => ordering.is_ge(),
+        Operator::Lte => ordering.is_le(),
     }
 }
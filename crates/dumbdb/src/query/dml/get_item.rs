@@ -8,7 +8,7 @@ use crate::{
     },
 };
 
-use super::common::{build_record, Record};
+use super::common::{build_record, coerce_key, Record};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct GetItemCommand {
@@ -24,13 +24,13 @@ pub async fn get_item(
     match catalog.get_table(&command.table_name) {
         None => Err(QueryError::TableNotFound(command.table_name)),
         Some(table) => {
-            let record = table
-                .table_buffer
-                .get(command.key, scan_file)
-                .await?
-                .map(|item| build_record(&table.columns, item));
-
-            Ok(record)
+            let mut key = command.key;
+            coerce_key(&table.columns, &table.primary_key, &mut key);
+            let Some(mut item) = table.storage.get(key, scan_file).await? else {
+                return Ok(None);
+            };
+            table.storage.resolve(&mut item, |_| true).await?;
+            Ok(Some(build_record(&table.columns, item)))
         }
     }
 }
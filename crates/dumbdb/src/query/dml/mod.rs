@@ -1,9 +1,13 @@
 pub mod common;
+pub mod delete_item;
 pub mod filter_item;
 pub mod get_item;
 pub mod put_item;
+pub mod update_item;
 
 pub use common::Record;
+pub use delete_item::{delete_item, delete_where, DeleteItemCommand, DeleteWhereCommand};
 pub use filter_item::{filter_item, FilterItemCommand};
 pub use get_item::{get_item, GetItemCommand};
 pub use put_item::{put_item, PutItemCommand};
+pub use update_item::{update_item, UpdateItemCommand};
@@ -2,10 +2,12 @@ use std::collections::HashMap;
 
 use serde::{Deserialize, Serialize};
 
-use crate::catalog::Catalog;
+use crate::query::batch::BatchPlan;
+use crate::query::dml::common::coerce_item;
 use crate::query::error::QueryError;
 use crate::query::types::{ColumnDefinition, ColumnName, ColumnType, ColumnValue, TableName};
 use crate::storage::Tuple;
+use crate::wal::Operation;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct PutItemCommand {
@@ -15,49 +17,89 @@ pub struct PutItemCommand {
 
 pub type Item = HashMap<ColumnName, ColumnValue>;
 
-pub async fn put_item(command: PutItemCommand, catalog: &mut Catalog) -> Result<(), QueryError> {
+/// Returns the primary key of the new record, which is generated if the item
+/// leaves it out and the primary key column is generated.
+pub async fn put_item(
+    command: PutItemCommand,
+    plan: &mut BatchPlan<'_>,
+) -> Result<ColumnValue, QueryError> {
     // check if table name is valid
-    match catalog.get_table_mut(&command.table_name) {
+    let table = match plan.get_table(&command.table_name) {
         None => return Err(QueryError::TableNotFound(command.table_name)),
-        Some(table) => {
-            // check if primary key is present in payload
-            let key = match command.item.get(&table.primary_key) {
-                None => {
-                    return Err(QueryError::ItemMustContainPrimaryKey(
-                        table.primary_key.clone(),
-                    ))
-                }
-                // we need a copy of the key to store in the index, along with
-                // the tuple being stored on disk. hence, the clone.
-                Some(primary_key_value) => primary_key_value.clone(),
-            };
-            // check to see if this primary key already exists
-            if table.table_buffer.contains_key(&key) {
-                return Err(QueryError::PrimaryKeyAlreadyExists(key));
+        Some(table) => table,
+    };
+    let mut item = command.item;
+    coerce_item(&table.columns, &mut item);
+    if let Some(generation) = table
+        .get_column(&table.primary_key)
+        .and_then(|column| column.generated)
+    {
+        item.entry(table.primary_key.clone())
+            .or_insert_with(|| ColumnValue::Uuid(generation.generate()));
+    }
+    // check if primary key is present in payload
+    let key = match item.get(&table.primary_key) {
+        None => return Err(QueryError::ItemMustContainPrimaryKey(table.primary_key)),
+        // we need a copy of the key to store in the index, along with the tuple
+        // being stored on disk. hence, the clone.
+        Some(primary_key_value) => primary_key_value.clone(),
+    };
+    // check to see if this primary key already exists
+    if plan.contains_key(&table.name, &key).await? {
+        return Err(QueryError::PrimaryKeyAlreadyExists(key));
+    }
+    // check if item data is valid
+    for (column_name, value) in &item {
+        match table.get_column(column_name) {
+            None => return Err(QueryError::UnknownColumnInItem(column_name.clone())),
+            Some(column) => typecheck_column(column, value)?,
+        }
+    }
+    // fill in the defaults of the columns which are left out; the rest of them
+    // are null, if they can be
+    for column in &table.columns {
+        if item.contains_key(&column.name) {
+            continue;
+        }
+        match &column.default {
+            Some(default) => {
+                let mut default = default.clone();
+                default.coerce(&column.r#type);
+                item.insert(column.name.clone(), default);
             }
-            // check if item data is valid
-            for (column_name, value) in &command.item {
-                match table.get_column(column_name) {
-                    None => return Err(QueryError::UnknownColumnInItem(column_name.clone())),
-                    Some(column) => typecheck_column(column, value)?,
-                }
+            None if !column.nullable => {
+                return Err(QueryError::NullNotAllowed(column.name.clone()))
             }
-            // finally write the data
-            let tuple = item_to_tuple(command.item, &table.columns);
-            table.table_buffer.write(key, tuple).await?;
+            None => (),
         }
     }
-    Ok(())
+    // finally write the data
+    let tuple = item_to_tuple(item, &table.columns);
+    plan.push(Operation::Put {
+        table: table.name,
+        key: key.clone(),
+        tuple,
+    });
+    Ok(key)
 }
 
-fn typecheck_column(column: &ColumnDefinition, value: &ColumnValue) -> Result<(), QueryError> {
+pub(crate) fn typecheck_column(
+    column: &ColumnDefinition,
+    value: &ColumnValue,
+) -> Result<(), QueryError> {
     match (&column.r#type, value) {
+        // references to values stored out of line only come from storage
+        (_, ColumnValue::Blob(_)) => return Err(QueryError::InvalidValue(value.clone())),
         (ColumnType::Boolean, ColumnValue::Boolean(_)) => (),
         (ColumnType::Integer, ColumnValue::Integer(_)) => (),
-        // (ColumnType::Float, ColumnValue::Float(_)) => (),
+        (ColumnType::Float, ColumnValue::Float(_)) => (),
         (ColumnType::Text, ColumnValue::Text(_)) => (),
+        (ColumnType::Timestamp, ColumnValue::Timestamp(_)) => (),
+        (ColumnType::Date, ColumnValue::Date(_)) => (),
+        (ColumnType::Uuid, ColumnValue::Uuid(_)) => (),
         (col_type, col_val) => {
             return Err(QueryError::ColumnTypeMismatch {
+                column: column.name.clone(),
                 expected: col_type.clone(),
                 given: col_val.to_type(),
             })
@@ -0,0 +1,66 @@
+use serde::{Deserialize, Serialize};
+
+use crate::query::batch::BatchPlan;
+use crate::query::error::QueryError;
+use crate::query::types::{ColumnValue, TableName};
+use crate::wal::Operation;
+
+use super::common::{coerce_item, coerce_key};
+use super::put_item::{typecheck_column, Item};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateItemCommand {
+    pub table_name: TableName,
+    pub key: ColumnValue,
+    pub item: Item,
+}
+
+/// Merge the columns given in the item into the existing record with the given
+/// primary key. The new version of the tuple is appended to the block, and the
+/// index is pointed to it.
+pub async fn update_item(
+    command: UpdateItemCommand,
+    plan: &mut BatchPlan<'_>,
+) -> Result<(), QueryError> {
+    // check if table name is valid
+    let table = match plan.get_table(&command.table_name) {
+        None => return Err(QueryError::TableNotFound(command.table_name)),
+        Some(table) => table,
+    };
+    let mut key = command.key;
+    coerce_key(&table.columns, &table.primary_key, &mut key);
+    let mut item = command.item;
+    coerce_item(&table.columns, &mut item);
+    // the primary key of a record can't be changed
+    if let Some(value) = item.get(&table.primary_key) {
+        if *value != key {
+            return Err(QueryError::PrimaryKeyUpdateNotAllowed(table.primary_key));
+        }
+    }
+    // check if item data is valid
+    for (column_name, value) in &item {
+        match table.get_column(column_name) {
+            None => return Err(QueryError::UnknownColumnInItem(column_name.clone())),
+            Some(column) => typecheck_column(column, value)?,
+        }
+    }
+    // fetch the current version of the record
+    let mut tuple = match plan.get_tuple(&table.name, &key).await? {
+        None => return Err(QueryError::RecordNotFound(key)),
+        Some(tuple) => tuple,
+    };
+    // merge the given columns into the tuple
+    for (column_name, value) in item {
+        // we have already checked that all columns exist
+        if let Some(position) = table.columns.iter().position(|c| c.name == column_name) {
+            tuple[position] = Some(value);
+        }
+    }
+    // finally write the new version of the tuple
+    plan.push(Operation::Put {
+        table: table.name,
+        key,
+        tuple,
+    });
+    Ok(())
+}
@@ -2,7 +2,10 @@ use std::path::PathBuf;
 
 use thiserror;
 
-use crate::{catalog::CatalogError, table::TableBufferError, TableName};
+use crate::{
+    backup::BackupError, catalog::CatalogError, compaction::CompactionError, fsck::FsckError,
+    migration::MigrationError, table::TableBufferError, wal::WalError, TableName,
+};
 
 use super::types::{ColumnName, ColumnType, ColumnValue};
 
@@ -10,16 +13,41 @@ use super::types::{ColumnName, ColumnType, ColumnValue};
 pub enum QueryError {
     #[error("Table '{0}' not found.")]
     TableNotFound(TableName),
+    #[error("Table '{0}' is unavailable: a write to it could not be made durable, so its contents may not match what is on disk. Reopen the database to use it again.")]
+    TablePoisoned(TableName),
     #[error("Table name '{0}' already exists.")]
     TableAlreadyExists(TableName),
+    #[error("Primary key '{0}' is not one of the table's columns.")]
+    PrimaryKeyNotInColumns(ColumnName),
     #[error("Item object must contain primary key: {0}.")]
     ItemMustContainPrimaryKey(ColumnName),
     #[error("Record with primary key '{0}' already exists.")]
     PrimaryKeyAlreadyExists(ColumnValue),
+    #[error("Record with primary key '{0}' does not exist.")]
+    RecordNotFound(ColumnValue),
+    #[error("Primary key column '{0}' cannot be updated.")]
+    PrimaryKeyUpdateNotAllowed(ColumnName),
+    #[error("Primary key column '{0}' cannot have a default value.")]
+    PrimaryKeyDefaultNotAllowed(ColumnName),
+    #[error("Column '{0}' cannot be generated; only a primary key column of type Uuid can.")]
+    GeneratedColumnNotAllowed(ColumnName),
+    #[error("Column '{0}' is not nullable, and must be given a value.")]
+    NullNotAllowed(ColumnName),
     #[error("Unknown column in item object: {0}.")]
     UnknownColumnInItem(ColumnName),
-    #[error("Column type mismatch. Column defined as type: {expected}, but provided value has type: {given}.")]
+    #[error("Unknown column: {0}.")]
+    UnknownColumn(ColumnName),
+    #[error("Column '{column}' of type {column_type} can't be compared with a value of type {value_type}.")]
+    IncomparableTypes {
+        column: ColumnName,
+        column_type: ColumnType,
+        value_type: ColumnType,
+    },
+    #[error("Invalid value: {0}.")]
+    InvalidValue(ColumnValue),
+    #[error("Column type mismatch. Column '{column}' defined as type: {expected}, but provided value has type: {given}.")]
     ColumnTypeMismatch {
+        column: ColumnName,
         expected: ColumnType,
         given: ColumnType,
     },
@@ -29,6 +57,16 @@ pub enum QueryError {
     TableStorageError(#[from] TableBufferError),
     #[error("Internal Error: {0}")]
     CatalogError(#[from] CatalogError),
+    #[error("Compaction Error: {0}")]
+    CompactionError(#[from] CompactionError),
+    #[error("Migration Error: {0}")]
+    MigrationError(#[from] MigrationError),
+    #[error("Fsck Error: {0}")]
+    FsckError(#[from] FsckError),
+    #[error("Backup Error: {0}")]
+    BackupError(#[from] BackupError),
+    #[error("Internal Error: {0}")]
+    WalError(#[from] WalError),
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -1,4 +1,7 @@
+pub(crate) mod batch;
 pub(crate) mod ddl;
 pub(crate) mod dml;
 pub mod error;
+pub(crate) mod time;
 pub mod types;
+pub(crate) mod uuid;
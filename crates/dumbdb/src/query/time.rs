@@ -0,0 +1,353 @@
+/// Timestamps and dates, the values of `Timestamp` and `Date` columns.
+///
+/// In JSON, they are RFC 3339 strings (e.g. `2024-10-01T12:30:00Z` and
+/// `2024-10-01`). In storage, where values are not tagged with their type, a
+/// timestamp is a map from `timestamp` to the number of microseconds since the
+/// Unix epoch, and a date a map from `date` to the number of days since it, so
+/// that they can't be mistaken for values of other types.
+///
+/// Wherever a timestamp or a date is expected, a time relative to now can be
+/// given too: `now`, or e.g. `now - 1 day` (see `parse_relative`).
+use std::{
+    fmt::Display,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{
+    de::{self, MapAccess, Visitor},
+    ser::SerializeMap,
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+const MICROS_PER_SECOND: i64 = 1_000_000;
+const MICROS_PER_MINUTE: i64 = 60 * MICROS_PER_SECOND;
+const MICROS_PER_HOUR: i64 = 60 * MICROS_PER_MINUTE;
+const MICROS_PER_DAY: i64 = 24 * MICROS_PER_HOUR;
+
+/// Key of the map a timestamp is stored as
+const TIMESTAMP_KEY: &str = "timestamp";
+/// Key of the map a date is stored as
+const DATE_KEY: &str = "date";
+
+/// A point in time, in UTC, with microsecond precision
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp {
+    /// Microseconds since the Unix epoch
+    micros: i64,
+}
+
+/// A calendar day
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Date {
+    /// Days since the Unix epoch
+    days: i64,
+}
+
+impl Timestamp {
+    pub fn from_micros(micros: i64) -> Self {
+        Self { micros }
+    }
+
+    /// Microseconds since the Unix epoch
+    pub fn micros(self) -> i64 {
+        self.micros
+    }
+
+    pub fn now() -> Self {
+        let micros = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(duration) => duration.as_micros() as i64,
+            Err(error) => -(error.duration().as_micros() as i64),
+        };
+        Self { micros }
+    }
+
+    /// Parse an RFC 3339 timestamp, e.g. `2024-10-01T12:30:00Z` or
+    /// `2024-10-01T14:30:00.25+02:00`. Digits of the seconds beyond
+    /// microseconds are cut off.
+    pub fn parse(value: &str) -> Option<Self> {
+        let date = Date::parse(value.get(..10)?)?;
+        if !matches!(value.as_bytes().get(10)?, b'T' | b't' | b' ') {
+            return None;
+        }
+        let rest = value.get(11..)?;
+        let hours = parse_digits(rest.get(..2)?)?;
+        let minutes = parse_digits(rest.get(3..5)?)?;
+        let seconds = parse_digits(rest.get(6..8)?)?;
+        if rest.get(2..3)? != ":" || rest.get(5..6)? != ":" {
+            return None;
+        }
+        // a leap second is the last microsecond of its minute
+        if hours > 23 || minutes > 59 || seconds > 60 {
+            return None;
+        }
+        let mut rest = &rest[8..];
+        let mut fraction = 0;
+        if let Some(digits) = rest.strip_prefix('.') {
+            let length = digits
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(digits.len());
+            if length == 0 {
+                return None;
+            }
+            for (position, digit) in digits[..length.min(6)].bytes().enumerate() {
+                fraction += (digit - b'0') as i64 * 10_i64.pow(5 - position as u32);
+            }
+            rest = &digits[length..];
+        }
+        let offset = match rest {
+            "Z" | "z" => 0,
+            _ => {
+                let sign = match rest.get(..1)? {
+                    "+" => 1,
+                    "-" => -1,
+                    _ => return None,
+                };
+                if rest.len() != 6 || rest.get(3..4)? != ":" {
+                    return None;
+                }
+                let (hours, minutes) = (
+                    parse_digits(rest.get(1..3)?)?,
+                    parse_digits(rest.get(4..6)?)?,
+                );
+                if hours > 23 || minutes > 59 {
+                    return None;
+                }
+                sign * (hours * MICROS_PER_HOUR + minutes * MICROS_PER_MINUTE)
+            }
+        };
+        let time = if seconds == 60 {
+            hours * MICROS_PER_HOUR + minutes * MICROS_PER_MINUTE + 60 * MICROS_PER_SECOND - 1
+        } else {
+            hours * MICROS_PER_HOUR
+                + minutes * MICROS_PER_MINUTE
+                + seconds * MICROS_PER_SECOND
+                + fraction
+        };
+        Some(Self {
+            micros: date.days * MICROS_PER_DAY + time - offset,
+        })
+    }
+
+    /// The day of the timestamp, in UTC
+    pub fn date(self) -> Date {
+        Date {
+            days: self.micros.div_euclid(MICROS_PER_DAY),
+        }
+    }
+}
+
+impl Date {
+    pub fn from_days(days: i64) -> Self {
+        Self { days }
+    }
+
+    /// Days since the Unix epoch
+    pub fn days(self) -> i64 {
+        self.days
+    }
+
+    /// Parse a date in the format of RFC 3339, e.g. `2024-10-01`
+    pub fn parse(value: &str) -> Option<Self> {
+        let bytes = value.as_bytes();
+        if bytes.len() != 10 || bytes[4] != b'-' || bytes[7] != b'-' {
+            return None;
+        }
+        let year = parse_digits(&value[..4])?;
+        let month = parse_digits(&value[5..7])?;
+        let day = parse_digits(&value[8..10])?;
+        if !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month) {
+            return None;
+        }
+        Some(Self {
+            days: days_from_civil(year, month, day),
+        })
+    }
+}
+
+/// Parse a time relative to now: `now`, or `now` plus or minus a number of
+/// units, e.g. `now - 1 day` or `now + 90 minutes`. The units are
+/// `microsecond`, `millisecond`, `second`, `minute`, `hour`, `day` and `week`
+/// (or their plurals).
+pub(crate) fn parse_relative(value: &str) -> Option<Timestamp> {
+    let rest = value.trim().strip_prefix("now")?.trim_start();
+    let now = Timestamp::now();
+    if rest.is_empty() {
+        return Some(now);
+    }
+    let (sign, rest) = match (rest.strip_prefix('+'), rest.strip_prefix('-')) {
+        (Some(rest), _) => (1, rest),
+        (_, Some(rest)) => (-1, rest),
+        _ => return None,
+    };
+    let [count, unit] = rest.split_whitespace().collect::<Vec<_>>()[..] else {
+        return None;
+    };
+    let count: i64 = count.parse().ok()?;
+    let unit = match unit.strip_suffix('s').unwrap_or(unit) {
+        "microsecond" => 1,
+        "millisecond" => 1_000,
+        "second" => MICROS_PER_SECOND,
+        "minute" => MICROS_PER_MINUTE,
+        "hour" => MICROS_PER_HOUR,
+        "day" => MICROS_PER_DAY,
+        "week" => 7 * MICROS_PER_DAY,
+        _ => return None,
+    };
+    let delta = count.checked_mul(unit)?.checked_mul(sign)?;
+    Some(Timestamp::from_micros(now.micros.checked_add(delta)?))
+}
+
+impl Display for Timestamp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let time = self.micros.rem_euclid(MICROS_PER_DAY);
+        write!(
+            f,
+            "{}T{:02}:{:02}:{:02}",
+            self.date(),
+            time / MICROS_PER_HOUR,
+            time % MICROS_PER_HOUR / MICROS_PER_MINUTE,
+            time % MICROS_PER_MINUTE / MICROS_PER_SECOND
+        )?;
+        let fraction = time % MICROS_PER_SECOND;
+        if fraction != 0 {
+            write!(f, ".{:06}", fraction)?;
+        }
+        write!(f, "Z")
+    }
+}
+
+impl Display for Date {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (year, month, day) = civil_from_days(self.days);
+        write!(f, "{:04}-{:02}-{:02}", year, month, day)
+    }
+}
+
+impl Serialize for Timestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.collect_str(self)
+        } else {
+            let mut map = serializer.serialize_map(Some(1))?;
+            map.serialize_entry(TIMESTAMP_KEY, &self.micros)?;
+            map.end()
+        }
+    }
+}
+
+impl Serialize for Date {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.collect_str(self)
+        } else {
+            let mut map = serializer.serialize_map(Some(1))?;
+            map.serialize_entry(DATE_KEY, &self.days)?;
+            map.end()
+        }
+    }
+}
+
+// both are read from either of their forms: in an untagged `ColumnValue`, a
+// string is always read as text, so only the stored form is ever read there
+impl<'de> Deserialize<'de> for Timestamp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer
+            .deserialize_any(TimeVisitor {
+                key: TIMESTAMP_KEY,
+                parse: |value| Timestamp::parse(value).map(Timestamp::micros),
+            })
+            .map(Timestamp::from_micros)
+    }
+}
+
+impl<'de> Deserialize<'de> for Date {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer
+            .deserialize_any(TimeVisitor {
+                key: DATE_KEY,
+                parse: |value| Date::parse(value).map(Date::days),
+            })
+            .map(Date::from_days)
+    }
+}
+
+// reads the number a timestamp or a date is stored as, or parses a string into
+// it
+struct TimeVisitor {
+    key: &'static str,
+    parse: fn(&str) -> Option<i64>,
+}
+
+impl<'de> Visitor<'de> for TimeVisitor {
+    type Value = i64;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(formatter, "an RFC 3339 {}", self.key)
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+        (self.parse)(value).ok_or_else(|| E::invalid_value(de::Unexpected::Str(value), &self))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let (key, value): (String, i64) = map
+            .next_entry()?
+            .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        if key != self.key || map.next_key::<String>()?.is_some() {
+            return Err(de::Error::invalid_value(de::Unexpected::Map, &self));
+        }
+        Ok(value)
+    }
+}
+
+// the value of two ASCII digits (or four, for a year)
+fn parse_digits(value: &str) -> Option<i64> {
+    if !value.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    value.parse().ok()
+}
+
+fn is_leap_year(year: i64) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// days since the Unix epoch of a date of the proleptic Gregorian calendar
+// (Howard Hinnant's `days_from_civil`)
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let month_from_march = (month + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+// the inverse of `days_from_civil`
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    };
+    let year = year_of_era + era * 400;
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
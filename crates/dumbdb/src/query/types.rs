@@ -1,19 +1,123 @@
-use std::fmt::Display;
+use std::{
+    cmp::Ordering,
+    fmt::Display,
+    hash::{Hash, Hasher},
+};
 
-use serde::{Deserialize, Serialize};
+use serde::{
+    de::{self, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
 use smol_str::SmolStr;
 
+use super::time::parse_relative;
+pub use super::time::{Date, Timestamp};
+pub use super::uuid::{KeyGeneration, Uuid};
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TableDefinition {
     pub name: TableName,
     pub columns: Vec<ColumnDefinition>,
     pub primary_key: ColumnName,
+    /// Overrides the database's durability mode for this table
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub durability: Option<Durability>,
+    /// Compresses the tuples of this table in its block
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<Compression>,
+    /// How the tuples of this table are stored; a log if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub engine: Option<StorageEngine>,
+}
+
+impl TableDefinition {
+    pub fn get_column(&self, name: &ColumnName) -> Option<&ColumnDefinition> {
+        self.columns.iter().find(|col| col.name == *name)
+    }
+
+    /// Position of the primary key column in the table's tuples
+    pub fn primary_key_position(&self) -> Option<usize> {
+        self.columns
+            .iter()
+            .position(|col| col.name == self.primary_key)
+    }
+
+    /// Fingerprint of the table's schema, i.e. its columns and its primary
+    /// key. It is stored in the file header of the table's block files.
+    pub(crate) fn schema_fingerprint(&self) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        for column in &self.columns {
+            hasher.update(column.name.0.as_bytes());
+            hasher.update(&[0]);
+            hasher.update(column.r#type.to_string().as_bytes());
+            hasher.update(&[0]);
+        }
+        hasher.update(self.primary_key.0.as_bytes());
+        hasher.finalize()
+    }
+}
+
+/// How a write to a table's block is made durable, before it is acknowledged.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum Durability {
+    /// Don't sync the block file; leave it to the OS to flush it to disk
+    /// eventually. A crash can lose acknowledged writes.
+    #[default]
+    NoSync,
+    /// Sync the block file after every write.
+    SyncEachWrite,
+    /// Every write waits for a sync of the block file, but concurrent writes
+    /// share a sync. The first writer waits for `window_ms` milliseconds, for
+    /// other writes to come in, and then syncs the file once for all of them.
+    GroupCommit { window_ms: u64 },
+}
+
+/// How the tuples of a table are compressed in its block. Every tuple is
+/// compressed on its own, so that it can still be read on its own.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    /// LZ4; fast, with a moderate compression ratio
+    Lz4,
+}
+
+/// How the tuples of a table are stored.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageEngine {
+    /// An append-only log of tuples, with an index of all the keys in memory
+    #[default]
+    Log,
+    /// An LSM tree: a memtable, and sorted SSTable files. Only the most recent
+    /// writes, and a sparse index and a bloom filter of every SSTable, are
+    /// kept in memory.
+    Lsm,
+    /// An LSM tree whose SSTables store the tuples by column, in chunks with
+    /// the minimum and maximum value of every column. Scans read only the
+    /// columns they need, and skip the chunks which can't match.
+    Columnar,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ColumnDefinition {
     pub name: ColumnName,
     pub r#type: ColumnType,
+    /// Can items leave the column out; the primary key can never be left out
+    #[serde(default = "default_nullable")]
+    pub nullable: bool,
+    /// Value of the column in items which leave it out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default: Option<ColumnValue>,
+    /// How the value of the column is generated, in items which leave it out;
+    /// only for a primary key of type `Uuid`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub generated: Option<KeyGeneration>,
+}
+
+// columns were nullable before they could be declared not to be
+fn default_nullable() -> bool {
+    true
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -22,6 +126,9 @@ pub enum ColumnType {
     Float,
     Text,
     Boolean,
+    Timestamp,
+    Date,
+    Uuid,
 }
 
 impl Display for ColumnType {
@@ -31,17 +138,132 @@ impl Display for ColumnType {
             Self::Float => write!(f, "Float"),
             Self::Boolean => write!(f, "Boolean"),
             Self::Text => write!(f, "Text"),
+            Self::Timestamp => write!(f, "Timestamp"),
+            Self::Date => write!(f, "Date"),
+            Self::Uuid => write!(f, "Uuid"),
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Hash, Clone)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
 #[serde(untagged)]
 pub enum ColumnValue {
-    Integer(u64),
-    // Float(f64), // <-- f64 doesn't have PartialOrd, Ord, Eq or Hash. So we can't use it as a key in our index.
+    /// A signed integer. Non-negative ones are serialized as unsigned, the
+    /// way they were before integers were signed, so that stored tuples and
+    /// keys (e.g. hashed into the bloom filters of SSTables) stay the same.
+    #[serde(serialize_with = "serialize_integer")]
+    Integer(i64),
     Boolean(bool),
+    /// Given as a string (i.e. text), which is converted to a UUID for a
+    /// `Uuid` column. Before text, which could be read from its bytes too.
+    Uuid(Uuid),
     Text(String),
+    // after the integers; a float is never read from an integer
+    Float(OrderedFloat),
+    /// Given as an RFC 3339 string (i.e. text), which is converted to a
+    /// timestamp for a `Timestamp` column
+    Timestamp(Timestamp),
+    /// Given as an RFC 3339 string, like a timestamp
+    Date(Date),
+    /// A `Text` value stored out of line, in a blob file of its table (see
+    /// `blob`). Only tuples in storage have these; records never do.
+    Blob(BlobRef),
+}
+
+/// Where a value stored out of line is, in the blob files of its table
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+pub struct BlobRef {
+    /// Id of the blob file
+    pub(crate) file: u64,
+    /// Byte-offset of the value's entry in the blob file
+    pub(crate) offset: u64,
+}
+
+/// A float which is totally ordered (see `f64::total_cmp`), so that it can be
+/// a key in the index. Negative zero is stored as zero, so that the two are
+/// equal.
+#[derive(Debug, Serialize, Clone, Copy)]
+#[serde(into = "f64")]
+pub struct OrderedFloat(f64);
+
+impl OrderedFloat {
+    pub fn new(value: f64) -> Self {
+        if value == 0.0 {
+            Self(0.0)
+        } else {
+            Self(value)
+        }
+    }
+
+    pub fn get(self) -> f64 {
+        self.0
+    }
+}
+
+// only floats are read as floats, and not integers, so that an integer which
+// is out of the range of `ColumnValue::Integer` is an error rather than a float
+impl<'de> Deserialize<'de> for OrderedFloat {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct FloatVisitor;
+
+        impl Visitor<'_> for FloatVisitor {
+            type Value = OrderedFloat;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("a float")
+            }
+
+            fn visit_f64<E: de::Error>(self, value: f64) -> Result<Self::Value, E> {
+                Ok(OrderedFloat::new(value))
+            }
+        }
+
+        deserializer.deserialize_f64(FloatVisitor)
+    }
+}
+
+impl From<f64> for OrderedFloat {
+    fn from(value: f64) -> Self {
+        Self::new(value)
+    }
+}
+
+impl From<OrderedFloat> for f64 {
+    fn from(value: OrderedFloat) -> Self {
+        value.0
+    }
+}
+
+impl PartialEq for OrderedFloat {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for OrderedFloat {}
+
+impl PartialOrd for OrderedFloat {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for OrderedFloat {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+impl Hash for OrderedFloat {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.to_bits().hash(state);
+    }
+}
+
+impl Display for OrderedFloat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
 }
 
 impl ColumnValue {
@@ -49,34 +271,104 @@ impl ColumnValue {
         match self {
             ColumnValue::Boolean(_) => ColumnType::Boolean,
             ColumnValue::Integer(_) => ColumnType::Integer,
-            ColumnValue::Text(_) => ColumnType::Text,
+            ColumnValue::Float(_) => ColumnType::Float,
+            ColumnValue::Timestamp(_) => ColumnType::Timestamp,
+            ColumnValue::Date(_) => ColumnType::Date,
+            ColumnValue::Uuid(_) => ColumnType::Uuid,
+            ColumnValue::Text(_) | ColumnValue::Blob(_) => ColumnType::Text,
         }
     }
+
+    /// Compare two values in a filter. Integers and floats are compared by
+    /// their numeric value; other values of different types by the order of
+    /// their variants.
+    pub(crate) fn compare(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Self::Integer(a), Self::Float(b)) => compare_integer_float(*a, b.get()),
+            (Self::Float(a), Self::Integer(b)) => compare_integer_float(*b, a.get()).reverse(),
+            _ => self.cmp(other),
+        }
+    }
+
+    /// Convert the value to the given column type, if it can be converted
+    /// (i.e. an integer given for a `Float` column, or text for a `Timestamp`,
+    /// a `Date` or a `Uuid` column); otherwise leave it as it is.
+    pub(crate) fn coerce(&mut self, r#type: &ColumnType) {
+        let value = match (r#type, &*self) {
+            (ColumnType::Float, Self::Integer(int)) => Self::Float(OrderedFloat::new(*int as f64)),
+            (ColumnType::Timestamp, Self::Text(text)) => {
+                match Timestamp::parse(text).or_else(|| parse_relative(text)) {
+                    Some(timestamp) => Self::Timestamp(timestamp),
+                    None => return,
+                }
+            }
+            (ColumnType::Date, Self::Text(text)) => {
+                match Date::parse(text).or_else(|| parse_relative(text).map(Timestamp::date)) {
+                    Some(date) => Self::Date(date),
+                    None => return,
+                }
+            }
+            (ColumnType::Uuid, Self::Text(text)) => match Uuid::parse(text) {
+                Some(uuid) => Self::Uuid(uuid),
+                None => return,
+            },
+            _ => return,
+        };
+        *self = value;
+    }
 }
 
 impl Display for ColumnValue {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::Integer(val) => write!(f, "{}", val),
-            // Self::Float(val) => write!(f, "{}", val),
+            Self::Float(val) => write!(f, "{}", val),
+            Self::Timestamp(val) => write!(f, "{}", val),
+            Self::Date(val) => write!(f, "{}", val),
+            Self::Uuid(val) => write!(f, "{}", val),
             Self::Boolean(val) => write!(f, "{}", val),
             Self::Text(val) => write!(f, "{}", val),
+            Self::Blob(blob) => write!(f, "<blob {}:{}>", blob.file, blob.offset),
         }
     }
 }
 
 impl From<String> for ColumnValue {
     fn from(value: String) -> Self {
-        match value.parse::<u64>() {
+        match value.parse::<i64>() {
             Ok(int) => ColumnValue::Integer(int),
-            Err(_) => match value.parse::<bool>() {
-                Ok(boolean) => ColumnValue::Boolean(boolean),
-                Err(_) => ColumnValue::Text(value),
+            Err(_) => match value.parse::<f64>() {
+                // "inf" and "NaN" are text
+                Ok(float) if float.is_finite() => ColumnValue::Float(OrderedFloat::new(float)),
+                _ => match value.parse::<bool>() {
+                    Ok(boolean) => ColumnValue::Boolean(boolean),
+                    Err(_) => ColumnValue::Text(value),
+                },
             },
         }
     }
 }
 
+fn serialize_integer<S: Serializer>(value: &i64, serializer: S) -> Result<S::Ok, S::Error> {
+    match u64::try_from(*value) {
+        Ok(value) => serializer.serialize_u64(value),
+        Err(_) => serializer.serialize_i64(*value),
+    }
+}
+
+// compare exactly, not only as far as the integer can be represented as a
+// float
+fn compare_integer_float(int: i64, float: f64) -> Ordering {
+    // 2^63, which is greater than any integer
+    const INTEGER_LIMIT: f64 = 9_223_372_036_854_775_808.0;
+    match (int as f64).total_cmp(&float) {
+        // the integer was rounded to the float, which is thus whole
+        Ordering::Equal if float >= INTEGER_LIMIT => Ordering::Less,
+        Ordering::Equal => int.cmp(&(float as i64)),
+        ordering => ordering,
+    }
+}
+
 impl From<&str> for ColumnValue {
     fn from(value: &str) -> Self {
         Self::from(value.to_string())
@@ -100,6 +392,12 @@ pub enum Expression {
     Or(Vec<Expression>),
     #[serde(rename = "$not")]
     Not(Box<Expression>),
+    /// The column is null
+    #[serde(rename = "$is_null")]
+    IsNull(ColumnName),
+    /// The column is not null
+    #[serde(rename = "$is_not_null")]
+    IsNotNull(ColumnName),
     #[serde(untagged)]
     ColumnComparison(ColumnComparison),
 }
@@ -132,7 +430,7 @@ pub enum Operator {
 
 // newtype structs..
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, derive_more::Display)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, derive_more::Display, Eq, Hash)]
 #[serde(into = "String")]
 #[serde(from = "String")]
 pub struct TableName(pub SmolStr);
@@ -0,0 +1,138 @@
+/// UUIDs, the values of `Uuid` columns, and their generation for primary keys
+/// declared as generated.
+///
+/// In JSON, a UUID is a string in the hyphenated format (e.g.
+/// `67e55044-10b1-426f-9247-bb680e5fe0c8`). In storage it is its 16 bytes, as
+/// MessagePack binary data, which no value of another type is stored as.
+use std::fmt::Display;
+
+use rand::RngCore;
+use serde::{
+    de::{self, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+use super::time::Timestamp;
+
+/// Positions of the hyphens in the hyphenated format
+const HYPHENS: [usize; 4] = [8, 13, 18, 23];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Uuid([u8; 16]);
+
+/// How the primary key of a table is generated, for items which leave it out
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyGeneration {
+    /// Random UUIDs (version 4)
+    UuidV4,
+    /// UUIDs ordered by the time they are generated, to the millisecond
+    /// (version 7), so that newer keys sort after older ones
+    UuidV7,
+}
+
+impl Uuid {
+    pub fn from_bytes(bytes: [u8; 16]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+
+    /// A random UUID (version 4)
+    pub fn new_v4() -> Self {
+        let mut bytes = [0; 16];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        Self::with_version(bytes, 4)
+    }
+
+    /// A UUID which starts with the current Unix time in milliseconds, and is
+    /// random after that (version 7)
+    pub fn new_v7() -> Self {
+        let mut bytes = [0; 16];
+        rand::thread_rng().fill_bytes(&mut bytes[6..]);
+        let millis = Timestamp::now().micros().div_euclid(1_000) as u64;
+        bytes[..6].copy_from_slice(&millis.to_be_bytes()[2..]);
+        Self::with_version(bytes, 7)
+    }
+
+    /// Parse a UUID in the hyphenated format, in either case
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value.as_bytes();
+        if value.len() != 36 || HYPHENS.iter().any(|position| value[*position] != b'-') {
+            return None;
+        }
+        let mut digits = value.iter().filter(|byte| **byte != b'-');
+        let mut bytes = [0; 16];
+        for byte in &mut bytes {
+            let high = (*digits.next()? as char).to_digit(16)?;
+            let low = (*digits.next()? as char).to_digit(16)?;
+            *byte = (high << 4 | low) as u8;
+        }
+        Some(Self(bytes))
+    }
+
+    // set the version, and the variant of RFC 9562
+    fn with_version(mut bytes: [u8; 16], version: u8) -> Self {
+        bytes[6] = (bytes[6] & 0x0f) | version << 4;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+        Self(bytes)
+    }
+}
+
+impl KeyGeneration {
+    pub(crate) fn generate(self) -> Uuid {
+        match self {
+            Self::UuidV4 => Uuid::new_v4(),
+            Self::UuidV7 => Uuid::new_v7(),
+        }
+    }
+}
+
+impl Display for Uuid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (position, byte) in self.0.iter().enumerate() {
+            if matches!(position, 4 | 6 | 8 | 10) {
+                write!(f, "-")?;
+            }
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for Uuid {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.collect_str(self)
+        } else {
+            serializer.serialize_bytes(&self.0)
+        }
+    }
+}
+
+// only read from bytes: in an untagged `ColumnValue`, a string must be read as
+// text (and converted to a UUID for a `Uuid` column), and not as a UUID
+impl<'de> Deserialize<'de> for Uuid {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct UuidVisitor;
+
+        impl Visitor<'_> for UuidVisitor {
+            type Value = Uuid;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("the 16 bytes of a UUID")
+            }
+
+            fn visit_bytes<E: de::Error>(self, value: &[u8]) -> Result<Self::Value, E> {
+                let bytes = value
+                    .try_into()
+                    .map_err(|_| E::invalid_length(value.len(), &self))?;
+                Ok(Uuid(bytes))
+            }
+        }
+
+        deserializer.deserialize_bytes(UuidVisitor)
+    }
+}
@@ -0,0 +1,385 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use futures::{Stream, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    backend::{Backend, BlockStorage},
+    query::types::{ColumnValue, Compression, Durability},
+    storage::{
+        calculate_new_offset, BlockEntry, EntrySize, PendingSync, StorageError, Tuple,
+        FILE_HEADER_SIZE,
+    },
+};
+
+/// Segments are numbered in the order they are created.
+pub(crate) type SegmentId = u32;
+
+/// The block of a table, split into numbered segment files. Segment 0 is stored
+/// at the block's path (i.e. `<table>.dat`), and every other segment next to it
+/// with the segment number as an extension (i.e. `<table>.dat.<n>`).
+///
+/// Writes are appended to the last segment (the active segment). Once it grows
+/// beyond the maximum segment size, it is synced and a new segment is started.
+/// The older segments are not written to anymore, except when they are
+/// compacted. Entries in a later segment are newer than the ones in an earlier
+/// segment.
+#[derive(Debug, Clone)]
+pub(crate) struct SegmentedBlock {
+    /// Path of segment 0
+    base_path: PathBuf,
+    backend: Backend,
+    durability: Durability,
+    /// Fingerprint of the table's schema, in the file header of every segment
+    schema_fingerprint: u32,
+    /// How tuples are compressed
+    compression: Option<Compression>,
+    /// Size (in bytes) the active segment is rolled over at
+    max_segment_bytes: u64,
+    pub(crate) segments: BTreeMap<SegmentId, Segment>,
+}
+
+/// A segment file, and its length.
+#[derive(Debug, Clone)]
+pub(crate) struct Segment {
+    pub(crate) block: Arc<dyn BlockStorage>,
+    /// Length of the segment file, in bytes (including its file header)
+    pub(crate) length: u64,
+    /// Number of bytes saved by compressing the entries of the segment, i.e.
+    /// how much longer it would be if they weren't compressed
+    pub(crate) saved_bytes: u64,
+}
+
+/// Location of an entry in a segmented block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct EntryPosition {
+    pub(crate) segment: SegmentId,
+    /// Byte-offset of the entry in the segment
+    pub(crate) offset: u64,
+    /// Number of bytes the entry takes up in the segment
+    pub(crate) length: u64,
+}
+
+impl SegmentedBlock {
+    /// Open the segments of a block. Segment 0 (i.e. the file at the given
+    /// path) must exist.
+    pub(crate) fn open(
+        backend: &Backend,
+        base_path: &Path,
+        durability: Durability,
+        schema_fingerprint: u32,
+        compression: Option<Compression>,
+        max_segment_bytes: u64,
+    ) -> Result<Self, StorageError> {
+        let mut segments = BTreeMap::new();
+        for (segment_id, path) in find_segment_files(backend, base_path)? {
+            let block = backend.open_block(&path, durability, schema_fingerprint)?;
+            let length = backend.file_length(&path)?;
+            // the saved bytes are counted when the segment is read, or loaded
+            // from the hint file
+            let segment = Segment {
+                block,
+                length,
+                saved_bytes: 0,
+            };
+            segments.insert(segment_id, segment);
+        }
+        if !segments.contains_key(&0) {
+            // fail the same way as a missing block file
+            backend.open_block(base_path, durability, schema_fingerprint)?;
+        }
+        Ok(Self {
+            base_path: base_path.to_path_buf(),
+            backend: backend.clone(),
+            durability,
+            schema_fingerprint,
+            compression,
+            max_segment_bytes,
+            segments,
+        })
+    }
+
+    pub(crate) fn backend(&self) -> &Backend {
+        &self.backend
+    }
+
+    pub(crate) fn durability(&self) -> Durability {
+        self.durability
+    }
+
+    pub(crate) fn schema_fingerprint(&self) -> u32 {
+        self.schema_fingerprint
+    }
+
+    pub(crate) fn compression(&self) -> Option<Compression> {
+        self.compression
+    }
+
+    /// The path of segment 0
+    pub(crate) fn path(&self) -> &Path {
+        &self.base_path
+    }
+
+    pub(crate) fn segment_path(&self, segment: SegmentId) -> PathBuf {
+        get_segment_path(&self.base_path, segment)
+    }
+
+    /// The segment writes are appended to
+    pub(crate) fn active_segment(&self) -> SegmentId {
+        self.segments.keys().next_back().copied().unwrap_or(0)
+    }
+
+    /// Total size of all the segments, in bytes
+    pub(crate) fn total_bytes(&self) -> u64 {
+        self.segments.values().map(|segment| segment.length).sum()
+    }
+
+    /// Total size the segments would have if their entries weren't
+    /// compressed, in bytes
+    pub(crate) fn uncompressed_bytes(&self) -> u64 {
+        self.segments
+            .values()
+            .map(|segment| segment.length + segment.saved_bytes)
+            .sum()
+    }
+
+    /// Read the tuple at the given position.
+    pub(crate) async fn seek_to_offset(
+        &self,
+        position: &EntryPosition,
+    ) -> Result<Tuple, StorageError> {
+        self.get_segment(position.segment)?
+            .block
+            .seek_to_offset(position.offset)
+            .await
+    }
+
+    /// Write a `Tuple` to the active segment and return its position.
+    pub(crate) async fn write(&mut self, tuple: Tuple) -> Result<EntryPosition, StorageError> {
+        self.roll_over_if_full().await?;
+        let compression = self.compression;
+        let (segment_id, segment) = self.active_segment_mut()?;
+        let size = segment.block.write(tuple, compression).await?;
+        Ok(segment.append(segment_id, size))
+    }
+
+    /// Write a tombstone for the tuple with the given primary key to the
+    /// active segment, and return its position.
+    pub(crate) async fn write_tombstone(
+        &mut self,
+        key: &ColumnValue,
+    ) -> Result<EntryPosition, StorageError> {
+        self.roll_over_if_full().await?;
+        let (segment_id, segment) = self.active_segment_mut()?;
+        let size = segment.block.write_tombstone(key).await?;
+        Ok(segment.append(segment_id, size))
+    }
+
+    /// Flush all the data written to the block to disk. Only the active
+    /// segment needs to be synced, as the others are synced when they are
+    /// rolled over.
+    pub(crate) async fn sync(&self) -> Result<(), StorageError> {
+        self.get_segment(self.active_segment())?.block.sync().await
+    }
+
+    /// The sync of the entries written to the block, in group commit mode.
+    /// Only the active segment can have any, as the others are synced when
+    /// they are rolled over.
+    pub(crate) fn pending_sync(&self) -> Result<Option<PendingSync>, StorageError> {
+        Ok(self
+            .get_segment(self.active_segment())?
+            .block
+            .pending_sync())
+    }
+
+    /// Truncate a segment to the given length. The entries cut off must not
+    /// have been counted in the segment's saved bytes.
+    pub(crate) async fn truncate(
+        &mut self,
+        segment_id: SegmentId,
+        length: u64,
+    ) -> Result<(), StorageError> {
+        let segment = self
+            .segments
+            .get_mut(&segment_id)
+            .ok_or_else(|| missing_segment(&self.base_path, segment_id))?;
+        segment.block.truncate(length).await?;
+        segment.length = length;
+        Ok(())
+    }
+
+    /// Stream all the entries of the block, segment by segment, along with
+    /// their position. Errors are reported the same way as in
+    /// `get_entries_from` of a block.
+    pub(crate) async fn get_entries(
+        &self,
+    ) -> Result<
+        impl Stream<Item = Result<(BlockEntry, EntryPosition), StorageError>> + '_,
+        StorageError,
+    > {
+        let segments = futures::stream::iter(self.segments.iter());
+        let stream = segments
+            .then(|(segment_id, segment)| async move {
+                segment
+                    .get_entries_from(*segment_id, FILE_HEADER_SIZE)
+                    .await
+            })
+            .try_flatten()
+            .map_ok(|(entry, position, _size)| (entry, position));
+        Ok(stream)
+    }
+
+    /// Get an iterator over the tuples of all the segments. Tombstone entries
+    /// are skipped.
+    #[cfg(test)]
+    pub(crate) async fn get_reader(
+        &self,
+    ) -> Result<impl Stream<Item = Result<Tuple, StorageError>> + '_, StorageError> {
+        let stream = self.get_entries().await?;
+        Ok(stream.filter_map(|x| {
+            futures::future::ready(match x {
+                Ok((BlockEntry::Tuple(tuple), _position)) => Some(Ok(tuple)),
+                Ok((BlockEntry::Tombstone(_), _position)) => None,
+                Err(err) => Some(Err(err)),
+            })
+        }))
+    }
+
+    pub(crate) fn get_segment(&self, segment_id: SegmentId) -> Result<&Segment, StorageError> {
+        self.segments
+            .get(&segment_id)
+            .ok_or_else(|| missing_segment(&self.base_path, segment_id))
+    }
+
+    fn active_segment_mut(&mut self) -> Result<(SegmentId, &mut Segment), StorageError> {
+        match self.segments.iter_mut().next_back() {
+            Some((segment_id, segment)) => Ok((*segment_id, segment)),
+            None => Err(missing_segment(&self.base_path, 0)),
+        }
+    }
+
+    // start a new segment, if the active one is full
+    async fn roll_over_if_full(&mut self) -> Result<(), StorageError> {
+        let active_segment = self.active_segment();
+        let segment = self.get_segment(active_segment)?;
+        if segment.length < self.max_segment_bytes {
+            return Ok(());
+        }
+        // the segment is not written to anymore; make sure all of it is on disk
+        segment.block.sync().await?;
+
+        let segment_id = active_segment + 1;
+        let path = self.segment_path(segment_id);
+        self.backend.create_block(&path, self.schema_fingerprint)?;
+        self.backend.sync_parent_dir(&path)?;
+        let block = self
+            .backend
+            .open_block(&path, self.durability, self.schema_fingerprint)?;
+        let segment = Segment {
+            block,
+            length: FILE_HEADER_SIZE,
+            saved_bytes: 0,
+        };
+        self.segments.insert(segment_id, segment);
+        Ok(())
+    }
+}
+
+impl Segment {
+    /// Stream the entries of the segment, starting at the given byte-offset,
+    /// along with their position and size.
+    pub(crate) async fn get_entries_from(
+        &self,
+        segment_id: SegmentId,
+        offset: u64,
+    ) -> Result<
+        impl Stream<Item = Result<(BlockEntry, EntryPosition, EntrySize), StorageError>>,
+        StorageError,
+    > {
+        let stream = self.block.get_entries_from(offset).await?;
+        Ok(stream.scan(offset, move |offset, result| {
+            let item = result.map(|(entry, size)| {
+                let position = EntryPosition {
+                    segment: segment_id,
+                    offset: *offset,
+                    length: size.stored,
+                };
+                *offset = calculate_new_offset(size.stored, *offset);
+                (entry, position, size)
+            });
+            futures::future::ready(Some(item))
+        }))
+    }
+
+    // record an entry of the given size written at the end of the segment
+    fn append(&mut self, segment_id: SegmentId, size: EntrySize) -> EntryPosition {
+        let position = EntryPosition {
+            segment: segment_id,
+            offset: self.length,
+            length: size.stored,
+        };
+        self.length = calculate_new_offset(size.stored, self.length);
+        self.saved_bytes += size.uncompressed - size.stored;
+        position
+    }
+}
+
+pub(crate) fn get_segment_path(base_path: &Path, segment: SegmentId) -> PathBuf {
+    if segment == 0 {
+        return base_path.to_path_buf();
+    }
+    let mut file_name = base_path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(format!(".{}", segment));
+    base_path.with_file_name(file_name)
+}
+
+/// Remove all the segment files of the block at the given path.
+pub(crate) fn remove_segment_files(
+    backend: &Backend,
+    base_path: &Path,
+) -> Result<(), std::io::Error> {
+    for (_, path) in find_segment_files(backend, base_path)? {
+        backend.remove_file(&path)?;
+    }
+    Ok(())
+}
+
+/// The segment files of the block at the given path, that exist
+pub(crate) fn find_segment_files(
+    backend: &Backend,
+    base_path: &Path,
+) -> Result<Vec<(SegmentId, PathBuf)>, std::io::Error> {
+    let mut segments = vec![];
+    if backend.file_exists(base_path) {
+        segments.push((0, base_path.to_path_buf()));
+    }
+    let (Some(directory), Some(base_name)) = (base_path.parent(), base_path.file_name()) else {
+        return Ok(segments);
+    };
+    let prefix = format!("{}.", base_name.to_string_lossy());
+    for file_name in backend.list_directory(directory)? {
+        let segment_id = file_name
+            .to_str()
+            .and_then(|name| name.strip_prefix(&prefix))
+            .and_then(|suffix| {
+                let segment_id = suffix.parse::<SegmentId>().ok()?;
+                (segment_id.to_string() == suffix).then_some(segment_id)
+            });
+        // other files next to the block (e.g. the hint file) are not segments
+        if let Some(segment_id) = segment_id.filter(|id| *id != 0) {
+            segments.push((segment_id, get_segment_path(base_path, segment_id)));
+        }
+    }
+    Ok(segments)
+}
+
+fn missing_segment(base_path: &Path, segment: SegmentId) -> StorageError {
+    StorageError::SegmentNotFound {
+        path: base_path.to_path_buf(),
+        segment,
+    }
+}
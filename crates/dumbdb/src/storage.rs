@@ -4,46 +4,154 @@ use std::pin::Pin;
 use std::{
     io::{Cursor, SeekFrom},
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
+use async_trait::async_trait;
 use futures::{Stream, StreamExt};
 use rmp_serde::{Deserializer, Serializer};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use tokio::fs::File;
 use tokio::{
-    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader},
-    sync::RwLock,
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader},
+    sync::{watch, RwLock},
 };
 
-use crate::query::types::ColumnValue;
+use crate::{
+    backend::{Backend, BlockStorage},
+    compression::{compress, decompress, DecompressError},
+    encryption::EncryptionError,
+    query::types::{ColumnValue, Compression, Durability},
+};
 
 /// A tuple is a list of values (well, possible values, hence `Option<..>`). In
 /// other words, this is a row of data.
 pub type Tuple = Vec<Option<ColumnValue>>;
 
+/// An entry in a block. Either a tuple, or a tombstone which marks the tuple
+/// with the given primary key as deleted.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlockEntry {
+    Tuple(Tuple),
+    Tombstone(ColumnValue),
+}
+
 /// A block stores a table (i.e. a list of tuples) on disk, backed by a single
-/// file.
+/// file. This is the disk implementation of `BlockStorage`.
 //
 /// It provides APIs to write new data, seek to a specific byte-offset, and read
 /// all of the contents of the block as an iterator fashion.
 ///
-/// Note: it does not provide any API to delete or update data.
+/// Note: the block is append-only. It does not provide any API to modify data
+/// in place. Updates are done by appending a new version of the tuple, and
+/// deletes by appending a tombstone entry. It is upto the layer above to know
+/// which version is the live one.
 ///
-/// Internally, this stores data in a length-prefixed binary format. So it can
-/// have a O(1) retrieval of a specific tuple. Otherwise, you can read all
-/// tuples in an iterator pattern.
+/// Internally, this stores data in a length-prefixed binary format, after the
+/// file header (see `FileHeader`). So it can have a O(1) retrieval of a
+/// specific tuple. Otherwise, you can read all tuples in an iterator pattern.
 #[derive(Debug, Clone)]
 pub struct Block {
     // file path of the file on disk
     file_path: PathBuf,
     write_handle: Arc<RwLock<File>>,
+    // a read-only handle to the block file, shared by all the point reads of
+    // the block; reads are positioned, so they don't move a shared cursor and
+    // can go on concurrently
+    read_handle: Arc<std::fs::File>,
+    durability: Durability,
+    // shared by all the writes of this block, in group commit mode
+    group_commit: Option<Arc<GroupCommit>>,
+    // the block is from before block files had a header, so its entries may
+    // not have a checksum
+    is_headerless: bool,
+}
+
+/// Group commit state of a block. Writers wait for a sync of the file (see
+/// `PendingSync`); the first writer to come in becomes the leader, waits for
+/// the commit window for more writes to come in, and syncs the file once for
+/// all of them.
+#[derive(Debug)]
+struct GroupCommit {
+    window: Duration,
+    // a handle to the block file, used only for syncing; so that writes can go
+    // on while the file is being synced
+    file: Arc<std::fs::File>,
+    // sequence number of the last write to the block
+    written: AtomicU64,
+    // is there a leader waiting out the commit window
+    has_leader: AtomicBool,
+    // sequence number of the last write known to be synced to disk
+    synced: watch::Sender<u64>,
+    // number of times the file was synced
+    syncs: AtomicU64,
+    // make the next sync fail, to test the handling of failed syncs
+    #[cfg(test)]
+    fail_next_sync: AtomicBool,
+}
+
+/// The sync of the entries written to a block in group commit mode, which is
+/// yet to be waited for. It doesn't hold on to the block, so that the writer
+/// can let go of the database before waiting, and the writes which come in
+/// meanwhile share the sync.
+#[derive(Debug)]
+pub struct PendingSync {
+    group_commit: Arc<GroupCommit>,
+    // sequence number of the last write to wait for
+    seq: u64,
 }
 
 // Size of the length prefix. We use these many bits to store the length of each
 // tuple.
 const LENGTH_PREFIX_SIZE: usize = 8;
 
+// Size of the checksum, which follows the length prefix.
+const CHECKSUM_SIZE: usize = 4;
+
+// The most significant bit of the length prefix marks the entry as a tombstone.
+// The data of a tombstone entry is the primary key of the deleted tuple.
+const TOMBSTONE_FLAG: u64 = 1 << 63;
+
+// The second most significant bit of the length prefix marks that the entry has
+// a checksum. Blocks written before checksums were introduced (i.e. before
+// block files had a header) don't have it, and those entries can't be
+// verified; in any other block, an entry without it is corrupt.
+const CHECKSUM_FLAG: u64 = 1 << 62;
+
+// The third most significant bit of the length prefix marks that the data of
+// the entry is compressed (see `compression`).
+const COMPRESSED_FLAG: u64 = 1 << 61;
+
+// The fourth most significant bit of the length prefix marks that the data of
+// the entry is encrypted (see `encryption`).
+const ENCRYPTED_FLAG: u64 = 1 << 60;
+
+const FLAGS_MASK: u64 = TOMBSTONE_FLAG | CHECKSUM_FLAG | COMPRESSED_FLAG | ENCRYPTED_FLAG;
+
+/// Number of bytes covered by `BlockStorage::tail_checksum`
+pub(crate) const TAIL_CHECKSUM_SIZE: u64 = 64;
+
+// Every block file starts with these bytes, followed by the rest of the file
+// header.
+const FILE_MAGIC: [u8; 4] = *b"DMDB";
+
+/// Version of the format of the block files written by this version of
+/// dumbdb. Version 0 is the format from before block files had a header.
+pub(crate) const FORMAT_VERSION: u32 = 1;
+
+/// Size of the file header; the first entry of a block starts right after it.
+pub(crate) const FILE_HEADER_SIZE: u64 = 16;
+
+/// Is the block which starts with these bytes from before block files had a
+/// header, i.e. in format version 0
+pub(crate) fn is_headerless(start: &[u8]) -> bool {
+    !start.starts_with(&FILE_MAGIC)
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum StorageError {
     #[error("Could not open block file. {0}")]
@@ -54,123 +162,819 @@ pub enum StorageError {
     SerializeBinary(#[from] rmp_serde::encode::Error),
     #[error("Failed to deserialize binary to data. {0}")]
     DeserializeBinary(#[from] rmp_serde::decode::Error),
+    #[error("Failed to decompress data. {0}")]
+    Decompress(#[from] DecompressError),
+    #[error("{0}")]
+    Encryption(#[from] EncryptionError),
+    #[error("Corrupt entry at byte-offset {offset} in block file {path}: checksum mismatch.")]
+    CorruptEntry { path: PathBuf, offset: u64 },
+    #[error("Block file {path} ends with a partially written entry at byte-offset {offset}.")]
+    TornTail { path: PathBuf, offset: u64 },
+    #[error("Segment {segment} of block file {path} not found.")]
+    SegmentNotFound { path: PathBuf, segment: u32 },
+    #[error("Block file {path} is in an older format (version {version}). Migrate the database to open it.")]
+    OutdatedFormat { path: PathBuf, version: u32 },
+    #[error("Block file {path} is in format version {version}, which is not supported by this version of dumbdb.")]
+    UnsupportedFormat { path: PathBuf, version: u32 },
+    #[error("Block file {path} has a corrupt file header.")]
+    CorruptFileHeader { path: PathBuf },
+    #[error("Block file {path} was written for a different table schema.")]
+    SchemaMismatch { path: PathBuf },
+    #[error("Entry at byte-offset {offset} in block file {path} has an integer greater than the largest signed 64-bit integer, which was written before Integer values were made signed. Run `fsck --repair` on the database to remove the records with such integers.")]
+    IntegerOutOfRange { path: PathBuf, offset: u64 },
+}
+
+/// The header at the start of a block file. It has the version of the format
+/// the block is written in, so that a block in a format this version of dumbdb
+/// doesn't know is never misread; and a fingerprint of the schema of the table
+/// the block belongs to.
+///
+/// It is stored as the magic bytes, the format version and the schema
+/// fingerprint, followed by a checksum of all of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct FileHeader {
+    pub(crate) version: u32,
+    pub(crate) schema_fingerprint: u32,
+}
+
+impl FileHeader {
+    /// The header of a block written by this version of dumbdb
+    pub(crate) fn new(schema_fingerprint: u32) -> Self {
+        Self {
+            version: FORMAT_VERSION,
+            schema_fingerprint,
+        }
+    }
+
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(FILE_HEADER_SIZE as usize);
+        buffer.extend_from_slice(&FILE_MAGIC);
+        buffer.extend_from_slice(&self.version.to_le_bytes());
+        buffer.extend_from_slice(&self.schema_fingerprint.to_le_bytes());
+        let checksum = crc32fast::hash(&buffer);
+        buffer.extend_from_slice(&checksum.to_le_bytes());
+        buffer
+    }
+
+    /// Decode the header from the start of a block file (`bytes` may be
+    /// shorter than the header, if the file is). Returns `None` for a block
+    /// from before block files had a header, i.e. one in format version 0.
+    pub(crate) fn decode(path: &Path, bytes: &[u8]) -> Result<Option<Self>, StorageError> {
+        if bytes.get(..FILE_MAGIC.len()) != Some(FILE_MAGIC.as_slice()) {
+            // an old block starts right away with the length prefix of its
+            // first entry, which can't be these bytes (it would be the prefix of
+            // an entry of over a gigabyte)
+            return Ok(None);
+        }
+        let corrupt = || StorageError::CorruptFileHeader {
+            path: path.to_path_buf(),
+        };
+        let header = bytes.get(..FILE_HEADER_SIZE as usize).ok_or_else(corrupt)?;
+        let read_u32 = |at: usize| u32::from_le_bytes(header[at..at + 4].try_into().unwrap());
+        if crc32fast::hash(&header[..12]) != read_u32(12) {
+            return Err(corrupt());
+        }
+        let version = read_u32(4);
+        if version != FORMAT_VERSION {
+            return Err(StorageError::UnsupportedFormat {
+                path: path.to_path_buf(),
+                version,
+            });
+        }
+        Ok(Some(Self {
+            version,
+            schema_fingerprint: read_u32(8),
+        }))
+    }
+}
+
+/// The header of an entry in the block, i.e. the length prefix and the
+/// checksum. The checksum covers the length prefix (and so the flags in it) as
+/// well as the data.
+#[derive(Debug, Clone, Copy)]
+pub struct EntryHeader {
+    data_length: u64,
+    flags: EntryFlags,
+    checksum: Option<u32>,
+}
+
+/// What the data of an entry is
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EntryFlags {
+    /// The entry is a tombstone; its data is the primary key of a deleted tuple
+    pub is_tombstone: bool,
+    /// The data of the entry is compressed
+    pub is_compressed: bool,
+    /// The data of the entry is encrypted; it is compressed (if at all) before
+    /// it is encrypted
+    pub is_encrypted: bool,
+}
+
+/// Number of bytes an entry takes up in a block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntrySize {
+    pub stored: u64,
+    /// Number of bytes the entry would take up, if it wasn't compressed
+    pub uncompressed: u64,
+}
+
+impl EntryHeader {
+    fn new(data: &[u8], flags: EntryFlags) -> Self {
+        let mut header = Self {
+            data_length: data.len() as u64,
+            flags,
+            checksum: Some(0),
+        };
+        header.checksum = Some(header.compute_checksum(data));
+        header
+    }
+
+    fn prefix(&self) -> u64 {
+        let mut prefix = self.data_length;
+        if self.flags.is_tombstone {
+            prefix |= TOMBSTONE_FLAG;
+        }
+        if self.flags.is_compressed {
+            prefix |= COMPRESSED_FLAG;
+        }
+        if self.flags.is_encrypted {
+            prefix |= ENCRYPTED_FLAG;
+        }
+        if self.checksum.is_some() {
+            prefix |= CHECKSUM_FLAG;
+        }
+        prefix
+    }
+
+    // decode the length prefix; the checksum (if any) is read separately
+    fn from_prefix(prefix: u64) -> Self {
+        Self {
+            data_length: prefix & !FLAGS_MASK,
+            flags: EntryFlags {
+                is_tombstone: prefix & TOMBSTONE_FLAG != 0,
+                is_compressed: prefix & COMPRESSED_FLAG != 0,
+                is_encrypted: prefix & ENCRYPTED_FLAG != 0,
+            },
+            checksum: (prefix & CHECKSUM_FLAG != 0).then_some(0),
+        }
+    }
+
+    pub(crate) fn flags(&self) -> EntryFlags {
+        self.flags
+    }
+
+    /// The same header, with other flags; for the data of the entry after it
+    /// is decoded (e.g. decrypted)
+    pub(crate) fn with_flags(self, flags: EntryFlags) -> Self {
+        Self { flags, ..self }
+    }
+
+    fn header_size(&self) -> u64 {
+        match self.checksum {
+            None => LENGTH_PREFIX_SIZE as u64,
+            Some(_) => (LENGTH_PREFIX_SIZE + CHECKSUM_SIZE) as u64,
+        }
+    }
+
+    /// Size of the whole entry (header and data) in the block
+    pub(crate) fn entry_size(&self) -> u64 {
+        self.header_size() + self.data_length
+    }
+
+    // an entry without a checksum can only be taken as it is in a block
+    // without a header
+    fn verify(&self, data: &[u8], is_headerless: bool) -> bool {
+        match self.checksum {
+            None => is_headerless,
+            Some(checksum) => checksum == self.compute_checksum(data),
+        }
+    }
+
+    fn compute_checksum(&self, data: &[u8]) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&self.prefix().to_le_bytes());
+        hasher.update(data);
+        hasher.finalize()
+    }
 }
 
+/// A stream of the entries of a block, as they are stored: the data of every
+/// entry, along with its header.
+pub type EntryDataStream =
+    Pin<Box<dyn Stream<Item = Result<(Vec<u8>, EntryHeader), StorageError>> + Send>>;
+
 impl Block {
     /// Create a new block. Takes a file path, where the data of the block is
-    /// stored on disk.
-    pub fn new(table_path: &Path) -> Result<Self, StorageError> {
+    /// stored on disk, and how writes to it are made durable.
+    pub fn new(table_path: &Path, durability: Durability) -> Result<Self, StorageError> {
         let file = std::fs::OpenOptions::new()
             .append(true)
             .open(table_path)
             .map_err(StorageError::FileOpen)?;
 
+        let group_commit = match durability {
+            Durability::GroupCommit { window_ms } => Some(Arc::new(GroupCommit {
+                window: Duration::from_millis(window_ms),
+                file: Arc::new(file.try_clone()?),
+                written: AtomicU64::new(0),
+                has_leader: AtomicBool::new(false),
+                synced: watch::Sender::new(0),
+                syncs: AtomicU64::new(0),
+                #[cfg(test)]
+                fail_next_sync: AtomicBool::new(false),
+            })),
+            Durability::NoSync | Durability::SyncEachWrite => None,
+        };
+        let read_handle = std::fs::File::open(table_path).map_err(StorageError::FileOpen)?;
+        let mut magic = [0u8; FILE_MAGIC.len()];
+        let is_headerless =
+            read_exact_at(&read_handle, &mut magic, 0).is_err() || is_headerless(&magic);
+
         Ok(Self {
             file_path: table_path.to_path_buf(),
             write_handle: Arc::new(RwLock::new(file.into())),
+            read_handle: Arc::new(read_handle),
+            durability,
+            group_commit,
+            is_headerless,
         })
     }
 
-    /// Read a specific tuple. This offers a O(1) seek to the tuple on the disk.
-    /// Given a byte-offset, seek to that specific offset in the block, and
-    /// return a `Tuple`
-    pub async fn seek_to_offset(&self, offset: u64) -> Result<Tuple, StorageError> {
+    /// Same as `seek_to_offset`, but opens the block file for every read, like
+    /// it was done before blocks kept a read handle. Only kept around to
+    /// compare the two in the benchmarks.
+    #[doc(hidden)]
+    pub async fn seek_to_offset_reopening(&self, offset: u64) -> Result<Tuple, StorageError> {
         // Seek to the correct byte offset
         let mut file = File::open(&self.file_path)
             .await
             .map_err(StorageError::FileOpen)?;
         file.seek(SeekFrom::Start(offset)).await?;
 
-        // Read the length prefix (8 bytes)
+        // Read the length prefix (8 bytes), and the checksum
         let mut length_buf = [0u8; LENGTH_PREFIX_SIZE];
         file.read_exact(&mut length_buf).await?;
-        // .with_context(|| "Internal Error: Could not read length-prefix from file.")?;
-        let tuple_length = u64::from_le_bytes(length_buf);
+        let mut header = EntryHeader::from_prefix(u64::from_le_bytes(length_buf));
+        if header.checksum.is_some() {
+            header.checksum = Some(file.read_u32_le().await?);
+        }
 
-        // Now read the tuple data based on its length
-        let mut data_buf = vec![0u8; tuple_length as usize];
+        // Now read the tuple data based on its length, if the block holds it
+        let block_length = file.metadata().await?.len();
+        if offset.saturating_add(header.entry_size()) > block_length {
+            return Err(StorageError::CorruptEntry {
+                path: self.file_path.clone(),
+                offset,
+            });
+        }
+        let mut data_buf = vec![0u8; header.data_length as usize];
         file.read_exact(&mut data_buf).await?;
+        if !header.verify(&data_buf, self.is_headerless) {
+            return Err(StorageError::CorruptEntry {
+                path: self.file_path.clone(),
+                offset,
+            });
+        }
 
-        deserialize_binary(&data_buf)
+        deserialize_binary(&decode_data(&self.file_path, data_buf, &header)?)
     }
+}
 
-    /// Get an iterator over the block to read tuples in an iterator pattern.
-    /// This uses Rust iterators, so it is memory efficient.
-    pub async fn get_reader(
-        &self,
-    ) -> Result<impl Stream<Item = Result<Tuple, StorageError>>, StorageError> {
-        // this is basically: getStream >>= traverse fst
-        let stream = self.get_reader_with_length().await?;
-        Ok(stream.map(|x| x.map(|(tuple, _length_prefix)| tuple)))
+#[async_trait]
+impl BlockStorage for Block {
+    fn path(&self) -> &Path {
+        &self.file_path
+    }
+
+    fn durability(&self) -> Durability {
+        self.durability
+    }
+
+    fn pending_sync(&self) -> Option<PendingSync> {
+        self.group_commit.as_ref().map(|group_commit| PendingSync {
+            group_commit: group_commit.clone(),
+            seq: group_commit.written.load(Ordering::SeqCst),
+        })
     }
 
-    /// Get an iterator over the block to read tuples along with its byte
-    /// offset, in an iterator pattern. This uses Rust iterators, so it is
-    /// memory efficient.
-    pub async fn get_reader_with_length(
+    async fn write_entry(&self, data: Vec<u8>, flags: EntryFlags) -> Result<u64, StorageError> {
+        // Write the length prefix, the checksum and then the actual data in one
+        // go, so that a crash can only leave a partial entry at the very end
+        let buffer = encode_entry(&data, flags);
+
+        let mut file = self.write_handle.write().await;
+        file.write_all(&buffer).await?;
+        // make sure the data is handed over to the OS, so that it can be read
+        // back
+        file.flush().await?;
+        match self.durability {
+            Durability::NoSync => (),
+            Durability::SyncEachWrite => file.sync_data().await?,
+            // the sync is waited for with `pending_sync`
+            Durability::GroupCommit { .. } => {
+                if let Some(group_commit) = &self.group_commit {
+                    group_commit.written.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        }
+        Ok(buffer.len() as u64)
+    }
+
+    async fn read_entry(&self, offset: u64) -> Result<(Vec<u8>, EntryHeader), StorageError> {
+        // positioned reads on the shared read handle, so there is no cursor to
+        // seek
+        let file = self.read_handle.clone();
+        let path = self.file_path.clone();
+        let is_headerless = self.is_headerless;
+        tokio::task::spawn_blocking(move || {
+            let block_length = file.metadata()?.len();
+            read_entry_at(
+                &path,
+                offset,
+                block_length,
+                is_headerless,
+                |buf, position| read_exact_at(&file, buf, position),
+            )
+        })
+        .await
+        .map_err(std::io::Error::from)?
+    }
+
+    async fn scan_entries(&self, offset: u64) -> Result<EntryDataStream, StorageError> {
+        let mut file = File::open(&self.file_path).await?;
+        let file_length = file.metadata().await?.len();
+        file.seek(SeekFrom::Start(offset)).await?;
+        Ok(stream_entries(
+            BufReader::new(file),
+            self.file_path.clone(),
+            offset,
+            file_length,
+            self.is_headerless,
+        ))
+    }
+
+    async fn truncate(&self, length: u64) -> Result<(), StorageError> {
+        let file = self.write_handle.write().await;
+        file.set_len(length).await?;
+        file.sync_all().await?;
+        Ok(())
+    }
+
+    async fn sync(&self) -> Result<(), StorageError> {
+        let mut file = self.write_handle.write().await;
+        file.flush().await?;
+        file.sync_all().await?;
+        Ok(())
+    }
+
+    async fn tail_checksum(&self, length: u64) -> Result<u32, StorageError> {
+        let start = length.saturating_sub(TAIL_CHECKSUM_SIZE);
+        let mut file = File::open(&self.file_path)
+            .await
+            .map_err(StorageError::FileOpen)?;
+        file.seek(SeekFrom::Start(start)).await?;
+        let mut buffer = vec![0u8; (length - start) as usize];
+        file.read_exact(&mut buffer).await?;
+        Ok(crc32fast::hash(&buffer))
+    }
+}
+
+/// The typed API of a block, on top of the entries of its `BlockStorage`.
+impl<'a> dyn BlockStorage + 'a {
+    /// Read a specific tuple. This offers a O(1) seek to the tuple on the disk.
+    /// Given a byte-offset, seek to that specific offset in the block, and
+    /// return a `Tuple`
+    pub async fn seek_to_offset(&self, offset: u64) -> Result<Tuple, StorageError> {
+        let (data, header) = self.read_entry(offset).await?;
+        let data = decode_data(self.path(), data, &header)?;
+        deserialize_binary(&data)
+            .map_err(|err| check_integer_range(self.path(), offset, &data, err))
+    }
+
+    /// Get an iterator over all the entries (tuples and tombstones) of the
+    /// block, starting at the given byte-offset, along with their size (i.e.
+    /// the number of bytes the entry takes up in the block), in an iterator
+    /// pattern. This uses Rust iterators, so it is memory efficient.
+    ///
+    /// A checksum mismatch results in a `StorageError::CorruptEntry`, except
+    /// when it is the last entry of the block; then, like an entry which is cut
+    /// short by the end of the file, it results in a `StorageError::TornTail`.
+    /// An entry which runs past the end of the file but is followed by valid
+    /// entries has a corrupt length, and is a `StorageError::CorruptEntry`
+    /// too. Both of these end the stream.
+    pub async fn get_entries_from(
         &self,
-    ) -> Result<impl Stream<Item = Result<(Tuple, u64), StorageError>>, StorageError> {
+        offset: u64,
+    ) -> Result<impl Stream<Item = Result<(BlockEntry, EntrySize), StorageError>>, StorageError>
+    {
         // this is basically: getStream >>= traverse deserialize_binary
-        let stream = self.get_stream_with_length().await?;
-        Ok(stream
-            .map(|(data, length)| deserialize_binary(&data).map(|tuple: Tuple| (tuple, length))))
+        let stream = self.scan_entries(offset).await?;
+        let path = self.path().to_path_buf();
+        let mut entry_offset = offset;
+        Ok(stream.map(move |result| {
+            let (data, header) = result?;
+            let offset = entry_offset;
+            entry_offset += header.entry_size();
+            let stored_data_length = data.len() as u64;
+            let data = decode_data(&path, data, &header)?;
+            let size = EntrySize {
+                stored: header.entry_size(),
+                uncompressed: header.entry_size() - stored_data_length + data.len() as u64,
+            };
+            deserialize_entry(&data, &header)
+                .map(|entry| (entry, size))
+                .map_err(|err| check_integer_range(&path, offset, &data, err))
+        }))
     }
 
-    async fn get_stream_with_length(
+    /// Write a `Tuple`, compressed with the given compression (if any), and
+    /// return the number of bytes it takes up in the block. A tuple which
+    /// doesn't get any smaller by compressing it is written uncompressed.
+    pub async fn write(
         &self,
-    ) -> Result<Pin<Box<impl Stream<Item = (Vec<u8>, u64)>>>, StorageError> {
-        let file = File::open(&self.file_path).await?;
-        let reader = BufReader::new(file);
-        // let offset: u64 = 0;
-        // Create a stream that reads the file and yields tuples with their offsets
-        let stream = futures::stream::unfold(reader, |mut reader| async move {
-            // Read the length prefix
-            let mut length_bytes = [0u8; LENGTH_PREFIX_SIZE];
-            match reader.read_exact(&mut length_bytes).await {
-                Err(_e) => {
-                    None // EOF or read error
-                }
-                Ok(_x) => {
-                    // Read the data frame
-                    let length = u64::from_le_bytes(length_bytes); // length of the data from the prefix
-                    let mut buffer = vec![0; length as usize];
-                    if reader.read_exact(&mut buffer).await.is_err() {
-                        return None; // Read error
+        tuple: Tuple,
+        compression: Option<Compression>,
+    ) -> Result<EntrySize, StorageError> {
+        let serialized = serialize_binary(&tuple)?;
+        let uncompressed_length = serialized.len() as u64;
+        let compressed = compression.and_then(|compression| compress(&serialized, compression));
+        let flags = EntryFlags {
+            is_compressed: compressed.is_some(),
+            ..Default::default()
+        };
+        let data = compressed.unwrap_or(serialized);
+        let data_length = data.len() as u64;
+        let stored = self.write_entry(data, flags).await?;
+        Ok(EntrySize {
+            stored,
+            uncompressed: stored - data_length + uncompressed_length,
+        })
+    }
+
+    /// Write a tombstone for the tuple with the given primary key, and return
+    /// the number of bytes it takes up in the block.
+    pub async fn write_tombstone(&self, key: &ColumnValue) -> Result<EntrySize, StorageError> {
+        let serialized = serialize_binary(key)?;
+        let flags = EntryFlags {
+            is_tombstone: true,
+            ..Default::default()
+        };
+        let stored = self.write_entry(serialized, flags).await?;
+        Ok(EntrySize {
+            stored,
+            uncompressed: stored,
+        })
+    }
+
+    /// Write a record of any serializable type, and return the number of
+    /// bytes it takes up in the block. This lets other append-only files of
+    /// the database (e.g. the write-ahead log) use the same checksummed,
+    /// length-prefixed format as the tables.
+    pub async fn write_record<T: Serialize + Sync>(&self, record: &T) -> Result<u64, StorageError> {
+        let serialized = serialize_binary_named(record)?;
+        self.write_entry(serialized, EntryFlags::default()).await
+    }
+
+    /// Read the record written by `write_record` at the given byte-offset.
+    pub async fn read_record<T: DeserializeOwned>(&self, offset: u64) -> Result<T, StorageError> {
+        let (data, header) = self.read_entry(offset).await?;
+        deserialize_binary(&decode_data(self.path(), data, &header)?)
+    }
+
+    /// Read all the records written by `write_record`, along with their
+    /// length. Errors are reported the same way as in
+    /// `get_entries_from`.
+    pub async fn get_records<T: DeserializeOwned>(
+        &self,
+    ) -> Result<impl Stream<Item = Result<(T, u64), StorageError>>, StorageError> {
+        let stream = self.scan_entries(FILE_HEADER_SIZE).await?;
+        let path = self.path().to_path_buf();
+        Ok(stream.map(move |result| {
+            let (data, header) = result?;
+            let data = decode_data(&path, data, &header)?;
+            deserialize_binary(&data).map(|record| (record, header.entry_size()))
+        }))
+    }
+}
+
+impl GroupCommit {
+    // wait until the write with the given sequence number is synced to disk
+    async fn wait_for_sync(&self, seq: u64) -> Result<(), StorageError> {
+        let mut synced = self.synced.subscribe();
+        loop {
+            if *synced.borrow_and_update() >= seq {
+                return Ok(());
+            }
+            let is_leader = self
+                .has_leader
+                .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok();
+            if is_leader {
+                // wait for other writes to come in, and sync all of them
+                tokio::time::sleep(self.window).await;
+                let synced_upto = self.written.load(Ordering::SeqCst);
+                let file = self.file.clone();
+                let result = tokio::task::spawn_blocking(move || file.sync_data())
+                    .await
+                    .map_err(std::io::Error::other)
+                    .and_then(|result| result);
+                #[cfg(test)]
+                let result = match self.fail_next_sync.swap(false, Ordering::SeqCst) {
+                    true => Err(std::io::Error::other("injected sync failure")),
+                    false => result,
+                };
+                self.has_leader.store(false, Ordering::SeqCst);
+                match result {
+                    Ok(()) => {
+                        self.syncs.fetch_add(1, Ordering::SeqCst);
+                        self.synced
+                            .send_modify(|synced| *synced = (*synced).max(synced_upto))
+                    }
+                    Err(err) => {
+                        // wake up the waiting writers, so that one of them
+                        // retries the sync
+                        self.synced.send_modify(|_| ());
+                        return Err(err.into());
                     }
-                    Some(((buffer, length), (reader)))
                 }
+            } else {
+                // wait for the leader to sync; the sender lives as long as
+                // self, so this can't fail
+                let _ = synced.changed().await;
             }
-        });
-        Ok(Box::pin(stream))
+        }
     }
+}
 
-    /// Write a `Tuple` and return the length of data written.
-    pub async fn write(&mut self, tuple: Tuple) -> Result<u64, StorageError> {
-        let serialized = serialize_binary(&tuple)?;
-        let length = serialized.len() as u64;
-        self.write_to_file(length.to_le_bytes(), serialized).await?;
-        Ok(length)
+impl PendingSync {
+    /// Wait until the entries written to the block before this was taken are
+    /// synced to disk.
+    pub(crate) async fn wait(self) -> Result<(), StorageError> {
+        self.group_commit.wait_for_sync(self.seq).await
     }
 
-    // write binary data to file
-    async fn write_to_file(
-        &mut self,
-        length_bytes: [u8; 8],
-        data: Vec<u8>,
-    ) -> Result<(), StorageError> {
-        let mut file = self.write_handle.write().await;
-        // Write the length prefix and then the actual data
-        file.write_all(&length_bytes).await?;
-        file.write_all(&data).await?;
-        // file.flush().await?;
-        // file.sync_all().await?;
-        Ok(())
+    /// Number of times the block was synced by its group commit
+    #[cfg(test)]
+    pub(crate) fn syncs(&self) -> u64 {
+        self.group_commit.syncs.load(Ordering::SeqCst)
     }
+
+    /// Make the next sync of the block fail
+    #[cfg(test)]
+    pub(crate) fn fail_next_sync(&self) {
+        self.group_commit
+            .fail_next_sync
+            .store(true, Ordering::SeqCst);
+    }
+}
+
+// state of the stream reading a block
+struct StreamState<R> {
+    reader: R,
+    path: PathBuf,
+    // byte-offset of the next entry
+    offset: u64,
+    // length of the file when the stream was opened; anything written after
+    // that is not read
+    file_length: u64,
+    is_headerless: bool,
+    done: bool,
 }
 
-pub fn calculate_new_offset(tuple_length: u64, current_offset: u64) -> u64 {
-    // new offset = current offset + length prefix + length of the tuple
-    current_offset + LENGTH_PREFIX_SIZE as u64 + tuple_length
+impl<R: AsyncRead + Unpin> StreamState<R> {
+    // read the next entry; returns None at the end of the file
+    async fn read_entry(&mut self) -> Result<Option<(Vec<u8>, EntryHeader)>, StorageError> {
+        if self.offset >= self.file_length {
+            return Ok(None);
+        }
+        if self.offset + LENGTH_PREFIX_SIZE as u64 > self.file_length {
+            return Err(self.torn_tail());
+        }
+        // Read the length prefix, and the checksum
+        let mut length_bytes = [0u8; LENGTH_PREFIX_SIZE];
+        self.reader.read_exact(&mut length_bytes).await?;
+        let mut header = EntryHeader::from_prefix(u64::from_le_bytes(length_bytes));
+        let entry_end = self.offset.saturating_add(header.entry_size());
+        if entry_end > self.file_length {
+            return Err(self.past_end().await);
+        }
+        if header.checksum.is_some() {
+            header.checksum = Some(self.reader.read_u32_le().await?);
+        }
+        // Read the data frame
+        let mut buffer = vec![0; header.data_length as usize];
+        self.reader.read_exact(&mut buffer).await?;
+        if !header.verify(&buffer, self.is_headerless) {
+            // a bad last entry is a write that didn't complete; otherwise the
+            // block is corrupt
+            return Err(if entry_end == self.file_length {
+                self.torn_tail()
+            } else {
+                StorageError::CorruptEntry {
+                    path: self.path.clone(),
+                    offset: self.offset,
+                }
+            });
+        }
+        self.offset = entry_end;
+        Ok(Some((buffer, header)))
+    }
+
+    fn torn_tail(&self) -> StorageError {
+        StorageError::TornTail {
+            path: self.path.clone(),
+            offset: self.offset,
+        }
+    }
+
+    // the error for an entry which runs past the end of the file. It is the
+    // last write, which didn't complete, unless a valid entry follows it; then
+    // its length prefix is corrupt, and cutting it off would lose the entries
+    // after it.
+    async fn past_end(&mut self) -> StorageError {
+        let rest_length = self.file_length - self.offset - LENGTH_PREFIX_SIZE as u64;
+        let mut rest = vec![];
+        if let Err(err) = (&mut self.reader)
+            .take(rest_length)
+            .read_to_end(&mut rest)
+            .await
+        {
+            return err.into();
+        }
+        if (0..rest.len()).any(|start| is_valid_entry(&rest[start..])) {
+            StorageError::CorruptEntry {
+                path: self.path.clone(),
+                offset: self.offset,
+            }
+        } else {
+            self.torn_tail()
+        }
+    }
+}
+
+// do the bytes start with a whole entry, which has a checksum and matches it
+fn is_valid_entry(bytes: &[u8]) -> bool {
+    let Some(prefix) = bytes.get(..LENGTH_PREFIX_SIZE) else {
+        return false;
+    };
+    let mut header = EntryHeader::from_prefix(u64::from_le_bytes(prefix.try_into().unwrap()));
+    if header.checksum.is_none() || header.entry_size() > bytes.len() as u64 {
+        return false;
+    }
+    let checksum = &bytes[LENGTH_PREFIX_SIZE..LENGTH_PREFIX_SIZE + CHECKSUM_SIZE];
+    header.checksum = Some(u32::from_le_bytes(checksum.try_into().unwrap()));
+    header.verify(
+        &bytes[header.header_size() as usize..header.entry_size() as usize],
+        false,
+    )
+}
+
+/// Stream the entries of a block from the reader, which is at the given
+/// byte-offset of the block. `length` is the length of the block, and
+/// `is_headerless` whether it is from before block files had a header.
+pub(crate) fn stream_entries<R>(
+    reader: R,
+    path: PathBuf,
+    offset: u64,
+    length: u64,
+    is_headerless: bool,
+) -> EntryDataStream
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    let state = StreamState {
+        reader,
+        path,
+        offset,
+        file_length: length,
+        is_headerless,
+        done: false,
+    };
+    // Create a stream that reads the block and yields the data of each entry
+    let stream = futures::stream::unfold(state, |mut state| async move {
+        if state.done {
+            return None;
+        }
+        match state.read_entry().await {
+            Ok(None) => None, // EOF
+            Ok(Some(item)) => Some((Ok(item), state)),
+            Err(err) => {
+                // nothing can be read beyond an invalid entry
+                state.done = true;
+                Some((Err(err), state))
+            }
+        }
+    });
+    Box::pin(stream)
+}
+
+/// Write a single record to a file, in the same format as an entry of a block.
+/// The file is replaced atomically, and sealed (see `Backend::seal_file`).
+pub fn write_record_file<T: Serialize>(
+    backend: &Backend,
+    path: &Path,
+    record: &T,
+) -> Result<(), StorageError> {
+    let buffer = encode_entry(&serialize_binary_named(record)?, EntryFlags::default());
+    backend.replace_file(path, &backend.seal_file(path, buffer))?;
+    Ok(())
+}
+
+/// Read a record written by `write_record_file`. Returns `None` if the file
+/// doesn't exist, or doesn't contain a valid record.
+pub fn read_record_file<T: DeserializeOwned>(
+    backend: &Backend,
+    path: &Path,
+) -> Result<Option<T>, StorageError> {
+    let buffer = match backend.read_file(path) {
+        Ok(buffer) => buffer,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let buffer = backend.unseal_file(path, buffer)?;
+    let Some(prefix) = buffer.get(..LENGTH_PREFIX_SIZE) else {
+        return Ok(None);
+    };
+    let mut header = EntryHeader::from_prefix(u64::from_le_bytes(prefix.try_into().unwrap()));
+    if header.entry_size() != buffer.len() as u64 {
+        return Ok(None);
+    }
+    if header.checksum.is_some() {
+        let checksum = &buffer[LENGTH_PREFIX_SIZE..LENGTH_PREFIX_SIZE + CHECKSUM_SIZE];
+        header.checksum = Some(u32::from_le_bytes(checksum.try_into().unwrap()));
+    }
+    let data = &buffer[header.header_size() as usize..];
+    if !header.verify(data, false) {
+        return Ok(None);
+    }
+    Ok(deserialize_binary(data).ok())
+}
+
+/// Read the data and the header of the entry at the given byte-offset of a
+/// block of the given length, with positioned reads (i.e. `read_exact_at`
+/// reads into the buffer from the given byte-offset).
+pub(crate) fn read_entry_at(
+    path: &Path,
+    offset: u64,
+    block_length: u64,
+    is_headerless: bool,
+    read_exact_at: impl Fn(&mut [u8], u64) -> std::io::Result<()>,
+) -> Result<(Vec<u8>, EntryHeader), StorageError> {
+    // Read the length prefix (8 bytes), and the checksum
+    let mut length_buf = [0u8; LENGTH_PREFIX_SIZE];
+    read_exact_at(&mut length_buf, offset)?;
+    let mut header = EntryHeader::from_prefix(u64::from_le_bytes(length_buf));
+    if header.checksum.is_some() {
+        let mut checksum_buf = [0u8; CHECKSUM_SIZE];
+        read_exact_at(&mut checksum_buf, offset + LENGTH_PREFIX_SIZE as u64)?;
+        header.checksum = Some(u32::from_le_bytes(checksum_buf));
+    }
+
+    // Now read the tuple data based on its length; a corrupt length must not
+    // make us allocate more than the block holds
+    let entry_end = offset.checked_add(header.entry_size());
+    if entry_end.is_none_or(|entry_end| entry_end > block_length) {
+        return Err(StorageError::CorruptEntry {
+            path: path.to_path_buf(),
+            offset,
+        });
+    }
+    let mut data_buf = vec![0u8; header.data_length as usize];
+    read_exact_at(&mut data_buf, offset + header.header_size())?;
+    if !header.verify(&data_buf, is_headerless) {
+        return Err(StorageError::CorruptEntry {
+            path: path.to_path_buf(),
+            offset,
+        });
+    }
+    Ok((data_buf, header))
+}
+
+#[cfg(unix)]
+fn read_exact_at(file: &std::fs::File, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &std::fs::File, mut buf: &mut [u8], mut offset: u64) -> std::io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+pub fn calculate_new_offset(entry_length: u64, current_offset: u64) -> u64 {
+    // new offset = current offset + length of the entry (header and the tuple)
+    current_offset + entry_length
 }
 
 fn serialize_binary<T>(value: &T) -> Result<Vec<u8>, StorageError>
@@ -182,6 +986,85 @@ where
     Ok(data)
 }
 
+/// The length prefix, the checksum and the data of an entry
+pub(crate) fn encode_entry(data: &[u8], flags: EntryFlags) -> Vec<u8> {
+    let header = EntryHeader::new(data, flags);
+    let mut buffer = Vec::with_capacity(header.entry_size() as usize);
+    buffer.extend_from_slice(&header.prefix().to_le_bytes());
+    if let Some(checksum) = header.checksum {
+        buffer.extend_from_slice(&checksum.to_le_bytes());
+    }
+    buffer.extend_from_slice(data);
+    buffer
+}
+
+// the data of an entry, decompressed if it is compressed. Encrypted entries
+// are decrypted by the block (see `EncryptedBlock`), so an entry which is still
+// encrypted is of a block opened without the encryption key.
+fn decode_data(path: &Path, data: Vec<u8>, header: &EntryHeader) -> Result<Vec<u8>, StorageError> {
+    if header.flags.is_encrypted {
+        return Err(EncryptionError::KeyRequired {
+            path: path.to_path_buf(),
+        }
+        .into());
+    }
+    if header.flags.is_compressed {
+        Ok(decompress(&data)?)
+    } else {
+        Ok(data)
+    }
+}
+
+/// Decode the data of an entry, as read from a `BlockStorage`, into a tuple or
+/// a tombstone.
+pub(crate) fn decode_entry(
+    path: &Path,
+    data: Vec<u8>,
+    header: &EntryHeader,
+) -> Result<BlockEntry, StorageError> {
+    deserialize_entry(&decode_data(path, data, header)?, header)
+}
+
+fn deserialize_entry(data: &[u8], header: &EntryHeader) -> Result<BlockEntry, StorageError> {
+    if header.flags.is_tombstone {
+        deserialize_binary(data).map(BlockEntry::Tombstone)
+    } else {
+        deserialize_binary(data).map(BlockEntry::Tuple)
+    }
+}
+
+// `Integer` values used to be unsigned, so entries written before they were
+// made signed can have integers which don't fit; report those as such, instead
+// of as entries which can't be deserialized
+fn check_integer_range(path: &Path, offset: u64, data: &[u8], err: StorageError) -> StorageError {
+    fn is_out_of_range(value: &serde_json::Value) -> bool {
+        match value {
+            serde_json::Value::Number(number) => number.as_u64() > Some(i64::MAX as u64),
+            serde_json::Value::Array(values) => values.iter().any(is_out_of_range),
+            serde_json::Value::Object(values) => values.values().any(is_out_of_range),
+            _ => false,
+        }
+    }
+    match rmp_serde::from_slice::<serde_json::Value>(data) {
+        Ok(value) if is_out_of_range(&value) => StorageError::IntegerOutOfRange {
+            path: path.to_path_buf(),
+            offset,
+        },
+        _ => err,
+    }
+}
+
+// unlike `serialize_binary`, this keeps the field names of structs, so that
+// fields which are skipped when serializing can be read back
+fn serialize_binary_named<T>(value: &T) -> Result<Vec<u8>, StorageError>
+where
+    T: Serialize,
+{
+    let mut data = Vec::new();
+    value.serialize(&mut Serializer::new(&mut data).with_struct_map())?;
+    Ok(data)
+}
+
 fn deserialize_binary<T>(value: &[u8]) -> Result<T, StorageError>
 where
     T: DeserializeOwned,
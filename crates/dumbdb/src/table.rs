@@ -1,40 +1,162 @@
 use std::{
     collections::HashMap,
     path::{Path, PathBuf},
+    pin::Pin,
     sync::Arc,
 };
 
-use futures::StreamExt;
-use tokio::sync::Mutex;
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    query::types::{ColumnValue, TableName},
-    storage::{calculate_new_offset, Block, StorageError, Tuple},
+    backend::Backend,
+    blob::{BlobError, BlobStore},
+    columnar::ScanFilter,
+    lsm::{LsmError, LsmTree},
+    query::types::{ColumnValue, Durability, StorageEngine, TableName},
+    segment::{EntryPosition, SegmentId, SegmentedBlock},
+    storage::{
+        read_record_file, write_record_file, BlockEntry, PendingSync, StorageError, Tuple,
+        FILE_HEADER_SIZE,
+    },
     TableDefinition,
 };
 
+/// Suffix of the hint file of a table, which is stored next to its block.
+const HINT_FILE_SUFFIX: &str = "hint";
+
 /// An abstraction that the query engine can query the data against. That is the
 /// query engine doesn't deal with the storage layer. This layer provides an
 /// abstraction over the storage layer. This is where we implement indexing.
 #[derive(Debug, Clone)]
 pub(crate) struct TableBuffer {
     /// The block backing this table
-    pub(crate) block: Block,
+    pub(crate) block: SegmentedBlock,
     /// The table index for O(1) lookups
     pub(crate) index: Index,
     /// Column index of the primary key
     pub(crate) pk_position: usize,
+    /// Set if a partially written entry was cut off the end of the block, when
+    /// it was opened
+    pub(crate) recovery: Option<TailRecovery>,
+    /// Lengths of the segments covered by the hint file, if there is one
+    pub(crate) hinted_segments: Option<Vec<(SegmentId, u64)>>,
+    /// Values stored out of line
+    pub(crate) blobs: BlobStore,
 }
 
-/// The index structure. It is a map of primary key to byte-offset in the block.
+/// Options of the storage of tables, which are set for the whole database.
+#[derive(Debug, Clone)]
+pub(crate) struct TableOptions {
+    /// Where the tables' files are kept
+    pub(crate) backend: Backend,
+    /// Durability mode of tables which don't override it
+    pub(crate) durability: Durability,
+    /// Size (in bytes) the active segment of a table's block is rolled over at
+    pub(crate) max_segment_bytes: u64,
+    /// Size (in bytes) the memtable of an LSM table is flushed at
+    pub(crate) memtable_bytes: u64,
+    /// Size (in bytes) above which `Text` values are stored out of line
+    pub(crate) blob_threshold_bytes: Option<u64>,
+}
+
+/// The storage of a table, in the engine set in its definition. The query
+/// engine reads and writes tables through this.
+#[derive(Debug, Clone)]
+pub(crate) enum TableStorage {
+    Log(TableBuffer),
+    Lsm(LsmTree),
+}
+
+/// A stream of the live tuples of a table
+pub(crate) type TupleStream<'a> =
+    Pin<Box<dyn Stream<Item = Result<Tuple, TableBufferError>> + Send + 'a>>;
+
+/// The index of a table saved to a file (a "hint" file, as in Bitcask), so that
+/// it doesn't have to be rebuilt from the entire block when the table is
+/// opened. Only the part of the block written after the hint is read.
+#[derive(Debug, Serialize, Deserialize)]
+struct Hint {
+    /// The segments the index covers
+    segments: Vec<HintedSegment>,
+    dead_bytes: u64,
+    /// Primary key and position of every live tuple
+    entries: Vec<(ColumnValue, EntryPosition)>,
+    /// Id and length of the active blob file
+    #[serde(default)]
+    blob_file: Option<(u64, u64)>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct HintedSegment {
+    segment: SegmentId,
+    /// Length of the segment the index covers
+    length: u64,
+    /// `BlockStorage::tail_checksum` of the covered part of the segment; the hint is
+    /// not used if the segment doesn't match it
+    tail_checksum: u32,
+    /// Bytes saved by compression in the covered part of the segment
+    #[serde(default)]
+    saved_bytes: u64,
+}
+
+/// Report of a partially written entry (e.g. from a crash in the middle of a
+/// write) which was cut off the end of a table's block when it was opened.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TailRecovery {
+    pub table_name: TableName,
+    /// The segment of the table's block the entry was cut off from
+    pub segment: u32,
+    /// Length of the segment up to the last valid entry
+    pub valid_length: u64,
+    /// Number of bytes that were cut off
+    pub truncated_bytes: u64,
+}
+
+/// The index structure. It is a map of primary key to the position of the live
+/// version of the tuple in the block.
 #[derive(Debug, Clone)]
 pub struct Index {
-    /// Byte-offset based index.
-    pub(crate) index: HashMap<ColumnValue, u64>,
-    /// The next byte offset we are pointing to. When a write comes, this value
-    /// will be used for that tuple. The byte offset is kept behind a lock, so
-    /// as to perform thread-safe updates.
-    pub(crate) byte_offset: Arc<Mutex<u64>>,
+    /// Position based index.
+    pub(crate) index: HashMap<ColumnValue, EntryPosition>,
+    /// Number of bytes in the block which are not reachable from the index
+    /// anymore, i.e. old versions of tuples, deleted tuples and tombstones.
+    /// Compaction reclaims these.
+    pub(crate) dead_bytes: u64,
+}
+
+/// Statistics about the storage of a table
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TableStats {
+    /// Number of live records
+    pub rows: usize,
+    /// Size of the table's block (i.e. all of its segments), in bytes
+    pub total_bytes: u64,
+    /// Number of segment files of the table's block; the number of SSTables,
+    /// for an LSM table
+    pub segments: usize,
+    /// Bytes taken up by old versions of tuples, deleted tuples and tombstones
+    pub dead_bytes: u64,
+    /// Size the table's block would have if its tuples weren't compressed, in
+    /// bytes
+    pub uncompressed_bytes: u64,
+    /// Size of the table's blob files (see `DatabaseOptions::blob_threshold_bytes`),
+    /// in bytes; not part of `total_bytes`
+    pub blob_bytes: u64,
+    /// How many times smaller the table's block is for being compressed, i.e.
+    /// `uncompressed_bytes / total_bytes`
+    pub compression_ratio: f64,
+}
+
+impl TableStats {
+    /// Fraction of the block which is dead, and can be reclaimed by compaction
+    pub fn dead_ratio(&self) -> f64 {
+        if self.total_bytes == 0 {
+            0.0
+        } else {
+            self.dead_bytes as f64 / self.total_bytes as f64
+        }
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -45,22 +167,47 @@ pub enum TableBufferError {
     PrimaryKeyNotInTuple,
     #[error("Internal Storage Engine Error: {0}")]
     StorageError(#[from] StorageError),
+    #[error("Table '{table}' can't be opened. {error}")]
+    IntegerOutOfRange {
+        table: TableName,
+        error: StorageError,
+    },
+    #[error("Internal Storage Engine Error: {0}")]
+    LsmError(#[from] LsmError),
+    #[error("Internal Storage Engine Error: {0}")]
+    BlobError(#[from] BlobError),
+    #[error("Internal Storage Engine Error: {0}")]
+    FileOperation(#[from] std::io::Error),
 }
 
 impl Index {
-    fn new() -> Self {
+    pub(crate) fn new() -> Self {
         Self {
             index: HashMap::new(),
-            byte_offset: Arc::new(Mutex::new(0)),
+            dead_bytes: 0,
         }
     }
-    pub(crate) fn get(&self, key: &ColumnValue) -> Option<&u64> {
+    pub(crate) fn get(&self, key: &ColumnValue) -> Option<&EntryPosition> {
         self.index.get(key)
     }
-    async fn update(&mut self, key: ColumnValue, tuple_length: u64) {
-        let mut curr_offset = self.byte_offset.lock().await;
-        self.index.insert(key, *curr_offset);
-        *curr_offset = calculate_new_offset(tuple_length, *curr_offset);
+    pub(crate) fn update(&mut self, key: ColumnValue, position: EntryPosition) {
+        // the previous version of the tuple, if any, is dead now
+        if let Some(old_position) = self.index.insert(key, position) {
+            self.dead_bytes += old_position.length;
+        }
+    }
+    pub(crate) fn remove(&mut self, key: &ColumnValue, tombstone_length: u64) {
+        // both the deleted tuple and the tombstone itself are dead
+        if let Some(old_position) = self.index.remove(key) {
+            self.dead_bytes += old_position.length;
+        }
+        self.dead_bytes += tombstone_length;
+    }
+    fn from_hint(hint: Hint) -> Self {
+        Self {
+            index: hint.entries.into_iter().collect(),
+            dead_bytes: hint.dead_bytes,
+        }
     }
 }
 
@@ -68,6 +215,7 @@ impl TableBuffer {
     pub async fn new(
         table_definition: &TableDefinition,
         directory_path: &Path,
+        options: &TableOptions,
     ) -> Result<Self, TableBufferError> {
         let table_path = get_table_path_(directory_path, &table_definition.name);
 
@@ -77,14 +225,33 @@ impl TableBuffer {
             .position(|col_def| col_def.name == table_definition.primary_key)
             .ok_or(TableBufferError::PrimaryKeyNotInDefn)?;
 
-        let block = Block::new(&table_path)?;
+        let durability = table_definition.durability.unwrap_or(options.durability);
+        let block = SegmentedBlock::open(
+            &options.backend,
+            &table_path,
+            durability,
+            table_definition.schema_fingerprint(),
+            table_definition.compression,
+            options.max_segment_bytes,
+        )?;
+        let blobs = BlobStore::open(
+            &options.backend,
+            &table_path,
+            durability,
+            table_definition.schema_fingerprint(),
+            table_definition.compression,
+            options.blob_threshold_bytes,
+        )?;
 
         let mut table = Self {
             block,
             pk_position: key_position,
             index: Index::new(),
+            recovery: None,
+            hinted_segments: None,
+            blobs,
         };
-        table.build_index().await?;
+        table.load_index(&table_definition.name).await?;
         Ok(table)
     }
 
@@ -94,8 +261,8 @@ impl TableBuffer {
         scan_file: bool,
     ) -> Result<Option<Tuple>, TableBufferError> {
         // read from the index; get the cursor
-        if let Some(offset) = self.index.get(&key) {
-            let tuple = self.block.seek_to_offset(*offset).await?;
+        if let Some(position) = self.index.get(&key) {
+            let tuple = self.block.seek_to_offset(position).await?;
             Ok(Some(tuple))
         // if not found in the index
         } else {
@@ -115,14 +282,58 @@ impl TableBuffer {
         }
     }
 
-    pub async fn write(&mut self, key: ColumnValue, tuple: Tuple) -> Result<(), TableBufferError> {
+    pub async fn write(
+        &mut self,
+        key: ColumnValue,
+        mut tuple: Tuple,
+    ) -> Result<(), TableBufferError> {
+        // long values go to a blob file first, so that the tuple never refers
+        // to a value which is not written yet
+        self.blobs.store(&mut tuple, self.pk_position).await?;
         // write the tuple
-        let length_bytes = self.block.write(tuple).await?;
+        let position = self.block.write(tuple).await?;
+        // update the index
+        self.index.update(key, position);
+        Ok(())
+    }
+
+    /// Delete the tuple with the given key, by writing a tombstone for it.
+    pub async fn delete(&mut self, key: ColumnValue) -> Result<(), TableBufferError> {
+        // write the tombstone
+        let position = self.block.write_tombstone(&key).await?;
         // update the index
-        self.index.update(key, length_bytes).await;
+        self.index.remove(&key, position.length);
         Ok(())
     }
 
+    /// Stream the live tuples of this table, i.e. the latest version of every
+    /// key present in the index. Older versions of a tuple, left behind in the
+    /// block by updates, deleted tuples and tombstones are skipped.
+    pub async fn scan(
+        &self,
+    ) -> Result<impl Stream<Item = Result<Tuple, TableBufferError>> + '_, TableBufferError> {
+        let stream = self.block.get_entries().await?;
+        let stream = stream.filter_map(move |result| {
+            let item = match result {
+                Err(err) => Some(Err(err.into())),
+                Ok((BlockEntry::Tombstone(_), _)) => None,
+                Ok((BlockEntry::Tuple(tuple), position)) => {
+                    self.is_live(&tuple, &position).then_some(Ok(tuple))
+                }
+            };
+            futures::future::ready(item)
+        });
+        Ok(stream)
+    }
+
+    /// Is the tuple stored at the given position the latest version of its key
+    fn is_live(&self, tuple: &Tuple, position: &EntryPosition) -> bool {
+        tuple[self.pk_position]
+            .as_ref()
+            .and_then(|key| self.index.get(key))
+            .is_some_and(|live_position| live_position == position)
+    }
+
     /// Does this table's index contains the given key
     pub fn contains_key(&self, key: &ColumnValue) -> bool {
         self.index.index.contains_key(key)
@@ -132,44 +343,419 @@ impl TableBuffer {
         self.index.index.len()
     }
 
+    pub async fn stats(&self) -> TableStats {
+        let total_bytes = self.block.total_bytes();
+        let uncompressed_bytes = self.block.uncompressed_bytes();
+        TableStats {
+            rows: self.size(),
+            total_bytes,
+            segments: self.block.segments.len(),
+            dead_bytes: self.index.dead_bytes,
+            uncompressed_bytes,
+            blob_bytes: self.blobs.total_bytes(),
+            compression_ratio: if total_bytes == 0 {
+                1.0
+            } else {
+                uncompressed_bytes as f64 / total_bytes as f64
+            },
+        }
+    }
+
     // scan the entire block to get an item
     async fn scan_block_get_item(
         &self,
         user_key: ColumnValue,
     ) -> Result<Option<Tuple>, TableBufferError> {
-        let mut stream = self.block.get_reader().await?;
-        while let Some(tuple) = stream.next().await {
-            let tuple = tuple?;
-            let key = tuple[self.pk_position]
-                .clone()
-                .ok_or(TableBufferError::PrimaryKeyNotInTuple)?;
-            if key == user_key {
-                return Ok(Some(tuple));
+        // a key can have multiple versions in the block, or be deleted by a
+        // tombstone; the last entry wins
+        let mut found = None;
+        let stream = self.block.get_entries().await?;
+        futures::pin_mut!(stream);
+        while let Some(entry) = stream.next().await {
+            match entry?.0 {
+                BlockEntry::Tuple(tuple) => {
+                    let key = tuple[self.pk_position]
+                        .clone()
+                        .ok_or(TableBufferError::PrimaryKeyNotInTuple)?;
+                    if key == user_key {
+                        found = Some(tuple);
+                    }
+                }
+                BlockEntry::Tombstone(key) => {
+                    if key == user_key {
+                        found = None;
+                    }
+                }
             }
         }
-        Ok(None)
+        Ok(found)
     }
 
-    // build the index during initialization by reading through the entire block
-    async fn build_index(&mut self) -> Result<(), TableBufferError> {
-        let mut stream = self.block.get_reader_with_length().await?;
-        while let Some(result) = stream.next().await {
-            let (tuple, length) = result?;
-            let index_key = tuple[self.pk_position]
-                .clone()
-                .ok_or(TableBufferError::PrimaryKeyNotInTuple)?;
-            // Calling the index.update function in this tight loop might be
-            // slow; as we obtain the lock, update the data and release the lock
-            // inside this tight loop. But it's fine until this practically
-            // becomes a problem. Then we can optimize it.
-            self.index.update(index_key, length).await;
+    /// Save the index to the hint file. The block must be synced before this.
+    pub(crate) async fn write_hint(&mut self) -> Result<(), TableBufferError> {
+        let segment_lengths: Vec<_> = self
+            .block
+            .segments
+            .iter()
+            .map(|(segment_id, segment)| (*segment_id, segment.length))
+            .collect();
+        if self.hinted_segments.as_ref() == Some(&segment_lengths) {
+            return Ok(());
+        }
+        let mut segments = vec![];
+        for (segment_id, length) in &segment_lengths {
+            let segment = self.block.get_segment(*segment_id)?;
+            segments.push(HintedSegment {
+                segment: *segment_id,
+                length: *length,
+                tail_checksum: segment.block.tail_checksum(*length).await?,
+                saved_bytes: segment.saved_bytes,
+            });
+        }
+        let hint = Hint {
+            segments,
+            dead_bytes: self.index.dead_bytes,
+            entries: self
+                .index
+                .index
+                .iter()
+                .map(|(key, position)| (key.clone(), *position))
+                .collect(),
+            blob_file: self.blobs.hint(),
+        };
+        write_record_file(
+            self.block.backend(),
+            &get_hint_path(self.block.path()),
+            &hint,
+        )?;
+        self.hinted_segments = Some(segment_lengths);
+        Ok(())
+    }
+
+    /// Remove the hint file. This must happen before any segment of the block
+    /// is replaced.
+    pub(crate) fn remove_hint(&mut self) -> Result<(), std::io::Error> {
+        remove_hint_file(self.block.backend(), self.block.path())?;
+        self.hinted_segments = None;
+        Ok(())
+    }
+
+    // load the index from the hint file, if there is a valid one, and read the
+    // rest of the block; otherwise build the index from the entire block
+    async fn load_index(&mut self, table_name: &TableName) -> Result<(), TableBufferError> {
+        let hint_path = get_hint_path(self.block.path());
+        let mut start_offsets = HashMap::new();
+        let mut hinted_blob_file = None;
+        if let Some(hint) = read_record_file::<Hint>(self.block.backend(), &hint_path)? {
+            if self.is_valid_hint(&hint).await? {
+                hinted_blob_file = hint.blob_file;
+                let segment_lengths: Vec<_> = hint
+                    .segments
+                    .iter()
+                    .map(|segment| (segment.segment, segment.length))
+                    .collect();
+                start_offsets.extend(segment_lengths.iter().copied());
+                self.hinted_segments = Some(segment_lengths);
+                for hinted in &hint.segments {
+                    if let Some(segment) = self.block.segments.get_mut(&hinted.segment) {
+                        segment.saved_bytes = hinted.saved_bytes;
+                    }
+                }
+                self.index = Index::from_hint(hint);
+            }
+        }
+        self.blobs.recover(hinted_blob_file).await?;
+        self.build_index(table_name, &start_offsets).await
+    }
+
+    // does the hint file match the segments of the block
+    async fn is_valid_hint(&self, hint: &Hint) -> Result<bool, TableBufferError> {
+        let Some(last_hinted) = hint.segments.last() else {
+            return Ok(false);
+        };
+        for hinted in &hint.segments {
+            let Some(segment) = self.block.segments.get(&hinted.segment) else {
+                return Ok(false);
+            };
+            if hinted.length > segment.length
+                || segment.block.tail_checksum(hinted.length).await? != hinted.tail_checksum
+            {
+                return Ok(false);
+            }
+        }
+        // segments which are not in the hint must be newer than the ones which
+        // are
+        let older_segments = self.block.segments.range(..=last_hinted.segment).count();
+        Ok(older_segments == hint.segments.len())
+    }
+
+    // build the index during initialization by reading through the segments of
+    // the block, starting at the given byte-offset of each segment
+    async fn build_index(
+        &mut self,
+        table_name: &TableName,
+        start_offsets: &HashMap<SegmentId, u64>,
+    ) -> Result<(), TableBufferError> {
+        let active_segment = self.block.active_segment();
+        let segment_ids: Vec<_> = self.block.segments.keys().copied().collect();
+        // bytes saved by compression in the part of each segment which is read
+        let mut saved_bytes: HashMap<SegmentId, u64> = HashMap::new();
+        for segment_id in segment_ids {
+            let offset = start_offsets
+                .get(&segment_id)
+                .copied()
+                .unwrap_or(FILE_HEADER_SIZE);
+            let segment = self.block.get_segment(segment_id)?;
+            let segment_length = segment.length;
+            let mut stream = Box::pin(segment.get_entries_from(segment_id, offset).await?);
+            while let Some(result) = stream.next().await {
+                // Updating the index in this tight loop might be slow; but it's
+                // fine until this practically becomes a problem. Then we can
+                // optimize it.
+                let result = match result {
+                    // the last write didn't complete; cut it off, so that new
+                    // writes are appended after the last valid entry. Only the
+                    // active segment is written to, so anywhere else this means
+                    // the segment is corrupt.
+                    Err(StorageError::TornTail { path, offset }) => {
+                        drop(stream);
+                        if segment_id != active_segment {
+                            return Err(StorageError::CorruptEntry { path, offset }.into());
+                        }
+                        self.block.truncate(segment_id, offset).await?;
+                        self.recovery = Some(TailRecovery {
+                            table_name: table_name.clone(),
+                            segment: segment_id,
+                            valid_length: offset,
+                            truncated_bytes: segment_length - offset,
+                        });
+                        break;
+                    }
+                    Err(error @ StorageError::IntegerOutOfRange { .. }) => {
+                        return Err(TableBufferError::IntegerOutOfRange {
+                            table: table_name.clone(),
+                            error,
+                        });
+                    }
+                    result => result?,
+                };
+                let (entry, position, size) = result;
+                *saved_bytes.entry(segment_id).or_default() += size.uncompressed - size.stored;
+                match entry {
+                    BlockEntry::Tuple(tuple) => {
+                        let index_key = tuple[self.pk_position]
+                            .clone()
+                            .ok_or(TableBufferError::PrimaryKeyNotInTuple)?;
+                        self.index.update(index_key, position);
+                    }
+                    // a tombstone deletes the key from the index
+                    BlockEntry::Tombstone(index_key) => {
+                        self.index.remove(&index_key, position.length);
+                    }
+                }
+            }
+        }
+        for (segment_id, saved_bytes) in saved_bytes {
+            if let Some(segment) = self.block.segments.get_mut(&segment_id) {
+                segment.saved_bytes += saved_bytes;
+            }
         }
         Ok(())
     }
 }
 
+impl TableStorage {
+    pub(crate) async fn new(
+        table_definition: &TableDefinition,
+        directory_path: &Path,
+        options: &TableOptions,
+    ) -> Result<Self, TableBufferError> {
+        match table_definition.engine.unwrap_or_default() {
+            StorageEngine::Log => Ok(Self::Log(
+                TableBuffer::new(table_definition, directory_path, options).await?,
+            )),
+            StorageEngine::Lsm | StorageEngine::Columnar => {
+                let table_path = get_table_path_(directory_path, &table_definition.name);
+                let tree = LsmTree::open(table_definition, &table_path, options).await?;
+                Ok(Self::Lsm(tree))
+            }
+        }
+    }
+
+    /// Get the live version of the tuple with the given key. `scan_file` only
+    /// matters for a log; an LSM tree always looks the key up in its files.
+    pub(crate) async fn get(
+        &self,
+        key: ColumnValue,
+        scan_file: bool,
+    ) -> Result<Option<Tuple>, TableBufferError> {
+        match self {
+            Self::Log(table_buffer) => table_buffer.get(key, scan_file).await,
+            Self::Lsm(tree) => Ok(tree.get(&key).await?),
+        }
+    }
+
+    pub(crate) async fn write(
+        &mut self,
+        key: ColumnValue,
+        tuple: Tuple,
+    ) -> Result<(), TableBufferError> {
+        match self {
+            Self::Log(table_buffer) => table_buffer.write(key, tuple).await,
+            Self::Lsm(tree) => Ok(tree.write(key, tuple).await?),
+        }
+    }
+
+    pub(crate) async fn delete(&mut self, key: ColumnValue) -> Result<(), TableBufferError> {
+        match self {
+            Self::Log(table_buffer) => table_buffer.delete(key).await,
+            Self::Lsm(tree) => Ok(tree.delete(key).await?),
+        }
+    }
+
+    pub(crate) async fn contains_key(&self, key: &ColumnValue) -> Result<bool, TableBufferError> {
+        match self {
+            Self::Log(table_buffer) => Ok(table_buffer.contains_key(key)),
+            Self::Lsm(tree) => Ok(tree.contains_key(key).await?),
+        }
+    }
+
+    /// Stream the live tuples of the table
+    pub(crate) async fn scan(&self) -> Result<TupleStream<'_>, TableBufferError> {
+        match self {
+            Self::Log(table_buffer) => Ok(Box::pin(table_buffer.scan().await?)),
+            Self::Lsm(tree) => Ok(Box::pin(
+                tree.scan(None)
+                    .await?
+                    .map(|result| result.map_err(Into::into)),
+            )),
+        }
+    }
+
+    /// Replace the references to values stored out of line in the tuple with
+    /// the values, for the columns at the positions `needs` is true for. Only
+    /// tables stored in a log have such references.
+    pub(crate) async fn resolve(
+        &self,
+        tuple: &mut Tuple,
+        needs: impl Fn(usize) -> bool,
+    ) -> Result<(), TableBufferError> {
+        match self {
+            Self::Log(table_buffer) => Ok(table_buffer.blobs.resolve(tuple, needs).await?),
+            Self::Lsm(_) => Ok(()),
+        }
+    }
+
+    /// Stream the live tuples of the table which the scan may need. A columnar
+    /// table leaves out the tuples and columns the filter rules out; other
+    /// tables stream every tuple in full.
+    pub(crate) async fn scan_filtered(
+        &self,
+        filter: ScanFilter,
+    ) -> Result<TupleStream<'_>, TableBufferError> {
+        match self {
+            Self::Log(_) => self.scan().await,
+            Self::Lsm(tree) => Ok(Box::pin(
+                tree.scan(Some(Arc::new(filter)))
+                    .await?
+                    .map(|result| result.map_err(Into::into)),
+            )),
+        }
+    }
+
+    pub(crate) fn size(&self) -> usize {
+        match self {
+            Self::Log(table_buffer) => table_buffer.size(),
+            Self::Lsm(tree) => tree.size(),
+        }
+    }
+
+    pub(crate) async fn stats(&self) -> TableStats {
+        match self {
+            Self::Log(table_buffer) => table_buffer.stats().await,
+            Self::Lsm(tree) => tree.stats(),
+        }
+    }
+
+    /// The partially written entry cut off the table's block when it was
+    /// opened, if any. The files of an LSM tree are never partially written.
+    pub(crate) fn recovery(&self) -> Option<&TailRecovery> {
+        match self {
+            Self::Log(table_buffer) => table_buffer.recovery.as_ref(),
+            Self::Lsm(_) => None,
+        }
+    }
+
+    /// Make everything written to the table durable: sync a log and save its
+    /// index to the hint file, or flush the memtable of an LSM tree.
+    pub(crate) async fn checkpoint(&mut self) -> Result<(), TableBufferError> {
+        match self {
+            Self::Log(table_buffer) => {
+                table_buffer.blobs.sync().await?;
+                table_buffer.block.sync().await?;
+                table_buffer.write_hint().await
+            }
+            Self::Lsm(tree) => Ok(tree.flush().await?),
+        }
+    }
+
+    /// The syncs of what was written to the table, in group commit mode. The
+    /// memtable of an LSM tree is not in a file (the write-ahead log makes it
+    /// durable), so there is nothing to sync for it.
+    pub(crate) fn pending_syncs(&self) -> Result<Vec<PendingSync>, TableBufferError> {
+        match self {
+            Self::Log(table_buffer) => Ok(table_buffer
+                .block
+                .pending_sync()?
+                .into_iter()
+                .chain(table_buffer.blobs.pending_sync())
+                .collect()),
+            Self::Lsm(_) => Ok(vec![]),
+        }
+    }
+
+    /// Remove the blob files retired by a compaction of a log.
+    pub(crate) fn remove_retired_files(&mut self) -> Result<(), TableBufferError> {
+        match self {
+            Self::Log(table_buffer) => Ok(table_buffer.blobs.remove_retired()?),
+            Self::Lsm(_) => Ok(()),
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn as_log(&self) -> Option<&TableBuffer> {
+        match self {
+            Self::Log(table_buffer) => Some(table_buffer),
+            Self::Lsm(_) => None,
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn as_log_mut(&mut self) -> Option<&mut TableBuffer> {
+        match self {
+            Self::Log(table_buffer) => Some(table_buffer),
+            Self::Lsm(_) => None,
+        }
+    }
+}
+
 // helpers
 pub fn get_table_path_(directory_path: &Path, table_name: &TableName) -> PathBuf {
     let table_rel_path = PathBuf::from(format!("{}.dat", table_name.0.as_str()));
     directory_path.join(table_rel_path)
 }
+
+pub(crate) fn get_hint_path(block_path: &Path) -> PathBuf {
+    let mut file_name = block_path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".");
+    file_name.push(HINT_FILE_SUFFIX);
+    block_path.with_file_name(file_name)
+}
+
+/// Remove the hint file of the block at the given path, if there is one
+pub(crate) fn remove_hint_file(backend: &Backend, block_path: &Path) -> Result<(), std::io::Error> {
+    match backend.remove_file(&get_hint_path(block_path)) {
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
@@ -0,0 +1,195 @@
+/// The write-ahead log (WAL) of the database. Every write (DDL and DML) is
+/// first appended to the WAL as a batch of operations, and only then applied to
+/// the catalog and the tables' blocks. When the database is opened, the
+/// batches in the WAL are applied again, so that a crash in the middle of
+/// applying a batch doesn't leave it half done. This makes a batch, which can
+/// span multiple tables, atomic.
+///
+/// A batch is written as a single checksummed entry, so a batch which was not
+/// written entirely before a crash is cut off the end of the WAL (like a torn
+/// tail of a table's block) and none of it is applied.
+///
+/// Operations are stored in their physical form (e.g. the full tuple to
+/// write, not the columns to update), so that applying them again on top of
+/// their own effects doesn't change the outcome. Once everything in the WAL
+/// is synced to the tables' blocks (a checkpoint), the WAL is truncated.
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    backend::{Backend, BlockStorage},
+    catalog::Catalog,
+    query::{
+        ddl,
+        error::QueryError,
+        types::{ColumnValue, Durability, TableDefinition, TableName},
+    },
+    storage::{PendingSync, StorageError, Tuple, FILE_HEADER_SIZE},
+};
+
+const WAL_FILE_NAME: &str = "wal.log";
+
+// the WAL has operations on all the tables, so it doesn't have a schema of its
+// own; this stands in for it in the WAL's file header
+pub(crate) const WAL_SCHEMA_FINGERPRINT: u32 = 0;
+
+/// The write-ahead log, backed by a block file in the database directory.
+#[derive(Debug, Clone)]
+pub(crate) struct Wal {
+    block: Arc<dyn BlockStorage>,
+    /// Size of the batches in the WAL, in bytes
+    size: u64,
+}
+
+/// A batch of operations, which are applied atomically.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct WalRecord {
+    pub(crate) operations: Vec<Operation>,
+}
+
+/// A physical write operation on the database.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum Operation {
+    CreateTable(TableDefinition),
+    DropTable(TableName),
+    /// Write a version of a tuple
+    Put {
+        table: TableName,
+        key: ColumnValue,
+        tuple: Tuple,
+    },
+    /// Delete the tuple with the given key
+    Delete {
+        table: TableName,
+        key: ColumnValue,
+    },
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum WalError {
+    #[error("Write-ahead log storage error: {0}")]
+    StorageError(#[from] StorageError),
+    #[error("Write-ahead log file operation failed. {0}")]
+    FileOperation(#[from] std::io::Error),
+}
+
+impl Wal {
+    /// Open the WAL in the given database directory, creating it if it doesn't
+    /// exist. Returns the batches in the WAL, which are to be applied again. A
+    /// partially written batch at the end of the WAL is cut off.
+    pub(crate) async fn open(
+        backend: &Backend,
+        directory_path: &Path,
+        durability: Durability,
+    ) -> Result<(Self, Vec<WalRecord>), WalError> {
+        let wal_path = get_wal_path(directory_path);
+        if !backend.file_exists(&wal_path) {
+            backend.create_block(&wal_path, WAL_SCHEMA_FINGERPRINT)?;
+            backend.sync_parent_dir(&wal_path)?;
+        }
+        let block = backend.open_block(&wal_path, durability, WAL_SCHEMA_FINGERPRINT)?;
+
+        let mut records = vec![];
+        let mut size = 0;
+        let mut stream = Box::pin(block.get_records::<WalRecord>().await?);
+        while let Some(result) = stream.next().await {
+            match result {
+                Ok((record, length)) => {
+                    records.push(record);
+                    size += length;
+                }
+                // the batch was not written entirely, so it was never applied
+                Err(StorageError::TornTail { offset, .. }) => {
+                    drop(stream);
+                    block.truncate(offset).await?;
+                    break;
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok((Self { block, size }, records))
+    }
+
+    /// Append a batch to the WAL. The WAL is synced according to its
+    /// durability mode; `force_sync` syncs it regardless.
+    pub(crate) async fn append(
+        &mut self,
+        record: &WalRecord,
+        force_sync: bool,
+    ) -> Result<(), WalError> {
+        self.size += self.block.write_record(record).await?;
+        if force_sync && self.block.durability() == Durability::NoSync {
+            self.block.sync().await?;
+        }
+        Ok(())
+    }
+
+    /// Empty the WAL. Everything in it must have been synced to the tables'
+    /// blocks before this.
+    pub(crate) async fn truncate(&mut self) -> Result<(), WalError> {
+        self.block.truncate(FILE_HEADER_SIZE).await?;
+        self.size = 0;
+        Ok(())
+    }
+
+    /// The sync of the batches appended to the WAL, in group commit mode
+    pub(crate) fn pending_sync(&self) -> Option<PendingSync> {
+        self.block.pending_sync()
+    }
+
+    pub(crate) fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl Operation {
+    /// The table this operation writes to
+    pub(crate) fn table_name(&self) -> &TableName {
+        match self {
+            Operation::CreateTable(table) => &table.name,
+            Operation::DropTable(table) => table,
+            Operation::Put { table, .. } => table,
+            Operation::Delete { table, .. } => table,
+        }
+    }
+
+    /// Apply the operation to the catalog and the tables' blocks. The
+    /// operation may have been applied before the database was last closed;
+    /// this is taken into account, so that applying it again is harmless.
+    pub(crate) async fn apply(self, catalog: &mut Catalog) -> Result<(), QueryError> {
+        match self {
+            Operation::CreateTable(table) => {
+                if catalog.get_table(&table.name).is_none() {
+                    ddl::create_table_on_disk(table, catalog).await?;
+                }
+            }
+            Operation::DropTable(table_name) => {
+                ddl::drop_table_from_disk(table_name, catalog).await?;
+            }
+            Operation::Put { table, key, tuple } => {
+                // the table can only be missing if it was dropped by a later
+                // operation, before the database was closed
+                if let Some(table) = catalog.get_table_mut(&table) {
+                    table.storage.write(key, tuple).await?;
+                }
+            }
+            Operation::Delete { table, key } => {
+                if let Some(table) = catalog.get_table_mut(&table) {
+                    if table.storage.contains_key(&key).await? {
+                        table.storage.delete(key).await?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn get_wal_path(directory_path: &Path) -> PathBuf {
+    directory_path.join(WAL_FILE_NAME)
+}
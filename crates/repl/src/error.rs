@@ -20,6 +20,10 @@ pub enum ParseError {
     Get,
     #[error("Error parsing JSON document for put item. {0}")]
     Put(serde_json::Error),
+    #[error("table name and key (separated by spaces) not found in delete command.")]
+    Delete,
+    #[error("Error parsing JSON filter expression for delete-where. {0}")]
+    DeleteWhere(serde_json::Error),
     #[error("Error: {0}")]
     CreateTable(CreateTableError),
     #[error("Unknown command: {0}")]
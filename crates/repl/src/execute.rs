@@ -54,8 +54,18 @@ pub async fn execute_command(
             Ok(Output::ResultOne(r))
         }
         Command::Put(cmd) => {
-            db.put_item(cmd).await?;
-            println!("Inserted");
+            let key = db.put_item(cmd).await?;
+            println!("Inserted {}", key);
+            Ok(Output::Done)
+        }
+        Command::Delete(cmd) => {
+            db.delete_item(cmd).await?;
+            println!("Deleted");
+            Ok(Output::Done)
+        }
+        Command::DeleteWhere(cmd) => {
+            let deleted = db.delete_where(cmd).await?;
+            println!("Deleted {} records", deleted);
             Ok(Output::Done)
         }
         Command::ListTables => {
@@ -68,5 +78,13 @@ pub async fn execute_command(
             println!();
             Ok(Output::Done)
         }
+        Command::CompactTable(table) => {
+            let stats = db.compact_table(&table).await?;
+            println!(
+                "Compacted table {}: {} bytes -> {} bytes",
+                stats.table_name, stats.old_size, stats.new_size
+            );
+            Ok(Output::Done)
+        }
     }
 }
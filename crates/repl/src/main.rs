@@ -3,7 +3,7 @@ mod execute;
 mod parse;
 
 use clap::Parser;
-use dumbdb::Database;
+use dumbdb::{Database, DatabaseOptions, EncryptionKey};
 use error::AppError;
 use execute::{execute_command, OurRecord, Output};
 use parse::parse_command;
@@ -32,6 +32,40 @@ struct ReplOptions {
     /// Filepath to store history.
     #[arg(long, default_value = HISTORY_FILE)]
     history_file: PathBuf,
+
+    /// Migrate the database's files, written by an older version of dumbdb,
+    /// to the current format before opening it.
+    #[arg(long)]
+    migrate: bool,
+
+    /// Restore the backup in this directory to the database directory (which
+    /// must be empty, or not exist yet) before starting the REPL.
+    #[arg(long)]
+    restore_from: Option<String>,
+
+    /// File with the key to encrypt the database with (64 hex digits).
+    #[arg(long, conflicts_with = "encryption_key_env")]
+    encryption_key_file: Option<PathBuf>,
+
+    /// Environment variable with the key to encrypt the database with (64 hex
+    /// digits).
+    #[arg(long)]
+    encryption_key_env: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Check the integrity of the database, print the problems found and exit,
+    /// instead of starting the REPL. Exits with an error if there are problems
+    /// left.
+    Fsck {
+        /// Repair the problems which can be repaired.
+        #[arg(long)]
+        repair: bool,
+    },
 }
 
 #[tokio::main]
@@ -41,9 +75,62 @@ async fn main() {
     let config = ReplOptions::parse();
     println!("Loading {}", config.database_path);
 
-    let mut db = Database::new(&config.database_path)
-        .await
-        .expect("Failed to initialize the database.");
+    let encryption_key = match (&config.encryption_key_file, &config.encryption_key_env) {
+        (Some(path), _) => {
+            Some(EncryptionKey::from_file(path).expect("Failed to read the key file."))
+        }
+        (None, Some(name)) => {
+            Some(EncryptionKey::from_env(name).expect("Failed to read the encryption key."))
+        }
+        (None, None) => None,
+    };
+    let options = DatabaseOptions {
+        encryption_key,
+        ..Default::default()
+    };
+
+    if let Some(Command::Fsck { repair }) = config.command {
+        let report = Database::fsck_with_options(&config.database_path, options, repair)
+            .await
+            .expect("Failed to check the database.");
+        for problem in &report.problems {
+            println!("{}", problem);
+        }
+        println!(
+            "Checked {} tables, {} entries: {} problems",
+            report.tables,
+            report.entries,
+            report.problems.len()
+        );
+        std::process::exit(if report.is_clean() { 0 } else { 1 });
+    }
+
+    if config.migrate {
+        let migrated = Database::migrate_with_options(&config.database_path, options.clone())
+            .await
+            .expect("Failed to migrate the database.");
+        for path in migrated {
+            println!("Migrated {} to the current format", path.display());
+        }
+    }
+
+    let mut db = match &config.restore_from {
+        Some(backup_path) => {
+            println!("Restoring the backup in {}", backup_path);
+            Database::restore(backup_path, &config.database_path, options)
+                .await
+                .expect("Failed to restore the backup.")
+        }
+        None => Database::new_with_options(&config.database_path, options)
+            .await
+            .expect("Failed to initialize the database."),
+    };
+    for recovery in db.tail_recoveries() {
+        println!(
+            "Warning: table '{}': cut off {} bytes of a partially written entry at the end of segment {} of its block; recovered up to byte {}",
+            recovery.table_name, recovery.truncated_bytes, recovery.segment, recovery.valid_length
+        );
+    }
     let mut editor = DefaultEditor::new().expect("Failed to create TUI editor");
     if editor.load_history(&config.history_file).is_err() {
         println!("No previous history.");
@@ -66,6 +153,15 @@ async fn main() {
                         pretty_print_output(res);
                     }
                 }
+                // compact the tables the command left with too much dead data
+                for table_name in db.take_due_compactions().await {
+                    if let Err(err) = db.compact_table(&table_name).await {
+                        println!(
+                            "Warning: compaction of table '{}' failed: {}",
+                            table_name, err
+                        );
+                    }
+                }
             }
             Err(ReadlineError::Interrupted) => {
                 println!("CTRL-C");
@@ -84,6 +180,11 @@ async fn main() {
         }
     }
     let _ = editor.save_history(&config.history_file);
+    // sync the tables and save their indexes, so that they don't have to be
+    // rebuilt the next time
+    if let Err(err) = db.checkpoint().await {
+        println!("Error: {}", err);
+    }
 }
 
 async fn eval(db: &mut Database, input: String) -> Result<Output<OurRecord>, AppError> {
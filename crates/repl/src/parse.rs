@@ -1,6 +1,7 @@
 use crate::error::{CreateTableError, ParseError};
 use dumbdb::{
-    CreateTableCommand, DropTableCommand, GetItemCommand, PutItemCommand, TableDefinition,
+    CreateTableCommand, DeleteItemCommand, DeleteWhereCommand, DropTableCommand, GetItemCommand,
+    PutItemCommand, TableDefinition, TableName,
 };
 
 #[derive(Debug)]
@@ -12,27 +13,37 @@ pub enum Command {
     // Actual DML commands
     Get(GetItemCommand),
     Put(PutItemCommand),
+    Delete(DeleteItemCommand),
+    DeleteWhere(DeleteWhereCommand),
     // Filter(FilterItemCommand),
 
     // Meta commands
     ListTables,
+    CompactTable(TableName),
 }
 
 pub fn parse_command(input: String) -> Result<Command, ParseError> {
     let input = input.trim();
     // get <table-name> <key>
     // put <table-name> <json-val>
+    // delete <table-name> <key>
+    // delete-where <table-name> <json-filter-expression>
     // create-table <table-name> <json-val> | *<json-val> = {"columns": [{name: str, type: Type}], "primary_key": str}
     // LATER: create-table authors [id Integer, name Text] [primary key id]
     // drop-table <table-name>
+    // .tables
+    // .compact <table-name>
     let (command_name, command_args) = take_while(input, ' ');
 
     match command_name {
         "get" => Ok(Command::Get(parse_get(command_args)?)),
         "put" => Ok(Command::Put(parse_put(command_args)?)),
+        "delete" => Ok(Command::Delete(parse_delete(command_args)?)),
+        "delete-where" => Ok(Command::DeleteWhere(parse_delete_where(command_args)?)),
         "create-table" => Ok(Command::CreateTable(parse_create_table(command_args)?)),
         "drop-table" => Ok(Command::DropTable(parse_drop_table(command_args))),
         ".tables" => Ok(Command::ListTables),
+        ".compact" => Ok(Command::CompactTable(command_args.trim().into())),
         _ => Err(ParseError::UnknownCommand(command_name.to_string())),
     }
 }
@@ -62,22 +73,59 @@ fn parse_put(tokens: &str) -> Result<PutItemCommand, ParseError> {
     })
 }
 
+fn parse_delete(tokens: &str) -> Result<DeleteItemCommand, ParseError> {
+    match tokens.split_whitespace().collect::<Vec<_>>().as_slice() {
+        [name, key] => Ok(DeleteItemCommand {
+            table_name: (*name).into(),
+            key: (*key).into(),
+        }),
+        _ => Err(ParseError::Delete),
+    }
+}
+
+fn parse_delete_where(tokens: &str) -> Result<DeleteWhereCommand, ParseError> {
+    let (table_name, args) = take_while(tokens, ' ');
+    let filter = serde_json::from_str(args).map_err(ParseError::DeleteWhere)?;
+    Ok(DeleteWhereCommand {
+        table_name: table_name.into(),
+        filter,
+    })
+}
+
 fn parse_create_table(tokens: &str) -> Result<CreateTableCommand, ParseError> {
     let (name, rest) = take_while(tokens, ' ');
     let json_val = serde_json::from_str(rest).map_err(CreateTableError::InvalidJson)?;
 
-    let cols = get_from_json_object(&json_val, "columns")
-        .ok_or_else(|| CreateTableError::ColumnsNotFound)?;
+    let cols =
+        get_from_json_object(&json_val, "columns").ok_or(CreateTableError::ColumnsNotFound)?;
     let columns = serde_json::from_value(cols).map_err(CreateTableError::InvalidJson)?;
 
     let pk = get_from_json_object(&json_val, "primary_key")
-        .ok_or_else(|| CreateTableError::PrimaryKeyNotFound)?;
+        .ok_or(CreateTableError::PrimaryKeyNotFound)?;
     let primary_key = serde_json::from_value(pk).map_err(CreateTableError::InvalidJson)?;
 
+    let durability = get_from_json_object(&json_val, "durability")
+        .map(serde_json::from_value)
+        .transpose()
+        .map_err(CreateTableError::InvalidJson)?;
+
+    let compression = get_from_json_object(&json_val, "compression")
+        .map(serde_json::from_value)
+        .transpose()
+        .map_err(CreateTableError::InvalidJson)?;
+
+    let engine = get_from_json_object(&json_val, "engine")
+        .map(serde_json::from_value)
+        .transpose()
+        .map_err(CreateTableError::InvalidJson)?;
+
     Ok(TableDefinition {
         name: name.into(),
         columns,
         primary_key,
+        durability,
+        compression,
+        engine,
     })
 }
 
@@ -1,6 +1,8 @@
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::process;
 use std::sync::Arc;
+use std::time::Duration;
 
 use axum::extract::Path;
 use axum::extract::State;
@@ -8,6 +10,7 @@ use axum::http::StatusCode;
 use axum::response::IntoResponse;
 use axum::routing::get;
 use axum::routing::post;
+use axum::Extension;
 use axum::Json;
 use axum::Router;
 use axum_macros::debug_handler;
@@ -21,11 +24,16 @@ use tokio::sync::RwLock;
 
 use dumbdb::error::QueryError;
 use dumbdb::{
-    Database, DropTableCommand, FilterItemCommand, GetItemCommand, PutItemCommand, Record,
-    TableDefinition, TableName,
+    backup_path, BackupError, BackupManifest, ColumnValue, CompactionError, CompactionStats,
+    Database, DatabaseOptions, DeleteItemCommand, DeleteWhereCommand, DropTableCommand, Durability,
+    EncryptionKey, FilterItemCommand, GetItemCommand, KeyError, PutItemCommand, Record,
+    TableDefinition, TableName, TableStats, UpdateItemCommand, WriteBatchCommand,
 };
 
 const DEFAULT_PORT: u16 = 3333;
+const DEFAULT_GROUP_COMMIT_WINDOW_MS: u64 = 5;
+/// How often the tables due for an automatic compaction are compacted
+const AUTO_COMPACTION_INTERVAL: Duration = Duration::from_secs(1);
 
 /// Our server's CLI
 #[derive(clap::Parser, Debug)]
@@ -38,6 +46,71 @@ struct ServerOptions {
     /// Port on which to run the server.
     #[arg(short, long, default_value_t = DEFAULT_PORT)]
     port: u16,
+
+    /// How writes are made durable. Tables can override this in their
+    /// definition.
+    #[arg(long, value_enum, default_value_t = DurabilityMode::NoSync)]
+    durability: DurabilityMode,
+
+    /// Commit window (in milliseconds) of the group-commit durability mode.
+    #[arg(long, default_value_t = DEFAULT_GROUP_COMMIT_WINDOW_MS)]
+    group_commit_window_ms: u64,
+
+    /// Migrate the database's files, written by an older version of dumbdb,
+    /// to the current format before opening it.
+    #[arg(long)]
+    migrate: bool,
+
+    /// Restore the backup in this directory to the database directory (which
+    /// must be empty, or not exist yet) before starting the server.
+    #[arg(long)]
+    restore_from: Option<String>,
+
+    /// Directory to write backups to; each backup is a directory in it, named
+    /// in the backup request. Backups can't be made without it.
+    #[arg(long)]
+    backup_dir: Option<PathBuf>,
+
+    /// File with the key to encrypt the database with (64 hex digits).
+    #[arg(long, conflicts_with = "encryption_key_env")]
+    encryption_key_file: Option<PathBuf>,
+
+    /// Environment variable with the key to encrypt the database with (64 hex
+    /// digits).
+    #[arg(long)]
+    encryption_key_env: Option<String>,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum DurabilityMode {
+    /// Don't sync writes to disk; leave it to the OS
+    NoSync,
+    /// Sync every write to disk
+    SyncEachWrite,
+    /// Concurrent writes share a sync to disk
+    GroupCommit,
+}
+
+impl ServerOptions {
+    fn database_options(&self) -> Result<DatabaseOptions, KeyError> {
+        let durability = match self.durability {
+            DurabilityMode::NoSync => Durability::NoSync,
+            DurabilityMode::SyncEachWrite => Durability::SyncEachWrite,
+            DurabilityMode::GroupCommit => Durability::GroupCommit {
+                window_ms: self.group_commit_window_ms,
+            },
+        };
+        let encryption_key = match (&self.encryption_key_file, &self.encryption_key_env) {
+            (Some(path), _) => Some(EncryptionKey::from_file(path)?),
+            (None, Some(name)) => Some(EncryptionKey::from_env(name)?),
+            (None, None) => None,
+        };
+        Ok(DatabaseOptions {
+            durability,
+            encryption_key,
+            ..Default::default()
+        })
+    }
 }
 
 struct AppState {
@@ -60,39 +133,138 @@ async fn main() {
         .init();
 
     let server_options = ServerOptions::parse();
+    let database_options = match server_options.database_options() {
+        Ok(database_options) => database_options,
+        Err(err) => {
+            println!("FATAL ERROR: {}", err);
+            process::exit(1);
+        }
+    };
+
+    if server_options.migrate {
+        match Database::migrate_with_options(
+            &server_options.database_path,
+            database_options.clone(),
+        )
+        .await
+        {
+            Ok(migrated) => {
+                for path in migrated {
+                    tracing::info!("migrated {} to the current format", path.display());
+                }
+            }
+            Err(err) => {
+                println!("FATAL ERROR: {}", err);
+                process::exit(1);
+            }
+        }
+    }
 
-    let db = match Database::new(&server_options.database_path).await {
+    let db = match &server_options.restore_from {
+        Some(backup_path) => {
+            tracing::info!(
+                "restoring the backup in {} to {}",
+                backup_path,
+                server_options.database_path
+            );
+            Database::restore(backup_path, &server_options.database_path, database_options).await
+        }
+        None => Database::new_with_options(&server_options.database_path, database_options).await,
+    };
+    let db = match db {
         Ok(db) => db,
         Err(err) => {
             println!("FATAL ERROR: {}", err);
             process::exit(1);
         }
     };
+    for recovery in db.tail_recoveries() {
+        tracing::warn!(
+            "table '{}': cut off {} bytes of a partially written entry at the end of segment {} of its block; recovered up to byte {}",
+            recovery.table_name,
+            recovery.truncated_bytes,
+            recovery.segment,
+            recovery.valid_length
+        );
+    }
 
     let shared_state = Arc::new(AppState {
         db: RwLock::new(db),
     });
+    tokio::spawn(auto_compaction(shared_state.clone()));
 
     // our router
-    let app = Router::new()
+    let mut app = Router::new()
         .route("/", get(root))
         .route("/healthz", get(healthz))
         .route("/api/v1/ddl/create_table", post(create_table_handler))
         .route("/api/v1/ddl/drop_table", post(drop_table_handler))
         .route("/api/v1/ddl/get_table_size/:table", get(table_size_handler))
+        .route(
+            "/api/v1/ddl/get_table_stats/:table",
+            get(table_stats_handler),
+        )
         .route("/api/v1/dml/get_item", post(get_item_handler))
         .route("/api/v1/dml/put_item", post(put_item_handler))
+        .route("/api/v1/dml/update_item", post(update_item_handler))
+        .route("/api/v1/dml/delete_item", post(delete_item_handler))
+        .route("/api/v1/dml/delete_where", post(delete_where_handler))
         .route("/api/v1/dml/filter_item", post(filter_item_handler))
+        .route("/api/v1/write_batch", post(write_batch_handler))
+        .route("/api/v1/admin/compact_table", post(compact_table_handler))
         // TODO: add tracing/logging back later. When we have proper benchmarks
         // indicating performance of operations, and we can prove that
         // tracing/logging is negligible
-        .with_state(shared_state);
+        .with_state(shared_state.clone());
+    // backups are only written to the backup directory, so there are none
+    // without one
+    if let Some(backup_dir) = server_options.backup_dir {
+        app = app.route(
+            "/api/v1/admin/backup",
+            post(backup_handler)
+                .layer(Extension(Arc::new(backup_dir)))
+                .with_state(shared_state.clone()),
+        );
+    }
 
     let addr = SocketAddr::from(([0, 0, 0, 0], server_options.port));
     // run our app with hyper, listening globally on port 3000
     let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
     tracing::info!("dumbdb listening on {}", addr);
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_signal())
+        .await
+        .unwrap();
+
+    // sync the tables and save their indexes, so that they don't have to be
+    // rebuilt on the next startup
+    tracing::info!("shutting down");
+    let mut db = shared_state.db.write().await;
+    if let Err(err) = db.checkpoint().await {
+        tracing::error!("failed to checkpoint the database: {}", err);
+    }
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl+C handler");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install signal handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
 }
 
 async fn root() -> &'static str {
@@ -107,8 +279,10 @@ async fn create_table_handler(
     State(state): State<Arc<AppState>>,
     Json(payload): Json<TableDefinition>,
 ) -> Result<Json<SuccessMessage>, AppError> {
-    let mut db = state.db.write().await;
-    db.create_table(payload).await?;
+    // the lock is released before waiting for the write to be synced, so that
+    // the writes which come in meanwhile share the sync (in group commit mode)
+    let commit = state.db.write().await.start_create_table(payload).await?;
+    commit.wait().await?;
     Ok(axum::response::Json(SuccessMessage::new("table created")))
 }
 
@@ -116,8 +290,8 @@ async fn drop_table_handler(
     State(state): State<Arc<AppState>>,
     Json(payload): Json<DropTableCommand>,
 ) -> Result<Json<SuccessMessage>, AppError> {
-    let mut db = state.db.write().await;
-    db.drop_table(payload).await?;
+    let commit = state.db.write().await.start_drop_table(payload).await?;
+    commit.wait().await?;
     Ok(axum::response::Json(SuccessMessage::new("table deleted")))
 }
 
@@ -130,6 +304,15 @@ async fn table_size_handler(
     Ok(axum::response::Json(size))
 }
 
+async fn table_stats_handler(
+    State(state): State<Arc<AppState>>,
+    Path(table): Path<TableName>,
+) -> Result<Json<Option<TableStats>>, AppError> {
+    let db = state.db.read().await;
+    let stats = db.get_stats(&table).await;
+    Ok(axum::response::Json(stats))
+}
+
 #[debug_handler]
 async fn get_item_handler(
     State(state): State<Arc<AppState>>,
@@ -143,9 +326,48 @@ async fn get_item_handler(
 async fn put_item_handler(
     State(state): State<Arc<AppState>>,
     Json(payload): Json<PutItemCommand>,
+) -> Result<Json<PutItemResponse>, AppError> {
+    let commit = state.db.write().await.start_put_item(payload).await?;
+    let key = commit.wait().await?;
+    Ok(axum::response::Json(PutItemResponse {
+        message: SuccessMessage::default().message,
+        key,
+    }))
+}
+
+async fn update_item_handler(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<UpdateItemCommand>,
 ) -> Result<Json<SuccessMessage>, AppError> {
-    let mut db = state.db.write().await;
-    db.put_item(payload).await?;
+    let commit = state.db.write().await.start_update_item(payload).await?;
+    commit.wait().await?;
+    Ok(axum::response::Json(SuccessMessage::default()))
+}
+
+async fn delete_item_handler(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<DeleteItemCommand>,
+) -> Result<Json<SuccessMessage>, AppError> {
+    let commit = state.db.write().await.start_delete_item(payload).await?;
+    commit.wait().await?;
+    Ok(axum::response::Json(SuccessMessage::default()))
+}
+
+async fn delete_where_handler(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<DeleteWhereCommand>,
+) -> Result<Json<DeleteWhereResponse>, AppError> {
+    let commit = state.db.write().await.start_delete_where(payload).await?;
+    let deleted = commit.wait().await?;
+    Ok(axum::response::Json(DeleteWhereResponse { deleted }))
+}
+
+async fn write_batch_handler(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<WriteBatchCommand>,
+) -> Result<Json<SuccessMessage>, AppError> {
+    let commit = state.db.write().await.start_write_batch(payload).await?;
+    commit.wait().await?;
     Ok(axum::response::Json(SuccessMessage::default()))
 }
 
@@ -158,6 +380,77 @@ async fn filter_item_handler(
     Ok(axum::response::Json(result))
 }
 
+async fn compact_table_handler(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<CompactTableCommand>,
+) -> Result<Json<CompactionStats>, AppError> {
+    let stats = compact_table(&state, &payload.table_name).await?;
+    Ok(axum::response::Json(stats))
+}
+
+async fn compact_table(
+    state: &AppState,
+    table_name: &TableName,
+) -> Result<CompactionStats, QueryError> {
+    // the compaction itself runs without holding the lock, so that reads and
+    // writes can go on in the meantime. The write lock is only needed to swap
+    // in the compacted block.
+    let mut compaction = {
+        let db = state.db.read().await;
+        db.start_compaction(table_name).await?
+    };
+    compaction.run().await?;
+    let mut db = state.db.write().await;
+    db.finish_compaction(compaction).await
+}
+
+// compact the tables which writes have left with too much dead data, in the
+// background. A failed compaction is only logged; the table is tried again
+// once it is written to.
+async fn auto_compaction(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(AUTO_COMPACTION_INTERVAL);
+    loop {
+        interval.tick().await;
+        let tables = state.db.write().await.take_due_compactions().await;
+        for table_name in tables {
+            match compact_table(&state, &table_name).await {
+                Ok(stats) => tracing::info!(
+                    "compacted table '{}' from {} to {} bytes",
+                    table_name,
+                    stats.old_size,
+                    stats.new_size
+                ),
+                Err(err) => tracing::warn!("compaction of table '{}' failed: {}", table_name, err),
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CompactTableCommand {
+    table_name: TableName,
+}
+
+async fn backup_handler(
+    State(state): State<Arc<AppState>>,
+    Extension(backup_dir): Extension<Arc<PathBuf>>,
+    Json(payload): Json<BackupCommand>,
+) -> Result<Json<BackupManifest>, AppError> {
+    let path = backup_path(&backup_dir, &payload.name)?;
+    // the lock is only held to start the backup; reads and writes go on while
+    // it is written
+    let backup = state.db.read().await.start_backup()?;
+    let manifest = backup.write(&path).await?;
+    Ok(axum::response::Json(manifest))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BackupCommand {
+    /// Name of the directory in the backup directory to write the backup to;
+    /// it must be empty, or not exist yet
+    name: String,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct SuccessMessage {
     message: String,
@@ -179,6 +472,18 @@ impl Default for SuccessMessage {
     }
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+struct PutItemResponse {
+    message: String,
+    /// The primary key of the new record, which may have been generated
+    key: ColumnValue,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DeleteWhereResponse {
+    deleted: usize,
+}
+
 #[derive(Debug)]
 struct AppError {
     error: QueryError,
@@ -204,17 +509,38 @@ where
 impl IntoResponse for AppError {
     fn into_response(self) -> axum::response::Response {
         match self.error {
+            QueryError::CompactionError(
+                CompactionError::Conflict(_) | CompactionError::InProgress(_),
+            ) => (StatusCode::CONFLICT, json_response(self)).into_response(),
+            QueryError::BackupError(
+                BackupError::DirectoryNotEmpty(_) | BackupError::InvalidBackupName(_),
+            ) => (StatusCode::BAD_REQUEST, json_response(self)).into_response(),
             QueryError::InternalError(_)
             | QueryError::CatalogError(_)
+            | QueryError::CompactionError(_)
+            | QueryError::MigrationError(_)
+            | QueryError::FsckError(_)
+            | QueryError::BackupError(_)
+            | QueryError::WalError(_)
+            | QueryError::TablePoisoned(_)
             | QueryError::TableStorageError(_) => {
                 (StatusCode::INTERNAL_SERVER_ERROR, json_response(self)).into_response()
             }
             QueryError::TableNotFound(_)
             | QueryError::TableAlreadyExists(_)
+            | QueryError::PrimaryKeyNotInColumns(_)
             | QueryError::ColumnTypeMismatch { .. }
             | QueryError::ItemMustContainPrimaryKey(_)
             | QueryError::PrimaryKeyAlreadyExists(_)
-            | QueryError::UnknownColumnInItem(_) => {
+            | QueryError::RecordNotFound(_)
+            | QueryError::PrimaryKeyUpdateNotAllowed(_)
+            | QueryError::PrimaryKeyDefaultNotAllowed(_)
+            | QueryError::NullNotAllowed(_)
+            | QueryError::GeneratedColumnNotAllowed(_)
+            | QueryError::UnknownColumnInItem(_)
+            | QueryError::UnknownColumn(_)
+            | QueryError::IncomparableTypes { .. }
+            | QueryError::InvalidValue(_) => {
                 (StatusCode::BAD_REQUEST, json_response(self)).into_response()
             }
         }